@@ -0,0 +1,200 @@
+use std::any::Any;
+use std::cmp::Ordering as Order;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use parking_lot::Mutex;
+
+// A value of the registered aggregate, with its type erased so the tree
+// needs no extra type parameter.
+pub(crate) type Summary = Arc<dyn Any + Send + Sync>;
+
+type LiftFn<K, V> = Arc<dyn Fn(&K, &V) -> Summary + Send + Sync>;
+type CombineFn = Arc<dyn Fn(&Summary, &Summary) -> Summary + Send + Sync>;
+type RankFn<V> = Arc<dyn Fn(&V, &V) -> Order + Send + Sync>;
+// best entries of a subtree under the registered ranking, best first
+pub(crate) type Best<K, V> = Arc<Vec<(K, V)>>;
+// a cached summary with the stamp and tree epoch it was computed under
+type Cached<T> = Mutex<Option<(u64, u64, T)>>;
+
+// The aggregate registered on a tree through `Bptree::set_aggregate`.
+pub(crate) struct Aggregator<K, V> {
+    pub(crate) identity: Summary,
+    pub(crate) lift: LiftFn<K, V>,
+    pub(crate) combine: CombineFn,
+}
+
+impl<K, V> Aggregator<K, V> {
+    pub(crate) fn new<A, L, C>(identity: A, lift: L, combine: C) -> Self
+        where A: Send + Sync + 'static,
+              L: Fn(&K, &V) -> A + Send + Sync + 'static,
+              C: Fn(&A, &A) -> A + Send + Sync + 'static,
+    {
+        Self{
+            identity: Arc::new(identity),
+            lift: Arc::new(move |key, val| Arc::new(lift(key, val))),
+            combine: Arc::new(move |a, b| {
+                let a = a.downcast_ref::<A>().unwrap();
+                let b = b.downcast_ref::<A>().unwrap();
+                Arc::new(combine(a, b))
+            }),
+        }
+    }
+}
+
+impl<K, V> Clone for Aggregator<K, V> {
+    fn clone(&self) -> Self {
+        Self{
+            identity: self.identity.clone(),
+            lift: self.lift.clone(),
+            combine: self.combine.clone(),
+        }
+    }
+}
+
+impl<K, V> Debug for Aggregator<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Aggregator").finish()
+    }
+}
+
+// The value ranking registered through `Bptree::set_value_ranking`: the
+// order `top_k_by_value` ranks values by, and how many of the best entries
+// of every child's subtree inner nodes keep cached.
+pub(crate) struct Ranking<V> {
+    pub(crate) width: usize,
+    cmp: RankFn<V>,
+}
+
+impl<V> Ranking<V> {
+    pub(crate) fn new<F>(width: usize, cmp: F) -> Self
+        where F: Fn(&V, &V) -> Order + Send + Sync + 'static
+    {
+        Self{
+            width,
+            cmp: Arc::new(cmp),
+        }
+    }
+
+    // Larger values first, ties going to the smaller key.
+    fn order<K: Ord>(&self, a: (&K, &V), b: (&K, &V)) -> Order {
+        (self.cmp)(b.1, a.1).then_with(|| a.0.cmp(b.0))
+    }
+
+    // Whether the entry would be among the best `k` in `top`.
+    pub(crate) fn makes_cut<K: Ord>(&self, top: &[(K, V)], k: usize, key: &K, val: &V) -> bool {
+        top.len() < k || self.order((key, val), (&top[k-1].0, &top[k-1].1)).is_lt()
+    }
+
+    // Puts the entry into `top`, kept best first and at most `k` long, if it
+    // makes the cut, and tells whether it did.
+    pub(crate) fn offer<K: Ord + Clone>(&self, top: &mut Vec<(K, V)>, k: usize, key: &K, val: &V) -> bool
+        where V: Clone
+    {
+        if !self.makes_cut(top, k, key, val) {
+            return false;
+        }
+        let at = top.partition_point(|(kept_key, kept)| self.order((kept_key, kept), (key, val)).is_lt());
+        top.insert(at, (key.clone(), val.clone()));
+        top.truncate(k);
+        true
+    }
+
+    // Orders best lists by their best entry, best first; empty ones last.
+    pub(crate) fn order_best<K: Ord>(&self, a: &[(K, V)], b: &[(K, V)]) -> Order {
+        match (a.first(), b.first()) {
+            (Some((a_key, a)), Some((b_key, b))) => self.order((a_key, a), (b_key, b)),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        }
+    }
+}
+
+impl<V> Clone for Ranking<V> {
+    fn clone(&self) -> Self {
+        Self{
+            width: self.width,
+            cmp: self.cmp.clone(),
+        }
+    }
+}
+
+impl<V> Debug for Ranking<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ranking").field("width", &self.width).finish()
+    }
+}
+
+// What an inner node keeps about one child: its live entry count, and the
+// aggregate summary and best entries of its subtree as last computed.
+// Every write below the child bumps `stamp`, so a cached summary is only
+// current while the stamp it was computed under still is. Writers touch
+// atomics only; the summaries are behind mutexes of their own that only
+// readers take.
+pub(crate) struct ChildStat<K, V> {
+    count: AtomicUsize,
+    stamp: AtomicU64,
+    summary: Cached<Summary>,
+    best: Cached<Best<K, V>>,
+}
+
+impl<K, V> ChildStat<K, V> {
+    pub(crate) fn new(count: usize) -> Self {
+        Self{
+            count: AtomicUsize::new(count),
+            stamp: AtomicU64::new(0),
+            summary: Mutex::new(None),
+            best: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    // Accounts for a change below the child, which also retires its
+    // summary, even when the count stays the same.
+    pub(crate) fn add(&self, delta: isize) {
+        if delta >= 0 {
+            self.count.fetch_add(delta as usize, Ordering::SeqCst);
+        }
+        else {
+            self.count.fetch_sub(delta.unsigned_abs(), Ordering::SeqCst);
+        }
+        self.stamp.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Read before computing a summary, to be handed back to `cache`. A
+    // write landing meanwhile moves the stamp on, so that summary is never
+    // taken for current.
+    pub(crate) fn stamp(&self) -> u64 {
+        self.stamp.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn cached(&self, stamp: u64, epoch: u64) -> Option<Summary> {
+        match &*self.summary.lock() {
+            Some((at, at_epoch, summary)) if *at == stamp && *at_epoch == epoch => Some(summary.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn cache(&self, stamp: u64, epoch: u64, summary: Summary) {
+        *self.summary.lock() = Some((stamp, epoch, summary));
+    }
+
+    pub(crate) fn cached_best(&self, stamp: u64, epoch: u64) -> Option<Best<K, V>> {
+        match &*self.best.lock() {
+            Some((at, at_epoch, best)) if *at == stamp && *at_epoch == epoch => Some(best.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn cache_best(&self, stamp: u64, epoch: u64, best: Best<K, V>) {
+        *self.best.lock() = Some((stamp, epoch, best));
+    }
+}
+
+impl<K, V> Debug for ChildStat<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.count())
+    }
+}
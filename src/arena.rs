@@ -0,0 +1,568 @@
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use super::builder::DuplicatePolicy;
+use super::cas::OccupiedError;
+use super::error::BptreeError;
+use super::hook::{Hooks, IndexHook};
+use super::kvtype::KVType;
+use super::limits::SizeLimits;
+use super::ttl::Now;
+use super::version::Versions;
+
+type NodeId = usize;
+
+#[derive(Debug)]
+enum Node<K, V> {
+    Inner{ keys: Vec<K>, children: Vec<NodeId> },
+    Leaf{ keys: Vec<K>, vals: Vec<Versions<V>>, next: Option<NodeId> },
+    // a slot on the free list
+    Free,
+}
+
+/// A B+ tree whose nodes live in one slab and refer to each other by index.
+/// There is no per-node allocation, reference count or latch, so small
+/// trees stay compact in memory and lookups touch no atomics. The price is
+/// that it has a single owner: writes take `&mut self`, and sharing it
+/// between threads means putting the whole tree behind one lock.
+///
+/// Entries behave as in a `Bptree`: they keep versions under a horizon,
+/// expire with a time to live, are reported to hooks, and are held to the
+/// size limits and duplicate policy, all of which `BptreeBuilder` sets up
+/// through `build_arena`. Range expiry, snapshots, transactions and the
+/// rest of what needs sharing are left to `Bptree`.
+#[derive(Debug)]
+pub struct ArenaBptree<K, V> {
+    nodes: Vec<Node<K, V>>,
+    free: Vec<NodeId>,
+    root: Option<NodeId>,
+    m: usize,
+    // live entries, counting those hidden by expiry until they are purged
+    len: usize,
+    seq: u64,
+    horizon: Option<u64>,
+    hooks: Hooks<K, V>,
+    limits: SizeLimits,
+    duplicates: DuplicatePolicy,
+}
+
+impl<K, V> ArenaBptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    pub fn new(m: usize) -> Self {
+        Self{
+            nodes: vec![],
+            free: vec![],
+            root: None,
+            m,
+            len: 0,
+            seq: 0,
+            horizon: None,
+            hooks: Hooks::new(),
+            limits: SizeLimits::new(None, None),
+            duplicates: DuplicatePolicy::Overwrite,
+        }
+    }
+
+    /// Number of live entries. Entries hidden by expiry count until
+    /// `purge_expired` sweeps them out.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Registers `hook` to hear about every later change to the tree's
+    /// entries: `set` and its variants, `remove` and `purge_expired`.
+    pub fn add_hook(&mut self, hook: Arc<dyn IndexHook<K, V>>) {
+        self.hooks = self.hooks.with(hook);
+    }
+
+    /// Sequence number of the last write, see `Bptree::seq`.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// See `Bptree::set_version_horizon`.
+    pub fn set_version_horizon(&mut self, horizon: Option<u64>) {
+        self.horizon = horizon;
+    }
+
+    // Only the builder picks the policy, as for `Bptree`.
+    pub(crate) fn set_duplicate_policy(&mut self, duplicates: DuplicatePolicy) {
+        self.duplicates = duplicates;
+    }
+
+    /// See `Bptree::set_max_key_len`.
+    pub fn set_max_key_len(&mut self, max: Option<usize>) {
+        self.limits.set_key(max);
+    }
+
+    /// See `Bptree::set_max_value_len`.
+    pub fn set_max_value_len(&mut self, max: Option<usize>) {
+        self.limits.set_val(max);
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        visible(self.versions(key)?, &Now::default())
+    }
+
+    /// Reads `key` as of sequence number `seq`, see `Bptree::get_at`.
+    pub fn get_at(&self, key: &K, seq: u64) -> Option<&V> {
+        self.versions(key)?.at(seq)
+    }
+
+    /// Inserts or overwrites `key`, as the tree's `DuplicatePolicy` has it.
+    /// Panics where `Bptree::set` does; `set_checked` reports the same
+    /// cases as errors instead.
+    pub fn set(&mut self, key: K, val: V) {
+        if let Err(err) = self.write(key, val, None) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Like `set`, but refuses what `set` panics on with an error, leaving
+    /// the tree as it was, see `Bptree::set_checked`.
+    pub fn set_checked(&mut self, key: K, val: V) -> Result<(), BptreeError<K, V>> {
+        self.write(key, val, None)
+    }
+
+    /// Like `set`, but the entry disappears from reads once `ttl` has
+    /// passed, until it is written again or `purge_expired` removes it.
+    pub fn set_with_ttl(&mut self, key: K, val: V, ttl: Duration) {
+        if let Err(err) = self.write(key, val, Some(Instant::now() + ttl)) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Removes `key` and returns its value. Under a version horizon the
+    /// entry is tombstoned instead, so `get_at` stays repeatable until `gc`.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let old_val = self.versions(key)?.latest()?.clone();
+        self.hooks.notify(key, Some(&old_val), None);
+        self.seq += 1;
+        self.len -= 1;
+        match self.horizon {
+            Some(horizon) => {
+                let seq = self.seq;
+                self.versions_mut(key)?.push(seq, None, Some(horizon));
+            },
+            None => {self.unlink(key);},
+        }
+        Some(old_val)
+    }
+
+    /// Removes every entry whose own time to live has run out and returns
+    /// how many there were.
+    pub fn purge_expired(&mut self) -> usize {
+        let now = Now::default();
+        let mut dead_keys = vec![];
+        for node in &self.nodes {
+            if let Node::Leaf{ keys, vals, .. } = node {
+                dead_keys.extend(keys.iter().zip(vals)
+                    .filter(|(_, versions)| versions.latest().is_some() && versions.expired(&now))
+                    .map(|(key, _)| key.clone()));
+            }
+        }
+        for key in &dead_keys {
+            let versions = self.unlink(key).expect("an expired entry is in the tree");
+            self.hooks.notify(key, versions.latest(), None);
+            self.len -= 1;
+        }
+        dead_keys.len()
+    }
+
+    /// Drops versions older than the horizon and physically removes entries
+    /// whose tombstones no reader can still see.
+    pub fn gc(&mut self) {
+        let horizon = self.horizon;
+        let mut dead_keys = vec![];
+        for node in &mut self.nodes {
+            if let Node::Leaf{ keys, vals, .. } = node {
+                for (key, versions) in keys.iter().zip(vals.iter_mut()) {
+                    if let Some(horizon) = horizon {
+                        versions.prune(horizon);
+                    }
+                    if versions.is_dead(horizon) {
+                        dead_keys.push(key.clone());
+                    }
+                }
+            }
+        }
+        for key in &dead_keys {
+            self.unlink(key);
+        }
+    }
+
+    /// Iterates over the live entries whose keys fall in `range`, in key
+    /// order, handing out references into the tree rather than copies.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> ArenaRange<'_, K, V> {
+        let start = range.start_bound();
+        let leaf = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self.find_leaf(key),
+            Bound::Unbounded => self.root.map(|root| self.edge_leaf(root)),
+        };
+        let pos = leaf.map_or(0, |leaf| match &self.nodes[leaf] {
+            Node::Leaf{ keys, .. } => keys.partition_point(|key| !(start, Bound::Unbounded).contains(key)),
+            _ => unreachable!(),
+        });
+        ArenaRange{
+            tree: self,
+            leaf,
+            pos,
+            end: range.end_bound().cloned(),
+            now: Now::default(),
+        }
+    }
+
+    pub fn iter(&self) -> ArenaRange<'_, K, V> {
+        self.range(..)
+    }
+
+    // Writes `key` as the duplicate policy has it, `Bptree::set_within` for
+    // a single owner.
+    fn write(&mut self, key: K, val: V, deadline: Option<Instant>) -> Result<(), BptreeError<K, V>> {
+        self.limits.check(&key, &val)?;
+        let old = self.versions(&key);
+        if self.duplicates != DuplicatePolicy::Overwrite {
+            if let Some(current) = old.and_then(|versions| visible(versions, &Now::default())) {
+                if self.duplicates == DuplicatePolicy::KeepFirst {
+                    return Ok(());
+                }
+                return Err(OccupiedError{ key, current: current.clone(), val }.into());
+            }
+        }
+        let was_live = old.is_some_and(|versions| versions.latest().is_some());
+        self.hooks.notify(&key, old.and_then(Versions::latest), Some(&val));
+        self.seq += 1;
+        if !was_live {
+            self.len += 1;
+        }
+        let root = match self.root {
+            Some(root) => root,
+            None => {
+                let mut versions = Versions::new(self.seq, val);
+                versions.set_deadline(deadline);
+                let leaf = self.alloc(Node::Leaf{ keys: vec![key], vals: vec![versions], next: None });
+                self.root = Some(leaf);
+                return Ok(());
+            }
+        };
+        if let Some((split_key, right)) = self.insert(root, key, val, deadline) {
+            let new_root = self.alloc(Node::Inner{ keys: vec![split_key], children: vec![root, right] });
+            self.root = Some(new_root);
+        }
+        Ok(())
+    }
+
+    fn versions(&self, key: &K) -> Option<&Versions<V>> {
+        match &self.nodes[self.find_leaf(key)?] {
+            Node::Leaf{ keys, vals, .. } => keys.binary_search(key).ok().map(|i| &vals[i]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn versions_mut(&mut self, key: &K) -> Option<&mut Versions<V>> {
+        let leaf = self.find_leaf(key)?;
+        match &mut self.nodes[leaf] {
+            Node::Leaf{ keys, vals, .. } => keys.binary_search(key).ok().map(|i| &mut vals[i]),
+            _ => unreachable!(),
+        }
+    }
+
+    // Takes `key` out of the tree, versions and all, rebalancing on the way
+    // back up.
+    fn unlink(&mut self, key: &K) -> Option<Versions<V>> {
+        let root = self.root?;
+        let versions = self.remove_from(root, key)?;
+        match &self.nodes[root] {
+            Node::Inner{ keys, children } if keys.is_empty() => {
+                self.root = Some(children[0]);
+                self.release(root);
+            },
+            Node::Leaf{ keys, .. } if keys.is_empty() => {
+                self.root = None;
+                self.release(root);
+            },
+            _ => {},
+        }
+        Some(versions)
+    }
+
+    fn alloc(&mut self, node: Node<K, V>) -> NodeId {
+        match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = node;
+                id
+            },
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            },
+        }
+    }
+
+    fn release(&mut self, id: NodeId) {
+        self.nodes[id] = Node::Free;
+        self.free.push(id);
+    }
+
+    // Takes a node out of the slab to edit it alongside others, leaving the
+    // slot empty until `put` returns it.
+    fn take(&mut self, id: NodeId) -> Node<K, V> {
+        std::mem::replace(&mut self.nodes[id], Node::Free)
+    }
+
+    fn put(&mut self, id: NodeId, node: Node<K, V>) {
+        self.nodes[id] = node;
+    }
+
+    fn find_leaf(&self, key: &K) -> Option<NodeId> {
+        let mut id = self.root?;
+        loop {
+            match &self.nodes[id] {
+                Node::Inner{ keys, children } => id = children[child_index(keys, key)],
+                Node::Leaf{ .. } => return Some(id),
+                Node::Free => unreachable!(),
+            }
+        }
+    }
+
+    fn edge_leaf(&self, mut id: NodeId) -> NodeId {
+        while let Node::Inner{ children, .. } = &self.nodes[id] {
+            id = children[0];
+        }
+        id
+    }
+
+    fn max_key_count(&self) -> usize {
+        self.m - 1
+    }
+
+    // Same occupancy rules as `Bptree`: leaves split evenly and merge below
+    // half full, inner nodes may run one key leaner.
+    fn leaf_min(&self) -> usize {
+        (self.max_key_count() / 2) + (self.max_key_count() % 2)
+    }
+
+    fn inner_min(&self) -> usize {
+        self.max_key_count() / 2
+    }
+
+    fn key_count(&self, id: NodeId) -> usize {
+        match &self.nodes[id] {
+            Node::Inner{ keys, .. } | Node::Leaf{ keys, .. } => keys.len(),
+            Node::Free => unreachable!(),
+        }
+    }
+
+    fn is_short(&self, id: NodeId) -> bool {
+        match &self.nodes[id] {
+            Node::Inner{ keys, .. } => keys.len() < self.inner_min(),
+            Node::Leaf{ keys, .. } => keys.len() < self.leaf_min(),
+            Node::Free => unreachable!(),
+        }
+    }
+
+    fn insert(&mut self, id: NodeId, key: K, val: V, deadline: Option<Instant>) -> Option<(K, NodeId)> {
+        let max_key_count = self.max_key_count();
+        let split_at = self.leaf_min();
+        let (seq, horizon) = (self.seq, self.horizon);
+        let index = match &mut self.nodes[id] {
+            Node::Leaf{ keys, vals, .. } => {
+                match keys.binary_search(&key) {
+                    Ok(i) => {
+                        vals[i].push(seq, Some(val), horizon);
+                        vals[i].set_deadline(deadline);
+                        return None;
+                    },
+                    Err(i) => {
+                        keys.insert(i, key);
+                        let mut versions = Versions::new(seq, val);
+                        versions.set_deadline(deadline);
+                        vals.insert(i, versions);
+                    },
+                }
+                return self.split_leaf(id, split_at);
+            },
+            Node::Inner{ keys, .. } => child_index(keys, &key),
+            Node::Free => unreachable!(),
+        };
+        let child = match &self.nodes[id] {
+            Node::Inner{ children, .. } => children[index],
+            _ => unreachable!(),
+        };
+        let (split_key, new_child) = self.insert(child, key, val, deadline)?;
+        let (keys, children) = match &mut self.nodes[id] {
+            Node::Inner{ keys, children } => (keys, children),
+            _ => unreachable!(),
+        };
+        keys.insert(index, split_key);
+        children.insert(index+1, new_child);
+        if keys.len() <= max_key_count {
+            return None;
+        }
+        let at = max_key_count / 2 + max_key_count % 2;
+        let right_keys = keys.split_off(at+1);
+        let split_key = keys.pop().unwrap();
+        let right_children = children.split_off(at+1);
+        let right = self.alloc(Node::Inner{ keys: right_keys, children: right_children });
+        Some((split_key, right))
+    }
+
+    fn split_leaf(&mut self, id: NodeId, split_at: usize) -> Option<(K, NodeId)> {
+        let right = match &mut self.nodes[id] {
+            Node::Leaf{ keys, vals, next } if keys.len() > self.m - 1 => {
+                Node::Leaf{ keys: keys.split_off(split_at), vals: vals.split_off(split_at), next: next.take() }
+            },
+            _ => return None,
+        };
+        let split_key = match &right {
+            Node::Leaf{ keys, .. } => keys[0].clone(),
+            _ => unreachable!(),
+        };
+        let right = self.alloc(right);
+        if let Node::Leaf{ next, .. } = &mut self.nodes[id] {
+            *next = Some(right);
+        }
+        Some((split_key, right))
+    }
+
+    fn remove_from(&mut self, id: NodeId, key: &K) -> Option<Versions<V>> {
+        match &mut self.nodes[id] {
+            Node::Leaf{ keys, vals, .. } => {
+                let i = keys.binary_search(key).ok()?;
+                keys.remove(i);
+                Some(vals.remove(i))
+            },
+            Node::Inner{ keys, children } => {
+                let index = child_index(keys, key);
+                let child = children[index];
+                let old_val = self.remove_from(child, key)?;
+                if self.is_short(child) {
+                    self.rebalance(id, index);
+                }
+                Some(old_val)
+            },
+            Node::Free => unreachable!(),
+        }
+    }
+
+    // Refills the short child at `index` of `parent` from a sibling, or
+    // merges it with one.
+    fn rebalance(&mut self, parent: NodeId, index: usize) {
+        let mut parent_node = self.take(parent);
+        let (parent_keys, parent_children) = match &mut parent_node {
+            Node::Inner{ keys, children } => (keys, children),
+            _ => unreachable!(),
+        };
+        let (left, right) = if index > 0 { (index - 1, index) } else { (index, index + 1) };
+        let (left_id, right_id) = (parent_children[left], parent_children[right]);
+        let lender_min = if matches!(self.nodes[left_id], Node::Leaf{ .. }) { self.leaf_min() } else { self.inner_min() };
+        let borrow_right = left == index && self.key_count(right_id) > lender_min;
+        let borrow_left = right == index && self.key_count(left_id) > lender_min;
+        let mut right_node = self.take(right_id);
+        let mut merged = false;
+        match (&mut self.nodes[left_id], &mut right_node) {
+            (Node::Leaf{ keys: left_keys, vals: left_vals, next }, Node::Leaf{ keys: right_keys, vals: right_vals, next: right_next }) => {
+                if borrow_right {
+                    left_keys.push(right_keys.remove(0));
+                    left_vals.push(right_vals.remove(0));
+                    parent_keys[left] = right_keys[0].clone();
+                }
+                else if borrow_left {
+                    right_keys.insert(0, left_keys.pop().unwrap());
+                    right_vals.insert(0, left_vals.pop().unwrap());
+                    parent_keys[left] = right_keys[0].clone();
+                }
+                else {
+                    left_keys.append(right_keys);
+                    left_vals.append(right_vals);
+                    *next = right_next.take();
+                    merged = true;
+                }
+            },
+            (Node::Inner{ keys: left_keys, children: left_children }, Node::Inner{ keys: right_keys, children: right_children }) => {
+                if borrow_right {
+                    left_keys.push(std::mem::replace(&mut parent_keys[left], right_keys.remove(0)));
+                    left_children.push(right_children.remove(0));
+                }
+                else if borrow_left {
+                    right_keys.insert(0, std::mem::replace(&mut parent_keys[left], left_keys.pop().unwrap()));
+                    right_children.insert(0, left_children.pop().unwrap());
+                }
+                else {
+                    left_keys.push(parent_keys[left].clone());
+                    left_keys.append(right_keys);
+                    left_children.append(right_children);
+                    merged = true;
+                }
+            },
+            _ => unreachable!(),
+        }
+        if merged {
+            parent_keys.remove(left);
+            parent_children.remove(right);
+            self.release(right_id);
+        }
+        else {
+            self.put(right_id, right_node);
+        }
+        self.put(parent, parent_node);
+    }
+}
+
+// The latest value of an entry, unless it was removed or has expired.
+fn visible<'v, V>(versions: &'v Versions<V>, now: &Now) -> Option<&'v V> {
+    versions.latest().filter(|_| !versions.expired(now))
+}
+
+fn child_index<K: Ord>(keys: &[K], key: &K) -> usize {
+    match keys.binary_search(key) {
+        Err(i) => i,
+        Ok(i) => i+1,
+    }
+}
+
+/// Iterator over a key range of an `ArenaBptree`, following the leaf chain.
+pub struct ArenaRange<'a, K, V> {
+    tree: &'a ArenaBptree<K, V>,
+    leaf: Option<NodeId>,
+    pos: usize,
+    end: Bound<K>,
+    now: Now,
+}
+
+impl<'a, K, V> Iterator for ArenaRange<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (keys, vals, next) = match &self.tree.nodes[self.leaf?] {
+                Node::Leaf{ keys, vals, next } => (keys, vals, next),
+                _ => unreachable!(),
+            };
+            if self.pos < keys.len() {
+                let key = &keys[self.pos];
+                if !(Bound::Unbounded, self.end.as_ref()).contains(key) {
+                    self.leaf = None;
+                    return None;
+                }
+                self.pos += 1;
+                if let Some(val) = visible(&vals[self.pos - 1], &self.now) {
+                    return Some((key, val));
+                }
+                continue;
+            }
+            self.leaf = *next;
+            self.pos = 0;
+        }
+    }
+}
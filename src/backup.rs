@@ -0,0 +1,63 @@
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::ops::Bound;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use super::bptree::Bptree;
+use super::kvtype::KVType;
+
+impl<K, V> Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// Writes every entry set or removed after sequence number `since` to
+    /// `writer`, and returns the checkpoint to pass as `since` next time.
+    /// `since` of 0 backs up the whole tree. Writes racing with the backup
+    /// may land in this one and the next, which `restore` tolerates.
+    ///
+    /// A removal is only seen while its tombstone is kept, so incremental
+    /// backups need a version horizon at or below the previous checkpoint;
+    /// without one, removed keys simply stop being written. Expired entries
+    /// are written as removals, and times to live are not carried over.
+    pub fn backup_since<W: Write>(&self, since: u64, mut writer: W) -> bincode::Result<u64>
+        where K: Serialize, V: Serialize
+    {
+        let checkpoint = self.seq();
+        let mut buf = vec![];
+        let mut from = Bound::Unbounded;
+        loop {
+            let more = self.read_changes(from.as_ref(), since, &mut buf);
+            for (key, val) in &buf {
+                bincode::serialize_into(&mut writer, &Some((key, val)))?;
+            }
+            match buf.pop() {
+                Some((last, _)) if more => from = Bound::Excluded(last),
+                _ => break,
+            }
+            buf.clear();
+        }
+        bincode::serialize_into(&mut writer, &None::<(K, Option<V>)>)?;
+        Ok(checkpoint)
+    }
+
+    /// Replays one backup written by `backup_since` on top of this tree and
+    /// returns the number of entries it held. Restore a full backup first,
+    /// then each incremental one in the order they were taken. Entries are
+    /// written back as they were backed up, overwriting whatever the tree
+    /// holds under their keys, whatever its `DuplicatePolicy`. An entry over
+    /// the tree's size limits stops the restore with an error, after the
+    /// entries before it were written.
+    pub fn restore<R: Read>(&self, mut reader: R) -> bincode::Result<usize>
+        where K: DeserializeOwned, V: DeserializeOwned
+    {
+        let mut entries = 0;
+        while let Some((key, val)) = bincode::deserialize_from::<_, Option<(K, Option<V>)>>(&mut reader)? {
+            match val {
+                Some(val) => self.overwrite(key, val).map_err(|err| bincode::ErrorKind::Custom(err.to_string()))?,
+                None => {self.remove(&key);},
+            }
+            entries += 1;
+        }
+        Ok(entries)
+    }
+}
@@ -0,0 +1,49 @@
+use std::fmt::Debug;
+use super::kvtype::KVType;
+
+/// Puts and deletes handed to `Bptree::apply` to be written as one unit.
+/// Operations apply in the order they were added, so a later write to a key
+/// wins over an earlier one.
+#[derive(Debug, Clone)]
+pub struct WriteBatch<K, V> {
+    // `None` is a delete
+    pub(crate) ops: Vec<(K, Option<V>)>,
+}
+
+impl<K, V> WriteBatch<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    pub fn new() -> Self {
+        Self{
+            ops: vec![],
+        }
+    }
+
+    pub fn put(&mut self, key: K, val: V) -> &mut Self {
+        self.ops.push((key, Some(val)));
+        self
+    }
+
+    pub fn delete(&mut self, key: K) -> &mut Self {
+        self.ops.push((key, None));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl<K, V> Default for WriteBatch<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,159 +1,3211 @@
-use std::cell::RefCell;
 use std::option::Option;
+use std::cell::{Cell, RefCell};
 use std::fmt::Debug;
-use std::fs::OpenOptions;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, Weak};
-use crate::bptree::BtreeNode::inner;
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::{Bound, Deref, RangeBounds};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, RawRwLock, RwLock, RwLockWriteGuard};
 use super::kvtype::KVType;
+use super::version::Versions;
+use super::txn::Txn;
+use super::batch::WriteBatch;
+use super::hook::{Hooks, IndexHook};
+use super::builder::{BptreeBuilder, DuplicatePolicy, MIN_ORDER};
+use super::ttl::{Now, RangeTtl};
+use super::iter::{Range, ResumeToken};
+use super::cursor::Cursor;
+use super::check::{InvariantReport, Violation};
+use super::stats::TreeStats;
+use super::trylock::{LockError, Wait};
+use super::limits::SizeLimits;
+use super::budget::{Budget, Eviction, Usage};
+use super::cas::{CasError, OccupiedError};
+use super::merge::{MergeFn, MergeOperator};
+use super::aggregate::{Aggregator, Best, ChildStat, Ranking, Summary};
+use super::error::BptreeError;
+use super::diff::Change;
 
+// separator, right half and the number of live entries that moved into it
+type SplitResult<K, V> = Option<(K, BtreeNode<K, V>, usize)>;
+type InnerNodeRef<K, V> = Arc<RwLock<InnerNode<K, V>>>;
+pub(crate) type LeafNodeRef<K, V> = Arc<RwLock<LeafNode<K, V>>>;
+type InnerGuard<K, V> = ArcRwLockWriteGuard<RawRwLock, InnerNode<K, V>>;
+type LeafGuard<K, V> = ArcRwLockWriteGuard<RawRwLock, LeafNode<K, V>>;
+type InnerReadGuard<K, V> = ArcRwLockReadGuard<RawRwLock, InnerNode<K, V>>;
+type LeafReadGuard<K, V> = ArcRwLockReadGuard<RawRwLock, LeafNode<K, V>>;
+// the inner nodes down to a leaf latched shared, with the child index taken
+// at each, and the leaf latched exclusively
+type LatchedPath<K, V> = (Vec<(InnerReadGuard<K, V>, usize)>, LeafGuard<K, V>);
+// the operations of a `WriteBatch`, `None` a delete
+type BatchOps<K, V> = Vec<(K, Option<V>)>;
+
+// The inner nodes from the root down to one leaf, each with the index of
+// the child leading on, as a cursor keeps it to write into its leaf again
+// without searching every level. Only trusted after checking, top down,
+// that each node still links to the next and routes the key there.
+pub(crate) struct LeafPath<K, V> {
+    inner: Vec<(InnerNodeRef<K, V>, usize)>,
+    leaf: LeafNodeRef<K, V>,
+}
+
+impl<K, V> LeafPath<K, V> {
+    pub(crate) fn leaf(&self) -> &LeafNodeRef<K, V> {
+        &self.leaf
+    }
+}
+
+// Searching descents to a leaf to write, on this thread, for tests to tell
+// them from writes through a recorded path.
+#[cfg(test)]
+thread_local! {
+    pub(crate) static WRITE_DESCENTS: Cell<usize> = const { Cell::new(0) };
+}
+
+// Leaves read by `top_k_by_value` on this thread, for tests to see which
+// subtrees it passed over.
+#[cfg(test)]
+thread_local! {
+    pub(crate) static LEAVES_RANKED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Share of node capacity `Bptree::rebalance` packs entries to, leaving
+/// room for inserts before the first splits.
+pub const REBALANCE_FILL: f64 = 0.75;
+
+/// Locking protocol. Latches are only ever acquired in this order, which
+/// rules out deadlock:
+///
+/// 1. the `root` latch, which guards the root pointer;
+/// 2. nodes top-down, parent before child;
+/// 3. within one level, left sibling before right sibling. Rebalancing
+///    takes both siblings through `latch_pair` while holding their parent,
+///    and leaf walks follow `next` hand over hand.
+///
+/// Nothing below the root latch ever reaches back up: a thread holding a
+/// node never takes its parent or the root latch, and `horizon` and
+/// `range_ttls` are never held while a node latch is acquired. A parent is
+/// only released once its child is latched (lock coupling), so readers and
+/// writers never observe a half-split node. Every latch is a reader-writer
+/// lock: readers only take shared latches and never block each other, while
+/// writers take exclusive latches on the nodes they may modify. The one
+/// exception are the per-child entry counts of inner nodes, which are atomic
+/// so a writer holding the path shared can still account for its leaf. The
+/// aggregate summaries cached beside them sit behind mutexes of their own,
+/// which only readers take and never hold while acquiring a node latch.
+///
+/// Every method takes `&self`, transactions included, and the tree is `Send`
+/// and `Sync` whenever its keys and values are. Share it between threads
+/// behind an `Arc`; no external mutex is needed. The latches come from
+/// `parking_lot` and are never poisoned, so a thread that panics while
+/// holding one does not make the tree unusable for every other thread;
+/// `heal` repairs whatever the interrupted operation left half done.
 #[derive(Debug)]
 pub struct Bptree<K, V> {
-    mutex: Mutex<bool>,
-    root: BtreeNode<K,V>,
+    root: RwLock<BtreeNode<K,V>>,
     m: usize,
+    seq: AtomicU64,
+    horizon: RwLock<Option<u64>>,
+    range_ttls: RwLock<Vec<RangeTtl<K>>>,
+    // earliest deadline of any entry written with `set_with_ttl` that may
+    // still be in the tree
+    next_deadline: RwLock<Option<Instant>>,
+    hooks: RwLock<Hooks<K, V>>,
+    // sequence numbers open snapshots read at, with how many read at each
+    pins: RwLock<BTreeMap<u64, usize>>,
+    // removals leave underfull leaves be, see `set_lazy_rebalance`
+    lazy_rebalance: AtomicBool,
+    limits: SizeLimits,
+    // set by `set_byte_budget`
+    budget: RwLock<Option<Budget<K, V>>>,
+    // set by `set_merge_operator`
+    merge_op: RwLock<Option<MergeOperator<K, V>>>,
+    // what `set` does to a key already present, fixed at construction
+    duplicates: DuplicatePolicy,
+    // set by `set_aggregate`
+    aggregate: RwLock<Option<Aggregator<K, V>>>,
+    // set by `set_value_ranking`
+    ranking: RwLock<Option<Ranking<V>>>,
+    // bumped by writes that change values without passing the inner nodes
+    // above them, and whenever the aggregate or ranking changes; summaries
+    // cached under an older epoch are no longer current
+    summary_epoch: AtomicU64,
 }
 
-impl<K, V> Bptree<K, V>
-    where K : Debug + Clone + Ord + KVType,
-          V : Debug + Clone + Ord + KVType,
-{
-    pub fn new(m: usize) -> Self {
-        Self{
-            mutex: Mutex::new(true),
-            root: BtreeNode::placehold,
-            m,
+impl<K, V> Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// An empty tree of order `m`: inner nodes hold up to `m` children and
+    /// leaves up to `m - 1` entries. Panics if `m` is below `MIN_ORDER`;
+    /// `builder` reports that as an error instead.
+    pub fn new(m: usize) -> Self {
+        assert!(m >= MIN_ORDER, "order {} is below the minimum of {}", m, MIN_ORDER);
+        Self{
+            root: RwLock::new(BtreeNode::placehold),
+            m,
+            seq: AtomicU64::new(0),
+            horizon: RwLock::new(None),
+            range_ttls: RwLock::new(vec![]),
+            next_deadline: RwLock::new(None),
+            hooks: RwLock::new(Hooks::new()),
+            pins: RwLock::new(BTreeMap::new()),
+            lazy_rebalance: AtomicBool::new(false),
+            limits: SizeLimits::new(None, None),
+            budget: RwLock::new(None),
+            merge_op: RwLock::new(None),
+            duplicates: DuplicatePolicy::Overwrite,
+            aggregate: RwLock::new(None),
+            ranking: RwLock::new(None),
+            summary_epoch: AtomicU64::new(0),
+        }
+    }
+
+    /// Registers `hook` to hear about every later change to the tree's
+    /// entries, see `IndexHook` for which writes report.
+    pub fn add_hook(&self, hook: Arc<dyn IndexHook<K, V>>) {
+        let mut hooks = self.hooks.write();
+        *hooks = hooks.with(hook);
+    }
+
+    // Taken before any node latch, like `horizon`.
+    fn hooks(&self) -> Hooks<K, V> {
+        self.hooks.read().clone()
+    }
+
+    pub fn builder() -> BptreeBuilder<K, V> {
+        BptreeBuilder::new()
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let leaf = self.find_leaf(key)?;
+        self.read_in(&leaf, key, &Now::default())
+    }
+
+    /// Like `get`, but fails with `LockError::WouldBlock` instead of waiting
+    /// when a latch on the way to `key` is held by a writer.
+    pub fn try_get(&self, key: &K) -> Result<Option<V>, LockError> {
+        self.get_within(key, &Wait::Never)
+    }
+
+    /// Like `get`, but waits at most `timeout` for latches, all of them
+    /// together, before failing with `LockError::Timeout`.
+    pub fn get_timeout(&self, key: &K, timeout: Duration) -> Result<Option<V>, LockError> {
+        self.get_within(key, &Wait::Until(Instant::now() + timeout))
+    }
+
+    fn get_within(&self, key: &K, wait: &Wait) -> Result<Option<V>, LockError> {
+        let leaf = self.descend_within(wait, |inner_node_content| inner_node_content.child_index(key), |leaf| wait.read(leaf))?;
+        Ok(leaf.and_then(|leaf| self.read_in(&leaf, key, &Now::default())))
+    }
+
+    /// Like `get`, but hands out a reference to the stored value instead of
+    /// a clone. The leaf holding it stays latched shared until the
+    /// reference is dropped, so writers to that leaf wait meanwhile, and the
+    /// holding thread must not write to the tree before letting go.
+    pub fn get_ref(&self, key: &K) -> Option<ValueRef<K, V>> {
+        let leaf = self.find_leaf(key)?;
+        let index = K::search(&leaf.keys, key).ok()?;
+        self.visible(key, &leaf.vals[index], &Now::default())?;
+        Some(ValueRef{ leaf, index })
+    }
+
+    /// Looks up many keys at once, returning their values in the order the
+    /// keys were given. Probes are visited in key order, and a key in the
+    /// leaf already latched or the one after it is read without going back
+    /// to the root, so clustered keys cost about one descent per leaf.
+    pub fn multi_get(&self, keys: &[K]) -> Vec<Option<V>> {
+        let now = Now::default();
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+        let mut vals = vec![None; keys.len()];
+        let mut leaf: Option<LeafReadGuard<K, V>> = None;
+        for i in order {
+            let key = &keys[i];
+            leaf = match leaf.take().and_then(|leaf_node_content| self.step_toward(leaf_node_content, key)) {
+                Some(leaf_node_content) => Some(leaf_node_content),
+                None => self.find_leaf(key),
+            };
+            vals[i] = leaf.as_ref().and_then(|leaf_node_content| self.read_in(leaf_node_content, key, &now));
+        }
+        vals
+    }
+
+    // The leaf `key` belongs to, if it is `leaf` itself or the leaf right
+    // after it. Holding `leaf` keeps it from splitting, so a key past its
+    // last entry but before the next leaf's first is in neither.
+    fn step_toward(&self, leaf: LeafReadGuard<K, V>, key: &K) -> Option<LeafReadGuard<K, V>> {
+        if leaf.retired {
+            return None;
+        }
+        if leaf.keys.last().is_none_or(|last| key <= last) {
+            return Some(leaf);
+        }
+        let next = leaf.next.as_ref()?.read_arc();
+        if next.retired || next.keys.last().is_none_or(|last| key > last) {
+            return None;
+        }
+        if next.keys[0] > *key {
+            return Some(leaf);
+        }
+        Some(next)
+    }
+
+    /// Walks the tree a leaf at a time, handing out each leaf latched shared
+    /// so its entries can be read in place instead of copied one by one.
+    /// Writers to a leaf wait while its chunk is alive. Drop each chunk
+    /// before taking the next: like `get_ref`, a chunk held across further
+    /// calls into the tree can deadlock against a splitting writer.
+    pub fn leaves(&self) -> Leaves<'_, K, V> {
+        Leaves{
+            tree: self,
+            next: None,
+            after: Bound::Unbounded,
+            done: false,
+        }
+    }
+
+    /// Iterates over every entry in key order, lending out references
+    /// instead of cloning keys and values. The current leaf stays latched
+    /// shared until the stream moves past it or is dropped, with the same
+    /// caveats as a chunk from `leaves`.
+    pub fn stream(&self) -> Stream<'_, K, V> {
+        Stream{
+            leaves: self.leaves(),
+            chunk: None,
+            pos: 0,
+        }
+    }
+
+    /// Iterates over the entries whose keys fall in `range`, lending out
+    /// each value mutably so it can be changed in place. Every leaf is
+    /// latched exclusively while the iterator is on it, so writers to it
+    /// wait, and the same caveats as for a chunk from `leaves` apply. With a
+    /// version horizon each value handed out becomes a new version first,
+    /// and hooks hear about every value that ends up changed.
+    pub fn range_mut<R: RangeBounds<K>>(&self, range: R) -> RangeMut<'_, K, V> {
+        let horizon = self.horizon();
+        let hooks = self.hooks();
+        let start = range.start_bound();
+        let leaf = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self.find_leaf_mut(key),
+            Bound::Unbounded => self.descend(|_| 0, |leaf| leaf.write_arc()),
+        };
+        let pos = leaf.as_ref().map_or(0, |leaf_node_content| {
+            leaf_node_content.keys.partition_point(|key| !(start, Bound::Unbounded).contains(key))
+        });
+        RangeMut{
+            tree: self,
+            leaf,
+            pos,
+            end: range.end_bound().cloned(),
+            horizon,
+            hooks,
+            now: Now::default(),
+            old_val: None,
+        }
+    }
+
+    pub fn iter_mut(&self) -> RangeMut<'_, K, V> {
+        self.range_mut(..)
+    }
+
+    /// Like `iter_mut`, lending out values only.
+    pub fn values_mut(&self) -> ValuesMut<'_, K, V> {
+        ValuesMut(self.iter_mut())
+    }
+
+    /// Latches the entries of `keys` all at once and lends out their
+    /// values mutably together, for changes that must touch several
+    /// entries atomically, like moving a quantity from one to another.
+    /// Their leaves stay latched exclusively until the guard is dropped,
+    /// with the same caveats as `range_mut`. Returns `None` if a key has
+    /// no live entry. Panics if a key is repeated.
+    ///
+    /// The leaves are found under the root latch held exclusively, with
+    /// writers already inside drained first, so latching them one by one
+    /// in key order cannot deadlock against a writer descending meanwhile.
+    pub fn get_many_mut<const N: usize>(&self, keys: [&K; N]) -> Option<ManyMut<'_, K, V, N>> {
+        let mut order: Vec<usize> = (0..N).collect();
+        order.sort_by(|&a, &b| keys[a].cmp(keys[b]));
+        assert!(order.windows(2).all(|pair| keys[pair[0]] != keys[pair[1]]), "get_many_mut called with a repeated key");
+        let horizon = self.horizon();
+        let hooks = self.hooks();
+        let now = Now::default();
+        let root = self.root.write();
+        // writers that got past the root latch hold the root node until done
+        drop(root.write_arc());
+        let mut leaves: Vec<LeafGuard<K, V>> = vec![];
+        let mut slots = [(0, 0); N];
+        for n in order {
+            let key = keys[n];
+            let leaf_node_ref = root.leaf_ref(&|inner_node_content| inner_node_content.child_index(key))?;
+            if !leaves.last().is_some_and(|leaf| Arc::ptr_eq(ArcRwLockWriteGuard::rwlock(leaf), &leaf_node_ref)) {
+                leaves.push(leaf_node_ref.write_arc());
+            }
+            let leaf_node_content = leaves.last().unwrap();
+            let i = K::search(&leaf_node_content.keys, key).ok()?;
+            self.visible(key, &leaf_node_content.vals[i], &now)?;
+            slots[n] = (leaves.len() - 1, i);
+        }
+        drop(root);
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut old_vals = vec![];
+        for &(l, i) in &slots {
+            let versions = &mut leaves[l].vals[i];
+            if !hooks.is_empty() {
+                old_vals.extend(versions.latest().cloned());
+            }
+            if horizon.is_some() {
+                let deadline = versions.deadline();
+                versions.push(seq, versions.latest().cloned(), horizon);
+                versions.set_deadline(deadline);
+            }
+        }
+        Some(ManyMut{ tree: self, leaves, slots, hooks, old_vals })
+    }
+
+    /// Removes the entry with the smallest key and returns it.
+    pub fn pop_first(&self) -> Option<(K, V)> {
+        loop {
+            let (key, _) = self.iter().next()?;
+            // lost a race with another remover, look again
+            if let Some(val) = self.remove(&key) {
+                return Some((key, val));
+            }
+        }
+    }
+
+    /// Removes the entry with the largest key and returns it.
+    pub fn pop_last(&self) -> Option<(K, V)> {
+        loop {
+            let (key, _) = self.iter().next_back()?;
+            if let Some(val) = self.remove(&key) {
+                return Some((key, val));
+            }
+        }
+    }
+
+    /// One page of a paginated scan: up to `limit` entries in key order
+    /// after `start_after`, or from the first key if it is `None`, along
+    /// with the key to pass as `start_after` for the next page, `None` once
+    /// nothing follows. One descent finds the first leaf and the page is
+    /// read along the leaf chain from there. Panics if `limit` is 0.
+    pub fn scan_after(&self, start_after: Option<&K>, limit: usize) -> (Vec<(K, V)>, Option<K>) {
+        assert!(limit > 0, "scan_after needs a limit of at least one entry");
+        let now = Now::default();
+        let from = start_after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut page = vec![];
+        let mut more = false;
+        self.walk_leaves(from, |leaf_node_content| {
+            for (key, versions) in leaf_node_content.keys.iter().zip(&leaf_node_content.vals) {
+                if !(from, Bound::Unbounded).contains(key) {
+                    continue;
+                }
+                if let Some(val) = self.visible(key, versions, &now) {
+                    if page.len() == limit {
+                        more = true;
+                        return false;
+                    }
+                    page.push((key.clone(), val.clone()));
+                }
+            }
+            true
+        });
+        let next = if more { page.last().map(|(key, _)| key.clone()) } else { None };
+        (page, next)
+    }
+
+    /// Floor lookup: the entry with the largest key at or below `key`.
+    pub fn get_le(&self, key: &K) -> Option<(K, V)> {
+        self.seek_backward(None, Bound::Included(key)).map(|(_, key, val)| (key, val))
+    }
+
+    /// Ceiling lookup: the entry with the smallest key at or above `key`.
+    pub fn get_ge(&self, key: &K) -> Option<(K, V)> {
+        self.seek_forward(None, Bound::Included(key)).map(|(_, key, val)| (key, val))
+    }
+
+    /// Number of entries with keys below `key`. Inner nodes keep the entry
+    /// count of every child, so this descends a single path. The counts are
+    /// exact once concurrent writers are done; while they run the result may
+    /// be off by the writes in flight.
+    pub fn rank(&self, key: &K) -> usize {
+        self.purge_if_expired();
+        self.count_within(Bound::Excluded(key))
+    }
+
+    /// Draws `n` keys uniformly at random, with replacement, and returns
+    /// them in key order. Each draw picks a rank from `rng`, which should
+    /// return uniformly distributed `u64`s, and finds it through the subtree
+    /// counts as `select` does, so no scan is needed. Fewer keys come back
+    /// if the tree shrinks while sampling.
+    pub fn sample<R: FnMut() -> u64>(&self, n: usize, mut rng: R) -> Vec<K> {
+        let total = self.count_range(..) as u128;
+        if total == 0 {
+            return vec![];
+        }
+        let mut keys: Vec<K> = (0..n)
+            .filter_map(|_| self.select(((rng() as u128 * total) >> 64) as usize))
+            .map(|(key, _)| key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Number of entries whose keys fall in `range`, found from the subtree
+    /// counts along the paths to both ends rather than by scanning, so it
+    /// carries the same caveat as `rank` under concurrent writes.
+    pub fn count_range<R: RangeBounds<K>>(&self, range: R) -> usize {
+        self.purge_if_expired();
+        self.estimate_range_size(range)
+    }
+
+    /// Cheap size of `range` for query planners, in O(height): `count_range`
+    /// without sweeping out expired entries first, so it never writes or
+    /// waits on a purge. Entries that expired but were not purged yet are
+    /// still counted.
+    pub fn estimate_range_size<R: RangeBounds<K>>(&self, range: R) -> usize {
+        let below_start = match range.start_bound() {
+            Bound::Included(key) => self.count_within(Bound::Excluded(key)),
+            Bound::Excluded(key) => self.count_within(Bound::Included(key)),
+            Bound::Unbounded => 0,
+        };
+        self.count_within(range.end_bound()).saturating_sub(below_start)
+    }
+
+    /// Registers an aggregate over the entries, replacing any set before:
+    /// `lift` turns one entry into an `A` and `combine` joins two of them.
+    /// `combine` must be associative with `identity` as its neutral
+    /// element, as for a sum, a minimum or a count; it need not commute.
+    pub fn set_aggregate<A, L, C>(&self, identity: A, lift: L, combine: C)
+        where A: Send + Sync + 'static,
+              L: Fn(&K, &V) -> A + Send + Sync + 'static,
+              C: Fn(&A, &A) -> A + Send + Sync + 'static,
+    {
+        *self.aggregate.write() = Some(Aggregator::new(identity, lift, combine));
+        self.retire_summaries();
+    }
+
+    /// The registered aggregate over the live entries in `range`, in key
+    /// order. Inner nodes cache the summary of every child's subtree, so
+    /// once the caches are warm only the two paths to the ends of the range
+    /// are walked, O(m log n). Writes retire the summaries along their path;
+    /// writes lending values out in place, like `range_mut`, `get_many_mut`
+    /// and cursor updates, retire them all. Expired entries are purged
+    /// first, as for `count_range`. Splits and merges wait while the walk
+    /// holds the root latch. Panics if no aggregate is registered or it is
+    /// not of type `A`.
+    pub fn aggregate_range<A, R>(&self, range: R) -> A
+        where A: Clone + 'static,
+              R: RangeBounds<K>,
+    {
+        self.purge_if_expired();
+        let aggregator = self.aggregate.read().clone().expect("aggregate_range called on a tree without an aggregate");
+        let epoch = self.summary_epoch.load(Ordering::SeqCst);
+        let start = match range.start_bound() {
+            Bound::Unbounded => None,
+            start => Some(start),
+        };
+        let end = match range.end_bound() {
+            Bound::Unbounded => None,
+            end => Some(end),
+        };
+        let summary = self.root.read().summarize(start, end, &aggregator, epoch);
+        summary.downcast_ref::<A>().expect("aggregate_range called with a type other than the aggregate's").clone()
+    }
+
+    /// Registers the order `top_k_by_value` ranks values by, replacing any
+    /// set before. Inner nodes then keep the best `width` entries of every
+    /// child's subtree cached beside its count, retired by writes as
+    /// aggregate summaries are. Panics if `width` is 0.
+    pub fn set_value_ranking<F>(&self, width: usize, cmp: F)
+        where F: Fn(&V, &V) -> std::cmp::Ordering + Send + Sync + 'static
+    {
+        assert!(width > 0, "a value ranking needs to cache at least one entry");
+        *self.ranking.write() = Some(Ranking::new(width, cmp));
+        self.retire_summaries();
+    }
+
+    /// The `k` entries in `range` with the largest values under the ranking
+    /// registered with `set_value_ranking`, largest first, ties going to the
+    /// smaller key. Subtrees lying wholly inside the range are visited best
+    /// cached entry first, and skipped once that entry cannot beat the `k`th
+    /// found so far; for `k` up to the cached width their cached entries
+    /// stand in for them entirely. So once the caches are warm only the
+    /// paths to the ends of the range are read. Expired entries are purged
+    /// first, as for `aggregate_range`. Panics if no ranking is registered.
+    pub fn top_k_by_value<R: RangeBounds<K>>(&self, range: R, k: usize) -> Vec<(K, V)> {
+        self.purge_if_expired();
+        let ranking = self.ranking.read().clone().expect("top_k_by_value called on a tree without a value ranking");
+        let epoch = self.summary_epoch.load(Ordering::SeqCst);
+        let start = match range.start_bound() {
+            Bound::Unbounded => None,
+            start => Some(start),
+        };
+        let end = match range.end_bound() {
+            Bound::Unbounded => None,
+            end => Some(end),
+        };
+        let mut top = Vec::with_capacity(k + 1);
+        if k > 0 {
+            self.root.read().rank(start, end, &ranking, epoch, k, &mut top);
+        }
+        top
+    }
+
+    // For writes that change values without going through the inner nodes
+    // above them. Call it once the change is made.
+    fn retire_summaries(&self) {
+        self.summary_epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// The entry at position `rank` in key order, counting from zero.
+    pub fn select(&self, rank: usize) -> Option<(K, V)> {
+        self.purge_if_expired();
+        let mut remaining = rank;
+        let mut leaf = self.descend(|inner_node_content| {
+            let mut index = 0;
+            while index + 1 < inner_node_content.childNodeptrs.len() && remaining >= inner_node_content.count(index) {
+                remaining -= inner_node_content.count(index);
+                index += 1;
+            }
+            index
+        }, |leaf| leaf.read_arc());
+        // a writer racing the descent can leave the counts slightly behind,
+        // so carry on along the leaf chain if this leaf falls short
+        while let Some(leaf_node_content) = leaf {
+            for (key, versions) in leaf_node_content.keys.iter().zip(leaf_node_content.vals.iter()) {
+                if let Some(val) = versions.latest() {
+                    if remaining == 0 {
+                        return Some((key.clone(), val.clone()));
+                    }
+                    remaining -= 1;
+                }
+            }
+            leaf = leaf_node_content.next.as_ref().map(|next| next.read_arc());
+        }
+        None
+    }
+
+    /// Reads `key` as of sequence number `seq`. Versions older than the
+    /// retention horizon may already be pruned, see `set_version_horizon`.
+    pub fn get_at(&self, key: &K, seq: u64) -> Option<V> {
+        self.find_leaf(key)?.get_at(key, seq)
+    }
+
+    /// Iterates over the entries whose keys fall in `range`, in key order.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, V> {
+        Range::new(self, range.start_bound().cloned(), range.end_bound().cloned())
+    }
+
+    pub fn iter(&self) -> Range<'_, K, V> {
+        self.range(..)
+    }
+
+    /// Continues a scan from a token exported by `Range::token`, possibly
+    /// taken on another tree holding the same keys.
+    pub fn resume(&self, token: ResumeToken<K>) -> Range<'_, K, V> {
+        Range::resume(self, token)
+    }
+
+    /// Opens a cursor on the first entry at or after `seek`.
+    pub fn cursor(&self, seek: &K) -> Cursor<'_, K, V> {
+        Cursor::new(self, seek)
+    }
+
+    /// Starts a transaction whose writes are applied together on commit.
+    /// Any number of transactions may be open on the tree at once.
+    pub fn begin(&self) -> Txn<'_, K, V> {
+        Txn::new(self)
+    }
+
+    /// Sequence number of the last write. It moves on before a write's
+    /// changes land, so reading the same `seq` before and after a scan means
+    /// no write raced with it. `expire_range` counts as a write, but a
+    /// deadline passing later, an entry's own or its range's, hides entries
+    /// without moving `seq`, so that goes unnoticed here.
+    pub fn seq(&self) -> u64 {
+        self.seq.load(Ordering::SeqCst)
+    }
+
+    /// Keeps every version still visible at `horizon` or later so `get_at`
+    /// stays repeatable for those sequence numbers; removed keys linger as
+    /// tombstones until `gc`. `None` (the default) keeps no history at all.
+    /// Open snapshots keep the versions they read on top of this.
+    pub fn set_version_horizon(&self, horizon: Option<u64>) {
+        *self.horizon.write() = horizon;
+    }
+
+    /// With `lazy` set, `remove` takes an entry out of its leaf like a write
+    /// that does not split, latching the path shared and only the leaf
+    /// exclusively, and leaves the leaf underfull instead of merging or
+    /// borrowing. Only a removal that would empty its leaf still
+    /// rebalances, so no leaf is left empty. Occupancy is restored by
+    /// `rebalance`.
+    pub fn set_lazy_rebalance(&self, lazy: bool) {
+        self.lazy_rebalance.store(lazy, Ordering::SeqCst);
+    }
+
+    // Only the builder picks the policy, before the tree is shared.
+    pub(crate) fn set_duplicate_policy(&mut self, duplicates: DuplicatePolicy) {
+        self.duplicates = duplicates;
+    }
+
+    /// Largest key, in `KVType::byte_len` bytes, that writes accept; `None`
+    /// lifts the limit. Entries already in the tree are left alone, and
+    /// values changed in place through `range_mut`, `update_with` or
+    /// `get_many_mut` are not checked.
+    pub fn set_max_key_len(&self, max: Option<usize>) {
+        self.limits.set_key(max);
+    }
+
+    /// Largest value writes accept, like `set_max_key_len`.
+    pub fn set_max_value_len(&self, max: Option<usize>) {
+        self.limits.set_val(max);
+    }
+
+    /// Caps the bytes the live entries may take up, as measured by
+    /// `KVType::byte_len` of their keys and latest values, at `max`. A
+    /// `set`, `apply` or cursor update that leaves the tree over budget
+    /// then runs `eviction` before returning. Calling it again changes the
+    /// budget and policy. Usage is tracked as a hook would, so it misses
+    /// entries moved in by `append` or out by `split_off`, and
+    /// `remove_range` takes its slower, reporting path from then on.
+    pub fn set_byte_budget(&self, max: usize, eviction: Eviction<K, V>) {
+        let mut budget = self.budget.write();
+        let (usage, evicting) = match budget.take() {
+            Some(budget) => (budget.usage, budget.evicting),
+            None => {
+                let usage = Arc::new(Usage::default());
+                self.add_hook(usage.clone());
+                usage.add(self.iter().map(|(key, val)| key.byte_len() + val.byte_len()).sum());
+                (usage, Arc::new(AtomicBool::new(false)))
+            },
+        };
+        *budget = Some(Budget{ max, eviction, usage, evicting });
+    }
+
+    /// Bytes the live entries take up, if a budget was set.
+    pub fn byte_usage(&self) -> Option<usize> {
+        self.budget.read().as_ref().map(|budget| budget.usage.bytes())
+    }
+
+    // Brings usage back within the budget if a write took it over. Only one
+    // thread evicts at a time; the others carry on meanwhile.
+    fn enforce_budget(&self) {
+        let budget = match self.budget.read().clone() {
+            Some(budget) if budget.usage.bytes() > budget.max => budget,
+            _ => return,
+        };
+        if budget.evicting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        match &budget.eviction {
+            Eviction::LowestKeys => {
+                while budget.usage.bytes() > budget.max && self.pop_first().is_some() {}
+            },
+            Eviction::Callback(callback) => callback(self, budget.usage.bytes().saturating_sub(budget.max)),
+        }
+        budget.evicting.store(false, Ordering::SeqCst);
+    }
+
+    /// Packs the tree back to `REBALANCE_FILL` after lazy removals, the way
+    /// `compact` does.
+    pub fn rebalance(&self) {
+        self.compact(REBALANCE_FILL);
+    }
+
+    // The horizon writers keep versions for: the one set, moved back to the
+    // oldest open snapshot.
+    fn horizon(&self) -> Option<u64> {
+        let oldest_pin = self.pins.read().keys().next().copied();
+        self.horizon.read().into_iter().chain(oldest_pin).min()
+    }
+
+    // Keeps every version visible at the current sequence number until
+    // `unpin`, and returns that sequence number.
+    pub(crate) fn pin(&self) -> u64 {
+        let mut pins = self.pins.write();
+        let seq = self.seq();
+        *pins.entry(seq).or_default() += 1;
+        seq
+    }
+
+    pub(crate) fn unpin(&self, seq: u64) {
+        let mut pins = self.pins.write();
+        if let Some(count) = pins.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&seq);
+            }
+        }
+    }
+
+    /// Expires every entry in `range` at `deadline`. Expired entries are
+    /// hidden from reads straight away and physically removed by
+    /// `purge_expired_ranges`, or when a write lands in the expired range.
+    /// Moves `seq` on, as a deadline already past changes what reads return
+    /// straight away.
+    pub fn expire_range<R: RangeBounds<K>>(&self, range: R, deadline: Instant) {
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        self.range_ttls.write().push(RangeTtl::new(range, deadline));
+    }
+
+    /// Removes the entries of every expired range, returning how many were
+    /// removed, and forgets those ranges. A range is only forgotten after all
+    /// of its entries are gone, so an interrupted purge leaves them hidden
+    /// and the next call picks up where it stopped.
+    pub fn purge_expired_ranges(&self) -> usize {
+        let now = Now::default();
+        let purge_seq = self.seq();
+        let expired: Vec<_> = self.range_ttls.read().iter()
+            .filter(|range_ttl| range_ttl.expired(&now))
+            .cloned()
+            .collect();
+
+        let mut dead_keys = vec![];
+        for range_ttl in &expired {
+            self.walk_leaves(range_ttl.start(), |leaf_node_content| {
+                for key in &leaf_node_content.keys {
+                    if range_ttl.is_past(key) {
+                        return false;
+                    }
+                    if range_ttl.contains(key) {
+                        dead_keys.push(key.clone());
+                    }
+                }
+                true
+            });
+        }
+        // entries rewritten since the purge started are fresh, leave them be
+        let removed = dead_keys.iter()
+            .filter(|key| self.remove_entry(key, &|versions| versions.latest_seq() <= purge_seq).is_some())
+            .count();
+        self.range_ttls.write().retain(|range_ttl| !expired.contains(range_ttl));
+        removed
+    }
+
+    /// Removes every entry whose own time to live has run out and returns
+    /// how many went. Only leaves are visited, left to right along the
+    /// chain; an entry rewritten since it was found is left alone.
+    pub fn purge_expired(&self) -> usize {
+        let now = Now::default();
+        *self.next_deadline.write() = None;
+        let mut dead_keys = vec![];
+        let mut next_deadline: Option<Instant> = None;
+        self.walk_leaves(Bound::Unbounded, |leaf_node_content| {
+            for (key, versions) in leaf_node_content.keys.iter().zip(&leaf_node_content.vals) {
+                if versions.expired(&now) {
+                    dead_keys.push(key.clone());
+                }
+                else if let Some(deadline) = versions.deadline() {
+                    next_deadline = Some(next_deadline.map_or(deadline, |next| next.min(deadline)));
+                }
+            }
+            true
+        });
+        let removed = dead_keys.iter()
+            .filter(|key| self.remove_entry(key, &|versions| versions.expired(&now)).is_some())
+            .count();
+        // deadlines set while the sweep ran were recorded meanwhile
+        if let Some(deadline) = next_deadline {
+            self.note_deadline(deadline);
+        }
+        removed
+    }
+
+    fn note_deadline(&self, deadline: Instant) {
+        let mut next = self.next_deadline.write();
+        *next = Some(next.map_or(deadline, |next| next.min(deadline)));
+    }
+
+    // Counts only cover entries still in the tree, so expired ranges and
+    // entries must be purged before they are consulted.
+    fn purge_if_expired(&self) {
+        let now = Now::default();
+        if self.range_ttls.read().iter().any(|range_ttl| range_ttl.expired(&now)) {
+            self.purge_expired_ranges();
+        }
+        if self.next_deadline.read().is_some_and(|deadline| now.get() >= deadline) {
+            self.purge_expired();
+        }
+    }
+
+    fn in_expired_range(&self, key: &K, now: &Now) -> bool {
+        self.range_ttls.read().iter().any(|range_ttl| range_ttl.expired(now) && range_ttl.contains(key))
+    }
+
+    // The current value of an entry, unless it was removed or has expired,
+    // either by its own deadline or with a range.
+    fn visible<'v>(&self, key: &K, versions: &'v Versions<V>, now: &Now) -> Option<&'v V> {
+        let val = versions.latest()?;
+        if versions.expired(now) || self.in_expired_range(key, now) {
+            return None;
+        }
+        Some(val)
+    }
+
+    // `visible` as of sequence number `at`, or as of now without one. Expiry
+    // hides an entry at any sequence number.
+    fn visible_at<'v>(&self, key: &K, versions: &'v Versions<V>, at: Option<u64>, now: &Now) -> Option<&'v V> {
+        let seq = match at {
+            Some(seq) if versions.latest_seq() > seq => seq,
+            _ => return self.visible(key, versions, now),
+        };
+        if self.in_expired_range(key, now) {
+            return None;
+        }
+        versions.at(seq)
+    }
+
+    // `get` as of sequence number `seq`, expiry included.
+    pub(crate) fn get_as_of(&self, key: &K, seq: u64) -> Option<V> {
+        let leaf_node_content = self.find_leaf(key)?;
+        let i = K::search(&leaf_node_content.keys, key).ok()?;
+        self.visible_at(key, &leaf_node_content.vals[i], Some(seq), &Now::default()).cloned()
+    }
+
+    fn read_in(&self, leaf_node_content: &LeafNode<K, V>, key: &K, now: &Now) -> Option<V> {
+        let i = K::search(&leaf_node_content.keys, key).ok()?;
+        self.visible(key, &leaf_node_content.vals[i], now).cloned()
+    }
+
+    /// Drops versions older than the horizon and physically removes entries
+    /// whose tombstones no reader can still see.
+    pub fn gc(&self) {
+        let horizon = self.horizon();
+        let mut dead_keys = vec![];
+        self.walk_leaves_mut(Bound::Unbounded, |leaf_node_content| {
+            dead_keys.append(&mut leaf_node_content.prune(horizon));
+            true
+        });
+        // a concurrent write may have revived a key since it was pruned
+        for key in dead_keys {
+            self.remove_entry(&key, &|versions| versions.is_dead(horizon));
+        }
+    }
+
+    /// Rebuilds the tree as if bulk-loaded from its own entries, packing
+    /// every node to `fill` of its capacity, so the nodes left nearly empty
+    /// by heavy deletes are freed. `fill` is kept between the minimum
+    /// occupancy rebalancing expects and a full node. Versions and
+    /// tombstones no reader can see any more are dropped, as `gc` would.
+    /// Writers wait while the new tree is built beside the old one; readers
+    /// already inside finish on the old nodes.
+    pub fn compact(&self, fill: f64) {
+        let horizon = self.horizon();
+        let mut root = self.root.write();
+        let first = match root.leaf_ref(&|_| 0) {
+            Some(first) => first,
+            None => return,
+        };
+        // writers that got past the root latch hold the root node until done
+        drop(root.write_arc());
+
+        let mut entries = vec![];
+        let mut leaf = Some(first);
+        while let Some(leaf_node_ref) = leaf {
+            let mut leaf_node_content = leaf_node_ref.write();
+            leaf_node_content.retired = true;
+            for (key, versions) in leaf_node_content.keys.iter().zip(&leaf_node_content.vals) {
+                let mut versions = versions.clone();
+                if let Some(horizon) = horizon {
+                    versions.prune(horizon);
+                }
+                if !versions.is_dead(horizon) {
+                    entries.push((key.clone(), versions));
+                }
+            }
+            leaf = leaf_node_content.next.clone();
+        }
+        *root = BtreeNode::bulk(entries, fill, self.m-1);
+    }
+
+    /// Inserts or overwrites `key`, as the tree's `DuplicatePolicy` has it.
+    /// Most writes only latch the target leaf exclusively and the path
+    /// above it shared, so writes to different leaves proceed in parallel;
+    /// a write that may split its leaf retries with the whole path latched
+    /// exclusively. Panics if the key or value is over the tree's size
+    /// limits, or on a key already present under `DuplicatePolicy::Error`;
+    /// `set_checked` reports either as an error instead.
+    pub fn set(&self, key: K, val: V)  {
+        self.set_until(key, val, None);
+    }
+
+    /// Like `set`, but refuses what `set` panics on with an error, leaving
+    /// the tree as it was: `BptreeError::Size` for a key or value over the
+    /// tree's size limits, `BptreeError::Occupied` for a key already present
+    /// under `DuplicatePolicy::Error`, handing back the key and value with
+    /// the one kept.
+    pub fn set_checked(&self, key: K, val: V) -> Result<(), BptreeError<K, V>> {
+        self.set_waiting(key, val, &Wait::Forever)
+    }
+
+    /// Like `set`, but the entry disappears from reads once `ttl` has
+    /// passed, until it is written again. Expired entries keep their slot
+    /// until `purge_expired` sweeps them out.
+    pub fn set_with_ttl(&self, key: K, val: V, ttl: Duration) {
+        let deadline = Instant::now() + ttl;
+        self.note_deadline(deadline);
+        self.set_until(key, val, Some(deadline));
+    }
+
+    /// Like `set_checked`, but fails with `LockError::WouldBlock` instead
+    /// of waiting when a latch it needs is held, leaving the tree as it was.
+    /// Writing into a range whose time to live has run out always fails,
+    /// since the range has to be purged first; `purge_expired_ranges` does
+    /// that.
+    pub fn try_set(&self, key: K, val: V) -> Result<(), BptreeError<K, V>> {
+        self.set_waiting(key, val, &Wait::Never)
+    }
+
+    /// Like `try_set`, but waits at most `timeout` for latches, all of them
+    /// together, before failing with `LockError::Timeout`. A write that has
+    /// to split waits for the whole path to come free within the timeout,
+    /// but may still block briefly behind a scan reaching the same leaf.
+    pub fn set_timeout(&self, key: K, val: V, timeout: Duration) -> Result<(), BptreeError<K, V>> {
+        self.set_waiting(key, val, &Wait::Until(Instant::now() + timeout))
+    }
+
+    fn set_waiting(&self, key: K, val: V, wait: &Wait) -> Result<(), BptreeError<K, V>> {
+        match self.set_within(key, val, None, wait, self.duplicates, None)? {
+            Some(occupied) if self.duplicates == DuplicatePolicy::Error => Err(occupied.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_until(&self, key: K, val: V, deadline: Option<Instant>) {
+        match self.set_within(key, val, deadline, &Wait::Forever, self.duplicates, None) {
+            Ok(Some(occupied)) => self.refuse(occupied),
+            Ok(None) => {},
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // What `set` does with a key the duplicate policy kept: nothing, unless
+    // the policy is to panic.
+    fn refuse(&self, occupied: OccupiedError<K, V>) {
+        if self.duplicates == DuplicatePolicy::Error {
+            panic!("{}", occupied);
+        }
+    }
+
+    // `set` regardless of the duplicate policy, for writes that carry over
+    // entries from elsewhere, like `append` and `restore`, which must land
+    // as they were. Only the size limits can refuse them.
+    pub(crate) fn overwrite(&self, key: K, val: V) -> Result<(), BptreeError<K, V>> {
+        self.set_within(key, val, None, &Wait::Forever, DuplicatePolicy::Overwrite, None).map(|_| ())
+    }
+
+    // Writes `key` as `duplicates` has it. A key that policy keeps as it was
+    // is handed back, with the value kept, and the tree left as it was. With
+    // a `hint`, a write that fits its leaf goes through the recorded path
+    // there, and records the one it took for next time.
+    fn set_within(&self, key: K, val: V, deadline: Option<Instant>, wait: &Wait, duplicates: DuplicatePolicy, hint: Option<&mut Option<LeafPath<K, V>>>) -> Result<Option<OccupiedError<K, V>>, BptreeError<K, V>> {
+        self.limits.check(&key, &val)?;
+        if self.in_expired_range(&key, &Now::default()) {
+            match wait {
+                Wait::Forever => {self.purge_expired_ranges();},
+                // purging walks and rewrites the whole range
+                _ => return Err(wait.fail().into()),
+            }
+        }
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let horizon = self.horizon();
+        let hooks = self.hooks();
+        let mut val = Some(val);
+        let now = Now::default();
+        // the live value the duplicate policy keeps in place of `val`
+        let kept = |leaf_node_content: &LeafNode<K, V>| {
+            if duplicates == DuplicatePolicy::Overwrite {
+                return None;
+            }
+            let i = K::search(&leaf_node_content.keys, &key).ok()?;
+            self.visible(&key, &leaf_node_content.vals[i], &now).cloned()
+        };
+        let done = self.update_leaf_hinted(&key, wait, hint, |leaf_node_content| {
+            if let Some(current) = kept(leaf_node_content) {
+                return Some((Some(current), 0));
+            }
+            if !leaf_node_content.has_room_for(&key, horizon) {
+                return None;
+            }
+            let delta = if leaf_node_content.is_live(&key) { 0 } else { 1 };
+            hooks.notify(&key, leaf_node_content.latest(&key), val.as_ref());
+            leaf_node_content.set(key.clone(), val.take().unwrap(), seq, horizon, deadline);
+            Some((None, delta))
+        })?;
+        let kept = match done {
+            Some(kept) => kept,
+            None => {
+                // an insert that may split nodes up to the root keeps the
+                // root latch and the whole path latched exclusively, fixing
+                // subtree counts on the way back up
+                let mut root = wait.write_root(&self.root)?;
+                if !matches!(wait, Wait::Forever) {
+                    Self::probe_path(&root, &key, wait)?;
+                }
+                let written = root.write_leaf(&key, self.m-1, |leaf_node_content| {
+                    if let Some(current) = kept(leaf_node_content) {
+                        return (Some(current), 0, None);
+                    }
+                    let delta = if leaf_node_content.is_live(&key) { 0 } else { 1 };
+                    hooks.notify(&key, leaf_node_content.latest(&key), val.as_ref());
+                    (None, delta, leaf_node_content.set(key.clone(), val.take().unwrap(), seq, horizon, deadline))
+                });
+                written.unwrap_or_else(|| {
+                    // the tree is empty
+                    root.insert(key.clone(), val.take().unwrap(), seq, horizon, deadline, &hooks, self.m-1);
+                    None
+                })
+            },
+        };
+        if let Some(current) = kept {
+            let val = val.take().unwrap();
+            return Ok(Some(OccupiedError{ key, current, val }));
+        }
+        self.enforce_budget();
+        Ok(None)
+    }
+
+    // Waits, within `wait`, for every latch on the path to `key` to come
+    // free, then lets go of them again. With the root latch held nobody can
+    // latch the path from above meanwhile, so an insert that follows does not
+    // block there; only a walk along the leaf chain can still get in first.
+    fn probe_path(root: &BtreeNode<K, V>, key: &K, wait: &Wait) -> Result<(), LockError> {
+        let mut node = root.clone();
+        loop {
+            node = match &node {
+                BtreeNode::inner(inner_node_ref) => {
+                    let inner_node_content = wait.write(inner_node_ref)?;
+                    inner_node_content.childNodeptrs[inner_node_content.child_index(key)].clone()
+                },
+                BtreeNode::leaf(leaf_node_ref) => {
+                    drop(wait.write(leaf_node_ref)?);
+                    return Ok(());
+                },
+                BtreeNode::placehold => return Ok(()),
+            };
+        }
+    }
+
+    /// Writes every operation of `batch` as one unit. The root latch is held
+    /// throughout, so readers and writers that start after `apply` does see
+    /// either none of the batch or all of it, and every write in it shares
+    /// one sequence number, so `get_at` never sees part of it either. An
+    /// iterator already running can still see part of it, as with any
+    /// write, since it holds no latch between entries.
+    ///
+    /// Puts follow the tree's duplicate policy, in batch order, so a put
+    /// meets the keys earlier operations of the batch wrote or deleted.
+    /// Under `KeepFirst` a put to a live key is dropped; under `Error` it
+    /// refuses the whole batch. Nothing is written when the batch is
+    /// refused, whether for a duplicate or for an entry over the tree's size
+    /// limits.
+    pub fn apply(&self, batch: WriteBatch<K, V>) -> Result<(), BptreeError<K, V>> {
+        for (key, val) in &batch.ops {
+            if let Some(val) = val {
+                self.limits.check(key, val)?;
+            }
+        }
+        let now = Now::default();
+        if batch.ops.iter().any(|(key, val)| val.is_some() && self.in_expired_range(key, &now)) {
+            self.purge_expired_ranges();
+        }
+        let mut root = self.root.write();
+        // writers that got past the root latch hold the root node until done
+        drop(root.write_arc());
+        let ops = match self.duplicates {
+            DuplicatePolicy::Error | DuplicatePolicy::KeepFirst => self.screen(&root, batch.ops, &now)?,
+            _ => batch.ops,
+        };
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let horizon = self.horizon();
+        let hooks = self.hooks();
+        for (key, val) in ops {
+            match val {
+                Some(val) => root.insert(key, val, seq, horizon, None, &hooks, self.m-1),
+                None if horizon.is_some() => {
+                    root.write_leaf(&key, self.m-1, |leaf_node_content| {
+                        let old_val = leaf_node_content.tombstone_at(&key, seq, horizon);
+                        hooks.notify(&key, old_val.as_ref(), None);
+                        ((), if old_val.is_some() { -1 } else { 0 }, None)
+                    });
+                },
+                None => {
+                    if let Some(old_versions) = root.remove(&key, &|_| true) {
+                        hooks.notify(&key, old_versions.latest(), None);
+                    }
+                    root.collapse();
+                },
+            }
+        }
+        drop(root);
+        self.enforce_budget();
+        Ok(())
+    }
+
+    // The operations of a batch the duplicate policy lets through, played
+    // in order against the live values under `root`, which the caller holds
+    // with no writer left below it. Under `Error` the first put to a live
+    // key refuses them all.
+    fn screen(&self, root: &BtreeNode<K, V>, ops: BatchOps<K, V>, now: &Now) -> Result<BatchOps<K, V>, BptreeError<K, V>> {
+        // what the operations let through so far left at their keys
+        let mut written: BTreeMap<K, Option<V>> = BTreeMap::new();
+        let mut passed = Vec::with_capacity(ops.len());
+        for (key, val) in ops {
+            let val = match val {
+                Some(val) => val,
+                None => {
+                    written.insert(key.clone(), None);
+                    passed.push((key, None));
+                    continue;
+                },
+            };
+            let current = match written.get(&key) {
+                Some(current) => current.clone(),
+                None => root.leaf_ref(&|inner_node_content| inner_node_content.child_index(&key)).and_then(|leaf_node_ref| {
+                    let leaf_node_content = leaf_node_ref.read();
+                    let i = K::search(&leaf_node_content.keys, &key).ok()?;
+                    self.visible(&key, &leaf_node_content.vals[i], now).cloned()
+                }),
+            };
+            match current {
+                Some(current) if self.duplicates == DuplicatePolicy::Error => {
+                    return Err(OccupiedError{ key, current, val }.into());
+                },
+                Some(_) => {},
+                None => {
+                    written.insert(key.clone(), Some(val.clone()));
+                    passed.push((key, Some(val)));
+                },
+            }
+        }
+        Ok(passed)
+    }
+
+    // Runs `f` on the leaf for `key` under an exclusive latch while every
+    // inner node above it stays latched shared, then adds the change in live
+    // entries `f` reports to the subtree counts along the path. Writers only
+    // share the path, so any number of them can be inside at once. `f`
+    // returns `None` to back out, as does an empty tree.
+    fn update_leaf<R, F>(&self, key: &K, f: F) -> Option<R>
+        where F: FnOnce(&mut LeafNode<K, V>) -> Option<(R, isize)>
+    {
+        self.update_leaf_within(key, &Wait::Forever, f).unwrap_or(None)
+    }
+
+    // `update_leaf`, giving up once a latch cannot be had within `wait`.
+    fn update_leaf_within<R, F>(&self, key: &K, wait: &Wait, f: F) -> Result<Option<R>, LockError>
+        where F: FnOnce(&mut LeafNode<K, V>) -> Option<(R, isize)>
+    {
+        self.update_leaf_hinted(key, wait, None, f)
+    }
+
+    // `update_leaf_within`, taking the recorded `hint` path instead of
+    // searching each level if it still leads to `key`'s leaf. Otherwise the
+    // path found descending is recorded in its place.
+    fn update_leaf_hinted<R, F>(&self, key: &K, wait: &Wait, hint: Option<&mut Option<LeafPath<K, V>>>, f: F) -> Result<Option<R>, LockError>
+        where F: FnOnce(&mut LeafNode<K, V>) -> Option<(R, isize)>
+    {
+        let latched = match hint.as_deref() {
+            Some(Some(recorded)) => self.latch_path(recorded, key, wait)?,
+            _ => None,
+        };
+        let (path, mut leaf) = match latched {
+            Some(latched) => latched,
+            None => {
+                let Some((path, leaf)) = self.latch_leaf_path(key, wait)? else { return Ok(None) };
+                if let Some(hint) = hint {
+                    *hint = Some(LeafPath{
+                        inner: path.iter().map(|(inner_node_content, index)| (ArcRwLockReadGuard::rwlock(inner_node_content).clone(), *index)).collect(),
+                        leaf: ArcRwLockWriteGuard::rwlock(&leaf).clone(),
+                    });
+                }
+                (path, leaf)
+            },
+        };
+        let Some((result, delta)) = f(&mut leaf) else { return Ok(None) };
+        for (inner_node_content, index) in &path {
+            inner_node_content.add_count(*index, delta);
+        }
+        Ok(Some(result))
+    }
+
+    // Descends to `key`'s leaf as `update_leaf_within` does, returning the
+    // path latched shared and the leaf exclusive.
+    fn latch_leaf_path(&self, key: &K, wait: &Wait) -> Result<Option<LatchedPath<K, V>>, LockError> {
+        #[cfg(test)]
+        WRITE_DESCENTS.with(|descents| descents.set(descents.get() + 1));
+        let root = wait.read_root(&self.root)?;
+        let mut path: Vec<(InnerReadGuard<K, V>, usize)> = vec![];
+        let (mut inner, mut leaf) = match &*root {
+            BtreeNode::leaf(leaf_node_ref) => (None, Some(wait.write(leaf_node_ref)?)),
+            BtreeNode::inner(inner_node_ref) => (Some(wait.read(inner_node_ref)?), None),
+            BtreeNode::placehold => return Ok(None),
+        };
+        drop(root);
+        while let Some(inner_node_content) = inner.take() {
+            let index = inner_node_content.child_index(key);
+            match &inner_node_content.childNodeptrs[index] {
+                BtreeNode::leaf(leaf_node_ref) => leaf = Some(wait.write(leaf_node_ref)?),
+                BtreeNode::inner(inner_node_ref) => inner = Some(wait.read(inner_node_ref)?),
+                BtreeNode::placehold => return Ok(None),
+            }
+            path.push((inner_node_content, index));
+        }
+        Ok(leaf.map(|leaf| (path, leaf)))
+    }
+
+    // Latches `recorded` as `latch_leaf_path` would the path it descends,
+    // in the same order, provided it still is that path: the root is its
+    // top, every node links to the next, and `key` lies within the fences
+    // each one routes by. A node latched shared cannot be split or merged
+    // away, so what is checked holds for as long as the latches do.
+    fn latch_path(&self, recorded: &LeafPath<K, V>, key: &K, wait: &Wait) -> Result<Option<LatchedPath<K, V>>, LockError> {
+        let root = wait.read_root(&self.root)?;
+        let is_top = match (&*root, recorded.inner.first()) {
+            (BtreeNode::inner(inner_node_ref), Some((top, _))) => Arc::ptr_eq(inner_node_ref, top),
+            (BtreeNode::leaf(leaf_node_ref), None) => Arc::ptr_eq(leaf_node_ref, &recorded.leaf),
+            _ => false,
+        };
+        if !is_top {
+            return Ok(None);
+        }
+        let mut root = Some(root);
+        let mut path: Vec<(InnerReadGuard<K, V>, usize)> = vec![];
+        for (level, (inner_node_ref, index)) in recorded.inner.iter().enumerate() {
+            let inner_node_content = wait.read(inner_node_ref)?;
+            drop(root.take());
+            let links = match (inner_node_content.childNodeptrs.get(*index), recorded.inner.get(level + 1)) {
+                (Some(BtreeNode::inner(child)), Some((next, _))) => Arc::ptr_eq(child, next),
+                (Some(BtreeNode::leaf(child)), None) => Arc::ptr_eq(child, &recorded.leaf),
+                _ => false,
+            };
+            let fenced = (*index == 0 || inner_node_content.keys[*index-1] <= *key)
+                && inner_node_content.keys.get(*index).is_none_or(|fence| key < fence);
+            if !links || !fenced {
+                return Ok(None);
+            }
+            path.push((inner_node_content, *index));
+        }
+        let leaf = wait.write(&recorded.leaf)?;
+        Ok(Some((path, leaf)))
+    }
+
+    /// Changes the value of `key` in place with `f` while its leaf is
+    /// latched, so no other write to the key comes in between and the value
+    /// is never cloned. Returns whether the key was there; `f` only runs if
+    /// it was. Under a version horizon `f` gets a copy pushed as a new
+    /// version, as with `range_mut`.
+    pub fn update_with<F: FnOnce(&mut V)>(&self, key: &K, f: F) -> bool {
+        let horizon = self.horizon();
+        let hooks = self.hooks();
+        let now = Now::default();
+        let updated = self.update_leaf(key, |leaf_node_content| {
+            let i = K::search(&leaf_node_content.keys, key).ok()?;
+            self.visible(key, &leaf_node_content.vals[i], &now)?;
+            let versions = &mut leaf_node_content.vals[i];
+            let old_val = if hooks.is_empty() { None } else { versions.latest().cloned() };
+            let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+            if horizon.is_some() {
+                let deadline = versions.deadline();
+                versions.push(seq, versions.latest().cloned(), horizon);
+                versions.set_deadline(deadline);
+            }
+            f(versions.latest_mut()?);
+            if old_val.is_some() && versions.latest() != old_val.as_ref() {
+                hooks.notify(key, old_val.as_ref(), versions.latest());
+            }
+            Some(((), 0))
+        });
+        self.enforce_budget();
+        updated.is_some()
+    }
+
+    /// Replaces the value of `key` with `new`, or removes it if `new` is
+    /// `None`, provided it currently holds `expected`, `None` meaning no
+    /// live entry. The comparison and the write happen under one latch, so
+    /// no other write to the key can come in between. On a mismatch the
+    /// tree is left as it was and the value found is returned as
+    /// `BptreeError::Mismatch`; a `new` value over the tree's size limits is
+    /// refused with `BptreeError::Size`.
+    pub fn compare_and_swap(&self, key: &K, expected: Option<&V>, new: Option<V>) -> Result<(), BptreeError<K, V>> {
+        if let Some(val) = &new {
+            self.limits.check(key, val)?;
+        }
+        self.swap_if(key, expected, new).0.map_err(BptreeError::Mismatch)
+    }
+
+    /// Inserts `key` unless it already has a live entry, which is then left
+    /// as it was and returned in `BptreeError::Occupied` along with `val`.
+    /// Unlike `set`, it never overwrites. An entry over the tree's size
+    /// limits is refused with `BptreeError::Size`.
+    pub fn try_insert(&self, key: K, val: V) -> Result<(), BptreeError<K, V>> {
+        self.limits.check(&key, &val)?;
+        match self.swap_if(&key, None, Some(val)) {
+            (Ok(()), _) => Ok(()),
+            (Err(CasError{ current }), val) => Err(BptreeError::Occupied(OccupiedError{
+                key,
+                current: current.expect("a failed swap from absent found an entry"),
+                val: val.expect("a failed swap hands back its value"),
+            })),
+        }
+    }
+
+    // `compare_and_swap`, also handing back `new` if it was not written. The
+    // caller checks `new` against the size limits.
+    fn swap_if(&self, key: &K, expected: Option<&V>, mut new: Option<V>) -> (Result<(), CasError<V>>, Option<V>) {
+        let now = Now::default();
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let horizon = self.horizon();
+        let hooks = self.hooks();
+        // the write itself, for a leaf found holding `expected`; `None` if
+        // it would have to split or merge the leaf
+        let mut swap = |leaf_node_content: &mut LeafNode<K, V>| -> Option<isize> {
+            match new.take() {
+                Some(val) => {
+                    if !leaf_node_content.has_room_for(key, horizon) {
+                        new = Some(val);
+                        return None;
+                    }
+                    let delta = if leaf_node_content.is_live(key) { 0 } else { 1 };
+                    hooks.notify(key, leaf_node_content.latest(key), Some(&val));
+                    leaf_node_content.set(key.clone(), val, seq, horizon, None);
+                    Some(delta)
+                },
+                None if expected.is_none() => Some(0),
+                None if horizon.is_some() => {
+                    let old_val = leaf_node_content.tombstone_at(key, seq, horizon);
+                    hooks.notify(key, old_val.as_ref(), None);
+                    Some(if old_val.is_some() { -1 } else { 0 })
+                },
+                None => None,
+            }
+        };
+        let current = |leaf_node_content: &LeafNode<K, V>| {
+            let i = K::search(&leaf_node_content.keys, key).ok()?;
+            self.visible(key, &leaf_node_content.vals[i], &now).cloned()
+        };
+        let done = self.update_leaf(key, |leaf_node_content| {
+            let found = current(leaf_node_content);
+            if found.as_ref() != expected {
+                return Some((Err(CasError{ current: found }), 0));
+            }
+            swap(leaf_node_content).map(|delta| (Ok(()), delta))
+        });
+        let result = match done {
+            Some(result) => result,
+            None if new.is_some() => {
+                let mut root = self.root.write();
+                let written = root.write_leaf(key, self.m-1, |leaf_node_content| {
+                    let found = current(leaf_node_content);
+                    if found.as_ref() != expected {
+                        return (Err(CasError{ current: found }), 0, None);
+                    }
+                    let delta = if leaf_node_content.is_live(key) { 0 } else { 1 };
+                    let val = new.take().unwrap();
+                    hooks.notify(key, leaf_node_content.latest(key), Some(&val));
+                    (Ok(()), delta, leaf_node_content.set(key.clone(), val, seq, horizon, None))
+                });
+                match written {
+                    Some(result) => result,
+                    // the tree is empty
+                    None if expected.is_none() => {
+                        root.insert(key.clone(), new.take().unwrap(), seq, horizon, None, &hooks, self.m-1);
+                        Ok(())
+                    },
+                    None => Err(CasError{ current: None }),
+                }
+            },
+            None => {
+                let found = RefCell::new(None);
+                let removed = self.remove_entry(key, &|versions| {
+                    let current = self.visible(key, versions, &now);
+                    *found.borrow_mut() = current.cloned();
+                    current == expected
+                });
+                match (removed, found.into_inner()) {
+                    (Some(_), _) => Ok(()),
+                    (None, current) if current.is_none() && expected.is_none() => Ok(()),
+                    (None, current) => Err(CasError{ current }),
+                }
+            },
+        };
+        if result.is_ok() {
+            self.enforce_budget();
+        }
+        (result, new)
+    }
+
+    /// Registers the function `merge` folds its operands in with, replacing
+    /// any registered before.
+    pub fn set_merge_operator(&self, merge: MergeFn<K, V>) {
+        *self.merge_op.write() = Some(MergeOperator(merge));
+    }
+
+    /// Sets `key` to the registered merge function applied to its current
+    /// value and `operand`, all under the latch of its leaf, so counters
+    /// and the like need no read-modify-write round trip and racing merges
+    /// all land. The function runs once per merge and must not use the
+    /// tree. Panics if no merge function is registered.
+    pub fn merge(&self, key: K, operand: V) {
+        let merge = self.merge_op.read().clone().expect("merge called without a merge operator");
+        let now = Now::default();
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let horizon = self.horizon();
+        let hooks = self.hooks();
+        let apply = |leaf_node_content: &mut LeafNode<K, V>| {
+            let i = K::search(&leaf_node_content.keys, &key).ok();
+            let current = i.and_then(|i| self.visible(&key, &leaf_node_content.vals[i], &now));
+            let val = (merge.0)(&key, current, &operand);
+            self.limits.assert(&key, &val);
+            let delta = if leaf_node_content.is_live(&key) { 0 } else { 1 };
+            hooks.notify(&key, leaf_node_content.latest(&key), Some(&val));
+            (delta, leaf_node_content.set(key.clone(), val, seq, horizon, None))
+        };
+        let done = self.update_leaf(&key, |leaf_node_content| {
+            if !leaf_node_content.has_room_for(&key, horizon) {
+                return None;
+            }
+            let (delta, split) = apply(leaf_node_content);
+            debug_assert!(split.is_none());
+            Some(((), delta))
+        });
+        if done.is_none() {
+            let mut root = self.root.write();
+            let written = root.write_leaf(&key, self.m-1, |leaf_node_content| {
+                let (delta, split) = apply(leaf_node_content);
+                ((), delta, split)
+            });
+            if written.is_none() {
+                // the tree is empty
+                let val = (merge.0)(&key, None, &operand);
+                self.limits.assert(&key, &val);
+                root.insert(key.clone(), val, seq, horizon, None, &hooks, self.m-1);
+            }
+        }
+        self.enforce_budget();
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let horizon = self.horizon();
+        if horizon.is_some() {
+            let hooks = self.hooks();
+            return self.update_leaf(key, |leaf_node_content| {
+                let old_val = leaf_node_content.tombstone(key, &self.seq, horizon);
+                hooks.notify(key, old_val.as_ref(), None);
+                let delta = if old_val.is_some() { -1 } else { 0 };
+                Some((old_val, delta))
+            })?;
+        }
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        if self.lazy_rebalance.load(Ordering::SeqCst) {
+            let hooks = self.hooks();
+            let removed = self.update_leaf(key, |leaf_node_content| {
+                if leaf_node_content.keys.len() == 1 && K::search(&leaf_node_content.keys, key).is_ok() {
+                    return None;
+                }
+                let old_val = leaf_node_content.remove(key, &|_| true).and_then(Versions::into_latest);
+                hooks.notify(key, old_val.as_ref(), None);
+                let delta = if old_val.is_some() { -1 } else { 0 };
+                Some((old_val, delta))
+            });
+            if let Some(old_val) = removed {
+                return old_val;
+            }
+        }
+        self.remove_entry(key, &|_| true)?.into_latest()
+    }
+
+    /// Removes `key` and returns its value, but only if `pred` holds for
+    /// the value. The check and the removal happen under one latch, so an
+    /// entry rewritten meanwhile is judged by its new value.
+    pub fn remove_if<F: FnOnce(&V) -> bool>(&self, key: &K, pred: F) -> Option<V> {
+        let now = Now::default();
+        let horizon = self.horizon();
+        if horizon.is_some() {
+            let hooks = self.hooks();
+            return self.update_leaf(key, |leaf_node_content| {
+                let i = K::search(&leaf_node_content.keys, key).ok()?;
+                if !self.visible(key, &leaf_node_content.vals[i], &now).is_some_and(pred) {
+                    return None;
+                }
+                let old_val = leaf_node_content.tombstone(key, &self.seq, horizon);
+                hooks.notify(key, old_val.as_ref(), None);
+                Some((old_val, -1))
+            })?;
+        }
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        let pred = Cell::new(Some(pred));
+        self.remove_entry(key, &|versions| {
+            self.visible(key, versions, &now).is_some_and(|val| pred.take().is_some_and(|pred| pred(val)))
+        })?.into_latest()
+    }
+
+    // Removal keeps the root latch and every node on its path locked, since
+    // rebalancing can reach all the way up to the root.
+    fn remove_entry(&self, key: &K, doomed: &dyn Fn(&Versions<V>) -> bool) -> Option<Versions<V>> {
+        let hooks = self.hooks();
+        let mut root = self.root.write();
+        let old_versions = root.remove(key, doomed)?;
+        root.collapse();
+        hooks.notify(key, old_versions.latest(), None);
+        Some(old_versions)
+    }
+
+    /// Removes every entry whose key falls in `range` and returns how many
+    /// were live. Subtrees lying wholly inside the range are unlinked in one
+    /// go rather than emptied key by key; only the two boundary leaves are
+    /// trimmed. With a version horizon set the entries are tombstoned
+    /// instead, as `remove` would, so `get_at` stays repeatable. With hooks
+    /// registered they are also removed one by one, so each gets reported.
+    pub fn remove_range<R: RangeBounds<K>>(&self, range: R) -> usize {
+        let (start, end) = (range.start_bound(), range.end_bound());
+        if let (Bound::Included(lo) | Bound::Excluded(lo), Bound::Included(hi) | Bound::Excluded(hi)) = (start, end) {
+            if lo > hi {
+                return 0;
+            }
+        }
+        if self.horizon().is_some() || !self.hooks().is_empty() {
+            let mut doomed = vec![];
+            self.walk_leaves(start, |leaf_node_content| {
+                doomed.extend(leaf_node_content.keys.iter().filter(|key| (start, end).contains(*key)).cloned());
+                leaf_node_content.keys.last().is_none_or(|key| (Bound::Unbounded, end).contains(key))
+            });
+            return doomed.iter().filter(|key| self.remove(key).is_some()).count();
+        }
+
+        // Holding the root latch keeps new writers out. The boundary leaves
+        // are trimmed and linked to each other first, then the subtrees in
+        // between are cut loose.
+        let mut root = self.root.write();
+        let first = root.leaf_ref(&|inner_node_content| inner_node_content.start_index(start));
+        let last = root.leaf_ref(&|inner_node_content| inner_node_content.end_index(end));
+        let (first, last) = match (first, last) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 0,
+        };
+        let mut removed = 0;
+        {
+            let mut first_leaf = first.write();
+            removed += first_leaf.remove_span(start, end);
+            if !Arc::ptr_eq(&first, &last) {
+                let mut last_leaf = last.write();
+                removed += last_leaf.remove_span(start, end);
+                first_leaf.next = Some(last.clone());
+            }
+        }
+        removed += root.drop_span(Some(start), Some(end));
+        root.collapse();
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        removed
+    }
+
+    /// Moves every entry of `other` into this tree and leaves `other` empty.
+    /// When all of `other`'s keys sort after this tree's and both trees have
+    /// the same order, the shorter tree is hung off the taller one's spine
+    /// and only the nodes along that spine are touched; otherwise the
+    /// entries are moved one by one. Either way an entry of `other` takes
+    /// the place of one under the same key here, whatever the tree's
+    /// `DuplicatePolicy`. Appended entries keep their version
+    /// history, and this tree's sequence numbers move past `other`'s so
+    /// later writes still order after them. Range expiries still pending on
+    /// `other` carry over and apply to this tree from then on.
+    pub fn append(&self, other: &mut Self) {
+        self.range_ttls.write().append(other.range_ttls.get_mut());
+        if let Some(deadline) = other.next_deadline.get_mut().take() {
+            self.note_deadline(deadline);
+        }
+        self.seq.fetch_max(other.seq(), Ordering::SeqCst);
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        // the nodes hung off this tree bring summaries cached under `other`'s
+        // epochs, so move past those too
+        self.summary_epoch.fetch_max(*other.summary_epoch.get_mut(), Ordering::SeqCst);
+        self.summary_epoch.fetch_add(1, Ordering::SeqCst);
+        let subtree = std::mem::replace(other.root.get_mut(), BtreeNode::placehold);
+        let separator = match subtree.first_key() {
+            Some(separator) => separator,
+            None => return,
+        };
+        let mut root = self.root.write();
+        match root.last_key() {
+            None => *root = subtree,
+            Some(last) if last < separator && self.m == other.m => {
+                *root = BtreeNode::join(Some(root.clone()), subtree, self.m-1).unwrap();
+            },
+            Some(_) => {
+                drop(root);
+                *other.root.get_mut() = subtree;
+                for (key, val) in other.iter() {
+                    if let Err(err) = self.overwrite(key, val) {
+                        panic!("{}", err);
+                    }
+                }
+                *other.root.get_mut() = BtreeNode::placehold;
+            },
+        }
+    }
+
+    // Adds `entries` in any order, later duplicates and those of `entries`
+    // over the tree's winning, whatever the duplicate policy, all under one
+    // sequence number. Unless hooks need to hear about each entry, they are
+    // bulk built into a tree of their own and appended, which only touches
+    // one spine when they all sort after this tree's keys. An entry over
+    // the size limits refuses the lot before anything is written.
+    #[cfg(any(feature = "csv", feature = "serde_json"))]
+    pub(crate) fn load(&self, mut entries: Vec<(K, V)>) -> Result<(), BptreeError<K, V>> {
+        for (key, val) in &entries {
+            self.limits.check(key, val)?;
+        }
+        if !self.hooks().is_empty() {
+            for (key, val) in entries {
+                self.overwrite(key, val)?;
+            }
+            return Ok(());
+        }
+        // the sort is stable, so after reversing dedup keeps the last write
+        entries.reverse();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries.dedup_by(|a, b| a.0 == b.0);
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let entries = entries.into_iter().map(|(key, val)| (key, Versions::new(seq, val))).collect();
+        let mut loaded = Self::new(self.m);
+        *loaded.seq.get_mut() = seq;
+        *loaded.root.get_mut() = BtreeNode::bulk(entries, 1.0, self.m-1);
+        self.append(&mut loaded);
+        Ok(())
+    }
+
+    /// Moves every entry at or after `key` into a new tree and returns it.
+    /// Only the nodes on the path to `key` are cut in two; the subtrees on
+    /// either side change hands whole and are stitched back into two valid
+    /// trees. The new tree takes over the order, version horizon, sequence
+    /// number and pending range expiries. Cursors already open on this tree
+    /// may still step onto entries that moved.
+    pub fn split_off(&self, key: &K) -> Self {
+        let mut root = self.root.write();
+        // pieces left of the path, tallest first, and right of it, shortest
+        // first, so each list can be joined in order
+        let mut left_pieces = vec![];
+        let mut right_pieces = vec![];
+        let mut node = root.clone();
+        let mut guard = node.write_arc();
+        loop {
+            match guard {
+                Some(NodeGuard::inner(mut inner_node_content)) => {
+                    let index = inner_node_content.child_index(key);
+                    let max_key_count = inner_node_content.max_key_count;
+                    let right_counts = inner_node_content.counts.drain(index+1..).collect();
+                    let left_counts = inner_node_content.counts.drain(..index).collect();
+                    right_pieces.push(BtreeNode::piece(&inner_node_content.keys[(index+1).min(inner_node_content.keys.len())..],
+                                                       &inner_node_content.childNodeptrs[index+1..], right_counts, max_key_count));
+                    left_pieces.push(BtreeNode::piece(&inner_node_content.keys[..index.saturating_sub(1)],
+                                                      &inner_node_content.childNodeptrs[..index], left_counts, max_key_count));
+                    node = inner_node_content.childNodeptrs[index].clone();
+                    guard = node.write_arc();
+                },
+                Some(NodeGuard::leaf(mut leaf_node_content)) => {
+                    let at = leaf_node_content.keys.partition_point(|k| k < key);
+                    let mut new_leaf = LeafNode::from(&leaf_node_content.keys[at..], &leaf_node_content.vals[at..], leaf_node_content.max_key_count);
+                    new_leaf.set_next(leaf_node_content.next.take());
+                    leaf_node_content.keys.truncate(at);
+                    leaf_node_content.vals.truncate(at);
+                    leaf_node_content.retired = at == 0;
+                    right_pieces.insert(0, Some(BtreeNode::leaf(Arc::new(RwLock::new(new_leaf)))));
+                    left_pieces.push(Some(node));
+                    break;
+                },
+                None => return Bptree::new(self.m),
+            }
+        }
+        right_pieces[1..].reverse();
+
+        let max_key_count = self.m-1;
+        let left = left_pieces.into_iter().flatten().fold(None, |acc, piece| BtreeNode::join(acc, piece, max_key_count));
+        let right = right_pieces.into_iter().flatten().fold(None, |acc, piece| BtreeNode::join(acc, piece, max_key_count));
+        if let Some(last) = left.as_ref().and_then(|left| left.leaf_ref(&|inner_node_content| inner_node_content.childNodeptrs.len() - 1)) {
+            last.write().next = None;
+        }
+        *root = left.unwrap_or(BtreeNode::placehold);
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        drop(root);
+        Self{
+            root: RwLock::new(right.unwrap_or(BtreeNode::placehold)),
+            m: self.m,
+            seq: AtomicU64::new(self.seq()),
+            horizon: RwLock::new(self.horizon()),
+            range_ttls: RwLock::new(self.range_ttls.read().clone()),
+            next_deadline: RwLock::new(*self.next_deadline.read()),
+            hooks: RwLock::new(Hooks::new()),
+            pins: RwLock::new(BTreeMap::new()),
+            lazy_rebalance: AtomicBool::new(self.lazy_rebalance.load(Ordering::SeqCst)),
+            limits: SizeLimits::new(self.limits.key(), self.limits.val()),
+            budget: RwLock::new(None),
+            merge_op: RwLock::new(self.merge_op.read().clone()),
+            duplicates: self.duplicates,
+            aggregate: RwLock::new(self.aggregate.read().clone()),
+            ranking: RwLock::new(self.ranking.read().clone()),
+            // nodes carried over may hold summaries cached under this tree's
+            // epochs, which mean nothing in the new one
+            summary_epoch: AtomicU64::new(self.summary_epoch.load(Ordering::SeqCst) + 1),
+        }
+    }
+
+    // Descends with shared latches on inner nodes, taking the child `pick`
+    // chooses at each level, and latches the leaf it reaches with
+    // `latch_leaf`. Each parent is released once its child is latched.
+    fn descend<L, P, F>(&self, pick: P, latch_leaf: F) -> Option<L>
+        where P: FnMut(&InnerNode<K, V>) -> usize,
+              F: Fn(&LeafNodeRef<K, V>) -> L,
+    {
+        self.descend_within(&Wait::Forever, pick, |leaf| Ok(latch_leaf(leaf))).unwrap_or(None)
+    }
+
+    // `descend`, giving up once a latch cannot be had within `wait`.
+    fn descend_within<L, P, F>(&self, wait: &Wait, mut pick: P, latch_leaf: F) -> Result<Option<L>, LockError>
+        where P: FnMut(&InnerNode<K, V>) -> usize,
+              F: Fn(&LeafNodeRef<K, V>) -> Result<L, LockError>,
+    {
+        let root = wait.read_root(&self.root)?;
+        let mut inner_node_content: InnerReadGuard<K, V> = match &*root {
+            BtreeNode::leaf(leaf_node_ref) => return latch_leaf(leaf_node_ref).map(Some),
+            BtreeNode::inner(inner_node_ref) => wait.read(inner_node_ref)?,
+            BtreeNode::placehold => return Ok(None),
+        };
+        drop(root);
+        loop {
+            let index = pick(&inner_node_content);
+            inner_node_content = match &inner_node_content.childNodeptrs[index] {
+                BtreeNode::leaf(leaf_node_ref) => return latch_leaf(leaf_node_ref).map(Some),
+                BtreeNode::inner(inner_node_ref) => wait.read(inner_node_ref)?,
+                BtreeNode::placehold => return Ok(None),
+            };
+        }
+    }
+
+    // Live entries below `to`: the counts of the children left of the path
+    // plus the matching entries of the leaf it ends in.
+    fn count_within(&self, to: Bound<&K>) -> usize {
+        let mut before = 0;
+        let leaf = self.descend(|inner_node_content| {
+            let index = inner_node_content.end_index(to);
+            before += (0..index).map(|i| inner_node_content.count(i)).sum::<usize>();
+            index
+        }, |leaf| leaf.read_arc());
+        let within = leaf.map_or(0, |leaf_node_content| {
+            leaf_node_content.keys.iter().zip(leaf_node_content.vals.iter())
+                .filter(|(key, versions)| (Bound::Unbounded, to).contains(*key) && versions.latest().is_some())
+                .count()
+        });
+        before + within
+    }
+
+    fn find_leaf(&self, key: &K) -> Option<LeafReadGuard<K, V>> {
+        self.descend(|inner_node_content| inner_node_content.child_index(key), |leaf| leaf.read_arc())
+    }
+
+    // Like `find_leaf`, but latches the leaf exclusively for an in-place update.
+    fn find_leaf_mut(&self, key: &K) -> Option<LeafGuard<K, V>> {
+        self.descend(|inner_node_content| inner_node_content.child_index(key), |leaf| leaf.write_arc())
+    }
+
+    fn start_leaf(&self, start: Bound<&K>) -> Option<LeafReadGuard<K, V>> {
+        match start {
+            Bound::Included(key) | Bound::Excluded(key) => self.find_leaf(key),
+            Bound::Unbounded => self.descend(|_| 0, |leaf| leaf.read_arc()),
+        }
+    }
+
+    // Visits leaves left to right from the one holding `start`, latching each
+    // leaf before letting go of its predecessor. Stops once `f` returns false.
+    fn walk_leaves<F>(&self, start: Bound<&K>, mut f: F)
+        where F: FnMut(&LeafNode<K, V>) -> bool
+    {
+        let mut leaf = self.start_leaf(start);
+        while let Some(leaf_node_content) = leaf {
+            if !f(&leaf_node_content) {
+                break;
+            }
+            leaf = leaf_node_content.next.as_ref().map(|next| next.read_arc());
+        }
+    }
+
+    // Same walk with exclusive latches, for passes that rewrite leaves.
+    fn walk_leaves_mut<F>(&self, start: Bound<&K>, mut f: F)
+        where F: FnMut(&mut LeafNode<K, V>) -> bool
+    {
+        let mut leaf = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self.find_leaf_mut(key),
+            Bound::Unbounded => self.descend(|_| 0, |leaf| leaf.write_arc()),
+        };
+        while let Some(mut leaf_node_content) = leaf {
+            if !f(&mut leaf_node_content) {
+                break;
+            }
+            leaf = leaf_node_content.next.as_ref().map(|next| next.write_arc());
+        }
+    }
+
+    // Copies the live entries after `from` out of the next leaf that has any,
+    // stopping at `end`, as of sequence number `at` if given and of the time
+    // `now`. Returns false once nothing can follow them.
+    pub(crate) fn read_chunk(&self, from: Bound<&K>, end: Bound<&K>, at: Option<u64>, now: &Now, buf: &mut VecDeque<(K, V)>) -> bool {
+        let mut leaf = self.start_leaf(from);
+        while let Some(leaf_node_content) = leaf {
+            for (key, versions) in leaf_node_content.keys.iter().zip(&leaf_node_content.vals) {
+                if !(from, Bound::Unbounded).contains(key) {
+                    continue;
+                }
+                if !(Bound::Unbounded, end).contains(key) {
+                    return false;
+                }
+                if let Some(val) = self.visible_at(key, versions, at, now) {
+                    buf.push_back((key.clone(), val.clone()));
+                }
+            }
+            if !buf.is_empty() {
+                return leaf_node_content.next.is_some();
+            }
+            leaf = leaf_node_content.next.as_ref().map(|next| next.read_arc());
+        }
+        false
+    }
+
+    // Number of entries `read_chunk` would copy out between `from` and `end`
+    // over all the leaves, without copying them.
+    pub(crate) fn count_chunks(&self, from: Bound<&K>, end: Bound<&K>, at: Option<u64>, now: &Now) -> usize {
+        let mut count = 0;
+        let mut leaf = self.start_leaf(from);
+        while let Some(leaf_node_content) = leaf {
+            for (key, versions) in leaf_node_content.keys.iter().zip(&leaf_node_content.vals) {
+                if !(from, Bound::Unbounded).contains(key) {
+                    continue;
+                }
+                if !(Bound::Unbounded, end).contains(key) {
+                    return count;
+                }
+                count += self.visible_at(key, versions, at, now).is_some() as usize;
+            }
+            leaf = leaf_node_content.next.as_ref().map(|next| next.read_arc());
+        }
+        count
+    }
+
+    // `read_chunk` walking backwards: copies the live entries before `end`
+    // out of the last leaf that has any, stopping at `start`, and puts them
+    // in front of `buf` in ascending order. Returns false once nothing can
+    // precede them.
+    pub(crate) fn read_chunk_back(&self, start: Bound<&K>, end: Bound<&K>, at: Option<u64>, now: &Now, buf: &mut VecDeque<(K, V)>) -> bool {
+        let mut bound = end.cloned();
+        loop {
+            let mut fence = None;
+            let leaf_node_content = match self.descend(|inner_node_content| {
+                let index = inner_node_content.keys.partition_point(|key| (Bound::Unbounded, bound.as_ref()).contains(key));
+                if index > 0 {
+                    fence = Some(inner_node_content.keys[index-1].clone());
+                }
+                index
+            }, |leaf| leaf.read_arc()) {
+                Some(leaf_node_content) => leaf_node_content,
+                None => return false,
+            };
+            let below = leaf_node_content.keys.partition_point(|key| (Bound::Unbounded, bound.as_ref()).contains(key));
+            for (key, versions) in leaf_node_content.keys[..below].iter().zip(&leaf_node_content.vals).rev() {
+                if !(start, Bound::Unbounded).contains(key) {
+                    return false;
+                }
+                if let Some(val) = self.visible_at(key, versions, at, now) {
+                    buf.push_front((key.clone(), val.clone()));
+                }
+            }
+            // every key left of this leaf sorts below its fence
+            let fence = match (fence, start) {
+                (Some(fence), Bound::Unbounded) => fence,
+                (Some(fence), Bound::Included(key) | Bound::Excluded(key)) if *key < fence => fence,
+                _ => return false,
+            };
+            if !buf.is_empty() {
+                return true;
+            }
+            bound = Bound::Excluded(fence);
+        }
+    }
+
+    // Like `read_chunk`, but copies out every entry written after `since`
+    // instead of the live ones, with `None` for entries that are removed or
+    // expired.
+    pub(crate) fn read_changes(&self, from: Bound<&K>, since: u64, buf: &mut Vec<(K, Option<V>)>) -> bool {
+        let now = Now::default();
+        self.scan_changes(from, since, buf, |key, versions| {
+            Some((key.clone(), self.visible(key, versions, &now).cloned()))
+        })
+    }
+
+    // `read_changes` as changes from what a read at `since` saw, skipping
+    // entries rewritten to what they were. The versions at `since` must
+    // still be kept, by a snapshot at `since` for one.
+    pub(crate) fn read_diff(&self, from: Bound<&K>, since: u64, buf: &mut Vec<Change<K, V>>) -> bool {
+        let now = Now::default();
+        self.scan_changes(from, since, buf, |key, versions| {
+            let old = self.visible_at(key, versions, Some(since), &now);
+            match (old, self.visible(key, versions, &now)) {
+                (None, None) => None,
+                (None, Some(new)) => Some(Change::Added(key.clone(), new.clone())),
+                (Some(old), None) => Some(Change::Removed(key.clone(), old.clone())),
+                (Some(old), Some(new)) if old != new => Some(Change::Changed(key.clone(), old.clone(), new.clone())),
+                (Some(_), Some(_)) => None,
+            }
+        })
+    }
+
+    // Walks the leaves from `from` on and fills `buf` with what `change`
+    // makes of the entries written after `since`, stopping at the end of the
+    // first leaf that yielded any. True if there may be more to read.
+    fn scan_changes<T>(&self, from: Bound<&K>, since: u64, buf: &mut Vec<T>, mut change: impl FnMut(&K, &Versions<V>) -> Option<T>) -> bool {
+        let mut leaf = self.start_leaf(from);
+        while let Some(leaf_node_content) = leaf {
+            for (key, versions) in leaf_node_content.keys.iter().zip(&leaf_node_content.vals) {
+                if (from, Bound::Unbounded).contains(key) && versions.latest_seq() > since {
+                    buf.extend(change(key, versions));
+                }
+            }
+            if !buf.is_empty() {
+                return leaf_node_content.next.is_some();
+            }
+            leaf = leaf_node_content.next.as_ref().map(|next| next.read_arc());
+        }
+        false
+    }
+
+    // Approximate position of `key` among all entries, from 0.0 to 1.0,
+    // derived from the child indices taken on the way down.
+    pub(crate) fn position(&self, key: &K) -> f64 {
+        let (mut lo, mut width) = (0.0, 1.0);
+        let leaf = self.descend(|inner_node_content| {
+            let index = inner_node_content.child_index(key);
+            let fanout = inner_node_content.childNodeptrs.len() as f64;
+            lo += width * index as f64 / fanout;
+            width /= fanout;
+            index
+        }, |leaf| leaf.read_arc());
+        match leaf {
+            Some(leaf_node_content) => {
+                let index = match K::search(&leaf_node_content.keys, key) {
+                    Ok(i) | Err(i) => i,
+                };
+                lo + width * index as f64 / leaf_node_content.keys.len().max(1) as f64
+            },
+            None => lo,
+        }
+    }
+
+    // Smallest live entry after `from`. Starts from the cursor's cached
+    // `hint` leaf while that leaf still covers `from`, and hands back the
+    // leaf the entry was found in for the next step to start from.
+    pub(crate) fn seek_forward(&self, hint: Option<&LeafNodeRef<K, V>>, from: Bound<&K>) -> Option<(LeafNodeRef<K, V>, K, V)> {
+        let now = Now::default();
+        let cached = hint.map(|leaf| (leaf.clone(), leaf.read_arc())).filter(|(_, leaf_node_content)| {
+            !leaf_node_content.retired && match from {
+                Bound::Included(key) | Bound::Excluded(key) => leaf_node_content.keys.first().is_some_and(|first| first <= key),
+                Bound::Unbounded => false,
+            }
+        });
+        let (mut leaf, mut leaf_node_content) = match cached {
+            Some(cached) => cached,
+            None => self.descend(|inner_node_content| inner_node_content.start_index(from), |leaf| (leaf.clone(), leaf.read_arc()))?,
+        };
+        loop {
+            for (key, versions) in leaf_node_content.keys.iter().zip(&leaf_node_content.vals) {
+                if !(from, Bound::Unbounded).contains(key) {
+                    continue;
+                }
+                if let Some(val) = self.visible(key, versions, &now) {
+                    return Some((leaf, key.clone(), val.clone()));
+                }
+            }
+            let next = leaf_node_content.next.clone()?;
+            leaf_node_content = next.read_arc();
+            leaf = next;
+        }
+    }
+
+    // Largest live entry within the upper bound `to`. Leaves only link
+    // forward, so unless the cached `hint` leaf holds the answer this
+    // descends again, stepping one leaf fence left per miss.
+    pub(crate) fn seek_backward(&self, hint: Option<&LeafNodeRef<K, V>>, to: Bound<&K>) -> Option<(LeafNodeRef<K, V>, K, V)> {
+        let now = Now::default();
+        if let Some(leaf) = hint {
+            let leaf_node_content = leaf.read();
+            let covers = !leaf_node_content.retired && match to {
+                Bound::Included(to) | Bound::Excluded(to) => leaf_node_content.keys.last().is_some_and(|last| last >= to),
+                Bound::Unbounded => leaf_node_content.next.is_none(),
+            };
+            if covers {
+                if let Some((key, val)) = self.last_live_within(&leaf_node_content, to, &now) {
+                    return Some((leaf.clone(), key, val));
+                }
+            }
+        }
+        let mut bound = to.cloned();
+        loop {
+            let mut fence = None;
+            let (leaf, leaf_node_content) = self.descend(|inner_node_content| {
+                let index = inner_node_content.keys.partition_point(|key| (Bound::Unbounded, bound.as_ref()).contains(key));
+                if index > 0 {
+                    fence = Some(inner_node_content.keys[index-1].clone());
+                }
+                index
+            }, |leaf| (leaf.clone(), leaf.read_arc()))?;
+            if let Some((key, val)) = self.last_live_within(&leaf_node_content, bound.as_ref(), &now) {
+                return Some((leaf, key, val));
+            }
+            // everything within `bound` in this leaf is gone, retry left of it
+            bound = Bound::Excluded(fence?);
+        }
+    }
+
+    fn last_live_within(&self, leaf_node_content: &LeafNode<K, V>, to: Bound<&K>, now: &Now) -> Option<(K, V)> {
+        let below = leaf_node_content.keys.partition_point(|key| (Bound::Unbounded, to).contains(key));
+        leaf_node_content.keys[..below].iter().zip(&leaf_node_content.vals).rev()
+            .find_map(|(key, versions)| Some((key.clone(), self.visible(key, versions, now)?.clone())))
+    }
+
+    // Replaces the value of a live `key` in place, trying the cursor's
+    // cached `hint` leaf before descending. False if the key is gone.
+    pub(crate) fn update_at(&self, hint: Option<&LeafNodeRef<K, V>>, key: &K, val: V) -> bool {
+        self.limits.assert(key, &val);
+        let horizon = self.horizon();
+        let hooks = self.hooks();
+        let cached = hint.map(|leaf| leaf.write_arc())
+            .filter(|leaf_node_content| !leaf_node_content.retired && K::search(&leaf_node_content.keys, key).is_ok());
+        let mut leaf_node_content = match cached {
+            Some(leaf_node_content) => leaf_node_content,
+            None => match self.find_leaf_mut(key) {
+                Some(leaf_node_content) => leaf_node_content,
+                None => return false,
+            },
+        };
+        if leaf_node_content.is_live(key) {
+            hooks.notify(key, leaf_node_content.latest(key), Some(&val));
+        }
+        let updated = leaf_node_content.update(key, val, &self.seq, horizon);
+        drop(leaf_node_content);
+        self.retire_summaries();
+        self.enforce_budget();
+        updated
+    }
+
+    // `set` for a cursor, going through the path to its leaf it recorded
+    // the last time, if any, instead of searching every level. A key that
+    // fits the leaf is written there, with the counts above fixed up along
+    // the path; one outside its fences is written as `set` would, recording
+    // the path to its own leaf, and one that splits the leaf takes the
+    // exclusive path as usual. Returns the value kept if the duplicate
+    // policy left an entry in place of `val`, and refuses what `set_checked`
+    // refuses.
+    pub(crate) fn set_at(&self, hint: Option<&mut Option<LeafPath<K, V>>>, key: K, val: V) -> Result<Option<V>, BptreeError<K, V>> {
+        match self.set_within(key, val, None, &Wait::Forever, self.duplicates, hint)? {
+            Some(occupied) if self.duplicates == DuplicatePolicy::Error => Err(occupied.into()),
+            kept => Ok(kept.map(|occupied| occupied.current)),
+        }
+    }
+
+    /// Walks the whole tree and reports every broken structural invariant:
+    /// key order within nodes, separator ranges, occupancy, uniform leaf
+    /// depth, the per-child entry counts and the leaf chain. Run it while no
+    /// writers are active; a write in flight can show up as a count mismatch.
+    pub fn check_invariants(&self) -> InvariantReport<K> {
+        let mut state = CheckState::new();
+        self.root.read().check(&mut vec![], Bound::Unbounded, Bound::Unbounded, &mut state);
+        // an adaptive split may leave the first or the last leaf short, and
+        // lazy removals any leaf, but only the root may be empty
+        let last = state.leaves.len().saturating_sub(1);
+        let lazy = self.lazy_rebalance.load(Ordering::SeqCst);
+        for (index, path, keys, min) in state.short_leaves.drain(..) {
+            if keys == 0 || !(lazy || index == 0 || index == last) {
+                state.violations.push(Violation::Underfull{ path, keys, min });
+            }
+        }
+        let mut leaves = state.leaves.iter().peekable();
+        while let Some((path, leaf)) = leaves.next() {
+            let next = leaf.read().next.clone();
+            let linked = match (next, leaves.peek()) {
+                (Some(next), Some((_, following))) => Arc::ptr_eq(&next, following),
+                (None, None) => true,
+                _ => false,
+            };
+            if !linked {
+                state.violations.push(Violation::BrokenLeafChain{ path: path.clone() });
+            }
+        }
+        InvariantReport{ violations: state.violations }
+    }
+
+    /// Repairs what a panic inside a tree operation, such as one raised by
+    /// a key's `Ord` or an `IndexHook`, may have left half done. The
+    /// latches are never poisoned, so the tree stays usable after such a
+    /// panic, but the write that was cut short can leave subtree counts or
+    /// nodes inconsistent. `heal` checks the invariants and, if any are
+    /// broken, rebuilds the tree from the entries its leaves hold, reached
+    /// through the inner nodes rather than the leaf chain. Returns what was
+    /// found before repairing. Writers wait while it runs.
+    pub fn heal(&self) -> InvariantReport<K> {
+        let report = self.check_invariants();
+        if report.is_ok() {
+            return report;
+        }
+        let horizon = self.horizon();
+        let mut root = self.root.write();
+        // writers that got past the root latch hold the root node until done
+        drop(root.write_arc());
+        let mut state = CheckState::new();
+        root.check(&mut vec![], Bound::Unbounded, Bound::Unbounded, &mut state);
+        let mut entries = vec![];
+        for (_, leaf_node_ref) in &state.leaves {
+            let mut leaf_node_content = leaf_node_ref.write();
+            leaf_node_content.retired = true;
+            for (key, versions) in leaf_node_content.keys.iter().zip(&leaf_node_content.vals) {
+                if !versions.is_dead(horizon) {
+                    entries.push((key.clone(), versions.clone()));
+                }
+            }
+        }
+        // a leaf cut short mid-write may hold keys out of place
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries.dedup_by(|(a, _), (b, _)| a == b);
+        *root = BtreeNode::bulk(entries, REBALANCE_FILL, self.m-1);
+        report
+    }
+
+    /// Renders the tree one level per line, root first: inner nodes as
+    /// their separator keys, leaves as their entries, with `~` marking a
+    /// tombstone. Meant for eyeballing splits and merges while debugging.
+    pub fn dump(&self) -> String {
+        let root = self.root.read();
+        let mut out = String::new();
+        let mut level = vec![root.clone()];
+        let mut depth = 0;
+        while !level.is_empty() {
+            let mut below = vec![];
+            let mut nodes = vec![];
+            for node in &level {
+                match node {
+                    BtreeNode::inner(inner_node_ref) => {
+                        let inner_node_content = inner_node_ref.read();
+                        nodes.push(format!("{:?}", inner_node_content.keys));
+                        below.extend(inner_node_content.childNodeptrs.iter().cloned());
+                    },
+                    BtreeNode::leaf(leaf_node_ref) => {
+                        let leaf_node_content = leaf_node_ref.read();
+                        let entries: Vec<String> = leaf_node_content.keys.iter().zip(&leaf_node_content.vals)
+                            .map(|(key, versions)| match versions.latest() {
+                                Some(val) => format!("{:?}: {:?}", key, val),
+                                None => format!("{:?}: ~", key),
+                            })
+                            .collect();
+                        nodes.push(format!("{{{}}}", entries.join(", ")));
+                    },
+                    BtreeNode::placehold => {},
+                }
+            }
+            if !nodes.is_empty() {
+                out.push_str(&format!("{}: {}\n", depth, nodes.join(" ")));
+            }
+            level = below;
+            depth += 1;
+        }
+        out
+    }
+
+    /// Measures the shape of the tree: height, node counts, entries and
+    /// how full the leaves are.
+    pub fn stats(&self) -> TreeStats {
+        let root = self.root.read();
+        let mut stats = TreeStats{
+            height: 0,
+            inner_nodes: 0,
+            leaf_nodes: 0,
+            entries: 0,
+            avg_fill: 0.0,
+            min_fill: 0.0,
+            max_fill: 0.0,
+        };
+        let mut fills = vec![];
+        let mut level = vec![root.clone()];
+        while !level.is_empty() {
+            let mut below = vec![];
+            for node in &level {
+                match node {
+                    BtreeNode::inner(inner_node_ref) => {
+                        stats.inner_nodes += 1;
+                        below.extend(inner_node_ref.read().childNodeptrs.iter().cloned());
+                    },
+                    BtreeNode::leaf(leaf_node_ref) => {
+                        let leaf_node_content = leaf_node_ref.read();
+                        stats.leaf_nodes += 1;
+                        stats.entries += leaf_node_content.live_count();
+                        fills.push(leaf_node_content.keys.len() as f64 / leaf_node_content.max_key_count as f64);
+                    },
+                    BtreeNode::placehold => {},
+                }
+            }
+            if level.iter().any(|node| !matches!(node, BtreeNode::placehold)) {
+                stats.height += 1;
+            }
+            level = below;
+        }
+        if !fills.is_empty() {
+            stats.avg_fill = fills.iter().sum::<f64>() / fills.len() as f64;
+            stats.min_fill = fills.iter().cloned().fold(f64::INFINITY, f64::min);
+            stats.max_fill = fills.iter().cloned().fold(0.0, f64::max);
+        }
+        stats
+    }
+
+    // Separator keys of the highest inner level with at least `want` of
+    // them, or of the lowest inner level if none has that many. Between two
+    // neighbouring fences lies a run of whole subtrees.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn fence_keys(&self, want: usize) -> Vec<K> {
+        let mut level = vec![self.root.read().clone()];
+        let mut fences = vec![];
+        while fences.len() < want {
+            let mut below = vec![];
+            let mut keys = vec![];
+            for node in &level {
+                if let BtreeNode::inner(inner_node_ref) = node {
+                    let inner_node_content = inner_node_ref.read();
+                    keys.extend(inner_node_content.keys.iter().cloned());
+                    below.extend(inner_node_content.childNodeptrs.iter().cloned());
+                }
+            }
+            if keys.is_empty() {
+                break;
+            }
+            // nodes are read one at a time, so a concurrent split may have
+            // moved keys between them
+            keys.sort();
+            keys.dedup();
+            fences = keys;
+            level = below;
+        }
+        fences
+    }
+
+    // Allocated key slots of each leaf, left to right.
+    #[cfg(test)]
+    pub(crate) fn leaf_capacities(&self) -> Vec<usize> {
+        let mut capacities = vec![];
+        self.walk_leaves(Bound::Unbounded, |leaf_node_content| {
+            capacities.push(leaf_node_content.keys.capacity());
+            true
+        });
+        capacities
+    }
+
+    // Breaks the tree on purpose, for tests to show `check_invariants`
+    // notices.
+    #[cfg(test)]
+    pub(crate) fn corrupt(&self, corruption: Corruption) {
+        let root = self.root.read();
+        let mut state = CheckState::new();
+        root.check(&mut vec![], Bound::Unbounded, Bound::Unbounded, &mut state);
+        match corruption {
+            Corruption::Shrink{ leaf } => {
+                let mut leaf_node_content = state.leaves[leaf].1.write();
+                leaf_node_content.max_key_count = leaf_node_content.keys.len() - 1;
+            },
+            Corruption::Unsort{ leaf } => state.leaves[leaf].1.write().keys.swap(0, 1),
+            Corruption::Unlink{ leaf } => state.leaves[leaf].1.write().next = None,
+            Corruption::Miscount{ delta } => match &*root {
+                BtreeNode::inner(inner_node_ref) => inner_node_ref.read().add_count(0, delta),
+                _ => unreachable!(),
+            },
+        }
+    }
+
+    // Number of live slots in each leaf, left to right.
+    #[cfg(test)]
+    pub(crate) fn leaf_sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![];
+        self.walk_leaves(Bound::Unbounded, |leaf_node_content| {
+            sizes.push(leaf_node_content.keys.len());
+            true
+        });
+        sizes
+    }
+}
+
+/// A value borrowed from the tree by `Bptree::get_ref`, keeping its leaf
+/// latched shared for as long as it lives.
+pub struct ValueRef<K, V> {
+    leaf: LeafReadGuard<K, V>,
+    index: usize,
+}
+
+impl<K, V> Deref for ValueRef<K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.leaf.vals[self.index].latest().unwrap()
+    }
+}
+
+/// Lending iterator handing out values mutably, see `Bptree::range_mut`.
+pub struct RangeMut<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    tree: &'a Bptree<K, V>,
+    leaf: Option<LeafGuard<K, V>>,
+    // next index into the leaf to look at
+    pos: usize,
+    end: Bound<K>,
+    horizon: Option<u64>,
+    hooks: Hooks<K, V>,
+    now: Now,
+    // value the last entry handed out had, kept while hooks need to hear
+    // whether it changed
+    old_val: Option<V>,
+}
+
+impl<'a, K, V> RangeMut<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// The next entry, its value lent out until the following call.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<(&K, &mut V)> {
+        self.settle();
+        loop {
+            let leaf_node_content = self.leaf.as_mut()?;
+            match leaf_node_content.keys.get(self.pos) {
+                Some(key) if !(Bound::Unbounded, self.end.as_ref()).contains(key) => {
+                    self.leaf = None;
+                    return None;
+                },
+                Some(key) if self.tree.visible(key, &leaf_node_content.vals[self.pos], &self.now).is_some() => break,
+                Some(_) => self.pos += 1,
+                None => {
+                    // the next leaf is latched before this one is let go
+                    self.leaf = leaf_node_content.next.as_ref().map(|next| next.write_arc());
+                    self.pos = 0;
+                },
+            }
+        }
+        let i = self.pos;
+        self.pos += 1;
+        let leaf_node_content = &mut **self.leaf.as_mut()?;
+        let versions = &mut leaf_node_content.vals[i];
+        if !self.hooks.is_empty() {
+            self.old_val = versions.latest().cloned();
+        }
+        let seq = self.tree.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.horizon.is_some() {
+            let deadline = versions.deadline();
+            versions.push(seq, versions.latest().cloned(), self.horizon);
+            versions.set_deadline(deadline);
+        }
+        Some((&leaf_node_content.keys[i], versions.latest_mut()?))
+    }
+
+    // Tells hooks about the entry handed out last, if its value changed.
+    fn settle(&mut self) {
+        let (Some(old_val), Some(leaf_node_content)) = (self.old_val.take(), self.leaf.as_ref()) else {
+            return;
+        };
+        let i = self.pos - 1;
+        let new_val = leaf_node_content.vals[i].latest();
+        if new_val != Some(&old_val) {
+            self.hooks.notify(&leaf_node_content.keys[i], Some(&old_val), new_val);
+        }
+    }
+}
+
+impl<'a, K, V> Drop for RangeMut<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    fn drop(&mut self) {
+        self.settle();
+        self.tree.retire_summaries();
+    }
+}
+
+/// Values of several entries lent out together, see `Bptree::get_many_mut`.
+pub struct ManyMut<'a, K, V, const N: usize>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    tree: &'a Bptree<K, V>,
+    leaves: Vec<LeafGuard<K, V>>,
+    // leaf and index within it of each entry, in the order asked for
+    slots: [(usize, usize); N],
+    hooks: Hooks<K, V>,
+    // values the entries had, kept while hooks need to hear which changed
+    old_vals: Vec<V>,
+}
+
+impl<'a, K, V, const N: usize> ManyMut<'a, K, V, N>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// The values, in the order their keys were given.
+    pub fn values(&mut self) -> [&mut V; N] {
+        let mut values: [Option<&mut V>; N] = std::array::from_fn(|_| None);
+        for (l, leaf_node_content) in self.leaves.iter_mut().enumerate() {
+            for (i, versions) in leaf_node_content.vals.iter_mut().enumerate() {
+                if let Some(n) = self.slots.iter().position(|&slot| slot == (l, i)) {
+                    values[n] = versions.latest_mut();
+                }
+            }
+        }
+        values.map(|val| val.expect("a latched entry stays live"))
+    }
+}
+
+impl<'a, K, V, const N: usize> Drop for ManyMut<'a, K, V, N>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    fn drop(&mut self) {
+        self.tree.retire_summaries();
+        for (&(l, i), old_val) in self.slots.iter().zip(&self.old_vals) {
+            let leaf_node_content = &self.leaves[l];
+            let new_val = leaf_node_content.vals[i].latest();
+            if new_val != Some(old_val) {
+                self.hooks.notify(&leaf_node_content.keys[i], Some(old_val), new_val);
+            }
+        }
+    }
+}
+
+/// Lending iterator handing out values mutably, see `Bptree::values_mut`.
+pub struct ValuesMut<'a, K, V>(RangeMut<'a, K, V>)
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType;
+
+impl<'a, K, V> ValuesMut<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<&mut V> {
+        self.0.next().map(|(_, val)| val)
+    }
+}
+
+/// A copy with nodes of its own, so writes to either tree never show in the
+/// other. The node layout, version history, sequence number and pending
+/// expiries are copied; hooks are not. Writers wait while the nodes are
+/// copied.
+impl<K, V> Clone for Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    fn clone(&self) -> Self {
+        let horizon = self.horizon();
+        let range_ttls = self.range_ttls.read().clone();
+        let next_deadline = *self.next_deadline.read();
+        let root = self.root.write();
+        // writers that got past the root latch hold the root node until done
+        drop(root.write_arc());
+        Self{
+            root: RwLock::new(root.deep_clone(&mut None)),
+            m: self.m,
+            seq: AtomicU64::new(self.seq()),
+            horizon: RwLock::new(horizon),
+            range_ttls: RwLock::new(range_ttls),
+            next_deadline: RwLock::new(next_deadline),
+            hooks: RwLock::new(Hooks::new()),
+            pins: RwLock::new(BTreeMap::new()),
+            lazy_rebalance: AtomicBool::new(self.lazy_rebalance.load(Ordering::SeqCst)),
+            limits: SizeLimits::new(self.limits.key(), self.limits.val()),
+            budget: RwLock::new(None),
+            merge_op: RwLock::new(self.merge_op.read().clone()),
+            duplicates: self.duplicates,
+            aggregate: RwLock::new(self.aggregate.read().clone()),
+            ranking: RwLock::new(self.ranking.read().clone()),
+            // nodes carried over may hold summaries cached under this tree's
+            // epochs, which mean nothing in the new one
+            summary_epoch: AtomicU64::new(self.summary_epoch.load(Ordering::SeqCst) + 1),
+        }
+    }
+}
+
+/// Iterator over the leaves of a tree, returned by `Bptree::leaves`. It
+/// follows the leaf chain, and goes back to the root only when the next
+/// leaf was merged away in the meantime.
+pub struct Leaves<'a, K, V> {
+    tree: &'a Bptree<K, V>,
+    next: Option<LeafNodeRef<K, V>>,
+    // last key handed out, to pick up from after a detour through the root
+    after: Bound<K>,
+    done: bool,
+}
+
+impl<'a, K, V> Iterator for Leaves<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    type Item = LeafChunk<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut leaf = match self.next.take().map(|next| next.read_arc()) {
+            Some(leaf_node_content) if !leaf_node_content.retired => Some(leaf_node_content),
+            _ => self.tree.start_leaf(self.after.as_ref()),
+        };
+        let now = Now::default();
+        while let Some(leaf_node_content) = leaf {
+            let from = leaf_node_content.keys.partition_point(|key| !(self.after.as_ref(), Bound::Unbounded).contains(key));
+            let live = leaf_node_content.keys[from..].iter().zip(&leaf_node_content.vals[from..])
+                .any(|(key, versions)| self.tree.visible(key, versions, &now).is_some());
+            if live {
+                self.after = Bound::Excluded(leaf_node_content.keys.last().unwrap().clone());
+                self.next = leaf_node_content.next.clone();
+                self.done = self.next.is_none();
+                return Some(LeafChunk{ tree: self.tree, leaf: leaf_node_content, from, now });
+            }
+            leaf = leaf_node_content.next.as_ref().map(|next| next.read_arc());
+        }
+        self.done = true;
+        None
+    }
+}
+
+/// One leaf handed out by `Leaves`, latched shared until dropped.
+pub struct LeafChunk<'a, K, V> {
+    tree: &'a Bptree<K, V>,
+    leaf: LeafReadGuard<K, V>,
+    // entries before this were handed out with an earlier chunk
+    from: usize,
+    now: Now,
+}
+
+impl<'a, K, V> LeafChunk<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// The live entries of the leaf in key order, borrowed in place.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.leaf.keys[self.from..].iter().zip(&self.leaf.vals[self.from..])
+            .filter_map(|(key, versions)| Some((key, self.tree.visible(key, versions, &self.now)?)))
+    }
+}
+
+/// Lending iterator over the entries of a tree, see `Bptree::stream`.
+pub struct Stream<'a, K, V> {
+    leaves: Leaves<'a, K, V>,
+    chunk: Option<LeafChunk<'a, K, V>>,
+    // next index into the chunk's leaf to look at
+    pos: usize,
+}
+
+impl<'a, K, V> Stream<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// The next entry, borrowed from the latched leaf until the following
+    /// call.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<(&K, &V)> {
+        loop {
+            if let Some(chunk) = &self.chunk {
+                let leaf = &chunk.leaf;
+                let found = (self.pos..leaf.keys.len())
+                    .find(|&i| chunk.tree.visible(&leaf.keys[i], &leaf.vals[i], &chunk.now).is_some());
+                if let Some(i) = found {
+                    self.pos = i + 1;
+                    break;
+                }
+            }
+            // let go of the old leaf first, the next may be found from the root
+            self.chunk = None;
+            let chunk = self.leaves.next()?;
+            self.pos = chunk.from;
+            self.chunk = Some(chunk);
+        }
+        let chunk = self.chunk.as_ref()?;
+        let i = self.pos - 1;
+        Some((&chunk.leaf.keys[i], chunk.tree.visible(&chunk.leaf.keys[i], &chunk.leaf.vals[i], &chunk.now)?))
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(non_camel_case_types)]
+pub enum BtreeNode<K, V> {
+    inner(InnerNodeRef<K, V>),
+    leaf(LeafNodeRef<K, V>),
+    placehold,
+}
+
+// Carried through `BtreeNode::check`.
+// What `Bptree::corrupt` breaks. Leaves are numbered left to right.
+#[cfg(test)]
+pub(crate) enum Corruption {
+    // lowers the leaf's capacity below what it holds
+    Shrink{ leaf: usize },
+    // swaps the leaf's first two keys
+    Unsort{ leaf: usize },
+    // cuts the chain after the leaf
+    Unlink{ leaf: usize },
+    // skews the count the root keeps for its first child
+    Miscount{ delta: isize },
+}
+
+struct CheckState<K, V> {
+    violations: Vec<Violation<K>>,
+    // every leaf in tree order, for checking the chain afterwards
+    leaves: Vec<(Vec<usize>, LeafNodeRef<K, V>)>,
+    leaf_depth: Option<usize>,
+    // non-root leaves below minimum occupancy, with their place among the
+    // leaves, which only the whole walk tells whether it is at an edge
+    short_leaves: Vec<(usize, Vec<usize>, usize, usize)>,
+}
+
+impl<K, V> CheckState<K, V> {
+    fn new() -> Self {
+        Self{
+            violations: vec![],
+            leaves: vec![],
+            leaf_depth: None,
+            short_leaves: vec![],
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+enum NodeGuard<K, V> {
+    inner(InnerGuard<K, V>),
+    leaf(LeafGuard<K, V>),
+}
+
+impl<K, V> BtreeNode<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    fn write_arc(&self) -> Option<NodeGuard<K, V>> {
+        match self{
+            Self::inner(inner_node_ref) => Some(NodeGuard::inner(inner_node_ref.write_arc())),
+            Self::leaf(leaf_node_ref) => Some(NodeGuard::leaf(leaf_node_ref.write_arc())),
+            Self::placehold => None,
+        }
+    }
+
+    pub fn remove(&self, key: &K, doomed: &dyn Fn(&Versions<V>) -> bool) -> Option<Versions<V>> {
+        match self{
+            Self::leaf(leaf_node_ref) => {
+                let mut leaf_node_content = leaf_node_ref.write();
+                leaf_node_content.remove(key, doomed)
+            },
+            Self::inner(inner_node_ref) => {
+                let mut inner_node_content = inner_node_ref.write();
+                inner_node_content.remove(key, doomed)
+            }
+            Self::placehold => {None}
+        }
+    }
+
+    fn need_merge(&self) -> bool {
+        match self{
+            Self::leaf(leaf_node_ref) => leaf_node_ref.read().need_merge(),
+            Self::inner(inner_node_ref) => inner_node_ref.read().need_merge(),
+            Self::placehold => false,
+        }
+    }
+
+    // Leaf reached by taking the child `pick` chooses at every level.
+    fn leaf_ref(&self, pick: &dyn Fn(&InnerNode<K, V>) -> usize) -> Option<LeafNodeRef<K, V>> {
+        match self{
+            Self::leaf(leaf_node_ref) => Some(leaf_node_ref.clone()),
+            Self::inner(inner_node_ref) => {
+                let inner_node_content = inner_node_ref.read();
+                inner_node_content.childNodeptrs[pick(&inner_node_content)].leaf_ref(pick)
+            },
+            Self::placehold => None,
         }
     }
 
-    pub fn get(&self, key: &K) -> Option<V> {
-        self.root.get(key)
+    fn drop_span(&self, start: Option<Bound<&K>>, end: Option<Bound<&K>>) -> usize {
+        match self{
+            Self::inner(inner_node_ref) => inner_node_ref.write().drop_span(start, end),
+            _ => 0,
+        }
     }
 
-    pub fn set(&mut self, key: K, val: V)  {
-        self.mutex.lock();
-        match self.root {
-            BtreeNode::placehold => {
-                let mut new_leaf = LeafNode::new(self.m-1);
-                new_leaf.set(key,val);
-                self.root = BtreeNode::leaf(Arc::new(Mutex::new(new_leaf)));
+    // Latches every node of a subtree that is being cut loose, parent before
+    // child, so writers still inside it finish first. Returns its live
+    // entry count.
+    fn sweep(&self) -> usize {
+        match self{
+            Self::leaf(leaf_node_ref) => {
+                let mut leaf_node_content = leaf_node_ref.write();
+                leaf_node_content.retired = true;
+                leaf_node_content.live_count()
+            },
+            Self::inner(inner_node_ref) => {
+                let inner_node_content = inner_node_ref.write();
+                inner_node_content.childNodeptrs.iter().map(|child| child.sweep()).sum()
             },
-            _ => {
-                match self.root.set(key,val) {
-                    None => {},
-                    Some((split_key, mut new_btree_node)) => {
-                        let left_child = self.root.clone();
-                        let mut new_inner = InnerNode::new(self.m-1);
-                        new_inner.keys.push(split_key);
-                        new_inner.childNodeptrs.push(left_child);
-                        new_inner.childNodeptrs.push(new_btree_node);
-
-                        self.root = BtreeNode::inner((Arc::new(Mutex::new(new_inner))));
+            Self::placehold => 0,
+        }
+    }
 
-                    }
-                }
-            }
+    // Inserts with the root latch already held exclusively, latching the
+    // whole path exclusively since the leaf may split.
+    #[allow(clippy::too_many_arguments)]
+    fn insert(&mut self, key: K, val: V, seq: u64, horizon: Option<u64>, deadline: Option<Instant>, hooks: &Hooks<K, V>, max_key_count: usize) {
+        if let Self::placehold = self {
+            hooks.notify(&key, None, Some(&val));
+            let mut new_leaf = LeafNode::new(max_key_count);
+            new_leaf.set(key, val, seq, horizon, deadline);
+            *self = BtreeNode::leaf(Arc::new(RwLock::new(new_leaf)));
+            return;
         }
+        let probe = key.clone();
+        self.write_leaf(&probe, max_key_count, |leaf_node_content| {
+            let delta = if leaf_node_content.is_live(&key) { 0 } else { 1 };
+            hooks.notify(&key, leaf_node_content.latest(&key), Some(&val));
+            ((), delta, leaf_node_content.set(key, val, seq, horizon, deadline))
+        });
     }
 
-    pub fn remove(&mut self, key: &K) -> Option<V> {
-        self.mutex.lock();
-        if self.root.keys_len() == 0 {
-            let root = self.root.clone();
-            if let BtreeNode::inner(inner_node_arc) = root {
-                let inner_node_content = inner_node_arc.lock().unwrap();
-                let child = inner_node_content.childNodeptrs[0].clone();
-                self.root = child;
+    // Runs `f` on the leaf for `key` with every node on the path latched
+    // exclusively, then applies the change in live entries and any split it
+    // reports on the way back up. Only for callers holding the root latch.
+    fn write_leaf<R, F>(&mut self, key: &K, max_key_count: usize, f: F) -> Option<R>
+        where F: FnOnce(&mut LeafNode<K, V>) -> (R, isize, SplitResult<K, V>)
+    {
+        let mut node = self.write_arc()?;
+        let mut path: Vec<(InnerGuard<K, V>, usize)> = vec![];
+        let mut leaf_node_content = loop {
+            match node {
+                NodeGuard::inner(inner_node_content) => {
+                    let index = inner_node_content.child_index(key);
+                    let child = inner_node_content.childNodeptrs[index].write_arc().unwrap();
+                    path.push((inner_node_content, index));
+                    node = child;
+                },
+                NodeGuard::leaf(leaf_node_content) => break leaf_node_content,
             }
+        };
+        leaf_node_content.leftmost = path.iter().all(|(_, index)| *index == 0);
+        leaf_node_content.rightmost = path.iter().all(|(inner_node_content, index)| *index == inner_node_content.childNodeptrs.len() - 1);
+        let (result, delta, split) = f(&mut leaf_node_content);
+        drop(leaf_node_content);
+        self.propagate(path, delta, split, max_key_count);
+        Some(result)
+    }
+
+    // Replaces an inner root left without keys by its only child, for as
+    // many levels as that applies.
+    fn collapse(&mut self) {
+        loop {
+            let child = match self {
+                Self::inner(inner_node_ref) => {
+                    let inner_node_content = inner_node_ref.read();
+                    if !inner_node_content.keys.is_empty() {
+                        return;
+                    }
+                    inner_node_content.childNodeptrs[0].clone()
+                },
+                _ => return,
+            };
+            *self = child;
         }
-        match self.root.remove(key, None, None) {
-            (None, None, None) => {return None;}
-            (_, _, Some(old_val)) => {
+    }
 
-                return Some(old_val);
+    // Builds a tree bottom-up from sorted entries, packing each node to
+    // `fill` of its capacity but no emptier than rebalancing allows.
+    fn bulk(entries: Vec<(K, Versions<V>)>, fill: f64, max_key_count: usize) -> Self {
+        let leaf_min = (max_key_count / 2) + (max_key_count % 2);
+        // first key, node and live entry count of every node on a level
+        let mut level: Vec<(K, Self, usize)> = vec![];
+        let mut entries = entries.into_iter();
+        let mut prev: Option<LeafNodeRef<K, V>> = None;
+        for size in chunk_sizes(entries.len(), fill * max_key_count as f64, leaf_min, max_key_count) {
+            let mut new_leaf = LeafNode::new(max_key_count);
+            for (key, versions) in entries.by_ref().take(size) {
+                new_leaf.keys.push(key);
+                new_leaf.vals.push(versions);
+            }
+            let first_key = new_leaf.keys[0].clone();
+            let count = new_leaf.live_count();
+            let new_leaf_arc = Arc::new(RwLock::new(new_leaf));
+            if let Some(prev) = prev {
+                prev.write().set_next(Some(new_leaf_arc.clone()));
             }
-            _ => {unreachable!()}
+            prev = Some(new_leaf_arc.clone());
+            level.push((first_key, Self::leaf(new_leaf_arc), count));
         }
+        while level.len() > 1 {
+            let sizes = chunk_sizes(level.len(), fill * (max_key_count + 1) as f64, max_key_count / 2 + 1, max_key_count + 1);
+            let mut children = level.into_iter();
+            level = sizes.into_iter().map(|size| {
+                let mut new_inner = InnerNode::new(max_key_count);
+                let mut first_key = None;
+                for (key, child, count) in children.by_ref().take(size) {
+                    match first_key {
+                        None => first_key = Some(key),
+                        Some(_) => new_inner.keys.push(key),
+                    }
+                    new_inner.childNodeptrs.push(child);
+                    new_inner.counts.push(ChildStat::new(count));
+                }
+                let count = new_inner.total();
+                (first_key.unwrap(), Self::inner(Arc::new(RwLock::new(new_inner))), count)
+            }).collect();
+        }
+        level.pop().map_or(Self::placehold, |(_, node, _)| node)
     }
 
+    // Carries a change of `delta` live entries below the end of `path` and
+    // a pending split of that node up to `self`, the node `path` starts
+    // from, growing a new root above it if the split gets that far.
+    fn propagate(&mut self, mut path: Vec<(InnerGuard<K, V>, usize)>, delta: isize, mut split: SplitResult<K, V>, max_key_count: usize) {
+        while let Some((mut parent, index)) = path.pop() {
+            parent.add_count(index, delta);
+            split = match split {
+                Some((split_key, new_btree_node, moved)) => {
+                    parent.add_count(index, -(moved as isize));
+                    parent.insert_child(split_key, new_btree_node, moved)
+                },
+                None => None,
+            };
+        }
+        if let Some((split_key, new_btree_node, moved)) = split {
+            let mut new_inner = InnerNode::new(max_key_count);
+            new_inner.keys.push(split_key);
+            new_inner.counts.push(ChildStat::new(self.total()));
+            new_inner.counts.push(ChildStat::new(moved));
+            new_inner.childNodeptrs.push(self.clone());
+            new_inner.childNodeptrs.push(new_btree_node);
+            *self = BtreeNode::inner(Arc::new(RwLock::new(new_inner)));
+        }
+    }
 
-}
+    // Levels of inner nodes above the leaves.
+    fn height(&self) -> usize {
+        match self{
+            Self::inner(inner_node_ref) => 1 + inner_node_ref.read().childNodeptrs[0].height(),
+            _ => 0,
+        }
+    }
 
-#[derive(Debug, Clone)]
-pub enum BtreeNode<K, V> {
-    inner(Arc<Mutex<InnerNode<K, V>>>),
-    leaf(Arc<Mutex<LeafNode<K, V>>>),
-    placehold,
-}
+    // Smallest and largest key physically present, tombstones included.
+    fn first_key(&self) -> Option<K> {
+        self.leaf_ref(&|_| 0)?.read().keys.first().cloned()
+    }
 
-impl<K, V> BtreeNode<K, V>
-    where K : Debug + Clone + Ord + KVType,
-          V : Debug + Clone + Ord + KVType,
-{
-    pub fn get(&self, key: &K) -> Option<V> {
-        match self{
-            Self::leaf(leaf_node_ref) =>{
-                let leaf_node_content = leaf_node_ref.lock().unwrap();
-                let res = leaf_node_content.get(key);
-                return res;
+    fn last_key(&self) -> Option<K> {
+        self.leaf_ref(&|inner_node_content| inner_node_content.childNodeptrs.len() - 1)?.read().keys.last().cloned()
+    }
+
+    // Copies the subtree into fresh nodes. Unlike `clone`, which only copies
+    // the pointer, nothing is shared with the original. `prev` is the last
+    // leaf copied so far, for chaining leaves in key order.
+    fn deep_clone(&self, prev: &mut Option<LeafNodeRef<K, V>>) -> Self {
+        match self {
+            Self::inner(inner_node_ref) => {
+                let inner_node_content = inner_node_ref.read();
+                let children: Vec<_> = inner_node_content.childNodeptrs.iter().map(|child| child.deep_clone(prev)).collect();
+                let counts = (0..children.len()).map(|i| ChildStat::new(inner_node_content.count(i))).collect();
+                let new_inner = InnerNode::from(&inner_node_content.keys, &children, counts, inner_node_content.max_key_count);
+                Self::inner(Arc::new(RwLock::new(new_inner)))
             },
-            Self::inner(inner_node_ref) =>{
-                let inner_node_content = inner_node_ref.lock().unwrap();
-                let res = inner_node_content.get(key);
-                return res;
+            Self::leaf(leaf_node_ref) => {
+                let leaf_node_content = leaf_node_ref.read();
+                let new_leaf = LeafNode::from(&leaf_node_content.keys, &leaf_node_content.vals, leaf_node_content.max_key_count);
+                let new_leaf_arc = Arc::new(RwLock::new(new_leaf));
+                if let Some(prev) = prev.replace(new_leaf_arc.clone()) {
+                    prev.write().set_next(Some(new_leaf_arc.clone()));
+                }
+                Self::leaf(new_leaf_arc)
             },
-            Self::placehold => {return None;}
+            Self::placehold => Self::placehold,
+        }
+    }
+
+    // Concatenates two trees whose keys do not interleave, `acc` holding the
+    // smaller ones. Either side may be missing or empty.
+    fn join(acc: Option<Self>, piece: Self, max_key_count: usize) -> Option<Self> {
+        let separator = match piece.first_key() {
+            Some(separator) => separator,
+            None => return acc,
+        };
+        let mut acc = match acc {
+            Some(acc) if acc.first_key().is_some() => acc,
+            _ => return Some(piece),
+        };
+        // the leaves meeting at the seam stop being the trees' edges
+        let mut piece = piece;
+        acc.fix_edge(true);
+        piece.fix_edge(false);
+        if acc.height() >= piece.height() {
+            acc.graft_right(piece, separator, max_key_count);
+            Some(acc)
+        }
+        else {
+            piece.graft_left(acc, separator, max_key_count);
+            Some(piece)
+        }
+    }
+
+    // Brings the last leaf, or the first, and the nodes above it up to
+    // minimum occupancy, which an adaptive split lets them fall short of
+    // while they sit at the tree's edge.
+    fn fix_edge(&mut self, last: bool) {
+        let edge = |inner_node_content: &InnerNode<K, V>| if last { inner_node_content.childNodeptrs.len() - 1 } else { 0 };
+        let mut path: Vec<InnerGuard<K, V>> = vec![];
+        let mut node = match self {
+            Self::inner(inner_node_ref) => inner_node_ref.write_arc(),
+            _ => return,
+        };
+        while let Self::inner(inner_node_ref) = &node.childNodeptrs[edge(&node)] {
+            let child = inner_node_ref.write_arc();
+            path.push(node);
+            node = child;
+        }
+        path.push(node);
+        // bottom up, as a merge below can leave the node above short
+        while let Some(mut node) = path.pop() {
+            let index = edge(&node);
+            node.fix_underflow(index);
+        }
+        self.collapse();
+    }
+
+    // One side of an inner node cut by `split_off`, which may hold too few
+    // children to stand as a node of its own.
+    fn piece(keys: &[K], children: &[Self], counts: Vec<ChildStat<K, V>>, max_key_count: usize) -> Option<Self> {
+        match children.len() {
+            0 => None,
+            1 => Some(children[0].clone()),
+            _ => Some(Self::inner(Arc::new(RwLock::new(InnerNode::from(keys, children, counts, max_key_count))))),
+        }
+    }
+
+    // Hangs `subtree`, whose keys all sort after this tree's and whose height
+    // is at most this tree's, off the right spine at the level where it
+    // fits, then repairs occupancy and splits on the way back up.
+    fn graft_right(&mut self, subtree: BtreeNode<K, V>, separator: K, max_key_count: usize) {
+        let count = subtree.total();
+        if let Some(last) = self.leaf_ref(&|inner_node_content| inner_node_content.childNodeptrs.len() - 1) {
+            last.write().next = subtree.leaf_ref(&|_| 0);
+        }
+        let depth = self.height() - subtree.height();
+        if depth == 0 {
+            self.propagate(vec![], 0, Some((separator, subtree, count)), max_key_count);
+            // both halves used to be roots, so either may run short
+            if let Self::inner(inner_node_ref) = self {
+                let mut inner_node_content = inner_node_ref.write();
+                inner_node_content.fix_underflow(1);
+                inner_node_content.fix_underflow(0);
+            }
+            self.collapse();
+            return;
+        }
+        let mut path: Vec<(InnerGuard<K, V>, usize)> = vec![];
+        let mut node = match self {
+            Self::inner(inner_node_ref) => inner_node_ref.write_arc(),
+            _ => unreachable!(),
+        };
+        for _ in 1..depth {
+            let index = node.childNodeptrs.len() - 1;
+            let child = match &node.childNodeptrs[index] {
+                Self::inner(inner_node_ref) => inner_node_ref.write_arc(),
+                _ => unreachable!(),
+            };
+            path.push((node, index));
+            node = child;
+        }
+        node.keys.push(separator);
+        node.childNodeptrs.push(subtree);
+        node.counts.push(ChildStat::new(count));
+        let index = node.childNodeptrs.len() - 1;
+        node.fix_underflow(index);
+        let split = node.split_if_full();
+        drop(node);
+        self.propagate(path, count as isize, split, max_key_count);
+    }
+
+    // Mirror image of `graft_right`: hangs `subtree`, whose keys all sort
+    // before this tree's and whose height is below this tree's, off the
+    // left spine.
+    fn graft_left(&mut self, subtree: BtreeNode<K, V>, separator: K, max_key_count: usize) {
+        let count = subtree.total();
+        if let Some(last) = subtree.leaf_ref(&|inner_node_content| inner_node_content.childNodeptrs.len() - 1) {
+            last.write().next = self.leaf_ref(&|_| 0);
+        }
+        let depth = self.height() - subtree.height();
+        let mut path: Vec<(InnerGuard<K, V>, usize)> = vec![];
+        let mut node = match self {
+            Self::inner(inner_node_ref) => inner_node_ref.write_arc(),
+            _ => unreachable!(),
+        };
+        for _ in 1..depth {
+            let child = match &node.childNodeptrs[0] {
+                Self::inner(inner_node_ref) => inner_node_ref.write_arc(),
+                _ => unreachable!(),
+            };
+            path.push((node, 0));
+            node = child;
         }
+        node.keys.insert(0, separator);
+        node.childNodeptrs.insert(0, subtree);
+        node.counts.insert(0, ChildStat::new(count));
+        node.fix_underflow(0);
+        let split = node.split_if_full();
+        drop(node);
+        self.propagate(path, count as isize, split, max_key_count);
     }
 
-    pub fn set(&mut self, key: K, val: V) -> Option<(K, BtreeNode<K, V>)> {
+    // Checks the subtree at `path`, whose keys must fall between `lo` and
+    // `hi`, and returns its live entry count.
+    fn check(&self, path: &mut Vec<usize>, lo: Bound<&K>, hi: Bound<&K>, state: &mut CheckState<K, V>) -> usize {
         match self{
             Self::leaf(leaf_node_ref) => {
-                let mut leaf_node_content = leaf_node_ref.lock().unwrap();
-                return leaf_node_content.set(key, val);
+                let leaf_node_content = leaf_node_ref.read();
+                let keys = leaf_node_content.keys.len();
+                check_keys(&leaf_node_content.keys, path, lo, hi, &mut state.violations);
+                if keys > leaf_node_content.max_key_count {
+                    state.violations.push(Violation::Overfull{ path: path.clone(), keys, max: leaf_node_content.max_key_count });
+                }
+                if !path.is_empty() && leaf_node_content.need_merge() {
+                    state.short_leaves.push((state.leaves.len(), path.clone(), keys, leaf_node_content.split_at()));
+                }
+                match state.leaf_depth {
+                    None => state.leaf_depth = Some(path.len()),
+                    Some(expected) if expected != path.len() => {
+                        state.violations.push(Violation::UnevenDepth{ path: path.clone(), depth: path.len(), expected });
+                    },
+                    Some(_) => {},
+                }
+                state.leaves.push((path.clone(), leaf_node_ref.clone()));
+                leaf_node_content.live_count()
             },
             Self::inner(inner_node_ref) => {
-                let mut inner_node_content = inner_node_ref.lock().unwrap();
-                return inner_node_content.set(key, val);
-            }
-            Self::placehold => {return None;}
+                let inner_node_content = inner_node_ref.read();
+                let keys = inner_node_content.keys.len();
+                let children = inner_node_content.childNodeptrs.len();
+                check_keys(&inner_node_content.keys, path, lo, hi, &mut state.violations);
+                if keys > inner_node_content.max_key_count {
+                    state.violations.push(Violation::Overfull{ path: path.clone(), keys, max: inner_node_content.max_key_count });
+                }
+                if !path.is_empty() && keys < inner_node_content.min_key_count() {
+                    state.violations.push(Violation::Underfull{ path: path.clone(), keys, min: inner_node_content.min_key_count() });
+                }
+                if children != keys + 1 {
+                    state.violations.push(Violation::ChildCountMismatch{ path: path.clone(), keys, children });
+                }
+                let mut total = 0;
+                for (index, child) in inner_node_content.childNodeptrs.iter().enumerate() {
+                    let child_lo = index.checked_sub(1).and_then(|i| inner_node_content.keys.get(i)).map_or(lo, Bound::Included);
+                    let child_hi = inner_node_content.keys.get(index).map_or(hi, Bound::Excluded);
+                    path.push(index);
+                    let actual = child.check(path, child_lo, child_hi, state);
+                    let recorded = inner_node_content.counts.get(index).map_or(0, |count| count.count());
+                    if recorded != actual {
+                        state.violations.push(Violation::CountMismatch{ path: path.clone(), recorded, actual });
+                    }
+                    path.pop();
+                    total += actual;
+                }
+                total
+            },
+            Self::placehold => 0,
         }
     }
 
-    pub fn remove(&mut self, key: &K, left_slibing: Option<BtreeNode<K,V>>,
-                  right_slibing: Option<BtreeNode<K,V>>) -> (Option<K>, Option<K>, Option<V>) {
-        match self{
+    // Summary of the live entries of the subtree within the bounds, a `None`
+    // bound meaning the range runs past the subtree on that side. Nodes are
+    // latched shared top-down and left to right, parents held throughout.
+    fn summarize(&self, start: Option<Bound<&K>>, end: Option<Bound<&K>>, aggregator: &Aggregator<K, V>, epoch: u64) -> Summary {
+        match self {
             Self::leaf(leaf_node_ref) => {
-                let mut leaf_node_content = leaf_node_ref.lock().unwrap();
-                return leaf_node_content.remove(key, left_slibing, right_slibing);
+                let bounds = (start.unwrap_or(Bound::Unbounded), end.unwrap_or(Bound::Unbounded));
+                leaf_node_ref.read().summarize(bounds, aggregator)
             },
-            Self::inner(inner_node_ref) => {
-                let mut inner_node_content = inner_node_ref.lock().unwrap();
-                return inner_node_content.remove(key, left_slibing, right_slibing);
-            }
-            Self::placehold => {return (None, None, None);}
+            Self::inner(inner_node_ref) => inner_node_ref.read().summarize(start, end, aggregator, epoch),
+            Self::placehold => aggregator.identity.clone(),
         }
     }
 
-    pub fn keys_len(&self) -> usize {
-        match self{
+    // Offers the live entries of the subtree within the bounds to `top`, as
+    // `summarize` walks them, passing over subtrees that cannot make the
+    // cut.
+    fn rank(&self, start: Option<Bound<&K>>, end: Option<Bound<&K>>, ranking: &Ranking<V>, epoch: u64, k: usize, top: &mut Vec<(K, V)>) {
+        match self {
             Self::leaf(leaf_node_ref) => {
-                let mut leaf_node_content = leaf_node_ref.lock().unwrap();
-                return leaf_node_content.keys.len();
+                let bounds = (start.unwrap_or(Bound::Unbounded), end.unwrap_or(Bound::Unbounded));
+                leaf_node_ref.read().rank(bounds, ranking, k, top);
             },
-            Self::inner(inner_node_ref) => {
-                let mut inner_node_content = inner_node_ref.lock().unwrap();
-                return inner_node_content.keys.len();
-            }
-            Self::placehold => {0}
+            Self::inner(inner_node_ref) => inner_node_ref.read().rank(start, end, ranking, epoch, k, top),
+            Self::placehold => {},
+        }
+    }
+
+    // The best `ranking.width` live entries of the subtree, best first.
+    fn best(&self, ranking: &Ranking<V>, epoch: u64) -> Vec<(K, V)> {
+        let mut best = Vec::with_capacity(ranking.width + 1);
+        self.rank(None, None, ranking, epoch, ranking.width, &mut best);
+        best
+    }
+
+    // Live entries in the subtree.
+    fn total(&self) -> usize {
+        match self{
+            Self::leaf(leaf_node_ref) => leaf_node_ref.read().live_count(),
+            Self::inner(inner_node_ref) => inner_node_ref.read().total(),
+            Self::placehold => 0,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+// Splits `n` items into groups as close to `target` as the bounds allow,
+// as evenly as possible.
+fn chunk_sizes(n: usize, target: f64, min: usize, max: usize) -> Vec<usize> {
+    if n == 0 {
+        return vec![];
+    }
+    let target = (target.round() as usize).clamp(min, max);
+    let count = n.div_ceil(target).clamp(n.div_ceil(max), (n / min).max(1));
+    (0..count).map(|i| n / count + usize::from(i < n % count)).collect()
+}
+
+// Keys of one node must ascend strictly and stay within `lo` and `hi`.
+fn check_keys<K: Ord + Clone>(keys: &[K], path: &[usize], lo: Bound<&K>, hi: Bound<&K>, violations: &mut Vec<Violation<K>>) {
+    if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+        violations.push(Violation::UnsortedKeys{ path: path.to_vec() });
+    }
+    for key in keys.iter().filter(|key| !(lo, hi).contains(*key)) {
+        violations.push(Violation::KeyOutOfRange{ path: path.to_vec(), key: key.clone() });
+    }
+}
+
+// The one place two nodes of the same level are latched together: always
+// left before right, so concurrent rebalances and leaf walks cannot wait on
+// each other in a cycle.
+fn latch_pair<'a, T>(left: &'a RwLock<T>, right: &'a RwLock<T>) -> (RwLockWriteGuard<'a, T>, RwLockWriteGuard<'a, T>) {
+    let left_guard = left.write();
+    (left_guard, right.write())
+}
+
+#[derive(Debug)]
+#[allow(non_snake_case)]
 pub struct InnerNode<K,V>{
     keys: Vec<K>,
     childNodeptrs: Vec<BtreeNode<K,V>>,
+    // live entries under each child, and its cached aggregate summary;
+    // atomic so writers holding this node shared can still account for the
+    // leaf they changed
+    counts: Vec<ChildStat<K, V>>,
     max_key_count: usize,
 }
 
@@ -161,217 +3213,383 @@ impl<K,V> InnerNode<K,V>
     where K : Debug + Clone + Ord + KVType,
           V : Debug + Clone + Ord + KVType,
 {
+    // Room for one key past full, which a node holds between an insert and
+    // the split it triggers, so a node never reallocates over its lifetime.
     pub fn new(max_key_count: usize) -> Self {
         Self{
-            keys: Vec::with_capacity(max_key_count),
-            childNodeptrs: Vec::with_capacity(max_key_count+1),
+            keys: Vec::with_capacity(max_key_count+1),
+            childNodeptrs: Vec::with_capacity(max_key_count+2),
+            counts: Vec::with_capacity(max_key_count+2),
             max_key_count,
         }
     }
 
-    pub fn from(keys: &[K], vals: &[BtreeNode<K, V>], max_key_count: usize) -> Self{
-        Self{
-            keys: keys.to_vec(),
-            childNodeptrs: vals.to_vec(),
-            max_key_count,
-        }
+    pub fn from(keys: &[K], vals: &[BtreeNode<K, V>], counts: Vec<ChildStat<K, V>>, max_key_count: usize) -> Self{
+        let mut new_inner = Self::new(max_key_count);
+        new_inner.keys.extend_from_slice(keys);
+        new_inner.childNodeptrs.extend_from_slice(vals);
+        new_inner.counts.extend(counts);
+        new_inner
     }
 
-    pub fn get(&self, key: &K) -> Option<V> {
-        let mut index = 0;
-        match self.keys.binary_search(key){
-            Err(i) =>{ index = i;},
-            Ok(i) =>{index = i+1;},
-        }
-        self.childNodeptrs[index].get(key)
+    fn count(&self, index: usize) -> usize {
+        self.counts[index].count()
+    }
+
+    fn add_count(&self, index: usize, delta: isize) {
+        self.counts[index].add(delta);
     }
 
-    pub fn set(&mut self, key: K, val: V) -> Option<(K, BtreeNode<K, V>)> {
-        let mut index = 0;
-        match self.keys.binary_search(&key){
-            Err(i) =>{ index = i;},
-            Ok(i) =>{index = i+1;},
+    fn total(&self) -> usize {
+        (0..self.counts.len()).map(|index| self.count(index)).sum()
+    }
+
+    // Children lying wholly inside the bounds contribute the summary cached
+    // for them while it is current, and have it computed and cached if not.
+    fn summarize(&self, start: Option<Bound<&K>>, end: Option<Bound<&K>>, aggregator: &Aggregator<K, V>, epoch: u64) -> Summary {
+        let first = start.map_or(0, |start| self.start_index(start));
+        let last = end.map_or(self.childNodeptrs.len() - 1, |end| self.end_index(end));
+        let mut summary = aggregator.identity.clone();
+        for index in first..=last {
+            let child_start = start.filter(|_| index == first);
+            let child_end = end.filter(|_| index == last);
+            let part = match (child_start, child_end) {
+                (None, None) => {
+                    let stat = &self.counts[index];
+                    let stamp = stat.stamp();
+                    stat.cached(stamp, epoch).unwrap_or_else(|| {
+                        let part = self.childNodeptrs[index].summarize(None, None, aggregator, epoch);
+                        stat.cache(stamp, epoch, part.clone());
+                        part
+                    })
+                },
+                _ => self.childNodeptrs[index].summarize(child_start, child_end, aggregator, epoch),
+            };
+            summary = (aggregator.combine)(&summary, &part);
         }
+        summary
+    }
 
-        match self.childNodeptrs[index].set(key, val) {
-            None =>{ return None;}
-            Some((split_key, new_btree_node)) => {
-                match self.keys.binary_search(&split_key) {
-                    Ok(_) => unreachable!(),
-                    Err(index) => {
-                        self.keys.insert(index, split_key);
-                        self.childNodeptrs.insert(index+1, new_btree_node);
-                    }
-                }
+    // Cached best entries of child `index`, computed and cached if not
+    // current, as `summarize` does for summaries.
+    fn child_best(&self, index: usize, ranking: &Ranking<V>, epoch: u64) -> Best<K, V> {
+        let stat = &self.counts[index];
+        let stamp = stat.stamp();
+        stat.cached_best(stamp, epoch).unwrap_or_else(|| {
+            let best = Arc::new(self.childNodeptrs[index].best(ranking, epoch));
+            stat.cache_best(stamp, epoch, best.clone());
+            best
+        })
+    }
 
-                match self.need_split() {
-                    false => { return None; },
-                    true => {
-                        if let Some((split_key, new_inner_cell)) = self.split(self.split_at()){
-                            let new_btree_node = BtreeNode::inner(new_inner_cell);
-                            return Some((split_key, new_btree_node));
-                        }
-                        else{
-                            unreachable!()
-                        }
+    // Children cut by the bounds are walked into. Those wholly inside go
+    // best cached entry first, until one whose best cannot make the cut
+    // ends the walk. When the cached entries hold all of the `k` best of
+    // such a child, because `k` is within the width or the child has fewer
+    // entries, they stand in for it; otherwise it is walked into as well.
+    fn rank(&self, start: Option<Bound<&K>>, end: Option<Bound<&K>>, ranking: &Ranking<V>, epoch: u64, k: usize, top: &mut Vec<(K, V)>) {
+        let first = start.map_or(0, |start| self.start_index(start));
+        let last = end.map_or(self.childNodeptrs.len() - 1, |end| self.end_index(end));
+        let mut inside = vec![];
+        for index in first..=last {
+            let child_start = start.filter(|_| index == first);
+            let child_end = end.filter(|_| index == last);
+            match (child_start, child_end) {
+                (None, None) => inside.push((index, self.child_best(index, ranking, epoch))),
+                _ => self.childNodeptrs[index].rank(child_start, child_end, ranking, epoch, k, top),
+            }
+        }
+        inside.sort_by(|(_, a), (_, b)| ranking.order_best(a, b));
+        for (index, best) in inside {
+            match best.first() {
+                Some((key, val)) if ranking.makes_cut(top, k, key, val) => {},
+                _ => break,
+            }
+            if k <= ranking.width || best.len() < ranking.width {
+                for (key, val) in best.iter() {
+                    if !ranking.offer(top, k, key, val) {
+                        break;
                     }
                 }
             }
+            else {
+                self.childNodeptrs[index].rank(None, None, ranking, epoch, k, top);
+            }
         }
     }
 
-    pub fn remove(&mut self,
-                  key: &K,
-                  left: Option<BtreeNode<K,V>>,
-                  right: Option<BtreeNode<K,V>>
-    ) -> (Option<K>, Option<K>, Option<V>)
-    {
-        let mut index = 0;
-        let mut old_val;
-        let min_key = self.keys[0].clone();
-        match self.keys.binary_search(key) {
-            Err(i) => {index = i;}
-            Ok(i) => {index = i+1;}
-        }
-        let left_slibing = self.left_slibing(index);
-        let right_slibing = self.right_slibing(index);
-        match self.childNodeptrs[index].remove(key, left_slibing, right_slibing) {
-            (Some(old_key), Some(new_key), Some(val))  => {
-                match self.keys.binary_search(&old_key) {
-                    Err(_) =>{}
-                    Ok(i) =>{self.keys[i] = new_key;}
-                }
-                old_val = val;
-            },
-            (Some(old_key), None, Some(val)) => {
-                match self.keys.binary_search(&old_key) {
-                    Err(_) =>{panic!("btree struct error!")}
-                    Ok(i) =>{
-                        self.keys.remove(i);
-                        self.childNodeptrs.remove(i+1);
-                    }
-                }
-                old_val = val;
-            },
-            (None, None, Some(old_val)) => {return (None,None,Some(old_val));},
-            (None, None, None) => {return (None,None,None);},
-            _ => {unreachable!()}
+    fn move_count(&self, from: usize, to: usize, count: usize) {
+        self.add_count(from, -(count as isize));
+        self.add_count(to, count as isize);
+    }
+
+    fn child_index(&self, key: &K) -> usize {
+        match K::search(&self.keys, key){
+            Err(i) => i,
+            Ok(i) => i+1,
         }
-        match self.need_merge() {
-            false => {
-                if self.keys[0] != min_key {
-                    let new_min_key = self.keys[0].clone();
-                    return (Some(min_key), Some(new_min_key), Some(old_val));
-                }
-                else{
-                    return (None, None, Some(old_val));
-                }
+    }
+
+    // Child holding `start`; an unbounded start maps to the first child.
+    fn start_index(&self, start: Bound<&K>) -> usize {
+        match start {
+            Bound::Included(key) | Bound::Excluded(key) => self.child_index(key),
+            Bound::Unbounded => 0,
+        }
+    }
+
+    // Child holding `end`; an unbounded end maps to the last child.
+    fn end_index(&self, end: Bound<&K>) -> usize {
+        match end {
+            Bound::Included(key) | Bound::Excluded(key) => self.child_index(key),
+            Bound::Unbounded => self.childNodeptrs.len() - 1,
+        }
+    }
+
+    // Links in the right half of a split child.
+    fn insert_child(&mut self, split_key: K, new_btree_node: BtreeNode<K, V>, count: usize) -> SplitResult<K, V> {
+        match K::search(&self.keys, &split_key) {
+            Ok(_) => unreachable!(),
+            Err(index) => {
+                self.keys.insert(index, split_key);
+                self.childNodeptrs.insert(index+1, new_btree_node);
+                self.counts.insert(index+1, ChildStat::new(count));
             }
+        }
+        self.split_if_full()
+    }
+
+    fn split_if_full(&mut self) -> SplitResult<K, V> {
+        match self.need_split() {
+            false => { None},
             true => {
-                if let Some(btree_node) = left {
-                    match btree_node {
-                        BtreeNode::placehold =>{}
-                        BtreeNode::leaf(_) => {panic!("bptree struct error!");}
-                        BtreeNode::inner(inner_node_cell) => {
-                            let mut inner_node_content = inner_node_cell.lock().unwrap();
-                            if inner_node_content.can_borrow(){
-                                let last_index = inner_node_content.keys.len()-1;
-                                let key = inner_node_content.keys.remove(last_index);
-                                let childptr = inner_node_content.childNodeptrs.remove(last_index);
-
-                                self.keys.insert(0, key.clone());
-                                self.childNodeptrs.insert(1, childptr);// insert behind the placehold
-                                return (Some(min_key), Some(key), Some(old_val));
-                            }
-                            else{
-                                self.childNodeptrs.remove(0);//remove the placehold
-                                inner_node_content.keys.append(&mut self.keys);
-                                inner_node_content.childNodeptrs.append(&mut self.childNodeptrs);
-
-                                return (Some(min_key), None, Some(old_val));
-                            }
-                        }
-                    }
+                if let Some((split_key, new_inner_cell, moved)) = self.split(self.split_at()){
+                    let new_btree_node = BtreeNode::inner(new_inner_cell);
+                    Some((split_key, new_btree_node, moved))
                 }
-                if let Some(btree_node) = right {
-                    match btree_node {
-                        BtreeNode::placehold =>{}
-                        BtreeNode::leaf(_) => {panic!("bptree struct error!");},
-                        BtreeNode::inner(inner_node_cell) => {
-                            let mut inner_node_content = inner_node_cell.lock().unwrap();
-
-                            if inner_node_content.can_borrow() {
-                                let key = inner_node_content.keys.remove(0);
-                                let childptr = inner_node_content.childNodeptrs.remove(1);
-                                let old_key = key.clone();
-                                let new_key = inner_node_content.keys[0].clone();
-                                self.keys.push(key);
-                                self.childNodeptrs.push(childptr);
-
-                                return (Some(old_key), Some(new_key), Some(old_val));
-                            }
-                            else{
-                                let old_key = inner_node_content.keys[0].clone();
-                                inner_node_content.childNodeptrs.remove(0);
-                                self.keys.append(&mut inner_node_content.keys);
-                                self.childNodeptrs.append(&mut inner_node_content.childNodeptrs);
-
-                                return (Some(old_key), None, Some(old_val));
-                            }
-
-                        }
-                    }
+                else{
+                    unreachable!()
                 }
             }
         }
-        (None,None,Some(old_val))
     }
-
-    fn can_borrow(&self) -> bool {
-        self.keys.len() > self.split_at()
+    pub fn remove(&mut self, key: &K, doomed: &dyn Fn(&Versions<V>) -> bool) -> Option<Versions<V>> {
+        let index = self.child_index(key);
+        // a node with no children yet holds nothing to remove
+        let old_versions = self.childNodeptrs.get(index)?.remove(key, doomed)?;
+        if old_versions.latest().is_some() {
+            self.add_count(index, -1);
+        }
+        if self.childNodeptrs[index].need_merge() {
+            self.rebalance(index);
+        }
+        Some(old_versions)
     }
 
-    fn left_slibing(&self, index: usize) -> Option<BtreeNode<K, V>> {
-        if index > 0 {
-            Some(self.childNodeptrs[index - 1].clone())
+    // Cuts loose the children lying wholly inside the range, recurses into
+    // the boundary children and rebalances them. A `None` bound means the
+    // range runs past this subtree on that side. The boundary leaves are
+    // trimmed by the caller beforehand.
+    fn drop_span(&mut self, start: Option<Bound<&K>>, end: Option<Bound<&K>>) -> usize {
+        let first = start.map(|start| self.start_index(start));
+        let last = end.map(|end| self.end_index(end));
+        let (lo, hi) = match (first, last) {
+            (Some(first), Some(last)) if last < first => return 0,
+            (Some(first), Some(last)) => (first + 1, last.max(first + 1)),
+            (Some(first), None) => (first + 1, self.childNodeptrs.len()),
+            (None, Some(last)) => (0, last),
+            (None, None) => unreachable!(),
+        };
+        let mut removed = self.childNodeptrs.drain(lo..hi).map(|child| child.sweep()).sum::<usize>();
+        self.counts.drain(lo..hi);
+        if lo > 0 {
+            self.keys.drain(lo-1..hi-1);
         }
         else {
-            None
+            self.keys.drain(..hi);
+        }
+
+        match (first, last) {
+            (Some(first), Some(last)) if first == last => {
+                removed += self.drop_child_span(first, start, end);
+                self.fix_underflow(first);
+            },
+            (Some(first), Some(_)) => {
+                removed += self.drop_child_span(first, start, None);
+                removed += self.drop_child_span(first+1, None, end);
+                self.fix_underflow(first+1);
+                self.fix_underflow(first);
+            },
+            (Some(first), None) => {
+                removed += self.drop_child_span(first, start, None);
+                self.fix_underflow(first);
+            },
+            (None, _) => {
+                removed += self.drop_child_span(0, None, end);
+                self.fix_underflow(0);
+            },
+        }
+        removed
+    }
+
+    // Recurses into a boundary child and recounts it, which also picks up
+    // the trimming of its boundary leaf.
+    fn drop_child_span(&mut self, index: usize, start: Option<Bound<&K>>, end: Option<Bound<&K>>) -> usize {
+        let removed = self.childNodeptrs[index].drop_span(start, end);
+        self.counts[index] = ChildStat::new(self.childNodeptrs[index].total());
+        removed
+    }
+
+    // A range removal can leave a child several keys short, so keep
+    // borrowing or merging until it is back above the minimum.
+    fn fix_underflow(&mut self, mut index: usize) {
+        loop {
+            let mut rebalanced = false;
+            while index < self.childNodeptrs.len() && self.childNodeptrs.len() > 1 && self.childNodeptrs[index].need_merge() {
+                let child_count = self.childNodeptrs.len();
+                self.rebalance(index);
+                rebalanced = true;
+                if self.childNodeptrs.len() < child_count && index > 0 {
+                    index -= 1;
+                }
+            }
+            if !rebalanced {
+                return;
+            }
+            // A child left with a single child of its own could not repair
+            // that one; now that it has siblings again, go down and do so.
+            // Merges down there can leave it short once more.
+            let neighbours = index.saturating_sub(1)..=(index+1).min(self.childNodeptrs.len() - 1);
+            for child in &self.childNodeptrs[neighbours.clone()] {
+                if let BtreeNode::inner(inner_node_ref) = child {
+                    inner_node_ref.write().fix_short_children();
+                }
+            }
+            match neighbours.into_iter().find(|&i| self.childNodeptrs[i].need_merge()) {
+                Some(short) => index = short,
+                None => return,
+            }
+        }
+    }
+
+    fn fix_short_children(&mut self) {
+        while self.childNodeptrs.len() > 1 {
+            match (0..self.childNodeptrs.len()).find(|&i| self.childNodeptrs[i].need_merge()) {
+                Some(short) => self.fix_underflow(short),
+                None => return,
+            }
         }
     }
 
-    fn right_slibing(&self, index: usize) -> Option<BtreeNode<K, V>> {
-        if index < self.childNodeptrs.len()-1 {
-            Some(self.childNodeptrs[index + 1].clone())
+    // Refills the underflowing child at `index` from a sibling, or merges it
+    // with one. Only the children adjacent to `index` are latched, through
+    // `latch_pair`, while this node is held by the caller.
+    fn rebalance(&mut self, index: usize) {
+        let (left, right) = if index > 0 { (index - 1, index) } else { (index, index + 1) };
+        if right >= self.childNodeptrs.len() {
+            return;
         }
-        else{
-            None
+        match (&self.childNodeptrs[left], &self.childNodeptrs[right]) {
+            (BtreeNode::leaf(left_arc), BtreeNode::leaf(right_arc)) => {
+                let (mut left_node, mut right_node) = latch_pair(left_arc, right_arc);
+                if left == index && right_node.can_borrow() {
+                    let key = right_node.keys.remove(0);
+                    let val = right_node.vals.remove(0);
+                    self.move_count(right, left, val.latest().is_some() as usize);
+                    self.keys[left] = K::separator(&key, &right_node.keys[0]);
+                    left_node.keys.push(key);
+                    left_node.vals.push(val);
+                }
+                else if right == index && left_node.can_borrow() {
+                    let key = left_node.keys.pop().unwrap();
+                    let val = left_node.vals.pop().unwrap();
+                    self.move_count(left, right, val.latest().is_some() as usize);
+                    self.keys[left] = K::separator(left_node.keys.last().unwrap(), &key);
+                    right_node.keys.insert(0, key);
+                    right_node.vals.insert(0, val);
+                }
+                else {
+                    self.move_count(right, left, self.count(right));
+                    left_node.keys.append(&mut right_node.keys);
+                    left_node.vals.append(&mut right_node.vals);
+                    left_node.next = right_node.next.take();
+                    right_node.retired = true;
+                    drop(right_node);
+                    drop(left_node);
+                    self.keys.remove(left);
+                    self.childNodeptrs.remove(right);
+                    self.counts.remove(right);
+                }
+            },
+            (BtreeNode::inner(left_arc), BtreeNode::inner(right_arc)) => {
+                let (mut left_node, mut right_node) = latch_pair(left_arc, right_arc);
+                if left == index && right_node.can_borrow() {
+                    let separator = std::mem::replace(&mut self.keys[left], right_node.keys.remove(0));
+                    left_node.keys.push(separator);
+                    left_node.childNodeptrs.push(right_node.childNodeptrs.remove(0));
+                    let stat = right_node.counts.remove(0);
+                    self.move_count(right, left, stat.count());
+                    left_node.counts.push(stat);
+                }
+                else if right == index && left_node.can_borrow() {
+                    let separator = std::mem::replace(&mut self.keys[left], left_node.keys.pop().unwrap());
+                    right_node.keys.insert(0, separator);
+                    right_node.childNodeptrs.insert(0, left_node.childNodeptrs.pop().unwrap());
+                    let stat = left_node.counts.pop().unwrap();
+                    self.move_count(left, right, stat.count());
+                    right_node.counts.insert(0, stat);
+                }
+                else {
+                    self.move_count(right, left, self.count(right));
+                    left_node.keys.push(self.keys.remove(left));
+                    left_node.keys.append(&mut right_node.keys);
+                    left_node.childNodeptrs.append(&mut right_node.childNodeptrs);
+                    left_node.counts.append(&mut right_node.counts);
+                    drop(right_node);
+                    drop(left_node);
+                    self.childNodeptrs.remove(right);
+                    self.counts.remove(right);
+                }
+            },
+            // all leaves sit at the same depth, so siblings are alike
+            _ => unreachable!(),
         }
     }
 
+    fn can_borrow(&self) -> bool {
+        self.keys.len() > self.min_key_count()
+    }
+
     fn need_split(&self) -> bool {
         self.keys.len() > self.max_key_count
     }
 
     fn need_merge(&self) -> bool {
-        self.keys.len() < self.split_at()
+        self.keys.len() < self.min_key_count()
+    }
+
+    // the separator moves down on merge, so inner nodes can run one key
+    // leaner than leaves without a merge ever overflowing
+    fn min_key_count(&self) -> usize {
+        self.max_key_count / 2
     }
 
     fn split_at(&self) -> usize {
-        ((self.max_key_count / 2) + (self.max_key_count % 2)) as usize
+        (self.max_key_count / 2) + (self.max_key_count % 2)
     }
 
-    fn split(&mut self, split_at: usize) -> Option<(K, Arc<Mutex<InnerNode<K, V>>>)> {
+    fn split(&mut self, split_at: usize) -> Option<(K, InnerNodeRef<K, V>, usize)> {
         let split_key = self.keys[split_at].clone();
-        let mut new_inner = InnerNode::from(self.keys[split_at..].as_ref(),
-                                            self.childNodeptrs[split_at+1..].as_ref(),
-                                            self.max_key_count);
-        new_inner.childNodeptrs.insert(0, BtreeNode::placehold);
+        let new_inner = InnerNode::from(self.keys[split_at+1..].as_ref(),
+                                        self.childNodeptrs[split_at+1..].as_ref(),
+                                        self.counts.drain(split_at+1..).collect(),
+                                        self.max_key_count);
 
         self.keys.drain(split_at..);
         self.childNodeptrs.drain(split_at+1..);
-        let new_btree_node = Arc::new(Mutex::new(new_inner));
-        Some((split_key, new_btree_node))
+        let moved = new_inner.total();
+        let new_btree_node = Arc::new(RwLock::new(new_inner));
+        Some((split_key, new_btree_node, moved))
 
     }
 }
@@ -380,58 +3598,85 @@ impl<K,V> InnerNode<K,V>
 #[derive(Debug, Clone)]
 pub struct LeafNode<K, V>{
     keys: Vec<K>,
-    vals: Vec<V>,
-    next: Option<Arc<Mutex<LeafNode<K, V>>>>,
+    vals: Vec<Versions<V>>,
+    next: Option<LeafNodeRef<K, V>>,
     max_key_count: usize,
+    // consecutive inserts landing at the end / front of this leaf, used to
+    // pick the split point for sequential workloads
+    append_streak: usize,
+    prepend_streak: usize,
+    // whether the leaf is the tree's first / last one, refreshed by every
+    // `write_leaf` that latches it, which any write that may split goes
+    // through
+    leftmost: bool,
+    rightmost: bool,
+    // set once the leaf is merged away or cut loose, so cursors holding on
+    // to it know to look the key up again
+    retired: bool,
 }
 
 impl<K, V> LeafNode<K, V>
     where K : Debug + Clone + Ord + KVType,
           V : Debug + Clone + Ord + KVType,
 {
+    // Sized once for the key a leaf holds past full until it splits, as
+    // inner nodes are.
     pub fn new(max_key_count: usize) -> Self{
         Self{
-            keys: Vec::with_capacity(max_key_count),
-            vals: Vec::with_capacity(max_key_count),
+            keys: Vec::with_capacity(max_key_count+1),
+            vals: Vec::with_capacity(max_key_count+1),
             next: Option::None,
             max_key_count,
+            append_streak: 0,
+            prepend_streak: 0,
+            leftmost: false,
+            rightmost: false,
+            retired: false,
         }
     }
 
-    pub fn from(keys: &[K], vals: &[V], max_key_count: usize) -> Self{
-        Self{
-            keys: keys.to_vec(),
-            vals: vals.to_vec(),
-            next: Option::None,
-            max_key_count,
-        }
+    pub fn from(keys: &[K], vals: &[Versions<V>], max_key_count: usize) -> Self{
+        let mut new_leaf = Self::new(max_key_count);
+        new_leaf.keys.extend_from_slice(keys);
+        new_leaf.vals.extend_from_slice(vals);
+        new_leaf
     }
 
-    fn set_next(&mut self, next: Option<Arc<Mutex<LeafNode<K, V>>>>) {
+    fn set_next(&mut self, next: Option<LeafNodeRef<K, V>>) {
         self.next = next;
     }
 
-    pub fn get(&self, key: &K) -> Option<V> {
-        match self.keys.binary_search(key){
-            Ok(i)=>{Some(self.vals[i].clone())}
+    pub fn get_at(&self, key: &K, seq: u64) -> Option<V> {
+        match K::search(&self.keys, key){
+            Ok(i)=>{self.vals[i].at(seq).cloned()}
             Err(_) => None,
         }
     }
 
-    pub fn set(&mut self, key: K, val: V) -> Option<(K, BtreeNode<K, V>)> {
-        match self.keys.binary_search(&key){
-            Ok(i) => {self.vals[i] = val;}
+    pub fn set(&mut self, key: K, val: V, seq: u64, horizon: Option<u64>, deadline: Option<Instant>) -> SplitResult<K, V> {
+        match K::search(&self.keys, &key){
+            Ok(i) => {
+                self.vals[i].push(seq, Some(val), horizon);
+                self.vals[i].set_deadline(deadline);
+            },
             Err(i) => {
+                self.track_insert(i);
                 self.keys.insert(i, key);
-                self.vals.insert(i, val);
+                let mut versions = Versions::new(seq, val);
+                versions.set_deadline(deadline);
+                self.vals.insert(i, versions);
             },
         }
+        // tombstones nobody can read any more may make room
+        if self.need_split() {
+            self.drop_dead(horizon);
+        }
         match self.need_split(){
-            false => { return None; },
+            false => { None},
             true => {
-                if let Some((split_key, new_leaf_arc)) = self.split(self.split_at()){
+                if let Some((split_key, new_leaf_arc, moved)) = self.split(self.adaptive_split_at()){
                     let new_btree_node = BtreeNode::leaf(new_leaf_arc);
-                    return Some((split_key, new_btree_node));
+                    Some((split_key, new_btree_node, moved))
                 }
                 else{
                     unreachable!()
@@ -440,93 +3685,116 @@ impl<K, V> LeafNode<K, V>
         }
     }
 
-    pub fn remove(&mut self, key:& K,  left: Option<BtreeNode<K,V>>,
-                  right: Option<BtreeNode<K,V>>) -> (Option<K>, Option<K>, Option<V>) {
-        let mut old_val = None;
-        let min_key = self.keys[0].clone();
-        match self.keys.binary_search(key) {
-            Err(_) => {return (None, None, None);},
-            Ok(i) => {
-                let mut old_key = self.keys.remove(i);
-                old_val = Some(self.vals.remove(i));
-
-                match self.need_merge() {
-                    false => {
-                        if i == 0 {
-                            let new_min_key = self.keys[0].clone();
-                            return (Some(min_key), Some(new_min_key), old_val);
-                        }
-                        else{
-                            return (None, None, old_val);
-                        }
-                    }
-                    true => {
-                        if let Some(btree_node) = left {
-                            match btree_node {
-                                BtreeNode::placehold =>{panic!("leaf node can not be placehold");}
-                                BtreeNode::inner(_) => {panic!("bptree struct error!");}
-                                BtreeNode::leaf(leaf_node_arc) => {
-                                    let mut leaf_node_content = leaf_node_arc.lock().unwrap();
-                                    if leaf_node_content.can_borrow(){
-                                        let last_index = leaf_node_content.keys.len()-1;
-                                        let key = leaf_node_content.keys.remove(last_index);
-                                        let val = leaf_node_content.vals.remove(last_index);
-                                        if i > 0 {
-                                            old_key = self.keys[0].clone();
-                                        }
-
-                                        self.keys.insert(0, key.clone());
-                                        self.vals.insert(0, val);
-
-                                        return (Some(old_key), Some(key), old_val);
-                                    }
-                                    else{
-                                        if i > 0 {
-                                            old_key = self.keys[0].clone();
-                                        }
-
-                                        leaf_node_content.keys.append(&mut self.keys);
-                                        leaf_node_content.vals.append(&mut self.vals);
-
-                                        return (Some(old_key), None, old_val);
-                                    }
-                                }
-                            }
-                        }
-                        if let Some(btree_node) = right {
-                            match btree_node {
-                                BtreeNode::placehold =>{panic!("leaf node can not be placehold");}
-                                BtreeNode::inner(_) => {panic!("bptree struct error!");},
-                                BtreeNode::leaf(leaf_node_arc) => {
-                                    let mut leaf_node_content = leaf_node_arc.lock().unwrap();
-
-                                    if leaf_node_content.can_borrow() {
-                                        let key = leaf_node_content.keys.remove(0);
-                                        let val = leaf_node_content.vals.remove(0);
-                                        old_key = key.clone();
-                                        let new_key = leaf_node_content.keys[0].clone();
-                                        self.keys.push(key);
-                                        self.vals.push(val);
-
-                                        return (Some(old_key), Some(new_key), old_val);
-                                    }
-                                    else{
-                                        old_key = leaf_node_content.keys[0].clone();
-
-                                        self.keys.append(&mut leaf_node_content.keys);
-                                        self.vals.append(&mut leaf_node_content.vals);
-
-                                        return (Some(old_key), None, old_val);
-                                    }
-
-                                }
-                            }
-                        }
-                    }
-                }
+    // Whether `key` can be set without splitting, after dropping dead
+    // tombstones if that is what it takes.
+    fn has_room_for(&mut self, key: &K, horizon: Option<u64>) -> bool {
+        if !self.may_split() || K::search(&self.keys, key).is_ok() {
+            return true;
+        }
+        // tombstones nobody can read any more may make room
+        self.drop_dead(horizon) && !self.may_split()
+    }
+
+    // Drops the entries removed for every read at `horizon` or later, as
+    // `gc` would. Returns whether there were any. Tombstones are not live,
+    // so the counts above the leaf stay right.
+    fn drop_dead(&mut self, horizon: Option<u64>) -> bool {
+        if !self.vals.iter().any(|versions| versions.is_dead(horizon)) {
+            return false;
+        }
+        let dead: Vec<bool> = self.vals.iter().map(|versions| versions.is_dead(horizon)).collect();
+        let mut flags = dead.iter();
+        self.keys.retain(|_| !flags.next().unwrap());
+        self.vals.retain(|versions| !versions.is_dead(horizon));
+        true
+    }
+
+    // Removes `key` if `doomed` agrees, judged under this leaf's latch.
+    pub fn remove(&mut self, key: &K, doomed: &dyn Fn(&Versions<V>) -> bool) -> Option<Versions<V>> {
+        let i = K::search(&self.keys, key).ok()?;
+        if !doomed(&self.vals[i]) {
+            return None;
+        }
+        self.keys.remove(i);
+        Some(self.vals.remove(i))
+    }
+
+    // Drops the entries between `start` and `end`, returning how many were live.
+    fn remove_span(&mut self, start: Bound<&K>, end: Bound<&K>) -> usize {
+        let lo = self.keys.partition_point(|key| !(start, Bound::Unbounded).contains(key));
+        let hi = self.keys.partition_point(|key| (Bound::Unbounded, end).contains(key)).max(lo);
+        self.keys.drain(lo..hi);
+        self.vals.drain(lo..hi).filter(|versions| versions.latest().is_some()).count()
+    }
+
+    fn update(&mut self, key: &K, val: V, seq: &AtomicU64, horizon: Option<u64>) -> bool {
+        let i = match K::search(&self.keys, key) {
+            Ok(i) if self.vals[i].latest().is_some() => i,
+            _ => return false,
+        };
+        let seq = seq.fetch_add(1, Ordering::SeqCst) + 1;
+        self.vals[i].push(seq, Some(val), horizon);
+        true
+    }
+
+    fn latest(&self, key: &K) -> Option<&V> {
+        self.vals[K::search(&self.keys, key).ok()?].latest()
+    }
+
+    fn is_live(&self, key: &K) -> bool {
+        K::search(&self.keys, key).is_ok_and(|i| self.vals[i].latest().is_some())
+    }
+
+    fn live_count(&self) -> usize {
+        self.vals.iter().filter(|versions| versions.latest().is_some()).count()
+    }
+
+    fn summarize(&self, bounds: (Bound<&K>, Bound<&K>), aggregator: &Aggregator<K, V>) -> Summary {
+        self.keys.iter().zip(&self.vals)
+            .filter(|(key, _)| bounds.contains(*key))
+            .filter_map(|(key, versions)| Some((aggregator.lift)(key, versions.latest()?)))
+            .fold(aggregator.identity.clone(), |summary, part| (aggregator.combine)(&summary, &part))
+    }
+
+    fn rank(&self, bounds: (Bound<&K>, Bound<&K>), ranking: &Ranking<V>, k: usize, top: &mut Vec<(K, V)>) {
+        #[cfg(test)]
+        LEAVES_RANKED.with(|ranked| ranked.set(ranked.get() + 1));
+        for (key, versions) in self.keys.iter().zip(&self.vals) {
+            if let Some(val) = versions.latest().filter(|_| bounds.contains(key)) {
+                ranking.offer(top, k, key, val);
+            }
+        }
+    }
+
+    // Marks `key` removed as of `seq`, keeping older versions readable.
+    fn tombstone(&mut self, key: &K, seq: &AtomicU64, horizon: Option<u64>) -> Option<V> {
+        if !self.is_live(key) {
+            return None;
+        }
+        self.tombstone_at(key, seq.fetch_add(1, Ordering::SeqCst) + 1, horizon)
+    }
+
+    fn tombstone_at(&mut self, key: &K, seq: u64, horizon: Option<u64>) -> Option<V> {
+        let i = K::search(&self.keys, key).ok()?;
+        let old_val = self.vals[i].latest().cloned();
+        if old_val.is_some() {
+            self.vals[i].push(seq, None, horizon);
+        }
+        old_val
+    }
+
+    // Prunes history below `horizon`, returning the keys that are now dead.
+    fn prune(&mut self, horizon: Option<u64>) -> Vec<K> {
+        let mut dead_keys = vec![];
+        for (key, versions) in self.keys.iter().zip(self.vals.iter_mut()) {
+            if let Some(horizon) = horizon {
+                versions.prune(horizon);
+            }
+            if versions.is_dead(horizon) {
+                dead_keys.push(key.clone());
             }
         }
-        (None, None, old_val)
+        dead_keys
     }
 
     fn can_borrow(&self) -> bool {
@@ -537,26 +3805,79 @@ impl<K, V> LeafNode<K, V>
         self.keys.len() > self.max_key_count
     }
 
+    // one more key could make this node split
+    fn may_split(&self) -> bool {
+        self.keys.len() >= self.max_key_count
+    }
+
     fn need_merge(&self) -> bool {
         self.keys.len() < self.split_at()
     }
 
     fn split_at(&self) -> usize {
-        ((self.max_key_count / 2) + (self.max_key_count % 2)) as usize
+        (self.max_key_count / 2) + (self.max_key_count % 2)
     }
 
-    fn split(&mut self, split_at: usize) -> Option<(K, Arc<Mutex<LeafNode<K, V>>>)> {
-        let split_key = self.keys[split_at].clone();
+    fn track_insert(&mut self, index: usize) {
+        if self.keys.is_empty() {
+            return;
+        }
+        if index == self.keys.len() {
+            self.append_streak += 1;
+            self.prepend_streak = 0;
+        }
+        else if index == 0 {
+            self.prepend_streak += 1;
+            self.append_streak = 0;
+        }
+        else {
+            self.append_streak = 0;
+            self.prepend_streak = 0;
+        }
+    }
+
+    // Sequential inserts only ever touch one edge of the leaf, so an even
+    // split would leave every finished leaf half empty. Once at least half a
+    // node of consecutive inserts hit the same edge, the split keeps the
+    // full side as large as it may. At the matching edge of the tree, where
+    // the run can only go on in the new leaf, only the newest key moves
+    // over; that short leaf is the one the tree tolerates below minimum
+    // occupancy. Anywhere else both sides keep at least the minimum, which
+    // leaves the split at most one key off even.
+    //
+    // Skewed workloads whose inserts land away from the tree's edges, like
+    // a zipfian one with scattered hot keys, build no streak and split
+    // evenly: a leaf that has to stay at minimum occupancy on both sides has
+    // no other split point to offer them.
+    fn adaptive_split_at(&self) -> usize {
+        let min = self.split_at();
+        if self.append_streak >= min {
+            if self.rightmost { self.keys.len() - 1 } else { self.keys.len() - min }
+        }
+        else if self.prepend_streak >= min {
+            if self.leftmost { 1 } else { min }
+        }
+        else {
+            min
+        }
+    }
+
+    fn split(&mut self, split_at: usize) -> Option<(K, LeafNodeRef<K, V>, usize)> {
+        let split_key = K::separator(&self.keys[split_at-1], &self.keys[split_at]);
         let mut new_leaf = LeafNode::from(self.keys[split_at..].as_ref(),
                                           self.vals[split_at..].as_ref(),
                                           self.max_key_count);
 
+        // ascending runs continue in the new right leaf
+        new_leaf.append_streak = self.append_streak;
+        self.append_streak = 0;
         new_leaf.set_next(self.next.take());
-        let new_leaf_arc = Arc::new(Mutex::new(new_leaf));
+        let moved = new_leaf.live_count();
+        let new_leaf_arc = Arc::new(RwLock::new(new_leaf));
         self.set_next(Some(new_leaf_arc.clone()));
         self.keys.drain(split_at..);
         self.vals.drain(split_at..);
 
-        Some((split_key,new_leaf_arc))
+        Some((split_key, new_leaf_arc, moved))
     }
-}
+}
\ No newline at end of file
@@ -0,0 +1,77 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+use super::bptree::Bptree;
+use super::hook::IndexHook;
+use super::kvtype::KVType;
+
+/// Called with a tree over its budget and by how many bytes.
+pub type EvictFn<K, V> = Arc<dyn Fn(&Bptree<K, V>, usize) + Send + Sync>;
+
+/// What a tree does once its entries take up more bytes than its budget,
+/// see `Bptree::set_byte_budget`.
+pub enum Eviction<K, V> {
+    /// Removes the entries with the lowest keys until the tree is back
+    /// within its budget.
+    LowestKeys,
+    /// Hands the tree and the number of bytes it is over budget to a
+    /// callback, which frees space as it sees fit. Writes the callback
+    /// makes do not call it again.
+    Callback(EvictFn<K, V>),
+}
+
+impl<K, V> Clone for Eviction<K, V> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::LowestKeys => Self::LowestKeys,
+            Self::Callback(callback) => Self::Callback(callback.clone()),
+        }
+    }
+}
+
+impl<K, V> Debug for Eviction<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LowestKeys => write!(f, "LowestKeys"),
+            Self::Callback(_) => write!(f, "Callback"),
+        }
+    }
+}
+
+// Bytes the live entries of a tree take up, kept in step by hearing about
+// every write as a hook does.
+#[derive(Debug, Default)]
+pub(crate) struct Usage(AtomicIsize);
+
+impl Usage {
+    pub(crate) fn bytes(&self) -> usize {
+        self.0.load(Ordering::SeqCst).max(0) as usize
+    }
+
+    pub(crate) fn add(&self, bytes: usize) {
+        self.0.fetch_add(bytes as isize, Ordering::SeqCst);
+    }
+}
+
+impl<K: KVType, V: KVType> IndexHook<K, V> for Usage {
+    fn on_insert(&self, key: &K, val: &V) {
+        self.0.fetch_add((key.byte_len() + val.byte_len()) as isize, Ordering::SeqCst);
+    }
+
+    fn on_update(&self, _key: &K, old: &V, new: &V) {
+        self.0.fetch_add(new.byte_len() as isize - old.byte_len() as isize, Ordering::SeqCst);
+    }
+
+    fn on_remove(&self, key: &K, old: &V) {
+        self.0.fetch_sub((key.byte_len() + old.byte_len()) as isize, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Budget<K, V> {
+    pub(crate) max: usize,
+    pub(crate) eviction: Eviction<K, V>,
+    pub(crate) usage: Arc<Usage>,
+    // set while some thread is bringing usage down
+    pub(crate) evicting: Arc<AtomicBool>,
+}
@@ -0,0 +1,177 @@
+use std::fmt::{self, Debug, Display};
+use std::marker::PhantomData;
+use super::arena::ArenaBptree;
+use super::bptree::Bptree;
+use super::kvtype::KVType;
+use super::multimap::BptreeMultimap;
+
+/// Smallest order a tree can split and rebalance with.
+pub const MIN_ORDER: usize = 3;
+
+/// Order a builder uses unless told otherwise.
+pub const DEFAULT_ORDER: usize = 32;
+
+/// What `set` and its variants, and the puts of `apply`, do with a key that
+/// already has a live entry. Writes that name the entry they change, like
+/// `update_with`, `compare_and_swap` or cursor updates, and writes carrying
+/// entries over from elsewhere, `append` and `restore`, always overwrite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// Replace the old value, as a map does.
+    #[default]
+    Overwrite,
+    /// `set` panics, naming the key; `set_checked`, `try_set` and
+    /// `set_timeout` report it as an error instead, and `apply` refuses the
+    /// whole batch.
+    Error,
+    /// Leave the old value and drop the new one.
+    KeepFirst,
+    /// Keep every value, in insertion order. A `Bptree` holds one value per
+    /// key, so this takes a `BptreeMultimap`, from
+    /// `BptreeBuilder::build_multimap`; `build` and `build_arena` refuse it.
+    Multi,
+}
+
+/// Why `BptreeBuilder::build` refused a configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    OrderTooSmall{ m: usize, min: usize },
+    MultiNeedsMultimap,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OrderTooSmall{ m, min } => write!(f, "order {} is below the minimum of {}", m, min),
+            Self::MultiNeedsMultimap => write!(f, "keeping every value per key takes build_multimap"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Collects the settings of a new tree and checks them before building it.
+#[derive(Debug, Clone)]
+pub struct BptreeBuilder<K, V> {
+    m: usize,
+    horizon: Option<u64>,
+    lazy_rebalance: bool,
+    max_key_len: Option<usize>,
+    max_value_len: Option<usize>,
+    duplicates: DuplicatePolicy,
+    marker: PhantomData<(K, V)>,
+}
+
+impl<K, V> BptreeBuilder<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    pub fn new() -> Self {
+        Self{
+            m: DEFAULT_ORDER,
+            horizon: None,
+            lazy_rebalance: false,
+            max_key_len: None,
+            max_value_len: None,
+            duplicates: DuplicatePolicy::Overwrite,
+            marker: PhantomData,
+        }
+    }
+
+    /// Most children an inner node may have; leaves hold one entry fewer.
+    pub fn order(mut self, m: usize) -> Self {
+        self.m = m;
+        self
+    }
+
+    /// Version horizon the tree starts with, see `Bptree::set_version_horizon`.
+    pub fn version_horizon(mut self, horizon: Option<u64>) -> Self {
+        self.horizon = horizon;
+        self
+    }
+
+    /// Whether removals defer rebalancing, see `Bptree::set_lazy_rebalance`.
+    pub fn lazy_rebalance(mut self, lazy: bool) -> Self {
+        self.lazy_rebalance = lazy;
+        self
+    }
+
+    /// Size limit on keys, see `Bptree::set_max_key_len`.
+    pub fn max_key_len(mut self, max: usize) -> Self {
+        self.max_key_len = Some(max);
+        self
+    }
+
+    /// Size limit on values, see `Bptree::set_max_value_len`.
+    pub fn max_value_len(mut self, max: usize) -> Self {
+        self.max_value_len = Some(max);
+        self
+    }
+
+    /// What `set` does with keys already present, see `DuplicatePolicy`.
+    pub fn duplicates(mut self, duplicates: DuplicatePolicy) -> Self {
+        self.duplicates = duplicates;
+        self
+    }
+
+    pub fn build(self) -> Result<Bptree<K, V>, ConfigError> {
+        self.check()?;
+        let mut tree = Bptree::new(self.m);
+        tree.set_duplicate_policy(self.duplicates);
+        tree.set_version_horizon(self.horizon);
+        tree.set_lazy_rebalance(self.lazy_rebalance);
+        tree.set_max_key_len(self.max_key_len);
+        tree.set_max_value_len(self.max_value_len);
+        Ok(tree)
+    }
+
+    /// Builds a `BptreeMultimap` with these settings, which keeps every
+    /// value inserted under a key whatever the duplicate policy; it is how
+    /// `DuplicatePolicy::Multi` is built. It stores each value under its key
+    /// and an insertion counter, which the key size limit leaves out.
+    pub fn build_multimap(self) -> Result<BptreeMultimap<K, V>, ConfigError> {
+        let tree = BptreeBuilder::<(K, u64), V>{
+            m: self.m,
+            horizon: self.horizon,
+            lazy_rebalance: self.lazy_rebalance,
+            max_key_len: self.max_key_len.map(|max| max.saturating_add(std::mem::size_of::<u64>())),
+            max_value_len: self.max_value_len,
+            // the counter makes every key distinct
+            duplicates: DuplicatePolicy::Overwrite,
+            marker: PhantomData,
+        }.build()?;
+        Ok(BptreeMultimap::from_tree(tree))
+    }
+
+    /// Builds an `ArenaBptree` with these settings, checked as `build`
+    /// checks them. The arena always rebalances on removal, so
+    /// `lazy_rebalance` is left out.
+    pub fn build_arena(self) -> Result<ArenaBptree<K, V>, ConfigError> {
+        self.check()?;
+        let mut tree = ArenaBptree::new(self.m);
+        tree.set_duplicate_policy(self.duplicates);
+        tree.set_version_horizon(self.horizon);
+        tree.set_max_key_len(self.max_key_len);
+        tree.set_max_value_len(self.max_value_len);
+        Ok(tree)
+    }
+
+    fn check(&self) -> Result<(), ConfigError> {
+        if self.m < MIN_ORDER {
+            return Err(ConfigError::OrderTooSmall{ m: self.m, min: MIN_ORDER });
+        }
+        if self.duplicates == DuplicatePolicy::Multi {
+            return Err(ConfigError::MultiNeedsMultimap);
+        }
+        Ok(())
+    }
+}
+
+impl<K, V> Default for BptreeBuilder<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
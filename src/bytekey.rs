@@ -0,0 +1,139 @@
+/// Keys with a byte encoding that sorts the same way the keys do, so code
+/// that only sees bytes (a page format, prefix compression) can still
+/// compare them. Encodings are self-delimiting, which lets tuples
+/// concatenate their components.
+pub trait ByteKey: Sized {
+    /// Appends the encoding of `self` to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Reads one key off the front of `bytes` and returns it with the rest,
+    /// or `None` if `bytes` does not start with a valid encoding.
+    fn decode(bytes: &[u8]) -> Option<(Self, &[u8])>;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(&mut out);
+        out
+    }
+
+    /// Decodes a key that takes up all of `bytes`.
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match Self::decode(bytes)? {
+            (key, []) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+// Big-endian, which sorts unsigned integers bytewise as is.
+macro_rules! byte_key_unsigned {
+    ($($t:ty),*) => {$(
+        impl ByteKey for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode(bytes: &[u8]) -> Option<(Self, &[u8])> {
+                let (head, rest) = bytes.split_first_chunk()?;
+                Some((<$t>::from_be_bytes(*head), rest))
+            }
+        }
+    )*};
+}
+byte_key_unsigned!(u8, u16, u32, u64, u128);
+
+// Flipping the sign bit moves negatives below the positives.
+macro_rules! byte_key_signed {
+    ($($t:ty => $u:ty),*) => {$(
+        impl ByteKey for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode(out);
+            }
+
+            fn decode(bytes: &[u8]) -> Option<(Self, &[u8])> {
+                let (flipped, rest) = <$u>::decode(bytes)?;
+                Some(((flipped ^ (1 << (<$u>::BITS - 1))) as $t, rest))
+            }
+        }
+    )*};
+}
+byte_key_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+// Zero bytes are escaped as 0x00 0xff and the end is marked 0x00 0x00, so a
+// byte string sorts before every longer one it is a prefix of.
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        out.push(byte);
+        if byte == 0 {
+            out.push(0xff);
+        }
+    }
+    out.extend_from_slice(&[0, 0]);
+}
+
+impl ByteKey for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_bytes(self, out);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let mut key = vec![];
+        let mut i = 0;
+        loop {
+            match *bytes.get(i)? {
+                0 => {
+                    match *bytes.get(i + 1)? {
+                        0 => return Some((key, &bytes[i + 2..])),
+                        0xff => key.push(0),
+                        _ => return None,
+                    }
+                    i += 2;
+                },
+                byte => {
+                    key.push(byte);
+                    i += 1;
+                },
+            }
+        }
+    }
+}
+
+// UTF-8 sorts bytewise in code point order, which is how `String` orders.
+impl ByteKey for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), out);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let (key, rest) = Vec::<u8>::decode(bytes)?;
+        Some((String::from_utf8(key).ok()?, rest))
+    }
+}
+
+impl<A: ByteKey, B: ByteKey> ByteKey for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let (a, rest) = A::decode(bytes)?;
+        let (b, rest) = B::decode(rest)?;
+        Some(((a, b), rest))
+    }
+}
+
+impl<A: ByteKey, B: ByteKey, C: ByteKey> ByteKey for (A, B, C) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+        self.2.encode(out);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let (a, rest) = A::decode(bytes)?;
+        let (b, rest) = B::decode(rest)?;
+        let (c, rest) = C::decode(rest)?;
+        Some(((a, b, c), rest))
+    }
+}
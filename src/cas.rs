@@ -0,0 +1,37 @@
+use std::fmt::{self, Debug, Display};
+
+/// Why `Bptree::compare_and_swap` left an entry alone: it held `current`
+/// rather than the expected value, `None` meaning no live entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CasError<V> {
+    pub current: Option<V>,
+}
+
+impl<V> Display for CasError<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.current {
+            Some(_) => write!(f, "entry holds a different value than expected"),
+            None => write!(f, "entry is absent"),
+        }
+    }
+}
+
+impl<V: Debug> std::error::Error for CasError<V> {}
+
+/// Why a write left an entry in place, as `Bptree::try_insert` always does
+/// and the `set` family does under `DuplicatePolicy::Error`: `key` already
+/// held `current`. Hands back the key and the value that was not written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OccupiedError<K, V> {
+    pub key: K,
+    pub current: V,
+    pub val: V,
+}
+
+impl<K: Debug, V> Display for OccupiedError<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "key {:?} is already present", self.key)
+    }
+}
+
+impl<K: Debug, V: Debug> std::error::Error for OccupiedError<K, V> {}
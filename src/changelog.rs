@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use std::time::Instant;
+use parking_lot::{Condvar, Mutex};
+use super::bptree::Bptree;
+use super::hook::IndexHook;
+use super::kvtype::KVType;
+use super::watch::ChangeEvent;
+
+/// One mutation recorded in a `ChangeLog`, at position `lsn`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry<K, V> {
+    pub lsn: u64,
+    pub event: ChangeEvent<K, V>,
+}
+
+/// The changes a reader asked for were already dropped from the log; it
+/// must catch up some other way, from a backup say, and go on from `oldest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogTruncated {
+    pub oldest: u64,
+}
+
+impl fmt::Display for LogTruncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the change log only goes back to {}", self.oldest)
+    }
+}
+
+impl std::error::Error for LogTruncated {}
+
+struct LogState<K, V> {
+    // position the next change gets
+    next: u64,
+    changes: VecDeque<LogEntry<K, V>>,
+}
+
+/// The mutations of a tree in the order they were made, as returned by
+/// `Bptree::change_log`. Every change gets the next log sequence number,
+/// starting from 1, and the newest `capacity` are kept in memory for
+/// readers to tail from wherever they left off.
+pub struct ChangeLog<K, V> {
+    capacity: usize,
+    state: Mutex<LogState<K, V>>,
+    appended: Condvar,
+}
+
+impl<K: Clone, V: Clone> ChangeLog<K, V> {
+    fn record(&self, event: ChangeEvent<K, V>) {
+        let mut state = self.state.lock();
+        let lsn = state.next;
+        state.next += 1;
+        if state.changes.len() == self.capacity {
+            state.changes.pop_front();
+        }
+        state.changes.push_back(LogEntry{ lsn, event });
+        drop(state);
+        self.appended.notify_all();
+    }
+
+    /// Sequence number the next change will get.
+    pub fn next_lsn(&self) -> u64 {
+        self.state.lock().next
+    }
+
+    /// Up to `max` changes starting at sequence number `from`, oldest
+    /// first; pass the one after the last change read to go on. Fails if
+    /// changes from `from` on were already dropped.
+    pub fn read_from(&self, from: u64, max: usize) -> Result<Vec<LogEntry<K, V>>, LogTruncated> {
+        let state = self.state.lock();
+        let oldest = state.next - state.changes.len() as u64;
+        if from < oldest {
+            return Err(LogTruncated{ oldest });
+        }
+        Ok(state.changes.iter().skip((from - oldest) as usize).take(max).cloned().collect())
+    }
+
+    /// Waits until a change at or after `from` is in the log or `deadline`
+    /// passes, and tells which.
+    pub fn wait_for(&self, from: u64, deadline: Instant) -> bool {
+        let mut state = self.state.lock();
+        while state.next <= from {
+            if self.appended.wait_until(&mut state, deadline).timed_out() {
+                return state.next > from;
+            }
+        }
+        true
+    }
+}
+
+impl<K, V> IndexHook<K, V> for ChangeLog<K, V>
+    where K: Clone + Send,
+          V: Clone + Send,
+{
+    fn on_insert(&self, key: &K, val: &V) {
+        self.record(ChangeEvent::Insert{ key: key.clone(), val: val.clone() });
+    }
+
+    fn on_update(&self, key: &K, old: &V, new: &V) {
+        self.record(ChangeEvent::Update{ key: key.clone(), old: old.clone(), new: new.clone() });
+    }
+
+    fn on_remove(&self, key: &K, old: &V) {
+        self.record(ChangeEvent::Remove{ key: key.clone(), old: old.clone() });
+    }
+}
+
+impl<K, V> Debug for ChangeLog<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangeLog").field("capacity", &self.capacity).finish()
+    }
+}
+
+impl<K, V> Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// Starts logging every change to the tree from now on, keeping the
+    /// newest `capacity`. Changes are recorded from the write path as hooks
+    /// hear them, see `IndexHook` for which writes report, with the entry's
+    /// leaf still latched. So changes to one key are logged in the order
+    /// they were made, and a change is in the log by the time its write
+    /// returns. The log lives in memory only; after a restart, restore a
+    /// backup and start a new one. Panics if `capacity` is 0.
+    pub fn change_log(&self, capacity: usize) -> Arc<ChangeLog<K, V>>
+        where K: Send + 'static, V: Send + 'static
+    {
+        assert!(capacity > 0, "a change log needs room for at least one change");
+        let log = Arc::new(ChangeLog{
+            capacity,
+            state: Mutex::new(LogState{ next: 1, changes: VecDeque::new() }),
+            appended: Condvar::new(),
+        });
+        self.add_hook(log.clone());
+        log
+    }
+}
@@ -0,0 +1,36 @@
+/// A broken structural invariant found by `Bptree::check_invariants`. Nodes
+/// are identified by the child indices leading to them from the root, so
+/// the root is the empty path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation<K> {
+    /// Keys within the node are not strictly ascending.
+    UnsortedKeys{ path: Vec<usize> },
+    /// A key lies outside the range the separators above the node allow.
+    KeyOutOfRange{ path: Vec<usize>, key: K },
+    Overfull{ path: Vec<usize>, keys: usize, max: usize },
+    /// A node other than the root is below its minimum occupancy. The first
+    /// and the last leaf, which an adaptive split may leave short, and any
+    /// leaf while lazy rebalancing is on, only count once empty.
+    Underfull{ path: Vec<usize>, keys: usize, min: usize },
+    /// An inner node without exactly one more child than keys.
+    ChildCountMismatch{ path: Vec<usize>, keys: usize, children: usize },
+    /// A leaf that is not as deep as the first leaf.
+    UnevenDepth{ path: Vec<usize>, depth: usize, expected: usize },
+    /// The entry count an inner node keeps for a child disagrees with the
+    /// live entries actually below it.
+    CountMismatch{ path: Vec<usize>, recorded: usize, actual: usize },
+    /// A leaf whose `next` is not the leaf that follows it in the tree.
+    BrokenLeafChain{ path: Vec<usize> },
+}
+
+/// Outcome of `Bptree::check_invariants`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvariantReport<K> {
+    pub violations: Vec<Violation<K>>,
+}
+
+impl<K> InvariantReport<K> {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt::Debug;
+use super::bptree::Bptree;
+use super::kvtype::KVType;
+
+/// Key components with a smallest value, so a scan can start below every
+/// key that shares the leading components.
+pub trait MinKey {
+    fn min_key() -> Self;
+}
+
+macro_rules! min_key_int {
+    ($($t:ty),*) => {$(
+        impl MinKey for $t {
+            fn min_key() -> Self {
+                <$t>::MIN
+            }
+        }
+    )*};
+}
+min_key_int!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+impl MinKey for String {
+    fn min_key() -> Self {
+        String::new()
+    }
+}
+
+impl MinKey for Vec<u8> {
+    fn min_key() -> Self {
+        Vec::new()
+    }
+}
+
+/// Tuple keys, which already order lexicographically, matched against a
+/// tuple of their leading components `P`.
+pub trait CompositeKey<P> {
+    /// The smallest key whose leading components are `prefix`.
+    fn first_with(prefix: &P) -> Self;
+    fn starts_with(&self, prefix: &P) -> bool;
+}
+
+impl<A: Clone + Eq, B: MinKey> CompositeKey<(A,)> for (A, B) {
+    fn first_with(prefix: &(A,)) -> Self {
+        (prefix.0.clone(), B::min_key())
+    }
+
+    fn starts_with(&self, prefix: &(A,)) -> bool {
+        self.0 == prefix.0
+    }
+}
+
+impl<A: Clone + Eq, B: MinKey, C: MinKey> CompositeKey<(A,)> for (A, B, C) {
+    fn first_with(prefix: &(A,)) -> Self {
+        (prefix.0.clone(), B::min_key(), C::min_key())
+    }
+
+    fn starts_with(&self, prefix: &(A,)) -> bool {
+        self.0 == prefix.0
+    }
+}
+
+impl<A: Clone + Eq, B: Clone + Eq, C: MinKey> CompositeKey<(A, B)> for (A, B, C) {
+    fn first_with(prefix: &(A, B)) -> Self {
+        (prefix.0.clone(), prefix.1.clone(), C::min_key())
+    }
+
+    fn starts_with(&self, prefix: &(A, B)) -> bool {
+        self.0 == prefix.0 && self.1 == prefix.1
+    }
+}
+
+impl<K, V> Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// Iterates over the entries whose tuple keys start with the components
+    /// in `prefix`, in key order, e.g. `scan_partial(&(tenant,))` on
+    /// `(tenant, table, id)` keys. Stops at the first key past the prefix.
+    pub fn scan_partial<P>(&self, prefix: &P) -> impl Iterator<Item = (K, V)> + '_
+        where K: CompositeKey<P>,
+              P: Clone + 'static,
+    {
+        let prefix = prefix.clone();
+        self.range(K::first_with(&prefix)..)
+            .take_while(move |(key, _)| key.starts_with(&prefix))
+    }
+}
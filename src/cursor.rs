@@ -0,0 +1,152 @@
+use std::fmt::Debug;
+use std::ops::Bound;
+use super::bptree::{Bptree, LeafNodeRef, LeafPath};
+use super::error::BptreeError;
+use super::kvtype::KVType;
+
+enum Position<K> {
+    Start,
+    // on `K`, or where it stood if it was deleted through the cursor
+    At(K),
+    End,
+}
+
+/// A movable position in the tree. The cursor remembers the leaf its entry
+/// came from, so stepping forward continues along the leaf chain instead of
+/// descending from the root. No latch is held between calls: the cached
+/// leaf is only trusted while it still covers the cursor's key, otherwise
+/// the next step looks the key up again. Writes between steps are never an
+/// error: each step lands on the nearest entry as the tree is now.
+pub struct Cursor<'a, K, V> {
+    tree: &'a Bptree<K, V>,
+    position: Position<K>,
+    val: Option<V>,
+    leaf: Option<LeafNodeRef<K, V>>,
+    // path to the leaf `insert_after` last wrote to
+    path: Option<LeafPath<K, V>>,
+}
+
+impl<'a, K, V> Cursor<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    pub fn new(tree: &'a Bptree<K, V>, seek: &K) -> Self {
+        let mut cursor = Self{
+            tree,
+            position: Position::Start,
+            val: None,
+            leaf: None,
+            path: None,
+        };
+        cursor.step_forward(Bound::Included(seek));
+        cursor
+    }
+
+    /// The entry under the cursor, `None` once it has run off either end or
+    /// its entry was deleted.
+    pub fn current(&self) -> Option<(&K, &V)> {
+        match (&self.position, &self.val) {
+            (Position::At(key), Some(val)) => Some((key, val)),
+            _ => None,
+        }
+    }
+
+    /// Moves to the following entry. Past the last entry the cursor stays
+    /// at the end, from where `prev` comes back to the last entry.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<(&K, &V)> {
+        let from = match &self.position {
+            Position::Start => Bound::Unbounded,
+            Position::At(key) => Bound::Excluded(key.clone()),
+            Position::End => return None,
+        };
+        self.step_forward(from.as_ref());
+        self.current()
+    }
+
+    /// Moves to the preceding entry. Before the first entry the cursor
+    /// stays at the start, from where `next` comes back to the first entry.
+    pub fn prev(&mut self) -> Option<(&K, &V)> {
+        let to = match &self.position {
+            Position::Start => return None,
+            Position::At(key) => Bound::Excluded(key.clone()),
+            Position::End => Bound::Unbounded,
+        };
+        match self.tree.seek_backward(self.leaf.as_ref(), to.as_ref()) {
+            Some((leaf, key, val)) => self.land(leaf, key, val),
+            None => {
+                self.position = Position::Start;
+                self.val = None;
+                self.leaf = None;
+            }
+        }
+        self.current()
+    }
+
+    /// Replaces the value of the entry under the cursor. Returns false if
+    /// there is none, including when another thread removed it meanwhile.
+    pub fn update(&mut self, val: V) -> bool {
+        let key = match (&self.position, &self.val) {
+            (Position::At(key), Some(_)) => key,
+            _ => return false,
+        };
+        if !self.tree.update_at(self.leaf.as_ref(), key, val.clone()) {
+            self.val = None;
+            return false;
+        }
+        self.val = Some(val);
+        true
+    }
+
+    /// Sets `key`, one after the cursor as in a merge join, as `Bptree::set`
+    /// would, and moves the cursor onto it. The cursor remembers the path
+    /// down to the leaf it wrote to, so a run of keys landing in one leaf
+    /// is written without searching from the root each time; a key the leaf
+    /// has no room for, or not after the cursor, takes the usual path. If
+    /// the duplicate policy keeps the entry already there, the cursor shows
+    /// that value. What `Bptree::set_checked` refuses is refused here too,
+    /// leaving the tree and the cursor as they were.
+    pub fn insert_after(&mut self, key: K, val: V) -> Result<(), BptreeError<K, V>> {
+        let after = match &self.position {
+            Position::Start => true,
+            Position::At(at) => *at < key,
+            Position::End => false,
+        };
+        let hint = if after { Some(&mut self.path) } else { None };
+        let kept = self.tree.set_at(hint, key.clone(), val.clone())?;
+        if let Some(path) = self.path.as_ref().filter(|_| after) {
+            self.leaf = Some(path.leaf().clone());
+        }
+        self.position = Position::At(key);
+        self.val = Some(kept.unwrap_or(val));
+        Ok(())
+    }
+
+    /// Removes the entry under the cursor and returns its value. The cursor
+    /// keeps its place, so `next` and `prev` continue from the removed key.
+    pub fn delete(&mut self) -> Option<V> {
+        let key = match (&self.position, &self.val) {
+            (Position::At(key), Some(_)) => key,
+            _ => return None,
+        };
+        self.val = None;
+        self.tree.remove(key)
+    }
+
+    fn step_forward(&mut self, from: Bound<&K>) {
+        match self.tree.seek_forward(self.leaf.as_ref(), from) {
+            Some((leaf, key, val)) => self.land(leaf, key, val),
+            None => {
+                self.position = Position::End;
+                self.val = None;
+                self.leaf = None;
+            }
+        }
+    }
+
+    fn land(&mut self, leaf: LeafNodeRef<K, V>, key: K, val: V) {
+        self.position = Position::At(key);
+        self.val = Some(val);
+        self.leaf = Some(leaf);
+    }
+}
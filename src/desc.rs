@@ -0,0 +1,66 @@
+use std::cmp::Reverse;
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use super::bptree::Bptree;
+use super::kvtype::KVType;
+
+/// A tree kept in descending key order: iteration and `pop_first` start
+/// from the largest key. Keys are stored as `Reverse<K>`, which this
+/// wrapper adds and strips so callers only ever see `K`.
+#[derive(Debug)]
+pub struct BptreeDesc<K, V> {
+    tree: Bptree<Reverse<K>, V>,
+}
+
+impl<K, V> BptreeDesc<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    pub fn new(m: usize) -> Self {
+        Self{
+            tree: Bptree::new(m),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.tree.get(&Reverse(key.clone()))
+    }
+
+    pub fn set(&self, key: K, val: V) {
+        self.tree.set(Reverse(key), val);
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.tree.remove(&Reverse(key.clone()))
+    }
+
+    /// Removes the entry with the largest key and returns it.
+    pub fn pop_first(&self) -> Option<(K, V)> {
+        self.tree.pop_first().map(|(Reverse(key), val)| (key, val))
+    }
+
+    /// Removes the entry with the smallest key and returns it.
+    pub fn pop_last(&self) -> Option<(K, V)> {
+        self.tree.pop_last().map(|(Reverse(key), val)| (key, val))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.count_range(..)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Entries whose keys fall in `range`, largest key first.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl DoubleEndedIterator<Item = (K, V)> + '_ {
+        let flip = |bound: Bound<&K>| bound.map(|key| Reverse(key.clone()));
+        self.tree.range((flip(range.end_bound()), flip(range.start_bound())))
+            .map(|(Reverse(key), val)| (key, val))
+    }
+
+    /// Every entry, largest key first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (K, V)> + '_ {
+        self.range(..)
+    }
+}
@@ -0,0 +1,220 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::iter::Peekable;
+use std::ops::Bound;
+use super::bptree::Bptree;
+use super::iter::Range;
+use super::kvtype::KVType;
+
+/// One difference between two trees, read as the edit that turns the tree
+/// `diff` was called on into the one it was given.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change<K, V> {
+    Added(K, V),
+    Removed(K, V),
+    /// The key is in both trees: its old value, then its new one.
+    Changed(K, V, V),
+}
+
+impl<K, V> Change<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Change::Added(key, _) | Change::Removed(key, _) | Change::Changed(key, _, _) => key,
+        }
+    }
+}
+
+/// Iterator over the changes between two trees, or between a snapshot and
+/// the tree it was taken of, in key order. Like `Range`, it holds no latch
+/// between calls to `next`.
+pub struct Diff<'a, K, V>(Walk<'a, K, V>)
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType;
+
+enum Walk<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    // boxed, as ranges are large
+    Trees(Box<Trees<'a, K, V>>),
+    // the entries of one tree written after `since`, a leaf at a time
+    Since{
+        tree: &'a Bptree<K, V>,
+        since: u64,
+        // last key read, to go on after
+        from: Option<K>,
+        buf: VecDeque<Change<K, V>>,
+        exhausted: bool,
+    },
+}
+
+// Two trees read side by side.
+struct Trees<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    old: Peekable<Range<'a, K, V>>,
+    new: Peekable<Range<'a, K, V>>,
+}
+
+/// Iterator over the entries written after a sequence number, as returned
+/// by `Bptree::diff_since`: each key with its value now, or `None` if it
+/// was removed or expired.
+pub struct Changes<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    tree: &'a Bptree<K, V>,
+    since: u64,
+    from: Option<K>,
+    buf: VecDeque<(K, Option<V>)>,
+    exhausted: bool,
+}
+
+impl<K, V> Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// Changes that turn this tree into `other`. Every tree owns its nodes
+    /// outright, so there are no shared subtrees to skip and both trees are
+    /// read in full, one leaf at a time. To follow one tree's changes,
+    /// `Snapshot::diff` and `diff_since` read only what was written.
+    pub fn diff<'a>(&'a self, other: &'a Self) -> Diff<'a, K, V> {
+        Diff(Walk::Trees(Box::new(Trees{
+            old: self.iter().peekable(),
+            new: other.iter().peekable(),
+        })))
+    }
+
+    /// Entries set or removed after sequence number `since`, the delta that
+    /// brings a replica up from `since` to now. Only the entries written
+    /// since are read, leaf by leaf, as `backup_since` does, and the same
+    /// holds for removals: they only show up while their tombstones are
+    /// kept, under a version horizon at or below `since`. Writes racing
+    /// with the iteration may or may not show up in it.
+    pub fn diff_since(&self, since: u64) -> Changes<'_, K, V> {
+        Changes{
+            tree: self,
+            since,
+            from: None,
+            buf: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    // Changes from what a read at `since` saw to the tree now, for
+    // `Snapshot::diff`, which keeps the versions at `since` around.
+    pub(crate) fn diff_at(&self, since: u64) -> Diff<'_, K, V> {
+        Diff(Walk::Since{
+            tree: self,
+            since,
+            from: None,
+            buf: VecDeque::new(),
+            exhausted: false,
+        })
+    }
+}
+
+// Start bound for the next chunk, after the last key read.
+fn after<K>(from: &Option<K>) -> Bound<&K> {
+    match from {
+        Some(key) => Bound::Excluded(key),
+        None => Bound::Unbounded,
+    }
+}
+
+/// Trees are equal when they hold the same live entries, however their
+/// nodes are laid out: order, fill, version history and sequence numbers
+/// do not matter.
+impl<K, V> PartialEq for Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other) || self.iter().eq(other.iter())
+    }
+}
+
+impl<K, V> Eq for Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{}
+
+impl<'a, K, V> Iterator for Diff<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    type Item = Change<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (old, new) = match &mut self.0 {
+            Walk::Trees(trees) => (&mut trees.old, &mut trees.new),
+            Walk::Since{ tree, since, from, buf, exhausted } => loop {
+                if let Some(change) = buf.pop_front() {
+                    return Some(change);
+                }
+                if *exhausted {
+                    return None;
+                }
+                let mut chunk = vec![];
+                let more = tree.read_diff(after(from), *since, &mut chunk);
+                match chunk.last() {
+                    Some(last) if more => *from = Some(last.key().clone()),
+                    _ => *exhausted = true,
+                }
+                buf.extend(chunk);
+            },
+        };
+        loop {
+            let order = match (old.peek(), new.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((old_key, _)), Some((new_key, _))) => old_key.cmp(new_key),
+            };
+            match order {
+                Ordering::Less => {
+                    let (key, val) = old.next()?;
+                    return Some(Change::Removed(key, val));
+                },
+                Ordering::Greater => {
+                    let (key, val) = new.next()?;
+                    return Some(Change::Added(key, val));
+                },
+                Ordering::Equal => {
+                    let (key, old_val) = old.next()?;
+                    let (_, new_val) = new.next()?;
+                    if old_val != new_val {
+                        return Some(Change::Changed(key, old_val, new_val));
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Changes<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    type Item = (K, Option<V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(change) = self.buf.pop_front() {
+                return Some(change);
+            }
+            if self.exhausted {
+                return None;
+            }
+            let mut chunk = vec![];
+            let more = self.tree.read_changes(after(&self.from), self.since, &mut chunk);
+            match chunk.last() {
+                Some((last, _)) if more => self.from = Some(last.clone()),
+                _ => self.exhausted = true,
+            }
+            self.buf.extend(chunk);
+        }
+    }
+}
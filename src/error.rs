@@ -0,0 +1,50 @@
+use std::fmt::{self, Debug, Display};
+use super::cas::{CasError, OccupiedError};
+use super::limits::SizeError;
+use super::trylock::LockError;
+
+/// Why a write was refused. The tree is left as it was.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BptreeError<K, V> {
+    /// The key or value is over the tree's size limits.
+    Size(SizeError),
+    /// The key already has a live entry and the tree's `DuplicatePolicy`
+    /// is `Error`.
+    Occupied(OccupiedError<K, V>),
+    /// `compare_and_swap` found something other than the value expected.
+    Mismatch(CasError<V>),
+    /// A latch the write needed was held, and the write was not allowed to
+    /// wait for it, or not for that long.
+    Lock(LockError),
+}
+
+impl<K: Debug, V> Display for BptreeError<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Size(err) => Display::fmt(err, f),
+            Self::Occupied(err) => Display::fmt(err, f),
+            Self::Mismatch(err) => Display::fmt(err, f),
+            Self::Lock(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl<K: Debug, V: Debug> std::error::Error for BptreeError<K, V> {}
+
+impl<K, V> From<SizeError> for BptreeError<K, V> {
+    fn from(err: SizeError) -> Self {
+        Self::Size(err)
+    }
+}
+
+impl<K, V> From<OccupiedError<K, V>> for BptreeError<K, V> {
+    fn from(err: OccupiedError<K, V>) -> Self {
+        Self::Occupied(err)
+    }
+}
+
+impl<K, V> From<LockError> for BptreeError<K, V> {
+    fn from(err: LockError) -> Self {
+        Self::Lock(err)
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use super::bptree::Bptree;
+use super::kvtype::KVType;
+
+// Export writes one record per entry in key order, streamed along the leaf
+// chain like `iter`. Import reads the whole input, then bulk loads it, so
+// input in any order works and later records for a key win.
+impl<K, V> Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// Writes every entry as a CSV row holding the key's fields followed by
+    /// the value's, with no header row. Returns the number of rows.
+    #[cfg(feature = "csv")]
+    pub fn export_csv<W: Write>(&self, writer: W) -> csv::Result<usize>
+        where K: Serialize, V: Serialize
+    {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(writer);
+        let mut rows = 0;
+        for entry in self.iter() {
+            writer.serialize(entry)?;
+            rows += 1;
+        }
+        writer.flush()?;
+        Ok(rows)
+    }
+
+    /// Reads rows as written by `export_csv` and loads them into the tree.
+    /// A row over the tree's size limits fails the import with an
+    /// `InvalidData` I/O error before anything is loaded.
+    #[cfg(feature = "csv")]
+    pub fn import_csv<R: Read>(&self, reader: R) -> csv::Result<usize>
+        where K: DeserializeOwned, V: DeserializeOwned
+    {
+        let mut reader = csv::ReaderBuilder::new().has_headers(false).from_reader(reader);
+        let entries = reader.deserialize().collect::<csv::Result<Vec<(K, V)>>>()?;
+        let rows = entries.len();
+        self.load(entries).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        Ok(rows)
+    }
+
+    /// Writes every entry as a `[key, value]` JSON array on a line of its
+    /// own. Returns the number of lines.
+    #[cfg(feature = "serde_json")]
+    pub fn export_jsonl<W: Write>(&self, mut writer: W) -> serde_json::Result<usize>
+        where K: Serialize, V: Serialize
+    {
+        let mut lines = 0;
+        for entry in self.iter() {
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n").map_err(serde_json::Error::io)?;
+            lines += 1;
+        }
+        writer.flush().map_err(serde_json::Error::io)?;
+        Ok(lines)
+    }
+
+    /// Reads lines as written by `export_jsonl` and loads them into the tree.
+    /// A line over the tree's size limits fails the import with an
+    /// `InvalidData` I/O error before anything is loaded.
+    #[cfg(feature = "serde_json")]
+    pub fn import_jsonl<R: Read>(&self, reader: R) -> serde_json::Result<usize>
+        where K: DeserializeOwned, V: DeserializeOwned
+    {
+        let entries = serde_json::Deserializer::from_reader(reader)
+            .into_iter::<(K, V)>()
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        let lines = entries.len();
+        self.load(entries).map_err(|err| serde_json::Error::io(io::Error::new(io::ErrorKind::InvalidData, err.to_string())))?;
+        Ok(lines)
+    }
+}
@@ -0,0 +1,191 @@
+//! C interface over a `Bptree<Vec<u8>, Vec<u8>>`, built with the `ffi`
+//! feature. Trees and iterators are opaque handles; keys and values cross
+//! the boundary as byte slices going in and as `ByteBuf`s coming out, which
+//! the caller hands back to `bptree_buf_free`. Every function takes `&self`
+//! on the tree, so one handle may be shared between threads. The crate
+//! builds as a plain Rust library; to get something C can link, ask for
+//! the crate type on the command line, e.g.
+//! `cargo rustc --release --features ffi --crate-type cdylib` (or
+//! `staticlib`).
+//!
+//! No panic unwinds into the caller: each entry point catches it and
+//! reports failure, with a null handle or `false`, instead.
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use super::bptree::Bptree;
+use super::ttl::Now;
+
+pub struct BptreeHandle(Bptree<Vec<u8>, Vec<u8>>);
+
+/// Walks a tree in key order, copying entries out one leaf at a time as
+/// `Range` does, so no latch is held between steps and writes made
+/// meanwhile do not invalidate it.
+pub struct BptreeIter {
+    tree: *const BptreeHandle,
+    buf: VecDeque<(Vec<u8>, Vec<u8>)>,
+    // last key read from the tree, to go on after
+    from: Option<Vec<u8>>,
+    exhausted: bool,
+}
+
+/// Bytes owned by the library, returned to it through `bptree_buf_free`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ByteBuf {
+    pub ptr: *mut u8,
+    pub len: usize,
+}
+
+impl ByteBuf {
+    fn from_vec(bytes: Vec<u8>) -> Self {
+        let bytes = Box::into_raw(bytes.into_boxed_slice());
+        Self{
+            ptr: bytes as *mut u8,
+            len: bytes.len(),
+        }
+    }
+
+    pub fn empty() -> Self {
+        Self{
+            ptr: ptr::null_mut(),
+            len: 0,
+        }
+    }
+}
+
+// Runs `f`, turning a panic into `failed`.
+fn guard<T>(failed: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(failed)
+}
+
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        return &[];
+    }
+    slice::from_raw_parts(ptr, len)
+}
+
+/// Creates a tree of order `m`, or returns null if `m` is below the
+/// minimum order. Free it with `bptree_free`.
+#[no_mangle]
+pub extern "C" fn bptree_new(m: usize) -> *mut BptreeHandle {
+    guard(ptr::null_mut(), || match Bptree::builder().order(m).build() {
+        Ok(tree) => Box::into_raw(Box::new(BptreeHandle(tree))),
+        Err(_) => ptr::null_mut(),
+    })
+}
+
+/// # Safety
+/// `tree` must come from `bptree_new` and not be used again afterwards,
+/// and every iterator over it must already be freed.
+#[no_mangle]
+pub unsafe extern "C" fn bptree_free(tree: *mut BptreeHandle) {
+    if !tree.is_null() {
+        guard((), || drop(Box::from_raw(tree)));
+    }
+}
+
+/// Looks up `key`, writing its value to `val` and returning true if found.
+///
+/// # Safety
+/// `tree` must be a live handle, `key` must point to `key_len` readable
+/// bytes and `val` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn bptree_get(tree: *const BptreeHandle, key: *const u8, key_len: usize, val: *mut ByteBuf) -> bool {
+    guard(false, || match (*tree).0.get(&bytes(key, key_len).to_vec()) {
+        Some(found) => {
+            *val = ByteBuf::from_vec(found);
+            true
+        },
+        None => false,
+    })
+}
+
+/// Sets `key` to `val`, returning false if the write failed.
+///
+/// # Safety
+/// `tree` must be a live handle and `key`, `val` must point to `key_len`
+/// and `val_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bptree_set(tree: *const BptreeHandle, key: *const u8, key_len: usize, val: *const u8, val_len: usize) -> bool {
+    guard(false, || {
+        (*tree).0.set(bytes(key, key_len).to_vec(), bytes(val, val_len).to_vec());
+        true
+    })
+}
+
+/// Removes `key`, returning true if it was present.
+///
+/// # Safety
+/// `tree` must be a live handle and `key` must point to `key_len` readable
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bptree_remove(tree: *const BptreeHandle, key: *const u8, key_len: usize) -> bool {
+    guard(false, || (*tree).0.remove(&bytes(key, key_len).to_vec()).is_some())
+}
+
+/// Starts a walk over the whole tree, or returns null if that failed. Free
+/// it with `bptree_iter_free`.
+///
+/// # Safety
+/// `tree` must be a live handle and outlive the iterator.
+#[no_mangle]
+pub unsafe extern "C" fn bptree_iter_new(tree: *const BptreeHandle) -> *mut BptreeIter {
+    guard(ptr::null_mut(), || Box::into_raw(Box::new(BptreeIter{
+        tree,
+        buf: VecDeque::new(),
+        from: None,
+        exhausted: false,
+    })))
+}
+
+/// Writes the next entry to `key` and `val` and returns true, or returns
+/// false once the walk is done.
+///
+/// # Safety
+/// `iter` must be a live iterator whose tree is still live, and `key` and
+/// `val` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn bptree_iter_next(iter: *mut BptreeIter, key: *mut ByteBuf, val: *mut ByteBuf) -> bool {
+    *key = ByteBuf::empty();
+    *val = ByteBuf::empty();
+    guard(false, || {
+        let iter = &mut *iter;
+        while iter.buf.is_empty() && !iter.exhausted {
+            let from = iter.from.as_ref().map_or(Bound::Unbounded, Bound::Excluded);
+            iter.exhausted = !(*iter.tree).0.read_chunk(from, Bound::Unbounded, None, &Now::default(), &mut iter.buf);
+            if let Some((last, _)) = iter.buf.back() {
+                iter.from = Some(last.clone());
+            }
+        }
+        match iter.buf.pop_front() {
+            Some((found_key, found_val)) => {
+                *key = ByteBuf::from_vec(found_key);
+                *val = ByteBuf::from_vec(found_val);
+                true
+            },
+            None => false,
+        }
+    })
+}
+
+/// # Safety
+/// `iter` must come from `bptree_iter_new` and not be used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn bptree_iter_free(iter: *mut BptreeIter) {
+    if !iter.is_null() {
+        guard((), || drop(Box::from_raw(iter)));
+    }
+}
+
+/// # Safety
+/// `buf` must have been filled in by this library and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn bptree_buf_free(buf: ByteBuf) {
+    if !buf.ptr.is_null() {
+        guard((), || drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buf.ptr, buf.len))));
+    }
+}
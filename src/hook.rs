@@ -0,0 +1,80 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+/// Told about every change to the live entries of a tree, for keeping
+/// secondary indexes in step with it. Hooks run on the writing thread while
+/// the entry's leaf is still latched, so each key's changes arrive in the
+/// order they were made; a hook must not call back into the same tree.
+///
+/// Reported are `set` and `set_with_ttl`, `remove`, `remove_if`,
+/// `remove_range`, `compare_and_swap`, `try_insert`, `merge`, `apply` and
+/// transaction commits, cursor updates, values changed through
+/// `range_mut`, `update_with` or `get_many_mut`, and entries removed by
+/// `purge_expired` or `purge_expired_ranges`. An entry hidden by expiry
+/// counts as live until it is purged. `append`, `split_off` and `compact`
+/// move entries around without reporting them. An `ArenaBptree` reports
+/// its own writes the same way.
+pub trait IndexHook<K, V>: Send + Sync {
+    fn on_insert(&self, key: &K, val: &V) {
+        let _ = (key, val);
+    }
+
+    fn on_update(&self, key: &K, old: &V, new: &V) {
+        let _ = (key, old, new);
+    }
+
+    fn on_remove(&self, key: &K, old: &V) {
+        let _ = (key, old);
+    }
+
+    /// True once the hook wants to hear no more, say because whoever it
+    /// reports to has gone away. Retired hooks are dropped the next time a
+    /// hook is registered on the tree.
+    fn is_retired(&self) -> bool {
+        false
+    }
+}
+
+/// The hooks registered on a tree. Writers take a cheap snapshot before
+/// latching any node, so registering a hook never waits on a node latch.
+pub(crate) struct Hooks<K, V>(Arc<Vec<Arc<dyn IndexHook<K, V>>>>);
+
+impl<K, V> Hooks<K, V> {
+    pub fn new() -> Self {
+        Self(Arc::new(vec![]))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn with(&self, hook: Arc<dyn IndexHook<K, V>>) -> Self {
+        let mut hooks: Vec<_> = self.0.iter().filter(|hook| !hook.is_retired()).cloned().collect();
+        hooks.push(hook);
+        Self(Arc::new(hooks))
+    }
+
+    /// Reports one entry going from `old` to `new`, `None` meaning absent.
+    pub fn notify(&self, key: &K, old: Option<&V>, new: Option<&V>) {
+        for hook in self.0.iter() {
+            match (old, new) {
+                (None, Some(new)) => hook.on_insert(key, new),
+                (Some(old), Some(new)) => hook.on_update(key, old, new),
+                (Some(old), None) => hook.on_remove(key, old),
+                (None, None) => {},
+            }
+        }
+    }
+}
+
+impl<K, V> Clone for Hooks<K, V> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<K, V> Debug for Hooks<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Hooks").field(&self.0.len()).finish()
+    }
+}
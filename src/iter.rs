@@ -0,0 +1,337 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt::{self, Debug};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde::{Deserialize, Serialize};
+use super::bptree::Bptree;
+use super::kvtype::KVType;
+use super::ttl::Now;
+
+type ProgressFn<'a> = Box<dyn FnMut(&ScanProgress) + 'a>;
+
+/// Reported to a range's progress callback at every leaf boundary.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScanProgress {
+    /// Entries read from the tree so far.
+    pub visited: usize,
+    /// Rough share of the range already covered, from 0.0 to 1.0, derived
+    /// from the position of the current leaf within the tree.
+    pub fraction: f64,
+}
+
+/// Shared flag for stopping a running scan from another thread. The scan
+/// checks it at leaf boundaries and ends early once it is set.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Where a range scan stopped, as exported by `Range::token`. It holds the
+/// keys bounding what is left to read rather than any position inside the
+/// tree, so it stays valid across writes and restarts, and serializes like
+/// any other value. Entries written behind the token are not revisited.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeToken<K> {
+    start: Bound<K>,
+    end: Bound<K>,
+    at: Option<u64>,
+}
+
+/// Iterator over a key range in ascending order, or descending through
+/// `rev`. Entries are copied out one leaf at a time, so no lock is held
+/// between calls to `next`; writes that race with the scan may or may not
+/// be observed. Whatever writers do, every key is returned at most once and
+/// keys keep to their order; `strict` turns a race with any write into an
+/// error instead. Progress is only reported for the forward direction.
+///
+/// `size_hint` counts what is left from the subtree counts, in O(height),
+/// and only holds while no writer adds to the range meanwhile.
+pub struct Range<'a, K, V> {
+    tree: &'a Bptree<K, V>,
+    // what is left to read lies between `start` and `end`; both move inwards
+    // as the two ends are read
+    start: Bound<K>,
+    end: Bound<K>,
+    buf: VecDeque<(K, V)>,
+    back_buf: VecDeque<(K, V)>,
+    // sequence number to read as of, for snapshots, and the time expiry is
+    // judged at, fixed with it so the entries read as of it cannot change
+    at: Option<u64>,
+    now: Option<Now>,
+    // entries left to return as of `at`, once counted
+    left: Cell<Option<usize>>,
+    exhausted: bool,
+    visited: usize,
+    progress: Option<ProgressFn<'a>>,
+    span: (f64, f64),
+    cancel: Option<CancelToken>,
+}
+
+impl<'a, K, V> Range<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    pub fn new(tree: &'a Bptree<K, V>, start: Bound<K>, end: Bound<K>) -> Self {
+        Self{
+            tree,
+            start,
+            end,
+            buf: VecDeque::new(),
+            back_buf: VecDeque::new(),
+            at: None,
+            now: None,
+            left: Cell::new(None),
+            exhausted: false,
+            visited: 0,
+            progress: None,
+            span: (0.0, 1.0),
+            cancel: None,
+        }
+    }
+
+    /// Calls `f` after each leaf is read.
+    pub fn on_progress<F: FnMut(&ScanProgress) + 'a>(mut self, f: F) -> Self {
+        let lo = match &self.start {
+            Bound::Included(key) | Bound::Excluded(key) => self.tree.position(key),
+            Bound::Unbounded => 0.0,
+        };
+        let hi = match &self.end {
+            Bound::Included(key) | Bound::Excluded(key) => self.tree.position(key),
+            Bound::Unbounded => 1.0,
+        };
+        self.span = (lo, hi);
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    // Reads the range as of sequence number `seq`, and judges expiry as of
+    // the first time it needs to.
+    pub(crate) fn at(mut self, seq: u64) -> Self {
+        self.at = Some(seq);
+        self.now = Some(Now::default());
+        self
+    }
+
+    // Entries left to return as of `at`, counted on first use and kept up
+    // to date as they are returned.
+    pub(crate) fn left_at(&self, seq: u64) -> usize {
+        let buffered = self.buf.len() + self.back_buf.len();
+        if self.exhausted {
+            return buffered;
+        }
+        let left = self.left.get().unwrap_or_else(|| {
+            let now = self.now.as_ref().expect("snapshot ranges fix their time");
+            buffered + self.tree.count_chunks(self.start.as_ref(), self.end.as_ref(), Some(seq), now)
+        });
+        self.left.set(Some(left));
+        left
+    }
+
+    fn returned(&self, entry: Option<(K, V)>) -> Option<(K, V)> {
+        if let (Some(_), Some(left)) = (&entry, self.left.get()) {
+            self.left.set(Some(left - 1));
+        }
+        entry
+    }
+
+    /// Yields `Err(Invalidated)`, and then nothing, as soon as a write has
+    /// moved the tree's `seq` since this call, so every entry it does yield
+    /// was read from the tree as it stood then. Writes include
+    /// `expire_range`, but a deadline passing mid-scan hides entries without
+    /// any write, and goes unnoticed.
+    pub fn strict(self) -> Strict<'a, K, V> {
+        Strict{
+            seq: self.tree.seq(),
+            range: Some(self),
+        }
+    }
+
+    /// Ends the scan at the next leaf boundary once `token` is cancelled.
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Exports the part of the range not yet returned from either end, to
+    /// continue with `Bptree::resume`. A snapshot scan resumes as of the same
+    /// sequence number, which only works while the tree keeps its versions.
+    pub fn token(&self) -> ResumeToken<K> {
+        // entries buffered at one end but not returned are still to come;
+        // once the scan is exhausted the other end's buffer may hold them too
+        let start = match (self.buf.front(), self.back_buf.front()) {
+            (Some((key, _)), _) => Bound::Included(key.clone()),
+            (None, Some((key, _))) if self.exhausted => Bound::Included(key.clone()),
+            _ => self.start.clone(),
+        };
+        let end = match (self.back_buf.back(), self.buf.back()) {
+            (Some((key, _)), _) => Bound::Included(key.clone()),
+            (None, Some((key, _))) if self.exhausted => Bound::Included(key.clone()),
+            _ => self.end.clone(),
+        };
+        ResumeToken{ start, end, at: self.at }
+    }
+
+    pub(crate) fn resume(tree: &'a Bptree<K, V>, token: ResumeToken<K>) -> Self {
+        let range = Self::new(tree, token.start, token.end);
+        match token.at {
+            Some(seq) => range.at(seq),
+            None => range,
+        }
+    }
+
+    fn fill(&mut self) {
+        if self.cancel.as_ref().is_some_and(|token| token.is_cancelled()) {
+            self.exhausted = true;
+            return;
+        }
+        let fresh = Now::default();
+        let now = self.now.as_ref().unwrap_or(&fresh);
+        self.exhausted = !self.tree.read_chunk(self.start.as_ref(), self.end.as_ref(), self.at, now, &mut self.buf);
+        self.visited += self.buf.len();
+        if let Some((key, _)) = self.buf.back() {
+            self.start = Bound::Excluded(key.clone());
+        }
+        if let Some(progress) = self.progress.as_mut() {
+            let (lo, hi) = self.span;
+            let fraction = match (&self.start, self.exhausted) {
+                (Bound::Excluded(key), false) if hi > lo => {
+                    ((self.tree.position(key) - lo) / (hi - lo)).clamp(0.0, 1.0)
+                },
+                (_, false) => 0.0,
+                (_, true) => 1.0,
+            };
+            progress(&ScanProgress{ visited: self.visited, fraction });
+        }
+    }
+
+    fn fill_back(&mut self) {
+        if self.cancel.as_ref().is_some_and(|token| token.is_cancelled()) {
+            self.exhausted = true;
+            return;
+        }
+        let fresh = Now::default();
+        let now = self.now.as_ref().unwrap_or(&fresh);
+        self.exhausted = !self.tree.read_chunk_back(self.start.as_ref(), self.end.as_ref(), self.at, now, &mut self.back_buf);
+        self.visited += self.back_buf.len();
+        if let Some((key, _)) = self.back_buf.front() {
+            self.end = Bound::Excluded(key.clone());
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Range<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buf.is_empty() && !self.exhausted {
+            self.fill();
+        }
+        let entry = self.buf.pop_front().or_else(|| self.back_buf.pop_front());
+        self.returned(entry)
+    }
+
+    // Exact as of a snapshot. Otherwise the buffered entries are sure to
+    // come, and the subtree counts bound the rest.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if let Some(seq) = self.at {
+            let left = self.left_at(seq);
+            return (left, Some(left));
+        }
+        let buffered = self.buf.len() + self.back_buf.len();
+        if self.exhausted {
+            return (buffered, Some(buffered));
+        }
+        (buffered, Some(buffered + self.tree.estimate_range_size((self.start.as_ref(), self.end.as_ref()))))
+    }
+
+    fn last(mut self) -> Option<Self::Item> {
+        self.next_back()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Range<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.back_buf.is_empty() && !self.exhausted {
+            self.fill_back();
+        }
+        let entry = self.back_buf.pop_back().or_else(|| self.buf.pop_back());
+        self.returned(entry)
+    }
+}
+
+/// Why a `strict` range stopped: the tree was written to mid-scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Invalidated;
+
+impl fmt::Display for Invalidated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the tree was written to during the scan")
+    }
+}
+
+impl std::error::Error for Invalidated {}
+
+/// A `Range` that fails once the tree is written to, from `Range::strict`.
+pub struct Strict<'a, K, V> {
+    // `None` once invalidated
+    range: Option<Range<'a, K, V>>,
+    seq: u64,
+}
+
+impl<'a, K, V> Strict<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    // `read` takes the next entry from the range, which `seq` moving on by
+    // the time it returns makes stale; checking after the read covers a
+    // write that raced with it.
+    fn checked(&mut self, read: impl FnOnce(&mut Range<'a, K, V>) -> Option<(K, V)>) -> Option<Result<(K, V), Invalidated>> {
+        let range = self.range.as_mut()?;
+        let entry = read(range);
+        if range.tree.seq() != self.seq {
+            self.range = None;
+            return Some(Err(Invalidated));
+        }
+        entry.map(Ok)
+    }
+}
+
+impl<'a, K, V> Iterator for Strict<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    type Item = Result<(K, V), Invalidated>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.checked(|range| range.next())
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Strict<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.checked(|range| range.next_back())
+    }
+}
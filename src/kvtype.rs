@@ -1,7 +1,95 @@
-
+use std::cmp::Reverse;
+use super::search;
 
 pub trait KVType{
     //type KV_type;
     //fn serlize_to() ;
     //fn deserlize_from(bytes: &[u8]) -> Self::KV_type;
-}
\ No newline at end of file
+
+    /// A key above `left` and no higher than `right`, for the parent of two
+    /// sibling leaves to route between them. Key types that can build a
+    /// shorter one than `right` itself override this to keep inner nodes
+    /// small.
+    fn separator(left: &Self, right: &Self) -> Self
+        where Self: Clone
+    {
+        let _ = left;
+        right.clone()
+    }
+
+    /// Looks `key` up among a node's sorted keys, with the same result as
+    /// `binary_search`. Key types with a faster way to search a node
+    /// override this.
+    fn search(keys: &[Self], key: &Self) -> Result<usize, usize>
+        where Self: Ord + Sized
+    {
+        keys.binary_search(key)
+    }
+
+    /// Bytes the key or value takes up, as checked against a tree's size
+    /// limits. Types owning data on the heap override this to count it.
+    fn byte_len(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+}
+
+macro_rules! vector_search {
+    ($($t:ty),*) => {$(
+        impl KVType for $t {
+            fn search(keys: &[Self], key: &Self) -> Result<usize, usize> {
+                search::search(keys, *key)
+            }
+        }
+    )*};
+}
+
+vector_search!(u8, i8, u16, i16, u32, i32, u64, i64);
+
+// Keys of a `BptreeDesc`.
+impl<T: KVType> KVType for Reverse<T> {
+    fn byte_len(&self) -> usize {
+        self.0.byte_len()
+    }
+}
+
+// The value type of `BptreeSet`.
+impl KVType for () {}
+
+impl<A: KVType, B: KVType> KVType for (A, B) {
+    fn byte_len(&self) -> usize {
+        self.0.byte_len() + self.1.byte_len()
+    }
+}
+
+impl<A: KVType, B: KVType, C: KVType> KVType for (A, B, C) {
+    fn byte_len(&self) -> usize {
+        self.0.byte_len() + self.1.byte_len() + self.2.byte_len()
+    }
+}
+
+// The shortest prefix of `right` that still sorts above `left`.
+impl KVType for Vec<u8> {
+    fn separator(left: &Self, right: &Self) -> Self {
+        let common = left.iter().zip(right).take_while(|(a, b)| a == b).count();
+        right[..(common + 1).min(right.len())].to_vec()
+    }
+
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl KVType for String {
+    fn separator(left: &Self, right: &Self) -> Self {
+        let common: usize = left.chars().zip(right.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+        let end = common + right[common..].chars().next().map_or(0, char::len_utf8);
+        right[..end].to_string()
+    }
+
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
@@ -1,29 +1,93 @@
-#![allow(dead_code)]
-
 mod bptree;
 mod kvtype;
+mod version;
+mod txn;
+mod batch;
+mod builder;
+mod hook;
+mod ttl;
+mod iter;
+mod tune;
+mod shard;
+mod multimap;
+mod set;
+mod desc;
+mod cursor;
+mod prefix;
+mod composite;
+mod bytekey;
+mod backup;
+mod snapshot;
+#[cfg(any(feature = "csv", feature = "serde_json"))]
+mod export;
+mod diff;
+mod check;
+mod stats;
+mod trylock;
+mod limits;
+mod budget;
+mod cas;
+mod merge;
+mod aggregate;
+mod watch;
+mod changelog;
+mod arena;
+mod search;
+mod error;
+#[cfg(feature = "ffi")]
+mod ffi;
+#[cfg(feature = "rayon")]
+mod par;
+
+pub use bptree::{Bptree, LeafChunk, Leaves, ManyMut, RangeMut, Stream, ValueRef, ValuesMut, REBALANCE_FILL};
+pub use kvtype::KVType;
+pub use txn::Txn;
+pub use batch::WriteBatch;
+pub use builder::{BptreeBuilder, ConfigError, DuplicatePolicy, DEFAULT_ORDER, MIN_ORDER};
+pub use hook::IndexHook;
+pub use iter::{CancelToken, Invalidated, Range, ResumeToken, ScanProgress, Strict};
+pub use tune::{order_for_node_bytes, Op, Trial, TuneReport, CANDIDATE_ORDERS};
+pub use shard::ShardedBptree;
+pub use multimap::BptreeMultimap;
+pub use set::BptreeSet;
+pub use desc::BptreeDesc;
+pub use cursor::Cursor;
+pub use prefix::PrefixKey;
+pub use composite::{CompositeKey, MinKey};
+pub use bytekey::ByteKey;
+pub use snapshot::{Snapshot, SnapshotIter, SnapshotRange};
+pub use diff::{Change, Changes, Diff};
+pub use check::{InvariantReport, Violation};
+pub use stats::TreeStats;
+pub use trylock::LockError;
+pub use limits::SizeError;
+pub use budget::{EvictFn, Eviction};
+pub use cas::{CasError, OccupiedError};
+pub use merge::MergeFn;
+pub use watch::ChangeEvent;
+pub use changelog::{ChangeLog, LogEntry, LogTruncated};
+pub use arena::{ArenaBptree, ArenaRange};
+pub use error::BptreeError;
+
 #[cfg(test)]
 mod tests {
-    use std::borrow::BorrowMut;
-    use std::cell::RefCell;
-    use std::sync::Arc;
     use kvtype::KVType;
     use crate::{bptree, kvtype};
     use bptree::Bptree;
 
-    impl KVType for i32{}
+
     impl KVType for &str {}
     #[test]
     fn it_works() {
-        let mut bt:Bptree<i32, &str> = Bptree::new(50);
-        for i in (0..100000) {
+        let bt:Bptree<i32, &str> = Bptree::new(50);
+        for i in 0..100000 {
             bt.set(i, "hello");
         }
 
 
-        for i in (0..100000) {
+        for i in 0..100000 {
 
-            if let Some(res) = bt.remove(&i){
+            if let Some(_res) = bt.remove(&i){
                 //println!("{}: removed", i);
             }
             else{
@@ -36,4 +100,3198 @@ mod tests {
 
     }
 
+    fn check_all(bt: &Bptree<i32, &str>, keys: &[i32]) {
+        for k in keys {
+            assert_eq!(bt.get(k), Some("hello"));
+        }
+    }
+
+    #[test]
+    fn adaptive_split_fills_sequential_leaves() {
+        let asc: Bptree<i32, &str> = Bptree::new(10);
+        for i in 0..1000 {
+            asc.set(i, "hello");
+        }
+        check_all(&asc, &(0..1000).collect::<Vec<_>>());
+        let sizes = asc.leaf_sizes();
+        assert!(sizes[..sizes.len() - 1].iter().all(|&n| n == 9));
+
+        let desc: Bptree<i32, &str> = Bptree::new(10);
+        for i in (0..1000).rev() {
+            desc.set(i, "hello");
+        }
+        check_all(&desc, &(0..1000).collect::<Vec<_>>());
+        let sizes = desc.leaf_sizes();
+        assert!(sizes[1..].iter().all(|&n| n == 9));
+    }
+
+    #[test]
+    fn adaptive_split_keeps_even_split_for_scattered_inserts() {
+        let bt: Bptree<i32, &str> = Bptree::new(10);
+        let mut seed: u32 = 2463534242;
+        let mut keys: Vec<i32> = (0..1000).collect();
+        for i in (1..keys.len()).rev() {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            keys.swap(i, seed as usize % (i + 1));
+        }
+        for k in &keys {
+            bt.set(*k, "hello");
+        }
+        check_all(&bt, &keys);
+        let sizes = bt.leaf_sizes();
+        assert_eq!(sizes.iter().sum::<usize>(), 1000);
+        assert!(sizes.iter().all(|&n| n >= 4));
+    }
+
+    #[test]
+    fn adaptive_split_keeps_skewed_workloads_at_minimum_occupancy() {
+        // keys crowding towards the low end, as a zipfian workload's do, and
+        // the same mixed with eight sources appending ascending runs each in
+        // their own part of the key space
+        for with_runs in [false, true] {
+            let bt: Bptree<i32, &str> = Bptree::new(10);
+            let mut seed: u32 = 2463534242;
+            let mut clocks = [0; 8];
+            for _ in 0..20000 {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                let key = if with_runs && seed & 1 == 0 {
+                    let source = (seed / 2 % 8) as usize;
+                    clocks[source] += 1;
+                    1_000_000 + source as i32 * 100_000 + clocks[source]
+                }
+                else {
+                    let r = (seed % 1000) as i32;
+                    r * r
+                };
+                bt.set(key, "hello");
+            }
+            let sizes = bt.leaf_sizes();
+            // a run ending inside the tree leaves no short leaf behind, only
+            // one at the tree's edge may be
+            assert!(sizes[..sizes.len() - 1].iter().all(|&n| n >= 5), "{:?}", sizes);
+            // even splits fill random inserts' leaves to about ln 2, and
+            // runs inside the tree to half
+            let fill = sizes.iter().sum::<usize>() as f64 / (sizes.len() * 9) as f64;
+            assert!(fill > if with_runs { 0.55 } else { 0.65 }, "{}", fill);
+        }
+    }
+
+    #[test]
+    fn get_at_reads_past_versions() {
+        let bt: Bptree<i32, &str> = Bptree::new(4);
+        bt.set_version_horizon(Some(0));
+        for i in 0..20 {
+            bt.set(i, "v1");
+        }
+        let before = bt.seq();
+        for i in 0..20 {
+            bt.set(i, "v2");
+        }
+        assert_eq!(bt.remove(&7), Some("v2"));
+        let after_remove = bt.seq();
+        bt.set(7, "v3");
+
+        for i in 0..20 {
+            assert_eq!(bt.get_at(&i, before), Some("v1"));
+        }
+        assert_eq!(bt.get_at(&7, after_remove - 1), Some("v2"));
+        assert_eq!(bt.get_at(&7, after_remove), None);
+        assert_eq!(bt.get(&7), Some("v3"));
+        assert_eq!(bt.get_at(&3, 0), None);
+
+        bt.set_version_horizon(Some(after_remove));
+        bt.gc();
+        assert_eq!(bt.get_at(&3, after_remove), Some("v2"));
+        assert_eq!(bt.get_at(&7, after_remove), None);
+        assert_eq!(bt.get(&7), Some("v3"));
+    }
+
+    #[test]
+    fn gc_purges_tombstones_behind_horizon() {
+        let bt: Bptree<i32, &str> = Bptree::new(4);
+        bt.set_version_horizon(Some(0));
+        for i in 0..50 {
+            bt.set(i, "hello");
+        }
+        let snapshot = bt.seq();
+        for i in 0..50 {
+            assert_eq!(bt.remove(&i), Some("hello"));
+        }
+        bt.gc();
+        assert_eq!(bt.get_at(&10, snapshot), Some("hello"));
+
+        bt.set_version_horizon(None);
+        bt.gc();
+        for i in 0..50 {
+            assert_eq!(bt.get_at(&i, snapshot), None);
+            assert_eq!(bt.get(&i), None);
+        }
+        assert!(bt.leaf_sizes().iter().all(|&n| n == 0));
+    }
+
+    #[test]
+    fn txn_commit_and_rollback() {
+        let bt: Bptree<i32, &str> = Bptree::new(4);
+        for i in 0..10 {
+            bt.set(i, "old");
+        }
+
+        let mut txn = bt.begin();
+        txn.set(3, "new");
+        txn.set(42, "new");
+        assert_eq!(txn.remove(&5), Some("old"));
+        assert_eq!(txn.get(&3), Some("new"));
+        assert_eq!(txn.get(&5), None);
+        assert_eq!(txn.get(&6), Some("old"));
+        txn.rollback();
+        assert_eq!(bt.get(&3), Some("old"));
+        assert_eq!(bt.get(&42), None);
+        assert_eq!(bt.get(&5), Some("old"));
+
+        let mut txn = bt.begin();
+        txn.set(3, "new");
+        txn.set(42, "new");
+        txn.remove(&5);
+        assert_eq!(txn.commit(), Ok(()));
+        assert_eq!(bt.get(&3), Some("new"));
+        assert_eq!(bt.get(&42), Some("new"));
+        assert_eq!(bt.get(&5), None);
+    }
+
+    #[test]
+    fn txns_on_shared_tree() {
+        use std::sync::Arc;
+
+        let bt: Arc<Bptree<i32, i32>> = Arc::new(Bptree::new(4));
+        let handles: Vec<_> = (0..4).map(|t| {
+            let bt = bt.clone();
+            std::thread::spawn(move || {
+                for round in 0..50 {
+                    let mut txn = bt.begin();
+                    for i in 0..10 {
+                        txn.set(t * 1000 + i, round);
+                    }
+                    assert_eq!(txn.get(&(t * 1000)), Some(round));
+                    assert_eq!(txn.commit(), Ok(()));
+                }
+            })
+        }).collect();
+
+        // reads through an open transaction see other threads' commits
+        let txn = bt.begin();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(txn.get(&3003), Some(49));
+        txn.rollback();
+        assert_eq!(bt.count_range(..), 40);
+    }
+
+    #[test]
+    fn expired_range_is_hidden_then_purged() {
+        use std::time::{Duration, Instant};
+
+        let bt: Bptree<i32, &str> = Bptree::new(4);
+        for i in 0..100 {
+            bt.set(i, "hello");
+        }
+        let now = Instant::now();
+        bt.expire_range(20..40, now);
+        bt.expire_range(60.., now + Duration::from_secs(3600));
+
+        assert_eq!(bt.get(&19), Some("hello"));
+        assert_eq!(bt.get(&20), None);
+        assert_eq!(bt.get(&39), None);
+        assert_eq!(bt.get(&40), Some("hello"));
+        assert_eq!(bt.get(&60), Some("hello"));
+
+        bt.set(25, "fresh");
+        assert_eq!(bt.get(&25), Some("fresh"));
+        assert_eq!(bt.get(&26), None);
+        assert_eq!(bt.purge_expired_ranges(), 0);
+        assert_eq!(bt.leaf_sizes().iter().sum::<usize>(), 81);
+    }
+
+    #[test]
+    fn entries_with_ttl_expire_then_get_purged() {
+        use std::time::Duration;
+
+        let bt: Bptree<i32, &str> = Bptree::new(4);
+        for i in 0..100 {
+            bt.set(i, "kept");
+        }
+        for i in (0..100).step_by(2) {
+            bt.set_with_ttl(i, "brief", Duration::ZERO);
+        }
+        bt.set_with_ttl(1, "lasting", Duration::from_secs(3600));
+        bt.set_with_ttl(2, "brief", Duration::ZERO);
+        bt.set(4, "rewritten");
+
+        assert_eq!(bt.get(&0), None);
+        assert_eq!(bt.get(&1), Some("lasting"));
+        assert_eq!(bt.get(&3), Some("kept"));
+        assert_eq!(bt.get(&4), Some("rewritten"));
+        assert!(bt.get_ref(&2).is_none());
+        assert_eq!(bt.multi_get(&[0, 1, 4]), vec![None, Some("lasting"), Some("rewritten")]);
+        assert_eq!(bt.iter().count(), 51);
+        assert_eq!(bt.get_le(&2), Some((1, "lasting")));
+        assert_eq!(bt.get_ge(&6), Some((7, "kept")));
+        assert_eq!(bt.leaves().map(|chunk| chunk.iter().count()).sum::<usize>(), 51);
+
+        // counted queries sweep expired entries out first
+        assert_eq!(bt.count_range(..), 51);
+        assert_eq!(bt.rank(&10), 6);
+        assert_eq!(bt.leaf_sizes().iter().sum::<usize>(), 51);
+        assert_eq!(bt.purge_expired(), 0);
+        assert_eq!(bt.check_invariants().violations, vec![]);
+    }
+
+    #[test]
+    fn hooks_keep_a_reverse_index_in_step() {
+        use std::collections::BTreeSet;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use crate::batch::WriteBatch;
+        use crate::hook::IndexHook;
+
+        // value -> key, as a secondary index on the values would hold
+        #[derive(Default)]
+        struct ByValue(Mutex<BTreeSet<(i32, i32)>>);
+        impl IndexHook<i32, i32> for ByValue {
+            fn on_insert(&self, key: &i32, val: &i32) {
+                assert!(self.0.lock().unwrap().insert((*val, *key)));
+            }
+            fn on_update(&self, key: &i32, old: &i32, new: &i32) {
+                let mut index = self.0.lock().unwrap();
+                assert!(index.remove(&(*old, *key)));
+                index.insert((*new, *key));
+            }
+            fn on_remove(&self, key: &i32, old: &i32) {
+                assert!(self.0.lock().unwrap().remove(&(*old, *key)));
+            }
+        }
+        let in_step = |bt: &Bptree<i32, i32>, index: &ByValue| {
+            let primary: BTreeSet<_> = bt.iter().map(|(key, val)| (val, key)).collect();
+            assert_eq!(*index.0.lock().unwrap(), primary);
+        };
+
+        for horizon in [None, Some(0)] {
+            let bt: Bptree<i32, i32> = Bptree::new(4);
+            bt.set_version_horizon(horizon);
+            let index = Arc::new(ByValue::default());
+            bt.add_hook(index.clone());
+
+            for i in 0..200 {
+                bt.set(i, i * 10);
+            }
+            for i in (0..200).step_by(3) {
+                bt.set(i, -i);
+            }
+            for i in (0..200).step_by(5) {
+                bt.remove(&i);
+            }
+            bt.remove(&1000);
+            in_step(&bt, &index);
+
+            assert!(bt.remove_range(50..80) > 0);
+            let mut batch = WriteBatch::new();
+            batch.put(60, 1);
+            batch.put(61, 2);
+            batch.delete(61);
+            batch.delete(1);
+            assert_eq!(bt.apply(batch), Ok(()));
+            in_step(&bt, &index);
+
+            let mut txn = bt.begin();
+            txn.set(300, 3);
+            txn.remove(&2);
+            assert_eq!(txn.commit(), Ok(()));
+            let mut cursor = bt.cursor(&3);
+            assert!(cursor.update(33));
+            in_step(&bt, &index);
+
+            bt.set_with_ttl(4, 4, Duration::ZERO);
+            bt.purge_expired();
+            in_step(&bt, &index);
+        }
+    }
+
+    #[test]
+    fn random_set_remove_matches_btreemap() {
+        use std::collections::BTreeMap;
+
+        for m in [3, 4, 5, 8, 17] {
+            let mut seed: u64 = 88172645463325252 + m as u64;
+            let mut rnd = || {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed
+            };
+            let bt: Bptree<i32, i32> = Bptree::new(m);
+            let mut map = BTreeMap::new();
+            for step in 0..20000 {
+                let key = (rnd() % 500) as i32;
+                if rnd() % 2 == 0 {
+                    bt.set(key, step);
+                    map.insert(key, step);
+                }
+                else {
+                    assert_eq!(bt.remove(&key), map.remove(&key));
+                }
+            }
+            for key in 0..500 {
+                assert_eq!(bt.get(&key), map.get(&key).cloned());
+            }
+            assert_eq!(bt.check_invariants().violations, vec![]);
+        }
+    }
+
+    #[test]
+    fn remove_range_matches_btreemap() {
+        use std::collections::BTreeMap;
+        use std::ops::Bound;
+
+        for m in [3, 4, 5, 8, 17] {
+            let mut seed: u64 = 2463534242 + m as u64;
+            let mut rnd = move || {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed
+            };
+            let bt: Bptree<i32, i32> = Bptree::new(m);
+            let mut map = BTreeMap::new();
+            for key in 0..3000 {
+                bt.set(key, key);
+                map.insert(key, key);
+            }
+            for round in 0..200 {
+                let lo = (rnd() % 3200) as i32 - 100;
+                let hi = lo + (rnd() % 400) as i32;
+                let range = match round % 4 {
+                    0 => (Bound::Included(lo), Bound::Excluded(hi)),
+                    1 => (Bound::Excluded(lo), Bound::Included(hi)),
+                    2 => (Bound::Unbounded, Bound::Excluded(lo)),
+                    _ => (Bound::Included(hi), Bound::Unbounded),
+                };
+                let doomed: Vec<i32> = map.range(range).map(|(k, _)| *k).collect();
+                for key in &doomed {
+                    map.remove(key);
+                }
+                assert_eq!(bt.remove_range(range), doomed.len());
+                for key in doomed.iter().step_by(7) {
+                    assert_eq!(bt.get(key), None);
+                }
+                for _ in 0..40 {
+                    let key = (rnd() % 3000) as i32;
+                    bt.set(key, round);
+                    map.insert(key, round);
+                }
+                assert_eq!(bt.iter().collect::<Vec<_>>(), map.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>());
+                assert_eq!(bt.check_invariants().violations, vec![]);
+            }
+            for key in 0..3000 {
+                assert_eq!(bt.remove(&key), map.remove(&key));
+            }
+            assert_eq!(bt.leaf_sizes().iter().sum::<usize>(), 0);
+        }
+    }
+
+    #[test]
+    fn remove_range_tombstones_when_keeping_history() {
+        use std::ops::Bound;
+
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        bt.set_version_horizon(Some(0));
+        for key in 0..100 {
+            bt.set(key, key);
+        }
+        let before = bt.seq();
+        assert_eq!(bt.remove_range(10..90), 80);
+        assert_eq!(bt.remove_range(10..90), 0);
+        assert_eq!(bt.remove_range((Bound::Included(50), Bound::Excluded(20))), 0);
+        assert_eq!(bt.iter().count(), 20);
+        assert_eq!(bt.get(&50), None);
+        assert_eq!(bt.get_at(&50, before), Some(50));
+        bt.set_version_horizon(None);
+        bt.gc();
+        assert_eq!(bt.leaf_sizes().iter().sum::<usize>(), 20);
+    }
+
+    #[test]
+    fn multimap_keeps_duplicates_in_insertion_order() {
+        use crate::multimap::BptreeMultimap;
+
+        let mm: BptreeMultimap<i32, i32> = BptreeMultimap::new(4);
+        for i in 0..300 {
+            mm.insert(i % 7, 300 - i);
+        }
+        for key in 0..7 {
+            let expected: Vec<i32> = (0..300).filter(|i| i % 7 == key).map(|i| 300 - i).collect();
+            assert_eq!(mm.get_all(&key).collect::<Vec<_>>(), expected);
+        }
+        assert_eq!(mm.get_all(&7).count(), 0);
+
+        mm.insert(3, 5);
+        mm.insert(3, 5);
+        assert!(mm.remove(&3, &5));
+        assert!(mm.remove(&3, &5));
+        assert!(!mm.remove(&3, &5));
+        assert!(!mm.remove(&3, &1000));
+        let threes: Vec<i32> = mm.get_all(&3).collect();
+        assert_eq!(threes.len(), 43);
+        assert!(threes.windows(2).all(|w| w[0] > w[1]));
+
+        assert_eq!(mm.remove_all(&4), 43);
+        assert_eq!(mm.get_all(&4).count(), 0);
+        let keys: Vec<i32> = mm.iter().map(|(k, _)| k).collect();
+        assert!(keys.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(keys.len(), 300 - 43);
+    }
+
+    #[test]
+    fn multimap_keeps_insertion_order_through_restructuring() {
+        use std::collections::BTreeMap;
+        use crate::multimap::BptreeMultimap;
+
+        // every key's values in insertion order, kept beside the multimap
+        fn in_step(mm: &BptreeMultimap<i32, i32>, expected: &BTreeMap<i32, Vec<i32>>) {
+            for (key, vals) in expected {
+                assert_eq!(mm.get_all(key).collect::<Vec<_>>(), *vals);
+            }
+            let pairs: Vec<(i32, i32)> = expected.iter().flat_map(|(key, vals)| vals.iter().map(move |val| (*key, *val))).collect();
+            assert_eq!(mm.iter().collect::<Vec<_>>(), pairs);
+        }
+
+        // few keys among many inserts, so leaves split inside a key's run
+        let mm: BptreeMultimap<i32, i32> = BptreeMultimap::new(4);
+        let mut expected: BTreeMap<i32, Vec<i32>> = BTreeMap::new();
+        let mut keys = vec![];
+        let mut seed: u32 = 2463534242;
+        for val in 0..2000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let key = (seed % 20) as i32;
+            mm.insert(key, val);
+            expected.entry(key).or_default().push(val);
+            keys.push(key);
+        }
+        in_step(&mm, &expected);
+
+        // taking most pairs out again merges leaves across runs
+        for val in (0..2000).filter(|val| val % 5 != 0) {
+            assert!(mm.remove(&keys[val as usize], &val));
+            expected.get_mut(&keys[val as usize]).unwrap().retain(|v| *v != val);
+        }
+        in_step(&mm, &expected);
+
+        // range removals cut runs out from between others
+        let removed: usize = (5..8).chain(0..=1).map(|key| expected.remove(&key).unwrap().len()).sum();
+        assert_eq!(mm.remove_range(5..8) + mm.remove_range(..=1), removed);
+        in_step(&mm, &expected);
+
+        // appended values follow those already here, whether they are moved
+        // one by one or hung off the tree whole
+        for (other_keys, vals) in [(vec![3, 12, 30], 5000..5300), (vec![100, 101], 6000..6300)] {
+            let mut other: BptreeMultimap<i32, i32> = BptreeMultimap::new(4);
+            for val in vals {
+                let key = other_keys[val as usize % other_keys.len()];
+                other.insert(key, val);
+                expected.entry(key).or_default().push(val);
+            }
+            mm.append(&mut other);
+            assert_eq!(other.iter().count(), 0);
+            in_step(&mm, &expected);
+        }
+        mm.insert(100, 7000);
+        expected.get_mut(&100).unwrap().push(7000);
+        in_step(&mm, &expected);
+    }
+
+    #[test]
+    fn cursor_moves_both_ways_and_edits_in_place() {
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for i in 0..100 {
+            bt.set(i * 2, i);
+        }
+        let mut cursor = bt.cursor(&31);
+        assert_eq!(cursor.current(), Some((&32, &16)));
+        assert_eq!(cursor.next(), Some((&34, &17)));
+        assert_eq!(cursor.prev(), Some((&32, &16)));
+        assert_eq!(cursor.prev(), Some((&30, &15)));
+
+        // scan-and-modify: double every value, drop multiples of 3
+        let mut cursor = bt.cursor(&0);
+        while let Some((&key, &val)) = cursor.current() {
+            if key % 3 == 0 {
+                assert_eq!(cursor.delete(), Some(val));
+                assert_eq!(cursor.current(), None);
+            }
+            else {
+                assert!(cursor.update(val * 2));
+            }
+            cursor.next();
+        }
+        assert_eq!(cursor.next(), None);
+        let expected: Vec<(i32, i32)> = (0..100).filter(|i| (i * 2) % 3 != 0).map(|i| (i * 2, i * 2)).collect();
+        assert_eq!(bt.iter().collect::<Vec<_>>(), expected);
+
+        // walk back from past the end over the rebalanced tree
+        let mut seen = vec![];
+        while let Some((&key, &val)) = cursor.prev() {
+            seen.push((key, val));
+        }
+        seen.reverse();
+        assert_eq!(seen, expected);
+        assert_eq!(cursor.prev(), None);
+        assert_eq!(cursor.next(), Some((&2, &2)));
+        assert_eq!(bt.cursor(&1000).current(), None);
+    }
+
+    #[test]
+    fn cursor_inserts_after_itself() {
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for i in 0..50 {
+            bt.set(i * 2, 0);
+        }
+        // merge the odd keys in, overwriting every even one on the way
+        let mut cursor = bt.cursor(&0);
+        for key in 0..100 {
+            assert_eq!(cursor.insert_after(key, key), Ok(()));
+            assert_eq!(cursor.current(), Some((&key, &key)));
+        }
+        assert_eq!(cursor.next(), None);
+        assert_eq!(bt.iter().collect::<Vec<_>>(), (0..100).map(|i| (i, i)).collect::<Vec<_>>());
+        assert_eq!(bt.count_range(..), 100);
+        assert_eq!(bt.check_invariants().violations, vec![]);
+    }
+
+    #[test]
+    fn cursor_inserts_through_its_leaf() {
+        use crate::bptree::WRITE_DESCENTS;
+        use crate::builder::DuplicatePolicy;
+        use crate::cas::OccupiedError;
+        use crate::error::BptreeError;
+        let descents = || WRITE_DESCENTS.with(|descents| descents.get());
+
+        let bt: Bptree<i32, i32> = Bptree::new(16);
+        for i in 0..100 {
+            bt.set(i * 100, i);
+        }
+        // leave room in every leaf
+        bt.rebalance();
+        // the first insert finds the leaf, the rest of the run fills it
+        // through the recorded path
+        let mut cursor = bt.cursor(&1000);
+        let before = descents();
+        for key in 1001..1004 {
+            assert_eq!(cursor.insert_after(key, key), Ok(()));
+            assert_eq!(cursor.current(), Some((&key, &key)));
+        }
+        assert_eq!(descents() - before, 1);
+        // past the leaf's fences the path is no good, and is found anew
+        assert_eq!(cursor.insert_after(5001, 5001), Ok(()));
+        assert_eq!(descents() - before, 2);
+        assert_eq!(cursor.insert_after(5002, 5002), Ok(()));
+        assert_eq!(descents() - before, 2);
+        // a key before the cursor is not trusted to the path
+        assert_eq!(cursor.insert_after(5000, -1), Ok(()));
+        assert_eq!(descents() - before, 3);
+        assert_eq!(bt.get(&5000), Some(-1));
+
+        // runs that split leaves on the way still count right
+        let mut cursor = bt.cursor(&2000);
+        for key in 2001..2099 {
+            assert_eq!(cursor.insert_after(key, key), Ok(()));
+        }
+        assert_eq!(cursor.next(), Some((&2100, &21)));
+        assert_eq!(bt.count_range(..), 100 + 5 + 98);
+        assert_eq!(bt.count_range(2000..2100), 99);
+        assert_eq!(bt.check_invariants().violations, vec![]);
+
+        // the duplicate policy holds, and the cursor shows what was kept
+        let kept = Bptree::<i32, i32>::builder().order(8).duplicates(DuplicatePolicy::KeepFirst).build().unwrap();
+        for i in 0..50 {
+            kept.set(i * 2, i);
+        }
+        let mut cursor = kept.cursor(&10);
+        assert_eq!(cursor.insert_after(11, 11), Ok(()));
+        assert_eq!(cursor.insert_after(12, -1), Ok(()));
+        assert_eq!(cursor.current(), Some((&12, &6)));
+        assert_eq!(kept.get(&12), Some(6));
+
+        // a refused duplicate leaves the cursor where it was
+        let strict = Bptree::<i32, i32>::builder().order(8).duplicates(DuplicatePolicy::Error).build().unwrap();
+        for i in 0..50 {
+            strict.set(i * 2, i);
+        }
+        let mut cursor = strict.cursor(&10);
+        let refused = Err(BptreeError::Occupied(OccupiedError{ key: 12, current: 6, val: -1 }));
+        assert_eq!(cursor.insert_after(12, -1), refused);
+        assert_eq!(cursor.current(), Some((&10, &5)));
+    }
+
+    #[test]
+    fn cursor_skips_tombstones_going_backwards() {
+        let bt: Bptree<i32, i32> = Bptree::new(3);
+        bt.set_version_horizon(Some(0));
+        for i in 0..200 {
+            bt.set(i, i);
+        }
+        for i in 1..199 {
+            bt.remove(&i);
+        }
+        let mut cursor = bt.cursor(&199);
+        assert_eq!(cursor.current(), Some((&199, &199)));
+        assert_eq!(cursor.prev(), Some((&0, &0)));
+        assert_eq!(cursor.prev(), None);
+        assert_eq!(cursor.next(), Some((&0, &0)));
+        assert_eq!(cursor.next(), Some((&199, &199)));
+        let mut cursor = bt.cursor(&5);
+        assert_eq!(cursor.current(), Some((&199, &199)));
+        bt.remove(&199);
+        assert!(!cursor.update(7));
+        assert_eq!(cursor.current(), None);
+    }
+
+    #[test]
+    fn scan_prefix_stops_at_the_prefix_end() {
+        use crate::prefix::PrefixKey;
+
+        let bt: Bptree<Vec<u8>, i32> = Bptree::new(4);
+        for user in [b"ann".to_vec(), b"bob".to_vec(), vec![0xff, 0xff]] {
+            for ts in 0..20 {
+                let mut key = user.clone();
+                key.extend_from_slice(format!(":{:04}", ts).as_bytes());
+                bt.set(key, ts);
+            }
+        }
+        bt.set(b"bob".to_vec(), -1);
+        bt.set(b"boc".to_vec(), -2);
+        let bob: Vec<i32> = bt.scan_prefix(&b"bob:".to_vec()).map(|(_, v)| v).collect();
+        assert_eq!(bob, (0..20).collect::<Vec<_>>());
+        assert_eq!(bt.scan_prefix(&b"bob".to_vec()).count(), 21);
+        assert_eq!(bt.scan_prefix(&vec![0xff]).count(), 20);
+        assert_eq!(bt.scan_prefix(&vec![]).count(), 62);
+        assert_eq!(vec![0x01, 0xff].prefix_end(), Some(vec![0x02]));
+        assert_eq!(vec![0xff].prefix_end(), None);
+
+        let names: Bptree<String, i32> = Bptree::new(4);
+        for (i, name) in ["ant", "anvil", "ap", "an\u{10FFFF}x", "an\u{D7FF}", "b"].iter().enumerate() {
+            names.set(name.to_string(), i as i32);
+        }
+        assert_eq!(names.scan_prefix(&"an".to_string()).count(), 4);
+        assert_eq!("a\u{D7FF}".to_string().prefix_end(), Some("a\u{E000}".to_string()));
+        assert_eq!("a\u{10FFFF}".to_string().prefix_end(), Some("b".to_string()));
+    }
+
+    #[test]
+    fn floor_and_ceiling_lookups() {
+        let bt: Bptree<i32, &str> = Bptree::new(4);
+        assert_eq!(bt.get_le(&5), None);
+        for i in (10..200).step_by(10) {
+            bt.set(i, "hello");
+        }
+        assert_eq!(bt.get_le(&55), Some((50, "hello")));
+        assert_eq!(bt.get_le(&50), Some((50, "hello")));
+        assert_eq!(bt.get_ge(&55), Some((60, "hello")));
+        assert_eq!(bt.get_ge(&60), Some((60, "hello")));
+        assert_eq!(bt.get_le(&9), None);
+        assert_eq!(bt.get_ge(&191), None);
+        assert_eq!(bt.get_ge(&-1000), Some((10, "hello")));
+        assert_eq!(bt.get_le(&1000), Some((190, "hello")));
+
+        bt.remove_range(30..=150);
+        assert_eq!(bt.get_le(&149), Some((20, "hello")));
+        assert_eq!(bt.get_ge(&21), Some((160, "hello")));
+    }
+
+    #[test]
+    fn order_statistics_match_btreemap() {
+        use std::collections::BTreeMap;
+        use std::ops::Bound;
+
+        for (m, keep_history) in [(3, false), (4, true), (5, false), (16, true)] {
+            let mut seed: u64 = 88172645463325252 + m as u64;
+            let mut rnd = move || {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed
+            };
+            let bt: Bptree<i32, i32> = Bptree::new(m);
+            let mut map = BTreeMap::new();
+            if keep_history {
+                bt.set_version_horizon(Some(0));
+            }
+            for round in 0..6000 {
+                let key = (rnd() % 1500) as i32;
+                match rnd() % 10 {
+                    0..=5 => {
+                        bt.set(key, round);
+                        map.insert(key, round);
+                    },
+                    6..=8 => assert_eq!(bt.remove(&key), map.remove(&key)),
+                    _ => {
+                        let doomed: Vec<i32> = map.range(key..key + 40).map(|(k, _)| *k).collect();
+                        for key in &doomed {
+                            map.remove(key);
+                        }
+                        assert_eq!(bt.remove_range(key..key + 40), doomed.len());
+                    },
+                }
+                if round % 500 == 0 {
+                    bt.gc();
+                    assert_eq!(bt.check_invariants().violations, vec![]);
+                }
+            }
+            let entries: Vec<(i32, i32)> = map.iter().map(|(k, v)| (*k, *v)).collect();
+            for (rank, entry) in entries.iter().enumerate() {
+                assert_eq!(bt.select(rank), Some(*entry));
+                assert_eq!(bt.rank(&entry.0), rank);
+            }
+            assert_eq!(bt.select(entries.len()), None);
+            for key in [-1, 700, 1499, 2000] {
+                assert_eq!(bt.rank(&key), map.range(..key).count());
+            }
+            for _ in 0..200 {
+                let lo = (rnd() % 1600) as i32 - 50;
+                let hi = lo + (rnd() % 300) as i32;
+                assert_eq!(bt.count_range(lo..hi), map.range(lo..hi).count());
+                assert_eq!(bt.count_range(lo..=hi), map.range(lo..=hi).count());
+                assert_eq!(bt.count_range((Bound::Excluded(lo), Bound::Unbounded)), map.range((Bound::Excluded(lo), Bound::Unbounded)).count());
+            }
+            assert_eq!(bt.count_range(..), map.len());
+        }
+    }
+
+    #[test]
+    fn append_grafts_disjoint_trees_and_merges_overlapping_ones() {
+        for m in [3, 4, 7] {
+            for (left_len, right_len) in [(0, 5), (5, 0), (1, 1), (2, 500), (500, 2), (300, 300), (40, 2000), (2000, 40)] {
+                let bt: Bptree<i32, i32> = Bptree::new(m);
+                let mut other: Bptree<i32, i32> = Bptree::new(m);
+                for key in 0..left_len {
+                    bt.set(key, key);
+                }
+                for key in 0..right_len {
+                    other.set(left_len + key, -key);
+                }
+                other.remove(&left_len);
+                bt.append(&mut other);
+                assert_eq!(other.iter().count(), 0);
+                assert_eq!(bt.check_invariants().violations, vec![]);
+
+                let mut expected: Vec<(i32, i32)> = (0..left_len).map(|key| (key, key)).collect();
+                expected.extend((1..right_len).map(|key| (left_len + key, -key)));
+                assert_eq!(bt.iter().collect::<Vec<_>>(), expected);
+                assert_eq!(bt.count_range(..), expected.len());
+                for (rank, entry) in expected.iter().enumerate().step_by(13) {
+                    assert_eq!(bt.select(rank), Some(*entry));
+                }
+                // the grafted tree must still split and merge correctly
+                for (key, _) in expected.iter().step_by(2) {
+                    assert!(bt.remove(key).is_some());
+                }
+                bt.set(-1, 0);
+                bt.set(i32::MAX, 0);
+                assert_eq!(bt.iter().count(), expected.len() / 2 + 2);
+                assert_eq!(bt.rank(&i32::MAX), expected.len() / 2 + 1);
+            }
+        }
+
+        let bt: Bptree<i32, &str> = Bptree::new(4);
+        let mut other: Bptree<i32, &str> = Bptree::new(4);
+        for key in 0..100 {
+            bt.set(key * 2, "even");
+            other.set(key * 2 + 1, "odd");
+        }
+        bt.append(&mut other);
+        assert_eq!(bt.iter().map(|(key, _)| key).collect::<Vec<_>>(), (0..200).collect::<Vec<_>>());
+        assert_eq!(bt.get(&51), Some("odd"));
+    }
+
+    #[test]
+    fn split_off_divides_the_tree_at_the_key() {
+        for m in [3, 4, 7] {
+            for len in [0, 1, 10, 200, 3000] {
+                for at in [-5, 0, 1, len / 3, len / 2, len - 1, len, len + 5] {
+                    let bt: Bptree<i32, i32> = Bptree::new(m);
+                    for key in 0..len {
+                        bt.set(key * 2, key);
+                    }
+                    let right = bt.split_off(&(at * 2 + 1));
+                    assert_eq!(bt.check_invariants().violations, vec![]);
+                    assert_eq!(right.check_invariants().violations, vec![]);
+                    let split = (at + 1).clamp(0, len);
+                    assert_eq!(bt.iter().collect::<Vec<_>>(), (0..split).map(|key| (key * 2, key)).collect::<Vec<_>>());
+                    assert_eq!(right.iter().collect::<Vec<_>>(), (split..len).map(|key| (key * 2, key)).collect::<Vec<_>>());
+                    assert_eq!(bt.count_range(..), split as usize);
+                    assert_eq!(right.count_range(..), (len - split) as usize);
+                    if split < len {
+                        assert_eq!(right.select(0), Some((split * 2, split)));
+                    }
+
+                    // both halves must keep working as ordinary trees
+                    for key in (0..len).step_by(3) {
+                        bt.remove(&(key * 2));
+                        right.remove(&(key * 2));
+                        bt.set(key * 2 + 1, 0);
+                    }
+                    let left_len = bt.iter().count();
+                    assert_eq!(bt.rank(&i32::MAX), left_len);
+                    assert_eq!(right.iter().filter(|(key, _)| key % 2 == 1).count(), 0);
+                    assert_eq!(right.count_range(..), right.iter().count());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn diff_reports_added_removed_and_changed_entries() {
+        use super::diff::Change;
+
+        let old: Bptree<i32, i32> = Bptree::new(4);
+        let new: Bptree<i32, i32> = Bptree::new(5);
+        for key in 0..300 {
+            old.set(key, key);
+            new.set(key, key);
+        }
+        new.remove(&0);
+        new.remove_range(100..103);
+        new.set(150, -1);
+        new.set(500, 500);
+        old.remove(&299);
+
+        assert_eq!(old.diff(&new).collect::<Vec<_>>(), vec![
+            Change::Removed(0, 0),
+            Change::Removed(100, 100),
+            Change::Removed(101, 101),
+            Change::Removed(102, 102),
+            Change::Changed(150, 150, -1),
+            Change::Added(299, 299),
+            Change::Added(500, 500),
+        ]);
+        assert_eq!(old.diff(&old).count(), 0);
+    }
+
+    #[test]
+    fn snapshot_and_seq_diffs_carry_a_replica_forward() {
+        use super::diff::Change;
+
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        let replica: Bptree<i32, i32> = Bptree::new(4);
+        for key in 0..300 {
+            bt.set(key, key);
+            replica.set(key, key);
+        }
+        bt.set_version_horizon(Some(bt.seq()));
+        let since = bt.seq();
+        let snapshot = bt.snapshot();
+        bt.remove(&0);
+        bt.remove_range(100..103);
+        bt.set(150, -1);
+        bt.set(200, 0);
+        bt.set(200, 200);
+        bt.set(500, 500);
+        bt.remove(&501);
+
+        // the same edit two whole trees give, from the written entries only
+        let changes = snapshot.diff().collect::<Vec<_>>();
+        assert_eq!(changes, replica.diff(&bt).collect::<Vec<_>>());
+        assert_eq!(changes, vec![
+            Change::Removed(0, 0),
+            Change::Removed(100, 100),
+            Change::Removed(101, 101),
+            Change::Removed(102, 102),
+            Change::Changed(150, 150, -1),
+            Change::Added(500, 500),
+        ]);
+        drop(snapshot);
+
+        // a key rewritten to its old value is in the delta all the same
+        let delta = bt.diff_since(since).collect::<Vec<_>>();
+        assert_eq!(delta.len(), 7);
+        assert!(delta.contains(&(200, Some(200))));
+        for (key, val) in delta {
+            match val {
+                Some(val) => {replica.set(key, val);},
+                None => {replica.remove(&key);},
+            }
+        }
+        assert!(replica == bt);
+        assert_eq!(bt.diff_since(bt.seq()).count(), 0);
+    }
+
+    #[test]
+    fn dump_prints_one_level_per_line() {
+        let bt: Bptree<i32, &str> = Bptree::new(3);
+        assert_eq!(bt.dump(), "");
+        for key in 1..=4 {
+            bt.set(key, "v");
+        }
+        bt.set_version_horizon(Some(bt.seq()));
+        bt.remove(&4);
+        assert_eq!(bt.dump(), "0: [3]\n1: {1: \"v\", 2: \"v\"} {3: \"v\", 4: ~}\n");
+    }
+
+    #[test]
+    fn scan_partial_matches_leading_components() {
+        let bt: Bptree<(u64, String, u64), i32> = Bptree::new(4);
+        for tenant in 0..5 {
+            for table in ["orders", "users"] {
+                for id in 0..10 {
+                    bt.set((tenant, table.to_string(), id), id as i32);
+                }
+            }
+        }
+        assert_eq!(bt.scan_partial(&(2,)).count(), 20);
+        assert!(bt.scan_partial(&(2,)).all(|((tenant, _, _), _)| tenant == 2));
+        let users: Vec<u64> = bt.scan_partial(&(4, "users".to_string())).map(|((_, _, id), _)| id).collect();
+        assert_eq!(users, (0..10).collect::<Vec<_>>());
+        assert_eq!(bt.scan_partial(&(9,)).count(), 0);
+        assert_eq!(bt.scan_partial(&(1, "user".to_string())).count(), 0);
+
+        let pairs: Bptree<(i32, i32), i32> = Bptree::new(3);
+        for i in -20..20i32 {
+            pairs.set((i.rem_euclid(3), i), i);
+        }
+        assert_eq!(pairs.scan_partial(&(0,)).map(|(_, v)| v).collect::<Vec<_>>(),
+                   (-18..20).step_by(3).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn byte_keys_sort_like_their_keys() {
+        use crate::bytekey::ByteKey;
+
+        fn sorted_same<K: ByteKey + Ord + Clone + std::fmt::Debug>(mut keys: Vec<K>) {
+            for key in &keys {
+                assert_eq!(K::from_bytes(&key.to_bytes()).as_ref(), Some(key));
+            }
+            let mut encoded: Vec<Vec<u8>> = keys.iter().map(ByteKey::to_bytes).collect();
+            keys.sort();
+            encoded.sort();
+            let decoded: Vec<K> = encoded.iter().map(|bytes| K::from_bytes(bytes).unwrap()).collect();
+            assert_eq!(decoded, keys);
+        }
+
+        sorted_same(vec![0u64, 1, 255, 256, u64::MAX, 1 << 40]);
+        sorted_same(vec![i32::MIN, -256, -1, 0, 1, 255, i32::MAX]);
+        sorted_same(vec![i8::MIN, -1, 0, i8::MAX]);
+        sorted_same(vec![vec![], vec![0], vec![0, 0], vec![0, 1], vec![0xff], vec![1, 0xff, 0]]);
+        sorted_same(["", "a", "a\0", "ab", "b", "\u{10FFFF}"].map(String::from).to_vec());
+        sorted_same(vec![(-1i64, "b".to_string()), (-1, "ab".to_string()), (0, "".to_string()), (-2, "zz".to_string())]);
+        sorted_same(vec![(1u8, vec![0u8], 2i16), (1, vec![], 3), (1, vec![0, 0], -3), (0, vec![0xff], 0)]);
+
+        assert_eq!(u32::from_bytes(&[0, 0, 1]), None);
+        assert_eq!(Vec::<u8>::from_bytes(&[1, 0, 2]), None);
+        assert_eq!(u8::from_bytes(&[1, 2]), None);
+    }
+
+    #[test]
+    fn incremental_backups_restore_to_the_same_tree() {
+        let bt: Bptree<i32, String> = Bptree::new(4);
+        bt.set_version_horizon(Some(0));
+        for i in 0..200 {
+            bt.set(i, format!("v{}", i));
+        }
+        let mut full = vec![];
+        let checkpoint = bt.backup_since(0, &mut full).unwrap();
+
+        bt.set_version_horizon(Some(checkpoint));
+        for i in (0..200).step_by(7) {
+            bt.set(i, "changed".to_string());
+        }
+        for i in (0..200).step_by(11) {
+            bt.remove(&i);
+        }
+        bt.set(500, "new".to_string());
+        let mut incremental = vec![];
+        let next = bt.backup_since(checkpoint, &mut incremental).unwrap();
+        assert_eq!(next, bt.seq());
+        assert!(incremental.len() < full.len() / 2);
+
+        let copy: Bptree<i32, String> = Bptree::new(5);
+        assert_eq!(copy.restore(&full[..]).unwrap(), 200);
+        assert_eq!(copy.restore(&incremental[..]).unwrap(), 29 + 19 - 3 + 1);
+        assert!(copy.iter().eq(bt.iter()));
+
+        // a restore writes entries back as they were, whatever the policy
+        use crate::builder::DuplicatePolicy;
+        for policy in [DuplicatePolicy::Error, DuplicatePolicy::KeepFirst] {
+            let strict = Bptree::<i32, String>::builder().order(5).duplicates(policy).build().unwrap();
+            strict.restore(&full[..]).unwrap();
+            strict.restore(&incremental[..]).unwrap();
+            assert!(strict.iter().eq(bt.iter()));
+        }
+
+        let mut empty = vec![];
+        bt.backup_since(next, &mut empty).unwrap();
+        assert_eq!(copy.restore(&empty[..]).unwrap(), 0);
+        assert!(copy.restore(&incremental[..incremental.len() - 1]).is_err());
+    }
+
+    #[cfg(all(feature = "csv", feature = "serde_json"))]
+    #[test]
+    fn csv_and_jsonl_round_trip() {
+        let bt: Bptree<u64, String> = Bptree::new(4);
+        for i in 0..100 {
+            bt.set(i * 2, format!("v{}, \"{}\"", i, i));
+        }
+
+        let mut csv = vec![];
+        assert_eq!(bt.export_csv(&mut csv).unwrap(), 100);
+        assert!(csv.starts_with(b"0,\"v0, \"\"0\"\"\"\n2,"));
+        let from_csv: Bptree<u64, String> = Bptree::new(5);
+        assert_eq!(from_csv.import_csv(&csv[..]).unwrap(), 100);
+        assert!(from_csv.iter().eq(bt.iter()));
+        assert_eq!(from_csv.check_invariants().violations, vec![]);
+
+        let mut jsonl = vec![];
+        assert_eq!(bt.export_jsonl(&mut jsonl).unwrap(), 100);
+        assert_eq!(jsonl.iter().filter(|&&b| b == b'\n').count(), 100);
+        let from_jsonl: Bptree<u64, String> = Bptree::new(3);
+        from_jsonl.set(1, "kept".to_string());
+        from_jsonl.set(2, "replaced".to_string());
+        assert_eq!(from_jsonl.import_jsonl(&jsonl[..]).unwrap(), 100);
+        assert_eq!(from_jsonl.get(&1), Some("kept".to_string()));
+        assert_eq!(from_jsonl.get(&2), bt.get(&2));
+        assert_eq!(from_jsonl.iter().count(), 101);
+        assert_eq!(from_jsonl.check_invariants().violations, vec![]);
+
+        // unsorted input with a repeated key, the later row wins
+        let unsorted: Bptree<u64, String> = Bptree::new(4);
+        assert_eq!(unsorted.import_jsonl(&b"[5,\"a\"]\n[1,\"b\"]\n[5,\"c\"]\n"[..]).unwrap(), 3);
+        assert_eq!(unsorted.iter().collect::<Vec<_>>(), vec![(1, "b".to_string()), (5, "c".to_string())]);
+        assert!(unsorted.import_csv(&b"1,b\nx,y\n"[..]).is_err());
+
+        // an entry over the size limits refuses the whole input
+        let limited = Bptree::<u64, String>::builder().order(4).max_value_len(4).build().unwrap();
+        let err = limited.import_jsonl(&b"[1,\"a\"]\n[2,\"too long\"]\n"[..]).unwrap_err();
+        assert_eq!(err.io_error_kind(), Some(std::io::ErrorKind::InvalidData));
+        assert!(limited.import_csv(&b"1,a\n2,too long\n"[..]).is_err());
+        assert_eq!(limited.iter().count(), 0);
+    }
+
+    #[test]
+    fn separators_are_truncated_to_a_distinguishing_prefix() {
+        use crate::kvtype::KVType;
+        assert_eq!(String::separator(&"apple".to_string(), &"apricot".to_string()), "apr");
+        assert_eq!(String::separator(&"ab".to_string(), &"abc".to_string()), "abc");
+        assert_eq!(String::separator(&"naïve".to_string(), &"naïzz".to_string()), "naïz");
+        assert_eq!(Vec::separator(&vec![1, 2], &vec![1, 3, 9]), vec![1, 3]);
+
+        let bt: Bptree<String, i32> = Bptree::new(4);
+        let key = |i: i32| format!("{:03}/a-rather-long-shared-path/{}", i * 7 % 500, i);
+        for i in 0..500 {
+            bt.set(key(i), i);
+        }
+        for i in (0..500).step_by(3) {
+            bt.remove(&key(i));
+        }
+        assert_eq!(bt.check_invariants().violations, vec![]);
+        for i in 0..500 {
+            assert_eq!(bt.get(&key(i)), if i % 3 == 0 { None } else { Some(i) });
+        }
+        let dump = bt.dump();
+        let inner_levels: Vec<_> = dump.lines().filter(|line| line.contains('[')).collect();
+        assert!(!inner_levels.is_empty());
+        assert!(inner_levels.iter().all(|line| !line.contains("long")));
+    }
+
+    #[test]
+    fn node_search_matches_binary_search() {
+        use crate::search::search;
+        let mut seed: u64 = 88172645463325252;
+        for len in [0, 1, 3, 15, 16, 17, 64, 255, 256] {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let keys: Vec<u64> = (0..len as u64).map(|i| i * (u64::MAX / 300) + seed % 7).collect();
+            for probe in keys.iter().flat_map(|&k| [k.wrapping_sub(1), k, k + 1]).chain([0, u64::MAX]) {
+                assert_eq!(search(&keys, probe), keys.binary_search(&probe));
+            }
+        }
+
+        let bt: Bptree<u64, u64> = Bptree::new(64);
+        for key in (0..20000).map(|i: u64| i.wrapping_mul(0x9E3779B97F4A7C15)) {
+            bt.set(key, key);
+        }
+        for key in (0..20000).map(|i: u64| i.wrapping_mul(0x9E3779B97F4A7C15)) {
+            assert_eq!(bt.get(&key), Some(key));
+            assert_eq!(bt.get(&(key ^ 1)), None);
+        }
+    }
+
+    #[test]
+    fn vector_search_agrees_with_scalar() {
+        use crate::search::Lane;
+        fn check<T: Lane + std::fmt::Debug>(keys: &mut Vec<T>, probes: &[T]) {
+            keys.sort();
+            keys.dedup();
+            for &probe in keys.iter().chain(probes) {
+                let paths = T::all_paths(keys, probe);
+                assert!(paths.iter().all(|&i| i == paths[0]), "{:?} in {} keys: {:?}", probe, keys.len(), paths);
+            }
+        }
+        let mut seed: u64 = 88172645463325252;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        for len in [64usize, 65, 100, 127, 128, 200, 255, 256] {
+            let raw: Vec<u64> = (0..len).map(|_| next()).collect();
+            check(&mut raw.iter().map(|&x| x as u8).collect(), &[0, 1, 127, 128, u8::MAX]);
+            check(&mut raw.iter().map(|&x| x as i8).collect(), &[i8::MIN, -1, 0, 1, i8::MAX]);
+            check(&mut raw.iter().map(|&x| x as u16).collect(), &[0, 0x7FFF, 0x8000, u16::MAX]);
+            check(&mut raw.iter().map(|&x| x as i16).collect(), &[i16::MIN, -1, 0, i16::MAX]);
+            check(&mut raw.iter().map(|&x| x as u32).collect(), &[0, 0x7FFF_FFFF, 0x8000_0000, u32::MAX]);
+            check(&mut raw.iter().map(|&x| x as i32).collect(), &[i32::MIN, -1, 0, i32::MAX]);
+            check(&mut raw.clone(), &[0, 1 << 63, u64::MAX]);
+            check(&mut raw.iter().map(|&x| x as i64).collect(), &[i64::MIN, -1, 0, i64::MAX]);
+            // dense runs, so every probe lands inside some vector
+            check(&mut (0..len as i32).map(|i| i - 128).collect(), &[-129, 0, 300]);
+        }
+    }
+
+    #[test]
+    fn leaves_keep_their_initial_capacity() {
+        let bt: Bptree<i32, i32> = Bptree::new(8);
+        for key in (0..2000).map(|i| i * 7919 % 2000) {
+            bt.set(key, key);
+        }
+        for key in (0..2000).step_by(3) {
+            bt.remove(&key);
+        }
+        let right = bt.split_off(&1000);
+        bt.compact(0.9);
+        for tree in [&bt, &right] {
+            assert!(tree.leaf_capacities().iter().all(|&capacity| capacity == 8));
+        }
+    }
+
+    #[test]
+    fn node_bytes_pick_the_order() {
+        use crate::tune::order_for_node_bytes;
+        // bincode writes an i32 in 4 bytes and a &str as an 8 byte length
+        // followed by its bytes
+        let sample: Vec<(i32, &str)> = vec![(1, "abcd"), (2, "abcdefghijkl")];
+        assert_eq!(order_for_node_bytes(4096, &sample), 4096 / 20 + 1);
+        assert_eq!(order_for_node_bytes(4096, &[] as &[(u64, u64)]), 4096 / 16 + 1);
+        assert_eq!(order_for_node_bytes(10, &sample), 3);
+
+        let bt = Bptree::with_node_bytes(100, &sample);
+        for key in 0..100 {
+            bt.set(key, "abcdefgh");
+        }
+        assert_eq!(bt.stats().max_fill, 1.0);
+        assert!(bt.leaf_sizes().iter().all(|&n| n <= 5));
+    }
+
+    #[test]
+    fn builder_validates_the_order() {
+        use crate::builder::ConfigError;
+        let err = Bptree::<i32, i32>::builder().order(2).build().unwrap_err();
+        assert_eq!(err, ConfigError::OrderTooSmall{ m: 2, min: 3 });
+        assert_eq!(err.to_string(), "order 2 is below the minimum of 3");
+        assert!(std::panic::catch_unwind(|| Bptree::<i32, i32>::new(1)).is_err());
+
+        let bt = Bptree::<i32, &str>::builder().order(3).version_horizon(Some(0)).build().unwrap();
+        for key in 0..20 {
+            bt.set(key, "old");
+        }
+        let before = bt.seq();
+        bt.remove(&7);
+        assert_eq!(bt.get_at(&7, before), Some("old"));
+        assert_eq!(bt.check_invariants().violations, vec![]);
+    }
+
+    #[test]
+    fn degenerate_trees_do_not_panic() {
+        use crate::batch::WriteBatch;
+        use crate::bptree::{InnerNode, LeafNode};
+        assert!(LeafNode::<i32, &str>::new(2).remove(&1, &|_| true).is_none());
+        assert!(InnerNode::<i32, &str>::new(2).remove(&1, &|_| true).is_none());
+
+        for keep_history in [false, true] {
+            let bt: Bptree<i32, &str> = Bptree::new(3);
+            if keep_history {
+                bt.set_version_horizon(Some(0));
+            }
+            // fresh, emptied by remove, emptied by remove_range, emptied by gc
+            for round in 0..4 {
+                assert_eq!(bt.remove(&1), None);
+                assert_eq!(bt.get(&1), None);
+                assert_eq!(bt.get_le(&1), None);
+                assert_eq!(bt.get_ge(&1), None);
+                assert_eq!(bt.select(0), None);
+                assert_eq!(bt.rank(&1), 0);
+                assert_eq!(bt.multi_get(&[1, 2]), vec![None, None]);
+                assert_eq!(bt.remove_range(..), 0);
+                assert_eq!(bt.iter().count(), 0);
+                let mut batch = WriteBatch::new();
+                batch.delete(1);
+                assert_eq!(bt.apply(batch), Ok(()));
+                assert_eq!(bt.split_off(&0).iter().count(), 0);
+                bt.gc();
+                bt.compact(1.0);
+                assert_eq!(bt.check_invariants().violations, vec![]);
+
+                for key in 0..10 {
+                    bt.set(key, "v");
+                }
+                match round {
+                    0 => for key in 0..10 {
+                        assert_eq!(bt.remove(&key), Some("v"));
+                    },
+                    1 => assert_eq!(bt.remove_range(..), 10),
+                    _ => {
+                        bt.remove_range(..);
+                        bt.set_version_horizon(None);
+                        bt.gc();
+                        if keep_history {
+                            bt.set_version_horizon(Some(bt.seq()));
+                        }
+                    },
+                }
+                assert_eq!(bt.remove(&3), None);
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_hand_out_every_entry_once() {
+        use std::time::Instant;
+        let bt: Bptree<i32, i32> = Bptree::new(5);
+        assert_eq!(bt.leaves().count(), 0);
+        for key in 0..1000 {
+            bt.set(key, key * 2);
+        }
+        bt.set_version_horizon(Some(bt.seq()));
+        bt.remove_range(100..300);
+        bt.expire_range(500..520, Instant::now());
+
+        let mut seen = vec![];
+        let mut chunks = 0;
+        for chunk in bt.leaves() {
+            chunks += 1;
+            seen.extend(chunk.iter().map(|(key, val)| (*key, *val)));
+        }
+        assert_eq!(seen, bt.iter().collect::<Vec<_>>());
+        assert!(chunks <= bt.stats().leaf_nodes);
+
+        // leaves merged away between chunks are found again from the root
+        let mut seen = vec![];
+        for (i, chunk) in bt.leaves().enumerate() {
+            seen.extend(chunk.iter().map(|(key, _)| *key));
+            drop(chunk);
+            if i == 2 {
+                bt.set_version_horizon(None);
+                bt.gc();
+                bt.compact(1.0);
+            }
+        }
+        assert_eq!(seen, bt.iter().map(|(key, _)| key).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn stream_lends_every_live_entry() {
+        use std::time::{Duration, Instant};
+        let bt: Bptree<i32, String> = Bptree::new(4);
+        assert!(bt.stream().next().is_none());
+        for key in 0..500 {
+            bt.set(key, key.to_string());
+        }
+        bt.set_version_horizon(Some(bt.seq()));
+        bt.remove_range(0..50);
+        bt.remove_range(100..120);
+        bt.expire_range(300..340, Instant::now());
+        for key in (400..500).step_by(2) {
+            bt.set_with_ttl(key, "gone".to_string(), Duration::ZERO);
+        }
+
+        let mut stream = bt.stream();
+        let mut seen = vec![];
+        while let Some((key, val)) = stream.next() {
+            seen.push((*key, val.clone()));
+        }
+        assert!(stream.next().is_none());
+        drop(stream);
+        assert_eq!(seen, bt.iter().collect::<Vec<_>>());
+        assert_eq!(seen.len(), 500 - 50 - 20 - 40 - 50);
+    }
+
+    #[test]
+    fn ranges_iterate_from_both_ends() {
+        use std::ops::Bound;
+        use std::time::Instant;
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        assert_eq!(bt.iter().next_back(), None);
+        for key in 0..400 {
+            bt.set(key, -key);
+        }
+        bt.set_version_horizon(Some(bt.seq()));
+        bt.remove_range(100..180);
+        bt.expire_range(250..300, Instant::now());
+        let bt_clean: Bptree<i32, i32> = Bptree::new(7);
+        for (key, val) in bt.iter() {
+            bt_clean.set(key, val);
+        }
+
+        let bounds = [Bound::Unbounded, Bound::Included(-1), Bound::Included(99), Bound::Excluded(100),
+                      Bound::Included(150), Bound::Excluded(250), Bound::Included(399), Bound::Excluded(500)];
+        for tree in [&bt, &bt_clean] {
+            for start in bounds {
+                for end in bounds {
+                    if let (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) = (start, end) {
+                        if s > e {
+                            continue;
+                        }
+                    }
+                    let forward: Vec<_> = tree.range((start, end)).collect();
+                    let mut backward: Vec<_> = tree.range((start, end)).rev().collect();
+                    backward.reverse();
+                    assert_eq!(backward, forward, "{:?}..{:?}", start, end);
+                    assert_eq!(tree.range((start, end)).last(), forward.last().cloned());
+
+                    // both ends meet in the middle without losing or repeating entries
+                    let mut range = tree.range((start, end));
+                    let (mut front, mut back) = (vec![], vec![]);
+                    while let Some(entry) = range.next() {
+                        front.push(entry);
+                        match range.next_back() {
+                            Some(entry) => back.push(entry),
+                            None => break,
+                        }
+                    }
+                    back.reverse();
+                    front.extend(back);
+                    assert_eq!(front, forward);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trees_compare_by_content_not_layout() {
+        let incremental: Bptree<i32, i32> = Bptree::new(4);
+        let packed: Bptree<i32, i32> = Bptree::new(9);
+        assert!(incremental == packed);
+        for key in (0..300).rev() {
+            incremental.set(key, key % 7);
+        }
+        for key in 0..400 {
+            packed.set(key, key % 7);
+        }
+        assert!(incremental != packed);
+        packed.remove_range(300..);
+        packed.compact(1.0);
+        assert!(incremental == packed);
+        assert!(packed == incremental);
+
+        packed.set(5, 100);
+        assert!(incremental != packed);
+        incremental.set_version_horizon(Some(0));
+        incremental.set(5, 100);
+        assert!(incremental == packed);
+        assert!(incremental == incremental);
+    }
+
+    #[test]
+    fn clones_do_not_share_nodes() {
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        bt.set_version_horizon(Some(0));
+        for key in 0..300 {
+            bt.set(key, key);
+        }
+        let before = bt.seq();
+        let copy = bt.clone();
+        assert!(copy == bt);
+        assert_eq!(copy.check_invariants().violations, vec![]);
+        assert_eq!(copy.seq(), before);
+
+        for key in 0..100 {
+            bt.set(key, -1);
+        }
+        bt.remove_range(200..);
+        copy.set(1000, 1000);
+        copy.remove(&150);
+        assert_eq!(copy.get(&50), Some(50));
+        assert_eq!(copy.get(&250), Some(250));
+        assert_eq!(bt.get(&1000), None);
+        assert_eq!(bt.get(&150), Some(150));
+        assert_eq!(copy.get_at(&150, before), Some(150));
+        assert_eq!(copy.iter().count(), 300);
+        assert_eq!(bt.iter().count(), 200);
+        assert_eq!(bt.check_invariants().violations, vec![]);
+        assert_eq!(copy.check_invariants().violations, vec![]);
+        assert!(Bptree::<i32, i32>::new(3).clone() == Bptree::new(5));
+    }
+
+    #[test]
+    fn values_change_in_place_during_a_scan() {
+        use std::ops::Bound;
+        use std::sync::{Arc, Mutex};
+        use std::time::Instant;
+        use crate::hook::IndexHook;
+
+        #[derive(Default)]
+        struct Updates(Mutex<Vec<(i32, i32, i32)>>);
+        impl IndexHook<i32, i32> for Updates {
+            fn on_update(&self, key: &i32, old: &i32, new: &i32) {
+                self.0.lock().unwrap().push((*key, *old, *new));
+            }
+        }
+
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for key in 0..300 {
+            bt.set(key, key);
+        }
+        bt.expire_range(200..250, Instant::now());
+        let mut values = bt.values_mut();
+        while let Some(val) = values.next() {
+            *val *= 2;
+        }
+        drop(values);
+        assert!(bt.iter().all(|(key, val)| val == key * 2));
+        assert_eq!(bt.iter().count(), 250);
+
+        bt.set_version_horizon(Some(0));
+        let before = bt.seq();
+        let updates = Arc::new(Updates::default());
+        bt.add_hook(updates.clone());
+        let mut range = bt.range_mut(10..=20);
+        while let Some((key, val)) = range.next() {
+            if key % 5 == 0 {
+                *val = -1;
+            }
+        }
+        drop(range);
+        assert_eq!(bt.range(9..22).map(|(_, val)| val).collect::<Vec<_>>(),
+                   vec![18, -1, 22, 24, 26, 28, -1, 32, 34, 36, 38, -1, 42]);
+        assert_eq!(bt.get_at(&15, before), Some(30));
+        assert_eq!(*updates.0.lock().unwrap(), vec![(10, 20, -1), (15, 30, -1), (20, 40, -1)]);
+
+        // the last entry handed out is reported when the iterator is dropped
+        let mut range = bt.range_mut((Bound::Excluded(290), Bound::Unbounded));
+        *range.next().unwrap().1 = 0;
+        drop(range);
+        assert_eq!(bt.get(&291), Some(0));
+        assert_eq!(updates.0.lock().unwrap().last(), Some(&(291, 582, 0)));
+        assert!(bt.range_mut(1000..).next().is_none());
+        assert_eq!(bt.check_invariants().violations, vec![]);
+    }
+
+    #[test]
+    fn seq_moves_on_every_write() {
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for key in 0..100 {
+            bt.set(key, key);
+        }
+        let mut other: Bptree<i32, i32> = Bptree::new(4);
+        other.set(500, 500);
+        let mut seen = bt.seq();
+        let mut moved = |bt: &Bptree<i32, i32>| {
+            let moved = bt.seq() > seen;
+            seen = bt.seq();
+            moved
+        };
+
+        assert_eq!(bt.iter().count(), 100);
+        bt.get(&5);
+        bt.compact(1.0);
+        assert!(!moved(&bt));
+        bt.set(5, 0);
+        assert!(moved(&bt));
+        bt.remove(&6);
+        assert!(moved(&bt));
+        bt.remove_range(10..20);
+        assert!(moved(&bt));
+        bt.append(&mut other);
+        assert!(moved(&bt));
+        *bt.iter_mut().next().unwrap().1 = 7;
+        assert!(moved(&bt));
+        bt.cursor(&30).update(1);
+        assert!(moved(&bt));
+        bt.expire_range(40..50, std::time::Instant::now());
+        assert!(moved(&bt));
+    }
+
+    #[test]
+    fn strict_range_fails_once_the_tree_is_written() {
+        use crate::iter::Invalidated;
+
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for key in 0..100 {
+            bt.set(key, key);
+        }
+        let untouched: Result<Vec<_>, _> = bt.range(10..60).strict().collect();
+        assert_eq!(untouched.unwrap(), (10..60).map(|key| (key, key)).collect::<Vec<_>>());
+
+        // whatever was buffered before the write is not handed out after it
+        let mut scan = bt.range(..).strict();
+        assert_eq!(scan.next(), Some(Ok((0, 0))));
+        bt.set(1, -1);
+        assert_eq!(scan.next(), Some(Err(Invalidated)));
+        assert_eq!(scan.next(), None);
+
+        let mut scan = bt.range(..).strict().rev();
+        assert_eq!(scan.next(), Some(Ok((99, 99))));
+        bt.expire_range(0..10, std::time::Instant::now());
+        assert_eq!(scan.next(), Some(Err(Invalidated)));
+        assert_eq!(scan.next(), None);
+    }
+
+    #[test]
+    fn snapshots_stay_frozen_while_writers_proceed() {
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for key in 0..300 {
+            bt.set(key, key);
+        }
+        let frozen: Vec<_> = bt.iter().collect();
+
+        let mut scan = bt.iter_snapshot();
+        let mut seen: Vec<_> = scan.by_ref().take(50).collect();
+        let snapshot = bt.snapshot();
+        for key in 0..300 {
+            bt.set(key, -key);
+        }
+        bt.remove_range(100..200);
+        bt.set(1000, 1000);
+        let mut values = bt.values_mut();
+        while let Some(val) = values.next() {
+            *val += 1;
+        }
+        drop(values);
+        seen.extend(scan);
+        assert_eq!(seen, frozen);
+        assert_eq!(snapshot.iter().collect::<Vec<_>>(), frozen);
+        assert_eq!(snapshot.range(95..105).rev().map(|(key, _)| key).collect::<Vec<_>>(), (95..105).rev().collect::<Vec<_>>());
+        assert_eq!(snapshot.get(&150), Some(150));
+        assert_eq!(snapshot.get(&1000), None);
+        assert_eq!(bt.get(&150), None);
+        assert_eq!(bt.get(&5), Some(-4));
+
+        // once the last snapshot is gone, gc drops the versions it kept
+        drop(snapshot);
+        bt.gc();
+        assert_eq!(bt.leaf_sizes().iter().sum::<usize>(), 201);
+        assert!(bt.snapshot().iter().eq(bt.iter()));
+    }
+
+    #[test]
+    fn range_sizes_come_from_subtree_counts() {
+        use std::time::Duration;
+
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for key in 0..300 {
+            bt.set(key, key);
+        }
+        let mut range = bt.range(10..250);
+        assert_eq!(range.size_hint(), (0, Some(240)));
+        range.next();
+        range.next_back();
+        let (buffered, most) = range.size_hint();
+        assert!(buffered > 0);
+        assert_eq!(most, Some(238));
+        assert_eq!(range.count(), 238);
+
+        // snapshot ranges keep their length whatever is written meanwhile
+        let snapshot = bt.snapshot();
+        let mut range = snapshot.range(10..250);
+        assert_eq!(range.len(), 240);
+        bt.remove_range(0..100);
+        bt.set(1000, 1000);
+        range.next();
+        assert_eq!(range.len(), 239);
+        bt.set(150, -1);
+        range.next_back();
+        assert_eq!(range.len(), 238);
+        let mut read = 0;
+        while range.next().is_some() {
+            read += 1;
+            assert_eq!(range.len(), 238 - read);
+        }
+        assert_eq!(read, 238);
+        assert_eq!(snapshot.range(..0).len(), 0);
+        drop(range);
+        drop(snapshot);
+
+        // an entry's deadline passing mid-scan does not shorten it
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for key in 0..100 {
+            bt.set_with_ttl(key, key, Duration::from_millis(if key < 50 { 3_600_000 } else { 50 }));
+        }
+        let mut scan = bt.iter_snapshot();
+        assert_eq!(scan.len(), 100);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(scan.len(), 100);
+        assert_eq!(scan.by_ref().count(), 100);
+    }
+
+    #[test]
+    fn lazy_rebalance_defers_merges() {
+        use std::collections::BTreeMap;
+        let bt: Bptree<i32, i32> = Bptree::builder().order(5).lazy_rebalance(true).build().unwrap();
+        let mut model = BTreeMap::new();
+        for key in 0..1000 {
+            bt.set(key, key);
+            model.insert(key, key);
+        }
+        let leaves = bt.stats().leaf_nodes;
+        // sequential inserts fill leaves of four, so each keeps one entry
+        for key in (0..1000).filter(|key| key % 4 != 0) {
+            assert_eq!(bt.remove(&key), model.remove(&key));
+        }
+        assert_eq!(bt.remove(&5), None);
+        assert_eq!(bt.stats().leaf_nodes, leaves);
+        assert!(bt.iter().eq(model.clone()));
+        assert_eq!(bt.check_invariants().violations, vec![]);
+
+        // leaves emptied to their last entry still merge
+        for key in (0..500).step_by(4) {
+            assert_eq!(bt.remove(&key), model.remove(&key));
+        }
+        assert!(bt.stats().leaf_nodes < leaves);
+        for key in (0..1000).step_by(3) {
+            bt.set(key, -key);
+            model.insert(key, -key);
+        }
+        assert!(bt.iter().eq(model.clone()));
+        assert_eq!(bt.check_invariants().violations, vec![]);
+
+        let before = bt.stats().leaf_nodes;
+        bt.rebalance();
+        assert!(bt.stats().leaf_nodes < before);
+        assert!(bt.iter().eq(model));
+        assert_eq!(bt.check_invariants().violations, vec![]);
+    }
+
+    #[test]
+    fn full_leaves_drop_unreadable_tombstones_instead_of_splitting() {
+        let bt: Bptree<i32, i32> = Bptree::new(5);
+        bt.set_version_horizon(Some(0));
+        for key in (0..400).step_by(2) {
+            bt.set(key, key);
+        }
+        for key in (0..400).step_by(4) {
+            bt.remove(&key);
+        }
+        let snapshot = bt.seq();
+        let leaves = bt.stats().leaf_nodes;
+
+        // still readable at `snapshot`, so every leaf has to split
+        let probe = bt.clone();
+        for key in (1..400).step_by(4) {
+            probe.set(key, key);
+        }
+        assert!(probe.stats().leaf_nodes > leaves);
+
+        bt.set_version_horizon(Some(snapshot));
+        for key in (1..400).step_by(4) {
+            bt.set(key, key);
+        }
+        assert_eq!(bt.stats().leaf_nodes, leaves);
+        assert_eq!(bt.iter().count(), 200);
+        assert_eq!(bt.get(&4), None);
+        assert_eq!(bt.get_at(&6, snapshot), Some(6));
+        assert_eq!(bt.check_invariants().violations, vec![]);
+    }
+
+    #[test]
+    fn stats_describe_the_tree_shape() {
+        let bt: Bptree<i32, i32> = Bptree::new(5);
+        assert_eq!(bt.stats().height, 0);
+        assert_eq!(bt.stats().leaf_nodes, 0);
+
+        for key in 0..1000 {
+            bt.set(key, key);
+        }
+        for key in (0..1000).step_by(10) {
+            bt.remove(&key);
+        }
+        let stats = bt.stats();
+        let sizes = bt.leaf_sizes();
+        assert_eq!(stats.entries, 900);
+        assert_eq!(stats.leaf_nodes, sizes.len());
+        assert!(stats.height >= 4 && stats.inner_nodes > 0);
+        assert_eq!(stats.min_fill, *sizes.iter().min().unwrap() as f64 / 4.0);
+        assert_eq!(stats.max_fill, *sizes.iter().max().unwrap() as f64 / 4.0);
+        assert!(stats.min_fill <= stats.avg_fill && stats.avg_fill <= stats.max_fill);
+    }
+
+    #[test]
+    fn compact_packs_leaves_after_heavy_deletes() {
+        for m in [3, 4, 9] {
+            let bt: Bptree<i32, i32> = Bptree::new(m);
+            for key in 0..5000 {
+                bt.set(key, key);
+            }
+            let snapshot = bt.seq();
+            bt.set_version_horizon(Some(snapshot));
+            for key in 0..5000 {
+                if key % 10 != 0 {
+                    bt.remove(&key);
+                }
+            }
+            // tombstones a reader at the horizon can still see are kept
+            bt.compact(1.0);
+            assert_eq!(bt.check_invariants().violations, vec![]);
+            assert_eq!(bt.get_at(&1, snapshot), Some(1));
+            assert_eq!(bt.get(&1), None);
+
+            let before = bt.stats();
+            bt.set_version_horizon(Some(bt.seq()));
+            bt.compact(1.0);
+            let after = bt.stats();
+            assert_eq!(bt.check_invariants().violations, vec![]);
+            assert_eq!(after.entries, 500);
+            assert!(after.leaf_nodes * 5 < before.leaf_nodes);
+            assert_eq!(bt.iter().collect::<Vec<_>>(), (0..5000).step_by(10).map(|key| (key, key)).collect::<Vec<_>>());
+
+            bt.set_version_horizon(None);
+            bt.compact(0.0);
+            assert_eq!(bt.check_invariants().violations, vec![]);
+            for key in 0..5000 {
+                bt.set(key, -key);
+            }
+            assert_eq!(bt.check_invariants().violations, vec![]);
+            assert_eq!(bt.count_range(..), 5000);
+        }
+    }
+
+    #[test]
+    fn arena_tree_matches_btreemap() {
+        use std::collections::BTreeMap;
+        use super::arena::ArenaBptree;
+
+        for m in [3, 4, 5, 16] {
+            let mut seed: u64 = 1181783497276652981 + m as u64;
+            let mut rnd = move || {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed
+            };
+            let mut bt: ArenaBptree<i32, i32> = ArenaBptree::new(m);
+            let mut map = BTreeMap::new();
+            for step in 0..20000 {
+                let key = (rnd() % 2000) as i32;
+                if rnd() % 3 == 0 {
+                    assert_eq!(bt.remove(&key), map.remove(&key));
+                }
+                else {
+                    bt.set(key, step);
+                    map.insert(key, step);
+                }
+            }
+            assert_eq!(bt.len(), map.len());
+            assert!(bt.iter().eq(map.iter()));
+            assert!(bt.range(500..700).eq(map.range(500..700)));
+            assert!(bt.range(..=-1).eq(map.range(..=-1)));
+            for key in 0..2000 {
+                assert_eq!(bt.get(&key), map.get(&key));
+            }
+            for key in 0..2000 {
+                assert_eq!(bt.remove(&key), map.remove(&key));
+            }
+            assert!(bt.is_empty());
+            assert_eq!(bt.iter().count(), 0);
+        }
+    }
+
+    #[test]
+    fn arena_tree_keeps_entries_as_bptree_does() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use super::arena::ArenaBptree;
+        use super::builder::{BptreeBuilder, ConfigError, DuplicatePolicy};
+        use super::cas::OccupiedError;
+        use super::error::BptreeError;
+        use super::hook::IndexHook;
+        use super::limits::SizeError;
+
+        // a key with its value before and after
+        type Write = (i32, Option<i32>, Option<i32>);
+        #[derive(Default)]
+        struct Log(Mutex<Vec<Write>>);
+        impl IndexHook<i32, i32> for Log {
+            fn on_insert(&self, key: &i32, val: &i32) {
+                self.0.lock().unwrap().push((*key, None, Some(*val)));
+            }
+            fn on_update(&self, key: &i32, old: &i32, new: &i32) {
+                self.0.lock().unwrap().push((*key, Some(*old), Some(*new)));
+            }
+            fn on_remove(&self, key: &i32, old: &i32) {
+                self.0.lock().unwrap().push((*key, Some(*old), None));
+            }
+        }
+
+        // versions under a horizon, until gc drops them
+        let mut bt: ArenaBptree<i32, i32> = BptreeBuilder::new().order(4).version_horizon(Some(0)).build_arena().unwrap();
+        let log = Arc::new(Log::default());
+        bt.add_hook(log.clone());
+        for key in 0..100 {
+            bt.set(key, key);
+        }
+        let before = bt.seq();
+        bt.set(5, -5);
+        assert_eq!(bt.remove(&7), Some(7));
+        assert_eq!(bt.remove(&7), None);
+        assert_eq!((bt.get(&5), bt.get_at(&5, before)), (Some(&-5), Some(&5)));
+        assert_eq!((bt.get(&7), bt.get_at(&7, before)), (None, Some(&7)));
+        assert_eq!((bt.len(), bt.iter().count()), (99, 99));
+        bt.set_version_horizon(Some(bt.seq()));
+        bt.gc();
+        assert_eq!(bt.get_at(&7, before), None);
+        assert_eq!(bt.get_at(&5, before), None);
+        bt.set(7, 70);
+        assert_eq!(bt.len(), 100);
+
+        // expired entries hide until purged, then count as removed
+        bt.set_with_ttl(200, 200, Duration::ZERO);
+        bt.set_with_ttl(201, 201, Duration::from_secs(3600));
+        assert_eq!((bt.get(&200), bt.get(&201)), (None, Some(&201)));
+        assert_eq!(bt.len(), 102);
+        assert_eq!(bt.iter().count(), 101);
+        assert_eq!(bt.purge_expired(), 1);
+        assert_eq!((bt.len(), bt.iter().count()), (101, 101));
+
+        let log = log.0.lock().unwrap();
+        assert_eq!(log.len(), 100 + 6);
+        assert_eq!(log[100..], [(5, Some(5), Some(-5)), (7, Some(7), None), (7, None, Some(70)),
+            (200, None, Some(200)), (201, None, Some(201)), (200, Some(200), None)]);
+        drop(log);
+
+        // the duplicate policy and size limits, as `set_checked` reports them
+        let mut bt: ArenaBptree<i32, String> = BptreeBuilder::new().duplicates(DuplicatePolicy::Error).max_value_len(3).build_arena().unwrap();
+        assert_eq!(bt.set_checked(1, "one".to_string()), Ok(()));
+        assert_eq!(bt.set_checked(1, "uno".to_string()), Err(BptreeError::Occupied(OccupiedError{
+            key: 1,
+            current: "one".to_string(),
+            val: "uno".to_string(),
+        })));
+        assert_eq!(bt.set_checked(2, "two!".to_string()), Err(BptreeError::Size(SizeError::ValueTooLarge{ len: 4, max: 3 })));
+        assert_eq!((bt.get(&1).map(String::as_str), bt.get(&2), bt.len()), (Some("one"), None, 1));
+        let mut bt: ArenaBptree<i32, i32> = BptreeBuilder::new().duplicates(DuplicatePolicy::KeepFirst).build_arena().unwrap();
+        bt.set(1, 1);
+        bt.set(1, 2);
+        assert_eq!(bt.get(&1), Some(&1));
+        assert_eq!(BptreeBuilder::<i32, i32>::new().duplicates(DuplicatePolicy::Multi).build_arena().err(), Some(ConfigError::MultiNeedsMultimap));
+        assert_eq!(BptreeBuilder::<i32, i32>::new().order(2).build_arena().err(), Some(ConfigError::OrderTooSmall{ m: 2, min: 3 }));
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn ffi_round_trip() {
+        use crate::ffi::*;
+        unsafe fn take(buf: ByteBuf) -> Vec<u8> {
+            let bytes = std::slice::from_raw_parts(buf.ptr, buf.len).to_vec();
+            bptree_buf_free(buf);
+            bytes
+        }
+        unsafe {
+            assert!(bptree_new(2).is_null());
+            let tree = bptree_new(4);
+            for key in [b"pear".as_slice(), b"apple", b"fig", b""] {
+                assert!(bptree_set(tree, key.as_ptr(), key.len(), b"ripe".as_ptr(), 4));
+            }
+            let mut val = ByteBuf::empty();
+            assert!(bptree_get(tree, b"fig".as_ptr(), 3, &mut val));
+            assert_eq!(take(val), b"ripe");
+            assert!(!bptree_get(tree, b"plum".as_ptr(), 4, &mut val));
+            assert!(bptree_remove(tree, b"fig".as_ptr(), 3));
+            assert!(!bptree_remove(tree, b"fig".as_ptr(), 3));
+
+            let iter = bptree_iter_new(tree);
+            let mut keys = vec![];
+            let mut key = ByteBuf::empty();
+            while bptree_iter_next(iter, &mut key, &mut val) {
+                keys.push(take(key));
+                take(val);
+            }
+            assert_eq!(keys, vec![b"".to_vec(), b"apple".to_vec(), b"pear".to_vec()]);
+            assert!(!bptree_iter_next(iter, &mut key, &mut val));
+            bptree_iter_free(iter);
+
+            // long walks go leaf by leaf and see writes behind the leaf
+            // they are on
+            for i in 0..200u32 {
+                let key = i.to_be_bytes();
+                bptree_set(tree, key.as_ptr(), key.len(), key.as_ptr(), key.len());
+            }
+            let iter = bptree_iter_new(tree);
+            let mut seen = 0;
+            while bptree_iter_next(iter, &mut key, &mut val) {
+                if seen == 10 {
+                    let late = 1000u32.to_be_bytes();
+                    bptree_set(tree, late.as_ptr(), late.len(), late.as_ptr(), late.len());
+                }
+                take(key);
+                take(val);
+                seen += 1;
+            }
+            assert_eq!(seen, 204);
+            bptree_iter_free(iter);
+            bptree_free(tree);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_range_matches_range() {
+        use rayon::prelude::*;
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        assert_eq!(bt.par_iter().count(), 0);
+        for key in 0..5000 {
+            bt.set(key, key);
+        }
+        assert_eq!(bt.par_iter().collect::<Vec<_>>(), bt.iter().collect::<Vec<_>>());
+        for (lo, hi) in [(0, 5000), (123, 4321), (2000, 2001), (-50, 10), (4000, 9000), (3000, 3000)] {
+            assert_eq!(bt.par_range(lo..hi).collect::<Vec<_>>(), bt.range(lo..hi).collect::<Vec<_>>());
+            assert_eq!(bt.par_range(lo..=hi).map(|(_, val)| val as i64).sum::<i64>(), bt.range(lo..=hi).map(|(_, val)| val as i64).sum::<i64>());
+        }
+    }
+
+    #[test]
+    fn multi_get_matches_get() {
+        let bt: Bptree<i32, &str> = Bptree::new(5);
+        for key in (0..1000).step_by(2) {
+            bt.set(key, "even");
+        }
+        let mut probes: Vec<i32> = (0..200).map(|i| (i * 37) % 1100 - 50).collect();
+        probes.extend([500, 500, 501, 999, 998]);
+        let expected: Vec<_> = probes.iter().map(|key| bt.get(key)).collect();
+        assert_eq!(bt.multi_get(&probes), expected);
+        assert_eq!(bt.multi_get(&[]), vec![]);
+
+        bt.compact(1.0);
+        assert_eq!(bt.multi_get(&probes), expected);
+    }
+
+    #[test]
+    fn write_batch_applies_as_one_unit() {
+        use std::collections::BTreeMap;
+        use crate::batch::WriteBatch;
+        for keep_history in [false, true] {
+            let bt: Bptree<i32, &str> = Bptree::new(4);
+            let mut expected = BTreeMap::new();
+            for key in 0..50 {
+                bt.set(key, "old");
+                expected.insert(key, "old");
+            }
+            if keep_history {
+                bt.set_version_horizon(Some(0));
+            }
+            let before = bt.seq();
+
+            let mut batch = WriteBatch::new();
+            for key in (0..100).step_by(3) {
+                batch.put(key, "new");
+                expected.insert(key, "new");
+            }
+            for key in (0..100).step_by(4) {
+                batch.delete(key);
+                expected.remove(&key);
+            }
+            batch.put(8, "again");
+            expected.insert(8, "again");
+            assert_eq!(bt.apply(batch), Ok(()));
+
+            assert_eq!(bt.seq(), before + 1);
+            assert_eq!(bt.iter().collect::<Vec<_>>(), expected.clone().into_iter().collect::<Vec<_>>());
+            assert_eq!(bt.count_range(..), expected.len());
+            assert_eq!(bt.check_invariants().violations, vec![]);
+            if keep_history {
+                assert_eq!(bt.get_at(&4, before), Some("old"));
+                assert_eq!(bt.get_at(&3, before), Some("old"));
+                assert_eq!(bt.get_at(&51, before), None);
+            }
+        }
+    }
+
+    #[test]
+    fn get_ref_borrows_without_cloning() {
+        let bt: Bptree<i32, &str> = Bptree::new(4);
+        for key in 0..100 {
+            bt.set(key, "hello");
+        }
+        bt.set(42, "answer");
+        assert_eq!(bt.get_ref(&42).as_deref(), Some(&"answer"));
+        assert!(bt.get_ref(&100).is_none());
+
+        bt.set_version_horizon(Some(bt.seq()));
+        bt.remove(&42);
+        assert!(bt.get_ref(&42).is_none());
+
+        // a reader holding a reference only holds up writers to its leaf
+        let held = bt.get_ref(&0).unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| bt.set(99, "far away"));
+        });
+        assert_eq!(*held, "hello");
+        drop(held);
+        assert_eq!(bt.get(&99), Some("far away"));
+    }
+
+    #[test]
+    fn concurrent_inserts_into_disjoint_ranges() {
+        let bt: Bptree<i32, i32> = Bptree::new(8);
+        std::thread::scope(|scope| {
+            for t in 0..8 {
+                let bt = &bt;
+                scope.spawn(move || {
+                    for i in 0..2000 {
+                        bt.set(t * 100000 + i, i);
+                    }
+                });
+            }
+            scope.spawn(|| {
+                for i in 0..2000 {
+                    if let Some(val) = bt.get(&i) {
+                        assert_eq!(val, i);
+                    }
+                }
+            });
+        });
+        for t in 0..8 {
+            for i in 0..2000 {
+                assert_eq!(bt.get(&(t * 100000 + i)), Some(i));
+            }
+        }
+        assert_eq!(bt.leaf_sizes().iter().sum::<usize>(), 16000);
+    }
+
+    #[test]
+    fn shared_tree_needs_no_external_lock() {
+        use std::sync::Arc;
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Bptree<i32, i32>>();
+
+        let bt: Arc<Bptree<i32, i32>> = Arc::new(Bptree::new(5));
+        bt.set_version_horizon(Some(0));
+        let handles: Vec<_> = (0..4).map(|t| {
+            let bt = Arc::clone(&bt);
+            std::thread::spawn(move || {
+                for i in 0..3000 {
+                    let key = i * 4 + t;
+                    bt.set(key, i);
+                    if i % 3 == 0 {
+                        assert_eq!(bt.remove(&key), Some(i));
+                    }
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        bt.set_version_horizon(None);
+        bt.gc();
+        let expected: Vec<i32> = (0..12000).filter(|key| (key / 4) % 3 != 0).collect();
+        assert_eq!(bt.iter().map(|(k, _)| k).collect::<Vec<_>>(), expected);
+        assert_eq!(bt.leaf_sizes().iter().sum::<usize>(), expected.len());
+    }
+
+    #[test]
+    fn concurrent_readers_share_the_tree_with_a_writer() {
+        let bt: Bptree<i32, i32> = Bptree::new(6);
+        for i in 0..1000 {
+            bt.set(i, i);
+        }
+        std::thread::scope(|scope| {
+            for _ in 0..6 {
+                let bt = &bt;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        let keys: Vec<i32> = bt.range(..1000).map(|(k, _)| k).collect();
+                        assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+                        for i in (0..1000).step_by(7) {
+                            assert_eq!(bt.get(&i), Some(i));
+                        }
+                    }
+                });
+            }
+            scope.spawn(|| {
+                for i in 1000..5000 {
+                    bt.set(i, i);
+                }
+            });
+        });
+        assert_eq!(bt.iter().count(), 5000);
+    }
+
+    // Writers on interleaved keys keep neighbouring leaves splitting and
+    // merging under each other while readers scan and gc advances the
+    // horizon. A lock-order violation shows up as the watchdog firing.
+    fn stress_mixed_workload(m: usize, keep_history: bool) {
+        use std::collections::BTreeMap;
+        use std::sync::{mpsc, Arc};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+
+        const WRITERS: i32 = 6;
+        let bt: Arc<Bptree<i32, i32>> = Arc::new(Bptree::new(m));
+        if keep_history {
+            bt.set_version_horizon(Some(0));
+        }
+        let (done_tx, done_rx) = mpsc::channel();
+        let mut handles = vec![];
+        for t in 0..WRITERS {
+            let bt = Arc::clone(&bt);
+            let done_tx = done_tx.clone();
+            handles.push(std::thread::spawn(move || {
+                let mut seed: u64 = 88172645463325252 + t as u64;
+                let mut model = BTreeMap::new();
+                for step in 0..4000 {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    let key = (seed % 300) as i32 * WRITERS + t;
+                    if seed.is_multiple_of(3) {
+                        assert_eq!(bt.remove(&key), model.remove(&key));
+                    }
+                    else {
+                        bt.set(key, step);
+                        model.insert(key, step);
+                    }
+                    assert_eq!(bt.get(&key), model.get(&key).cloned());
+                }
+                done_tx.send(()).unwrap();
+                model
+            }));
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut helpers = vec![];
+        for _ in 0..2 {
+            let (bt, stop) = (Arc::clone(&bt), Arc::clone(&stop));
+            helpers.push(std::thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    let keys: Vec<i32> = bt.iter().map(|(k, _)| k).collect();
+                    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                }
+            }));
+        }
+        {
+            let (bt, stop) = (Arc::clone(&bt), Arc::clone(&stop));
+            helpers.push(std::thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    if keep_history {
+                        bt.set_version_horizon(Some(bt.seq()));
+                    }
+                    bt.gc();
+                }
+            }));
+        }
+        for _ in 0..WRITERS {
+            done_rx.recv_timeout(Duration::from_secs(120)).expect("writers stalled, likely a deadlock");
+        }
+        stop.store(true, Ordering::SeqCst);
+        for handle in helpers {
+            handle.join().unwrap();
+        }
+
+        let mut expected = BTreeMap::new();
+        for handle in handles {
+            expected.append(&mut handle.join().unwrap());
+        }
+        bt.set_version_horizon(None);
+        bt.gc();
+        let expected: Vec<_> = expected.into_iter().collect();
+        assert_eq!(bt.check_invariants().violations, vec![]);
+        assert_eq!(bt.rank(&i32::MAX), expected.len());
+        if let Some(middle) = expected.get(expected.len() / 2) {
+            assert_eq!(bt.select(expected.len() / 2), Some(*middle));
+        }
+        assert_eq!(bt.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn concurrent_stress_without_history() {
+        for m in [3, 4, 7] {
+            stress_mixed_workload(m, false);
+        }
+    }
+
+    #[test]
+    fn concurrent_stress_with_history_and_gc() {
+        for m in [3, 5] {
+            stress_mixed_workload(m, true);
+        }
+    }
+
+    #[test]
+    fn auto_tune_measures_every_candidate_order() {
+        use crate::tune::{Op, CANDIDATE_ORDERS};
+
+        let mut sample = vec![];
+        for i in 0..2000 {
+            sample.push(Op::Set(i, i));
+            if i % 4 == 0 {
+                sample.push(Op::Get(i / 2));
+            }
+            if i % 10 == 0 {
+                sample.push(Op::Remove(i / 3));
+            }
+        }
+        let report = Bptree::auto_tune(&sample);
+        assert_eq!(report.trials.len(), CANDIDATE_ORDERS.len());
+        assert!(report.trials.windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+        assert!(CANDIDATE_ORDERS.contains(&report.best().m));
+        for trial in &report.trials {
+            assert!(trial.leaves > 0);
+            assert!(trial.fill > 0.0 && trial.fill <= 1.0);
+        }
+    }
+
+    #[test]
+    fn sharded_tree_writes_in_parallel_and_iterates_in_order() {
+        use crate::shard::ShardedBptree;
+
+        let sharded: ShardedBptree<i32, i32> = ShardedBptree::new(6, vec![3000, 1000, 2000, 2000]);
+        assert_eq!(sharded.shard_count(), 4);
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let sharded = &sharded;
+                scope.spawn(move || {
+                    for i in 0..1000 {
+                        sharded.set(t * 1000 + i, t);
+                    }
+                });
+            }
+        });
+        assert_eq!(sharded.get(&999), Some(0));
+        assert_eq!(sharded.get(&1000), Some(1));
+        assert_eq!(sharded.remove(&2500), Some(2));
+        assert_eq!(sharded.get(&2500), None);
+
+        let keys: Vec<i32> = sharded.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, (0..4000).filter(|&k| k != 2500).collect::<Vec<_>>());
+        let keys: Vec<i32> = sharded.range(995..=2004).map(|(k, _)| k).collect();
+        assert_eq!(keys, (995..=2004).collect::<Vec<_>>());
+        assert_eq!(sharded.range(3500..).count(), 500);
+    }
+
+    #[test]
+    fn range_reports_progress_and_stops_on_cancel() {
+        use std::ops::Bound;
+        use crate::iter::{CancelToken, ScanProgress};
+
+        let bt: Bptree<i32, i32> = Bptree::new(8);
+        for i in 0..1000 {
+            bt.set(i, i * 2);
+        }
+        assert_eq!(bt.range(10..20).collect::<Vec<_>>(), (10..20).map(|i| (i, i * 2)).collect::<Vec<_>>());
+        assert_eq!(bt.range((Bound::Excluded(997), Bound::Unbounded)).count(), 2);
+        assert_eq!(bt.iter().count(), 1000);
+
+        let mut reports: Vec<ScanProgress> = vec![];
+        let scanned = bt.range(100..900).on_progress(|p| reports.push(*p)).count();
+        assert_eq!(scanned, 800);
+        assert!(reports.len() > 1);
+        assert!(reports.windows(2).all(|w| w[0].visited < w[1].visited && w[0].fraction <= w[1].fraction));
+        assert_eq!(reports.last().unwrap().visited, 800);
+        assert_eq!(reports.last().unwrap().fraction, 1.0);
+
+        let token = CancelToken::new();
+        let mut seen = 0;
+        for (key, _) in bt.iter().with_cancel(token.clone()) {
+            seen += 1;
+            if key == 500 {
+                token.cancel();
+            }
+        }
+        assert!(seen > 500 && seen < 520);
+    }
+
+    #[test]
+    fn interrupted_purge_resumes() {
+        use std::cell::Cell;
+        use std::cmp::Ordering;
+        use std::panic::{self, AssertUnwindSafe};
+        use std::time::Instant;
+
+        thread_local!(static CMP_BUDGET: Cell<usize> = const { Cell::new(usize::MAX) });
+
+        // key whose comparisons start panicking once the budget runs out
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        struct Flaky(i32);
+        impl KVType for Flaky {}
+        impl PartialOrd for Flaky {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for Flaky {
+            fn cmp(&self, other: &Self) -> Ordering {
+                CMP_BUDGET.with(|budget| {
+                    assert!(budget.get() > 0, "comparison budget exhausted");
+                    budget.set(budget.get() - 1);
+                });
+                self.0.cmp(&other.0)
+            }
+        }
+
+        let bt: Bptree<Flaky, i32> = Bptree::new(4);
+        for i in 0..100 {
+            bt.set(Flaky(i), i);
+        }
+        bt.expire_range(Flaky(10)..Flaky(60), Instant::now());
+
+        CMP_BUDGET.with(|budget| budget.set(400));
+        let interrupted = panic::catch_unwind(AssertUnwindSafe(|| bt.purge_expired_ranges()));
+        assert!(interrupted.is_err());
+        CMP_BUDGET.with(|budget| budget.set(usize::MAX));
+
+        assert_eq!(bt.get(&Flaky(30)), None);
+        assert!(bt.purge_expired_ranges() > 0);
+        assert_eq!(bt.iter().count(), 50);
+        assert_eq!(bt.purge_expired_ranges(), 0);
+    }
+
+    #[test]
+    fn try_variants_give_up_on_held_latches() {
+        use std::time::Duration;
+        use crate::error::BptreeError;
+        use crate::trylock::LockError;
+
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for i in 0..100 {
+            bt.set(i, i);
+        }
+        // the range holds the first leaf latched exclusively until dropped
+        let held = bt.range_mut(0..1);
+        assert_eq!(bt.try_get(&0), Err(LockError::WouldBlock));
+        assert_eq!(bt.get_timeout(&0, Duration::from_millis(10)), Err(LockError::Timeout));
+        assert_eq!(bt.try_set(0, -1), Err(BptreeError::Lock(LockError::WouldBlock)));
+        assert_eq!(bt.set_timeout(0, -1, Duration::from_millis(10)), Err(BptreeError::Lock(LockError::Timeout)));
+        assert_eq!(bt.try_get(&50), Ok(Some(50)));
+        assert_eq!(bt.try_set(50, -50), Ok(()));
+        drop(held);
+
+        assert_eq!(bt.try_get(&0), Ok(Some(0)));
+        for i in 100..200 {
+            assert_eq!(bt.try_set(i, i), Ok(()));
+        }
+        assert_eq!(bt.get_timeout(&150, Duration::from_millis(10)), Ok(Some(150)));
+        assert_eq!(bt.get(&50), Some(-50));
+        assert_eq!(bt.check_invariants().violations, vec![]);
+    }
+
+    #[test]
+    fn check_invariants_reports_a_broken_tree() {
+        use crate::bptree::Corruption;
+        use crate::check::Violation;
+
+        let build = || {
+            let bt: Bptree<i32, i32> = Bptree::new(4);
+            for i in 0..100 {
+                bt.set(i * 7 % 100, i);
+            }
+            assert_eq!(bt.check_invariants().violations, vec![]);
+            bt
+        };
+        // the path to the first leaf
+        let first = vec![0; build().stats().height - 1];
+
+        let bt = build();
+        bt.corrupt(Corruption::Shrink{ leaf: 0 });
+        let keys = bt.leaf_sizes()[0];
+        assert_eq!(bt.check_invariants().violations, vec![Violation::Overfull{ path: first.clone(), keys, max: keys - 1 }]);
+
+        let bt = build();
+        bt.corrupt(Corruption::Unsort{ leaf: 0 });
+        assert_eq!(bt.check_invariants().violations, vec![Violation::UnsortedKeys{ path: first.clone() }]);
+
+        let bt = build();
+        bt.corrupt(Corruption::Unlink{ leaf: 0 });
+        assert_eq!(bt.check_invariants().violations, vec![Violation::BrokenLeafChain{ path: first.clone() }]);
+
+        let bt = build();
+        bt.corrupt(Corruption::Miscount{ delta: 3 });
+        let violations = bt.check_invariants().violations;
+        assert!(matches!(&violations[..], [Violation::CountMismatch{ path, recorded, actual }] if *path == [0] && *recorded == actual + 3), "{:?}", violations);
+
+        // leaves left short by lazy removals stay so once rebalancing is
+        // back on, inside the tree as at its edges
+        let bt = build();
+        bt.set_lazy_rebalance(true);
+        let sizes = bt.leaf_sizes();
+        let middle: i32 = sizes[..sizes.len() / 2].iter().sum::<usize>() as i32;
+        for key in middle..middle + sizes[sizes.len() / 2] as i32 - 1 {
+            assert!(bt.remove(&key).is_some());
+        }
+        assert_eq!(bt.check_invariants().violations, vec![]);
+        bt.set_lazy_rebalance(false);
+        let violations = bt.check_invariants().violations;
+        assert!(matches!(violations[..], [Violation::Underfull{ keys: 1, min: 2, .. }]), "{:?}", violations);
+        bt.rebalance();
+        assert_eq!(bt.check_invariants().violations, vec![]);
+    }
+
+    #[test]
+    fn heal_repairs_counts_a_panicking_hook_left_behind() {
+        use std::panic::{self, AssertUnwindSafe};
+        use std::sync::Arc;
+        use crate::check::Violation;
+        use crate::hook::IndexHook;
+
+        struct Refuses;
+        impl IndexHook<i32, i32> for Refuses {
+            fn on_remove(&self, _key: &i32, _old: &i32) {
+                panic!("index refused the removal");
+            }
+        }
+
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        bt.set_version_horizon(Some(0));
+        bt.add_hook(Arc::new(Refuses));
+        for i in 0..100 {
+            bt.set(i, i);
+        }
+        // the tombstone lands before the hook runs, the count change after
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| bt.remove(&42))).is_err());
+        assert_eq!(bt.get(&42), None);
+        assert_eq!(bt.iter().count(), 99);
+
+        let report = bt.heal();
+        assert!(report.violations.iter().any(|violation| matches!(violation, Violation::CountMismatch{..})));
+        assert_eq!(bt.check_invariants().violations, vec![]);
+        assert_eq!(bt.count_range(..), 99);
+        assert_eq!(bt.heal().violations, vec![]);
+        assert_eq!(bt.get(&41), Some(41));
+    }
+
+    #[test]
+    fn size_limits_refuse_oversized_entries() {
+        use std::panic::{self, AssertUnwindSafe};
+        use crate::limits::SizeError;
+        use crate::error::BptreeError;
+
+        let bt = Bptree::<String, Vec<u8>>::builder().order(4).max_key_len(8).max_value_len(16).build().unwrap();
+        assert_eq!(bt.set_checked("short".to_string(), vec![0; 16]), Ok(()));
+        let err = bt.set_checked("much too long".to_string(), vec![]).unwrap_err();
+        assert_eq!(err, BptreeError::Size(SizeError::KeyTooLarge{ len: 13, max: 8 }));
+        assert_eq!(err.to_string(), "key of 13 bytes exceeds the limit of 8");
+        assert_eq!(bt.set_checked("blob".to_string(), vec![0; 17]), Err(BptreeError::Size(SizeError::ValueTooLarge{ len: 17, max: 16 })));
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| bt.set("blob".to_string(), vec![0; 17]))).is_err());
+        assert_eq!(bt.get(&"blob".to_string()), None);
+
+        // every write that returns a Result reports the limit instead
+        let too_large = Err(BptreeError::Size(SizeError::ValueTooLarge{ len: 17, max: 16 }));
+        assert_eq!(bt.try_set("blob".to_string(), vec![0; 17]), too_large);
+        assert_eq!(bt.set_timeout("blob".to_string(), vec![0; 17], std::time::Duration::from_secs(1)), too_large);
+        assert_eq!(bt.try_insert("blob".to_string(), vec![0; 17]), too_large);
+        assert_eq!(bt.compare_and_swap(&"blob".to_string(), None, Some(vec![0; 17])), too_large);
+        let mut cursor = bt.cursor(&"short".to_string());
+        assert_eq!(cursor.insert_after("sick".to_string(), vec![0; 17]), too_large);
+        assert_eq!(cursor.current(), Some((&"short".to_string(), &vec![0; 16])));
+        let mut batch = crate::batch::WriteBatch::new();
+        batch.put("fine".to_string(), vec![]).put("blob".to_string(), vec![0; 17]);
+        assert_eq!(bt.apply(batch), too_large);
+        assert_eq!(bt.iter().count(), 1);
+
+        bt.set_max_value_len(None);
+        bt.set("blob".to_string(), vec![0; 17]);
+        assert_eq!(bt.iter().count(), 2);
+    }
+
+    #[test]
+    fn byte_budget_evicts_once_exceeded() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use crate::budget::Eviction;
+
+        // every entry is two 8-byte integers
+        let bt: Bptree<u64, u64> = Bptree::new(4);
+        for i in 0..5 {
+            bt.set(i, i);
+        }
+        bt.set_byte_budget(160, Eviction::LowestKeys);
+        assert_eq!(bt.byte_usage(), Some(80));
+        for i in 5..100 {
+            bt.set(i, i);
+        }
+        assert_eq!(bt.byte_usage(), Some(160));
+        assert_eq!(bt.iter().map(|(key, _)| key).collect::<Vec<_>>(), (90..100).collect::<Vec<_>>());
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = calls.clone();
+        bt.set_byte_budget(320, Eviction::Callback(Arc::new(move |tree: &Bptree<u64, u64>, over| {
+            seen.fetch_add(1, Ordering::SeqCst);
+            assert_eq!(over, 16);
+            tree.remove(&tree.iter().next_back().unwrap().0);
+        })));
+        for i in 0..15 {
+            bt.set(i, i);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+        assert_eq!(bt.byte_usage(), Some(320));
+        assert_eq!(bt.get(&90), Some(90));
+        assert_eq!(bt.get(&99), None);
+    }
+
+    #[test]
+    fn update_with_changes_values_in_place() {
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for i in 0..100 {
+            bt.set(i, i);
+        }
+        let before = bt.seq();
+        assert!(bt.update_with(&42, |val| *val += 1000));
+        assert_eq!(bt.get(&42), Some(1042));
+        assert!(!bt.update_with(&1000, |_| unreachable!()));
+        assert!(bt.seq() > before);
+
+        bt.set_version_horizon(Some(0));
+        let before = bt.seq();
+        assert!(bt.update_with(&7, |val| *val = -7));
+        assert_eq!(bt.get(&7), Some(-7));
+        assert_eq!(bt.get_at(&7, before), Some(7));
+        bt.remove(&7);
+        assert!(!bt.update_with(&7, |_| unreachable!()));
+        assert_eq!(bt.count_range(..), 99);
+    }
+
+    #[test]
+    fn compare_and_swap_only_writes_over_the_expected_value() {
+        use std::thread;
+        use crate::cas::CasError;
+        use crate::error::BptreeError;
+
+        for horizon in [None, Some(0)] {
+            let bt: Bptree<i32, i32> = Bptree::new(4);
+            bt.set_version_horizon(horizon);
+            assert_eq!(bt.compare_and_swap(&1, Some(&1), Some(2)), Err(BptreeError::Mismatch(CasError{ current: None })));
+            assert_eq!(bt.compare_and_swap(&1, None, None), Ok(()));
+            for i in 0..100 {
+                assert_eq!(bt.compare_and_swap(&i, None, Some(i)), Ok(()));
+            }
+            assert_eq!(bt.compare_and_swap(&5, None, Some(0)), Err(BptreeError::Mismatch(CasError{ current: Some(5) })));
+            assert_eq!(bt.compare_and_swap(&5, Some(&4), None), Err(BptreeError::Mismatch(CasError{ current: Some(5) })));
+            assert_eq!(bt.compare_and_swap(&5, Some(&5), Some(50)), Ok(()));
+            assert_eq!(bt.get(&5), Some(50));
+            for i in (0..100).step_by(2) {
+                assert_eq!(bt.compare_and_swap(&i, bt.get(&i).as_ref(), None), Ok(()));
+            }
+            assert_eq!(bt.compare_and_swap(&6, Some(&6), Some(6)), Err(BptreeError::Mismatch(CasError{ current: None })));
+            assert_eq!(bt.count_range(..), 50);
+            assert_eq!(bt.check_invariants().violations, vec![]);
+        }
+
+        // a counter bumped by racing threads loses no increment
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        bt.set(0, 0);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..250 {
+                        let mut seen = bt.get(&0);
+                        while let Err(BptreeError::Mismatch(CasError{ current })) = bt.compare_and_swap(&0, seen.as_ref(), seen.map(|n| n + 1)) {
+                            seen = current;
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(bt.get(&0), Some(1000));
+    }
+
+    #[test]
+    fn merges_fold_operands_into_values() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let bt: Bptree<u64, u64> = Bptree::new(4);
+        bt.set_merge_operator(Arc::new(|_key: &u64, old: Option<&u64>, add: &u64| old.unwrap_or(&0) + add));
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..500 {
+                        bt.merge(i % 50, 1);
+                    }
+                });
+            }
+        });
+        assert!((0..50).all(|key| bt.get(&key) == Some(40)));
+        assert_eq!(bt.count_range(..), 50);
+        assert_eq!(bt.check_invariants().violations, vec![]);
+
+        bt.remove(&7);
+        bt.merge(7, 5);
+        assert_eq!(bt.get(&7), Some(5));
+    }
+
+    #[test]
+    fn remove_if_checks_the_value_it_removes() {
+        for horizon in [None, Some(0)] {
+            let bt: Bptree<i32, i32> = Bptree::new(4);
+            bt.set_version_horizon(horizon);
+            for i in 0..100 {
+                bt.set(i, i % 3);
+            }
+            for i in 0..100 {
+                let removed = bt.remove_if(&i, |val| *val == 0);
+                assert_eq!(removed, if i % 3 == 0 { Some(0) } else { None });
+            }
+            assert_eq!(bt.remove_if(&0, |_| unreachable!()), None);
+            assert_eq!(bt.remove_if(&1000, |_| unreachable!()), None);
+            assert_eq!(bt.count_range(..), 66);
+            assert!(bt.iter().all(|(_, val)| val != 0));
+            assert_eq!(bt.check_invariants().violations, vec![]);
+        }
+    }
+
+    #[test]
+    fn try_insert_never_overwrites() {
+        use crate::cas::OccupiedError;
+        use crate::error::BptreeError;
+
+        let bt: Bptree<i32, &str> = Bptree::new(4);
+        for i in 0..50 {
+            assert_eq!(bt.try_insert(i, "first"), Ok(()));
+        }
+        let err = bt.try_insert(7, "second").unwrap_err();
+        assert_eq!(err, BptreeError::Occupied(OccupiedError{ key: 7, current: "first", val: "second" }));
+        assert_eq!(err.to_string(), "key 7 is already present");
+        assert_eq!(bt.get(&7), Some("first"));
+
+        bt.remove(&7);
+        assert_eq!(bt.try_insert(7, "second"), Ok(()));
+        assert_eq!(bt.get(&7), Some("second"));
+        assert_eq!(bt.count_range(..), 50);
+    }
+
+    #[test]
+    fn set_keys_carry_no_history_allocation() {
+        use std::mem::size_of;
+        use crate::version::Versions;
+
+        // what `BptreeSet` documents per key, next to the key itself, and
+        // what a map entry without history or deadline takes
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!((size_of::<Versions<()>>(), size_of::<Versions<u64>>()), (16, 24));
+        let mut versions = Versions::new(1, ());
+        assert_eq!(versions.history_bytes(), 0);
+        // rewriting without a horizon keeps no history
+        versions.push(2, Some(()), None);
+        assert_eq!(versions.history_bytes(), 0);
+        // a deadline takes room until the write that drops it
+        versions.set_deadline(Some(std::time::Instant::now()));
+        assert!(versions.history_bytes() > 0);
+        versions.push(3, Some(()), None);
+        assert_eq!((versions.history_bytes(), versions.deadline()), (0, None));
+        // a horizon keeps history until no read can see it any more
+        versions.push(4, None, Some(3));
+        assert!(versions.history_bytes() > 0);
+        assert_eq!(versions.at(3), Some(&()));
+        assert_eq!(versions.latest_seq(), 4);
+        versions.prune(4);
+        assert_eq!(versions.history_bytes(), 0);
+        assert!(versions.is_dead(Some(4)));
+    }
+
+    #[test]
+    fn sets_combine_in_order() {
+        use crate::set::BptreeSet;
+
+        let odd = BptreeSet::new(4);
+        let thirds = BptreeSet::new(4);
+        assert!(odd.is_empty());
+        for i in 0..100u64 {
+            if i % 2 == 1 {
+                assert!(odd.insert(i));
+            }
+            if i % 3 == 0 {
+                assert!(thirds.insert(i));
+            }
+        }
+        assert!(!odd.insert(1));
+        assert!(odd.contains(&1) && !odd.contains(&2));
+        assert_eq!(odd.len(), 50);
+        assert_eq!(odd.range(10..20).collect::<Vec<_>>(), vec![11, 13, 15, 17, 19]);
+
+        let union: Vec<u64> = odd.union(&thirds).collect();
+        assert_eq!(union, (0..100).filter(|i| i % 2 == 1 || i % 3 == 0).collect::<Vec<_>>());
+        let both: Vec<u64> = odd.intersection(&thirds).collect();
+        assert_eq!(both, (0..100).filter(|i| i % 6 == 3).collect::<Vec<_>>());
+
+        assert!(odd.remove(&3));
+        assert!(!odd.remove(&3));
+        assert_eq!(odd.intersection(&thirds).next(), Some(9));
+    }
+
+    #[test]
+    fn duplicate_policies_govern_set() {
+        use std::panic::{self, AssertUnwindSafe};
+        use crate::batch::WriteBatch;
+        use crate::builder::DuplicatePolicy;
+        use crate::cas::OccupiedError;
+        use crate::error::BptreeError;
+
+        for policy in [DuplicatePolicy::Overwrite, DuplicatePolicy::Error, DuplicatePolicy::KeepFirst] {
+            let bt = Bptree::<i32, i32>::builder().order(4).duplicates(policy).build().unwrap();
+            for i in 0..50 {
+                bt.set(i, i);
+            }
+            let second = panic::catch_unwind(AssertUnwindSafe(|| bt.set(7, -7)));
+            assert_eq!(second.is_err(), policy == DuplicatePolicy::Error);
+            let expected = if policy == DuplicatePolicy::Overwrite { -7 } else { 7 };
+            assert_eq!(bt.get(&7), Some(expected));
+
+            // the fallible writes report a refused duplicate instead
+            let refused = policy == DuplicatePolicy::Error;
+            assert_eq!(bt.try_set(6, -6).is_err(), refused);
+            assert_eq!(bt.set_timeout(5, -5, std::time::Duration::from_secs(1)).is_err(), refused);
+            if refused {
+                assert_eq!(bt.try_set(6, -6), Err(BptreeError::Occupied(OccupiedError{ key: 6, current: 6, val: -6 })));
+                assert_eq!(bt.set_checked(4, -4), Err(BptreeError::Occupied(OccupiedError{ key: 4, current: 4, val: -4 })));
+                assert_eq!(bt.get(&4), Some(4));
+                assert_eq!(bt.set_checked(60, 60), Ok(()));
+                bt.remove(&60);
+            }
+            else {
+                assert_eq!(bt.set_checked(4, -4), Ok(()));
+            }
+            assert_eq!(bt.get(&6), Some(if policy == DuplicatePolicy::Overwrite { -6 } else { 6 }));
+
+            // a batch's puts meet its own earlier operations, and under
+            // Error one refused put refuses the whole batch
+            let seq = bt.seq();
+            let mut batch = WriteBatch::new();
+            batch.put(70, 1).put(70, 2).delete(71).put(71, 3).put(3, -3);
+            let applied = bt.apply(batch);
+            let expected = match policy {
+                DuplicatePolicy::Overwrite => [Some(2), Some(3), Some(-3)],
+                DuplicatePolicy::KeepFirst => [Some(1), Some(3), Some(3)],
+                _ => {
+                    assert_eq!(applied, Err(BptreeError::Occupied(OccupiedError{ key: 70, current: 1, val: 2 })));
+                    assert_eq!(bt.seq(), seq);
+                    [None, None, Some(3)]
+                },
+            };
+            assert_eq!(applied.is_ok(), !refused);
+            assert_eq!([bt.get(&70), bt.get(&71), bt.get(&3)], expected);
+            let mut txn = bt.begin();
+            txn.set(2, -2);
+            txn.set(72, 72);
+            assert_eq!(txn.commit().is_err(), refused);
+            assert_eq!(bt.get(&2), Some(if policy == DuplicatePolicy::Overwrite { -2 } else { 2 }));
+            assert_eq!(bt.get(&72), if refused { None } else { Some(72) });
+            for key in [70, 71, 72] {
+                bt.remove(&key);
+            }
+
+            // appended entries take the place of those here either way, by
+            // the entry or by hanging the other tree off this one
+            let mut other = Bptree::<i32, i32>::builder().order(4).duplicates(policy).build().unwrap();
+            for i in 40..60 {
+                other.set(i, -i);
+            }
+            bt.append(&mut other);
+            assert_eq!(bt.get(&45), Some(-45));
+            let mut other = Bptree::<i32, i32>::builder().order(4).duplicates(policy).build().unwrap();
+            other.set(60, 60);
+            bt.append(&mut other);
+            assert_eq!(bt.get(&60), Some(60));
+
+            // a removed key is free again
+            bt.remove(&8);
+            bt.set(8, -8);
+            assert_eq!(bt.get(&8), Some(-8));
+            assert_eq!(bt.count_range(..), 61);
+            assert_eq!(bt.check_invariants().violations, vec![]);
+        }
+
+        // keeping every value takes a multimap
+        use crate::builder::ConfigError;
+        let multi = Bptree::<i32, i32>::builder().order(4).duplicates(DuplicatePolicy::Multi);
+        assert_eq!(multi.clone().build().unwrap_err(), ConfigError::MultiNeedsMultimap);
+        let mm = multi.max_key_len(4).build_multimap().unwrap();
+        for i in 0..50 {
+            mm.insert(i % 10, i);
+        }
+        assert_eq!(mm.get_all(&7).collect::<Vec<_>>(), vec![7, 17, 27, 37, 47]);
+        assert_eq!(mm.iter().count(), 50);
+    }
+
+    #[test]
+    fn get_many_mut_moves_quantities_atomically() {
+        use std::panic::{self, AssertUnwindSafe};
+        use std::thread;
+
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for i in 0..20 {
+            bt.set(i, 100);
+        }
+        // transfers between accounts in neighbouring and distant leaves
+        thread::scope(|scope| {
+            for t in 0..4 {
+                let bt = &bt;
+                scope.spawn(move || {
+                    for n in 0..200 {
+                        let (from, to) = ((n + t) % 20, (n * 7 + t + 1) % 20);
+                        if from == to {
+                            continue;
+                        }
+                        let mut accounts = bt.get_many_mut([&to, &from]).unwrap();
+                        let [to_val, from_val] = accounts.values();
+                        *from_val -= 1;
+                        *to_val += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(bt.iter().map(|(_, val)| val).sum::<i32>(), 2000);
+        assert!(bt.get_many_mut([&1, &100]).is_none());
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| bt.get_many_mut([&1, &1]).is_none())).is_err());
+
+        bt.set_version_horizon(Some(0));
+        let before = bt.seq();
+        let mut both = bt.get_many_mut([&3, &4]).unwrap();
+        let [a, b] = both.values();
+        let (a, b) = (std::mem::replace(a, -1), *b);
+        drop(both);
+        assert_eq!(bt.get(&3), Some(-1));
+        assert_eq!(bt.get_at(&3, before), Some(a));
+        assert_eq!(bt.get(&4), Some(b));
+    }
+
+    #[test]
+    fn descending_trees_start_from_the_largest_key() {
+        use crate::desc::BptreeDesc;
+
+        let scores: BptreeDesc<u64, &str> = BptreeDesc::new(4);
+        for score in 0..50 {
+            scores.set(score * 10, "player");
+        }
+        assert_eq!(scores.iter().next(), Some((490, "player")));
+        assert_eq!(scores.range(100..=130).map(|(key, _)| key).collect::<Vec<_>>(), vec![130, 120, 110, 100]);
+        assert_eq!(scores.range(..30).map(|(key, _)| key).collect::<Vec<_>>(), vec![20, 10, 0]);
+        assert_eq!(scores.pop_first(), Some((490, "player")));
+        assert_eq!(scores.pop_last(), Some((0, "player")));
+        assert_eq!(scores.get(&480), Some("player"));
+        assert_eq!(scores.remove(&480), Some("player"));
+        assert_eq!(scores.len(), 47);
+        assert_eq!(scores.iter().next_back(), Some((10, "player")));
+
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        assert_eq!(bt.pop_first(), None);
+        for i in 0..10 {
+            bt.set(i, i);
+        }
+        assert_eq!(bt.pop_first(), Some((0, 0)));
+        assert_eq!(bt.pop_last(), Some((9, 9)));
+        assert_eq!(bt.count_range(..), 8);
+    }
+
+    #[test]
+    fn range_size_estimates_skip_the_purge() {
+        use std::time::{Duration, Instant};
+
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for i in 0..100 {
+            bt.set(i, i);
+        }
+        assert_eq!(bt.estimate_range_size(10..20), 10);
+        bt.expire_range(0..50, Instant::now() - Duration::from_secs(1));
+        assert_eq!(bt.estimate_range_size(..), 100);
+        assert_eq!(bt.count_range(..), 50);
+        assert_eq!(bt.estimate_range_size(..), 50);
+    }
+
+    #[test]
+    fn samples_spread_over_the_whole_tree() {
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        assert!(bt.sample(10, || 0).is_empty());
+        for i in 0..1000 {
+            bt.set(i, i);
+        }
+        // xorshift, seeded
+        let mut state = 0x2545f4914f6cdd1du64;
+        let keys = bt.sample(400, || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        });
+        assert_eq!(keys.len(), 400);
+        assert!(keys.windows(2).all(|pair| pair[0] <= pair[1]));
+        for decile in 0..10 {
+            let hits = keys.iter().filter(|&&key| key / 100 == decile).count();
+            assert!((15..=65).contains(&hits), "decile {} drew {} keys", decile, hits);
+        }
+        assert_eq!(bt.sample(3, || u64::MAX), vec![999, 999, 999]);
+    }
+
+    #[test]
+    fn scan_after_pages_through_the_tree() {
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        assert_eq!(bt.scan_after(None, 10), (vec![], None));
+        for i in 0..95 {
+            bt.set(i, i);
+        }
+        bt.remove(&50);
+        let mut pages = 0;
+        let mut seen = vec![];
+        let mut after = None;
+        loop {
+            let (page, next) = bt.scan_after(after.as_ref(), 10);
+            assert!(page.len() <= 10);
+            seen.extend(page);
+            pages += 1;
+            match next {
+                Some(key) => after = Some(key),
+                None => break,
+            }
+        }
+        assert_eq!(pages, 10);
+        assert_eq!(seen, (0..95).filter(|&i| i != 50).map(|i| (i, i)).collect::<Vec<_>>());
+        assert_eq!(bt.scan_after(Some(&89), 5), ((90..95).map(|i| (i, i)).collect(), None));
+    }
+
+    #[test]
+    fn scans_resume_from_serialized_tokens() {
+        use crate::iter::ResumeToken;
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        for i in 0..60 {
+            bt.set(i, i);
+        }
+        let mut scan = bt.range(10..50);
+        let front: Vec<_> = scan.by_ref().take(7).collect();
+        assert_eq!(front.last(), Some(&(16, 16)));
+        assert_eq!(scan.next_back(), Some((49, 49)));
+        let bytes = bincode::serialize(&scan.token()).unwrap();
+        drop(scan);
+        bt.set(5, 5);
+        bt.remove(&30);
+        let token: ResumeToken<i32> = bincode::deserialize(&bytes).unwrap();
+        let rest: Vec<_> = bt.resume(token).map(|(key, _)| key).collect();
+        assert_eq!(rest, (17..49).filter(|&i| i != 30).collect::<Vec<_>>());
+        let mut scan = bt.iter();
+        scan.by_ref().for_each(drop);
+        assert_eq!(bt.resume(scan.token()).count(), 0);
+    }
+
+    #[test]
+    fn aggregates_track_every_kind_of_write() {
+        let bt: Bptree<i32, i64> = Bptree::new(4);
+        bt.set_aggregate(0i64, |_, val: &i64| *val, |a: &i64, b: &i64| a + b);
+        let mut model = std::collections::BTreeMap::new();
+        let mut state = 7u64;
+        let mut next = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) as i32
+        };
+        for round in 0..600 {
+            let key = next() % 200;
+            match round % 6 {
+                0 | 1 => {
+                    bt.set(key, key as i64);
+                    model.insert(key, key as i64);
+                },
+                2 => {
+                    bt.remove(&key);
+                    model.remove(&key);
+                },
+                3 => {
+                    bt.update_with(&key, |val| *val += 5);
+                    if let Some(val) = model.get_mut(&key) {
+                        *val += 5;
+                    }
+                },
+                4 => {
+                    let mut values = bt.range_mut(key..key + 10);
+                    while let Some((_, val)) = values.next() {
+                        *val -= 1;
+                    }
+                    for (_, val) in model.range_mut(key..key + 10) {
+                        *val -= 1;
+                    }
+                },
+                _ => {
+                    bt.remove_range(key..key + 3);
+                    model.retain(|k, _| !(key..key + 3).contains(k));
+                },
+            }
+            let (lo, hi) = (next() % 220 - 10, next() % 220 - 10);
+            let expected: i64 = model.range(lo.min(hi)..=lo.max(hi)).map(|(_, val)| val).sum();
+            assert_eq!(bt.aggregate_range::<i64, _>(lo.min(hi)..=lo.max(hi)), expected);
+            assert_eq!(bt.aggregate_range::<i64, _>(..), model.values().sum::<i64>());
+        }
+        assert!(bt.check_invariants().is_ok());
+
+        // a combine that does not commute still sees the entries in order
+        bt.set_aggregate(vec![], |key, _| vec![*key], |a: &Vec<i32>, b: &Vec<i32>| [a.as_slice(), b].concat());
+        let keys: Vec<i32> = model.range(50..150).map(|(key, _)| *key).collect();
+        assert_eq!(bt.aggregate_range::<Vec<i32>, _>(50..150), keys);
+        assert!(bt.aggregate_range::<Vec<i32>, _>(1000..).is_empty());
+    }
+
+    #[test]
+    fn top_k_by_value_ranks_a_slice_of_keys() {
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        bt.set_value_ranking(3, |a, b| a.cmp(b));
+        for i in 0..100 {
+            bt.set(i, (i * 37) % 101);
+        }
+        bt.remove(&12);
+        let mut expected: Vec<_> = (10..60).filter(|&i| i != 12).map(|i| (i, (i * 37) % 101)).collect();
+        expected.sort_by_key(|&(_, val)| std::cmp::Reverse(val));
+        // within the cached width and past it
+        for k in [2, 3, 5, 49, 60] {
+            let mut expected = expected.clone();
+            expected.truncate(k);
+            assert_eq!(bt.top_k_by_value(10..60, k), expected);
+        }
+        assert!(bt.top_k_by_value(.., 0).is_empty());
+
+        bt.set_value_ranking(3, |a, b| b.cmp(a));
+        let smallest = bt.top_k_by_value(.., 3);
+        assert_eq!(smallest.iter().map(|(_, val)| *val).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        // ties keep key order
+        let ties: Bptree<i32, i32> = Bptree::new(4);
+        ties.set_value_ranking(2, |a, b| a.cmp(b));
+        for i in 0..20 {
+            ties.set(i, i % 2);
+        }
+        assert_eq!(ties.top_k_by_value(5.., 3), vec![(5, 1), (7, 1), (9, 1)]);
+        assert_eq!(ties.top_k_by_value(..=8, 2), vec![(1, 1), (3, 1)]);
+    }
+
+    #[test]
+    fn top_k_by_value_passes_over_subtrees() {
+        use crate::bptree::LEAVES_RANKED;
+        let ranked = || LEAVES_RANKED.with(|ranked| ranked.get());
+        let expect = |bt: &Bptree<i32, i32>, start: i32, end: i32, k: usize| {
+            let mut all: Vec<_> = bt.range(start..end).collect();
+            all.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            all.truncate(k);
+            all
+        };
+
+        let bt: Bptree<i32, i32> = Bptree::new(8);
+        bt.set_value_ranking(4, |a, b| a.cmp(b));
+        for i in 0..10000 {
+            bt.set(i, (i * 7919) % 10007);
+        }
+        let leaves = bt.stats().leaf_nodes;
+        assert_eq!(bt.top_k_by_value(.., 4), expect(&bt, 0, 10000, 4));
+
+        // with the caches warm only the leaves at the ends of the range
+        // are read
+        let before = ranked();
+        assert_eq!(bt.top_k_by_value(.., 4), expect(&bt, 0, 10000, 4));
+        assert_eq!(ranked() - before, 0);
+        let before = ranked();
+        assert_eq!(bt.top_k_by_value(123..9876, 3), expect(&bt, 123, 9876, 3));
+        assert!(ranked() - before <= 2);
+
+        // past the cached width subtrees are walked into, but only those
+        // whose best can still make the cut
+        let before = ranked();
+        assert_eq!(bt.top_k_by_value(.., 20), expect(&bt, 0, 10000, 20));
+        assert!(ranked() - before < leaves / 10, "{} of {} leaves read", ranked() - before, leaves);
+
+        // writes retire the caches along their path only
+        bt.set(5000, 20000);
+        bt.remove(&(bt.top_k_by_value(.., 1)[0].0 + 1));
+        let before = ranked();
+        assert_eq!(bt.top_k_by_value(.., 4), expect(&bt, 0, 10000, 4));
+        assert!(ranked() - before <= 2);
+        // and off-path writes retire them all
+        let mut range = bt.range_mut(0..10);
+        while let Some((_, val)) = range.next() {
+            *val = 30000;
+        }
+        drop(range);
+        assert_eq!(bt.top_k_by_value(..100, 4), expect(&bt, 0, 100, 4));
+        assert_eq!(bt.top_k_by_value(.., 4)[0], (0, 30000));
+    }
+
+    #[test]
+    fn watchers_hear_changes_in_their_range() {
+        use std::sync::Arc;
+        use crate::watch::ChangeEvent;
+        let bt: Bptree<i32, i32> = Bptree::new(4);
+        bt.set(10, 0);
+        let events = bt.watch(10..20);
+        bt.set(10, 1);
+        bt.set(15, 2);
+        bt.set(25, 3);
+        bt.remove(&15);
+        bt.remove(&5);
+        bt.update_with(&10, |val| *val += 1);
+        bt.remove_range(0..30);
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![
+            ChangeEvent::Update{ key: 10, old: 0, new: 1 },
+            ChangeEvent::Insert{ key: 15, val: 2 },
+            ChangeEvent::Remove{ key: 15, old: 2 },
+            ChangeEvent::Update{ key: 10, old: 1, new: 2 },
+            ChangeEvent::Remove{ key: 10, old: 2 },
+        ]);
+
+        let writer = {
+            let bt = Arc::new(bt);
+            let watched = bt.watch(..);
+            let handle = std::thread::spawn({
+                let bt = bt.clone();
+                move || (0..100).for_each(|i| bt.set(i, i))
+            });
+            assert_eq!(watched.iter().take(100).count(), 100);
+            handle.join().unwrap();
+            bt
+        };
+        // a dropped receiver retires its watch, and the next watch set up
+        // unregisters it
+        writer.set(11, 11);
+        let late = writer.watch(11..12);
+        writer.set(11, 12);
+        assert_eq!(late.try_recv(), Ok(ChangeEvent::Update{ key: 11, old: 11, new: 12 }));
+    }
+
+    #[test]
+    fn change_log_can_be_tailed_from_any_position() {
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+        use crate::changelog::LogTruncated;
+        use crate::watch::ChangeEvent;
+        let bt = Arc::new(Bptree::<i32, i32>::new(4));
+        bt.set(1, 0);
+        let log = bt.change_log(8);
+        assert_eq!(log.next_lsn(), 1);
+        bt.set(1, 1);
+        bt.set(2, 2);
+        bt.remove(&1);
+        let changes = log.read_from(1, 10).unwrap();
+        assert_eq!(changes.iter().map(|change| change.lsn).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(changes[0].event, ChangeEvent::Update{ key: 1, old: 0, new: 1 });
+        assert_eq!(changes[2].event, ChangeEvent::Remove{ key: 1, old: 1 });
+        assert_eq!(log.read_from(3, 10).unwrap().len(), 1);
+        assert!(log.read_from(4, 10).unwrap().is_empty());
+
+        for i in 0..10 {
+            bt.set(i, i);
+        }
+        assert_eq!(log.read_from(1, 10), Err(LogTruncated{ oldest: 6 }));
+        assert_eq!(log.read_from(6, 3).unwrap().first().map(|change| change.lsn), Some(6));
+
+        assert!(!log.wait_for(14, Instant::now() + Duration::from_millis(10)));
+        let writer = std::thread::spawn({
+            let bt = bt.clone();
+            move || bt.set(100, 100)
+        });
+        assert!(log.wait_for(14, Instant::now() + Duration::from_secs(10)));
+        writer.join().unwrap();
+        assert_eq!(log.read_from(14, 1).unwrap()[0].event, ChangeEvent::Insert{ key: 100, val: 100 });
+    }
+
 }
@@ -0,0 +1,73 @@
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use super::kvtype::KVType;
+
+/// Why a write was refused by the size limits of a tree, with sizes in
+/// `KVType::byte_len` bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SizeError {
+    KeyTooLarge{ len: usize, max: usize },
+    ValueTooLarge{ len: usize, max: usize },
+}
+
+impl Display for SizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KeyTooLarge{ len, max } => write!(f, "key of {} bytes exceeds the limit of {}", len, max),
+            Self::ValueTooLarge{ len, max } => write!(f, "value of {} bytes exceeds the limit of {}", len, max),
+        }
+    }
+}
+
+impl std::error::Error for SizeError {}
+
+// Largest key and value a tree accepts, `usize::MAX` when unlimited.
+#[derive(Debug)]
+pub(crate) struct SizeLimits {
+    key: AtomicUsize,
+    val: AtomicUsize,
+}
+
+impl SizeLimits {
+    pub(crate) fn new(key: Option<usize>, val: Option<usize>) -> Self {
+        Self{
+            key: AtomicUsize::new(key.unwrap_or(usize::MAX)),
+            val: AtomicUsize::new(val.unwrap_or(usize::MAX)),
+        }
+    }
+
+    pub(crate) fn key(&self) -> Option<usize> {
+        Some(self.key.load(Ordering::SeqCst)).filter(|&max| max != usize::MAX)
+    }
+
+    pub(crate) fn val(&self) -> Option<usize> {
+        Some(self.val.load(Ordering::SeqCst)).filter(|&max| max != usize::MAX)
+    }
+
+    pub(crate) fn set_key(&self, max: Option<usize>) {
+        self.key.store(max.unwrap_or(usize::MAX), Ordering::SeqCst);
+    }
+
+    pub(crate) fn set_val(&self, max: Option<usize>) {
+        self.val.store(max.unwrap_or(usize::MAX), Ordering::SeqCst);
+    }
+
+    pub(crate) fn check<K: KVType, V: KVType>(&self, key: &K, val: &V) -> Result<(), SizeError> {
+        let (len, max) = (key.byte_len(), self.key.load(Ordering::SeqCst));
+        if len > max {
+            return Err(SizeError::KeyTooLarge{ len, max });
+        }
+        let (len, max) = (val.byte_len(), self.val.load(Ordering::SeqCst));
+        if len > max {
+            return Err(SizeError::ValueTooLarge{ len, max });
+        }
+        Ok(())
+    }
+
+    // For writes that have no way to report the error.
+    pub(crate) fn assert<K: KVType, V: KVType>(&self, key: &K, val: &V) {
+        if let Err(err) = self.check(key, val) {
+            panic!("{}", err);
+        }
+    }
+}
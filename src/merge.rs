@@ -0,0 +1,21 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+/// Folds an operand passed to `Bptree::merge` into the value a key holds,
+/// `None` if it holds none, giving the key's new value.
+pub type MergeFn<K, V> = Arc<dyn Fn(&K, Option<&V>, &V) -> V + Send + Sync>;
+
+// The merge function registered on a tree.
+pub(crate) struct MergeOperator<K, V>(pub(crate) MergeFn<K, V>);
+
+impl<K, V> Clone for MergeOperator<K, V> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<K, V> Debug for MergeOperator<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MergeOperator").finish()
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU64, Ordering};
+use super::bptree::Bptree;
+use super::kvtype::KVType;
+
+/// A tree that keeps every value inserted under a key instead of
+/// overwriting. Each pair is stored under `(key, n)`, where `n` counts
+/// insertions, so the values of one key sit next to each other in
+/// insertion order. Splits, merges and range removals only ever move
+/// entries as ordered runs, and `append` numbers the pairs it brings in
+/// after every pair already here, so that order holds for the life of the
+/// tree.
+#[derive(Debug)]
+pub struct BptreeMultimap<K, V> {
+    tree: Bptree<(K, u64), V>,
+    inserted: AtomicU64,
+}
+
+impl<K, V> BptreeMultimap<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    pub fn new(m: usize) -> Self {
+        Self::from_tree(Bptree::new(m))
+    }
+
+    // Around an empty tree, as built by `BptreeBuilder::build_multimap`.
+    pub(crate) fn from_tree(tree: Bptree<(K, u64), V>) -> Self {
+        Self{
+            tree,
+            inserted: AtomicU64::new(0),
+        }
+    }
+
+    pub fn insert(&self, key: K, val: V) {
+        let n = self.inserted.fetch_add(1, Ordering::SeqCst);
+        self.tree.set((key, n), val);
+    }
+
+    /// Values stored under `key`, oldest first: always in the order they
+    /// were inserted, however the tree split, merged or had ranges removed
+    /// since. Values brought in by `append` follow those already here, in
+    /// the order they had in the other multimap.
+    pub fn get_all(&self, key: &K) -> impl Iterator<Item = V> + '_ {
+        self.tree.range((key.clone(), 0)..=(key.clone(), u64::MAX)).map(|(_, val)| val)
+    }
+
+    /// Removes the oldest pair equal to `(key, val)`, returning whether one
+    /// was found.
+    pub fn remove(&self, key: &K, val: &V) -> bool {
+        loop {
+            let found = self.tree.range((key.clone(), 0)..=(key.clone(), u64::MAX))
+                .find(|(_, v)| v == val);
+            match found {
+                None => return false,
+                Some((slot, _)) => {
+                    // lost a race with another remover, look again
+                    if self.tree.remove(&slot).is_some() {
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes every value stored under `key`, returning how many there were.
+    pub fn remove_all(&self, key: &K) -> usize {
+        self.tree.remove_range((key.clone(), 0)..=(key.clone(), u64::MAX))
+    }
+
+    /// Removes every value stored under a key in `range`, returning how many
+    /// there were.
+    pub fn remove_range<R: RangeBounds<K>>(&self, range: R) -> usize {
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included((key.clone(), 0)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Bound::Included((key.clone(), u64::MAX)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.tree.remove_range((start, end))
+    }
+
+    /// Moves every pair of `other` into this multimap and leaves `other`
+    /// empty. The pairs are numbered after every pair inserted here so far,
+    /// keeping the order they had among themselves, then handed over in one
+    /// `Bptree::append`, which hangs them off this tree whole when all of
+    /// their keys sort after this one's.
+    pub fn append(&self, other: &mut Self) {
+        let pairs: Vec<(K, V)> = other.iter().collect();
+        other.tree.remove_range(..);
+        let first = self.inserted.fetch_add(pairs.len() as u64, Ordering::SeqCst);
+        for (n, (key, val)) in (first..).zip(pairs) {
+            other.tree.set((key, n), val);
+        }
+        self.tree.append(&mut other.tree);
+    }
+
+    /// Iterates over all pairs in key order, the values of a key in the
+    /// order `get_all` yields them.
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.tree.iter().map(|((key, _), val)| (key, val))
+    }
+}
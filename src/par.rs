@@ -0,0 +1,34 @@
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use rayon::prelude::*;
+use super::bptree::Bptree;
+use super::kvtype::KVType;
+
+// Runs to cut a scan into per pool thread, so threads that finish early
+// can pick up more work.
+const RUNS_PER_THREAD: usize = 4;
+
+impl<K, V> Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType + Send + Sync,
+          V : Debug + Clone + Ord + KVType + Send + Sync,
+{
+    /// Scans `range` on the rayon thread pool. The range is cut at the
+    /// separator keys of an inner level into runs of whole subtrees, and
+    /// each run is read like `range` would read it. Collecting the result
+    /// keeps key order.
+    pub fn par_range<R: RangeBounds<K>>(&self, range: R) -> impl ParallelIterator<Item = (K, V)> + '_ {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        let fences: Vec<K> = self.fence_keys(rayon::current_num_threads() * RUNS_PER_THREAD)
+            .into_iter()
+            .filter(|fence| (start.as_ref(), end.as_ref()).contains(fence))
+            .collect();
+        let lows: Vec<Bound<K>> = std::iter::once(start).chain(fences.iter().cloned().map(Bound::Included)).collect();
+        let highs = fences.into_iter().map(Bound::Excluded).chain(std::iter::once(end));
+        let runs: Vec<(Bound<K>, Bound<K>)> = lows.into_iter().zip(highs).collect();
+        runs.into_par_iter().flat_map_iter(move |run| self.range(run))
+    }
+
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = (K, V)> + '_ {
+        self.par_range(..)
+    }
+}
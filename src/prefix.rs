@@ -0,0 +1,58 @@
+use std::fmt::Debug;
+use std::ops::Bound;
+use super::bptree::Bptree;
+use super::iter::Range;
+use super::kvtype::KVType;
+
+/// Keys that can be matched by prefix.
+pub trait PrefixKey: Sized {
+    /// The smallest key ordering after every key that starts with `self`,
+    /// or `None` when every larger key starts with `self`.
+    fn prefix_end(&self) -> Option<Self>;
+}
+
+impl PrefixKey for Vec<u8> {
+    fn prefix_end(&self) -> Option<Self> {
+        let mut end = self.clone();
+        while let Some(last) = end.pop() {
+            if last < u8::MAX {
+                end.push(last + 1);
+                return Some(end);
+            }
+        }
+        None
+    }
+}
+
+impl PrefixKey for String {
+    fn prefix_end(&self) -> Option<Self> {
+        let mut end = self.clone();
+        while let Some(last) = end.pop() {
+            // skip over the surrogate gap, which holds no chars
+            let next = match last as u32 + 1 {
+                0xD800 => Some('\u{E000}'),
+                code => char::from_u32(code),
+            };
+            if let Some(next) = next {
+                end.push(next);
+                return Some(end);
+            }
+        }
+        None
+    }
+}
+
+impl<K, V> Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType + PrefixKey,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// Iterates over the entries whose keys start with `prefix`, in key
+    /// order, without visiting anything past the last match.
+    pub fn scan_prefix(&self, prefix: &K) -> Range<'_, K, V> {
+        let end = match prefix.prefix_end() {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.range((Bound::Included(prefix.clone()), end))
+    }
+}
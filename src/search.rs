@@ -0,0 +1,181 @@
+//! Vectorized search within a node for integer keys. Nodes are small enough
+//! that comparing every key a few lanes at a time beats halving the range
+//! with a branch per step, so the scan below stands in for `binary_search`.
+//!
+//! There is one set of kernels per key width, working on the keys' bits as
+//! unsigned lanes; signed keys share them by flipping their sign bit, which
+//! maps their order onto the unsigned one. x86_64 takes AVX2 where it is
+//! there and SSE otherwise, aarch64 takes NEON.
+use std::slice;
+
+/// Same result as `keys.binary_search(&key)` on sorted, distinct keys.
+pub fn search<T: Lane>(keys: &[T], key: T) -> Result<usize, usize> {
+    let i = T::lower_bound(keys, key);
+    if keys.get(i) == Some(&key) { Ok(i) } else { Err(i) }
+}
+
+// Below a few vectors' worth of keys the setup costs more than it saves.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const MIN_VECTOR_LEN: usize = 16;
+
+/// Integer key types with a vectorized node search.
+pub trait Lane: Copy + Ord {
+    #[doc(hidden)]
+    fn lower_bound(keys: &[Self], key: Self) -> usize;
+
+    // The answer of every path `lower_bound` may take on this machine,
+    // scalar first, for tests to hold them to the same one.
+    #[doc(hidden)]
+    #[cfg(test)]
+    fn all_paths(keys: &[Self], key: Self) -> Vec<usize>;
+}
+
+// x86 only compares signed lanes, so unsigned lanes get their sign bit
+// flipped and signed ones are compared as they are. The keys are sorted, so
+// the lanes below `key` are a prefix of each vector, and the first vector
+// with a key at or above `key` ends the scan. Every lane sets one mask bit
+// per byte, hence the division.
+macro_rules! x86_kernels {
+    ($avx2:ident, $sse:ident, $sse_feature:tt, $u:ty, $s:ty,
+     $set1_256:ident, $cmpgt_256:ident, $set1_128:ident, $cmpgt_128:ident) => {
+        #[cfg(target_arch = "x86_64")]
+        #[target_feature(enable = "avx2")]
+        unsafe fn $avx2(keys: &[$u], key: $u, signed: bool) -> usize {
+            use std::arch::x86_64::*;
+            let bias = if signed { 0 } else { <$s>::MIN as $u };
+            let flip = $set1_256(bias as $s);
+            let needle = $set1_256((key ^ bias) as $s);
+            let chunks = keys.chunks_exact(32 / std::mem::size_of::<$u>());
+            let rest = chunks.remainder();
+            let mut less = 0;
+            for chunk in chunks {
+                let lanes = _mm256_xor_si256(_mm256_loadu_si256(chunk.as_ptr() as *const __m256i), flip);
+                let mask = _mm256_movemask_epi8($cmpgt_256(needle, lanes)) as u32;
+                less += mask.count_ones() as usize / std::mem::size_of::<$u>();
+                if mask != u32::MAX {
+                    return less;
+                }
+            }
+            less + rest.iter().take_while(|k| ((**k ^ bias) as $s) < ((key ^ bias) as $s)).count()
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        #[target_feature(enable = $sse_feature)]
+        unsafe fn $sse(keys: &[$u], key: $u, signed: bool) -> usize {
+            use std::arch::x86_64::*;
+            let bias = if signed { 0 } else { <$s>::MIN as $u };
+            let flip = $set1_128(bias as $s);
+            let needle = $set1_128((key ^ bias) as $s);
+            let chunks = keys.chunks_exact(16 / std::mem::size_of::<$u>());
+            let rest = chunks.remainder();
+            let mut less = 0;
+            for chunk in chunks {
+                let lanes = _mm_xor_si128(_mm_loadu_si128(chunk.as_ptr() as *const __m128i), flip);
+                let mask = _mm_movemask_epi8($cmpgt_128(needle, lanes)) as u32;
+                less += mask.count_ones() as usize / std::mem::size_of::<$u>();
+                if mask != 0xFFFF {
+                    return less;
+                }
+            }
+            less + rest.iter().take_while(|k| ((**k ^ bias) as $s) < ((key ^ bias) as $s)).count()
+        }
+    };
+}
+
+x86_kernels!(lower_bound_avx2_8, lower_bound_sse_8, "sse2", u8, i8,
+    _mm256_set1_epi8, _mm256_cmpgt_epi8, _mm_set1_epi8, _mm_cmpgt_epi8);
+x86_kernels!(lower_bound_avx2_16, lower_bound_sse_16, "sse2", u16, i16,
+    _mm256_set1_epi16, _mm256_cmpgt_epi16, _mm_set1_epi16, _mm_cmpgt_epi16);
+x86_kernels!(lower_bound_avx2_32, lower_bound_sse_32, "sse2", u32, i32,
+    _mm256_set1_epi32, _mm256_cmpgt_epi32, _mm_set1_epi32, _mm_cmpgt_epi32);
+x86_kernels!(lower_bound_avx2_64, lower_bound_sse_64, "sse4.2", u64, i64,
+    _mm256_set1_epi64x, _mm256_cmpgt_epi64, _mm_set1_epi64x, _mm_cmpgt_epi64);
+
+// NEON compares unsigned lanes, so it is the signed ones that get their sign
+// bit flipped. It has no movemask; the lanes below `key` are counted by
+// summing their low bits instead. NEON is always there on aarch64, so no
+// runtime check is needed.
+macro_rules! neon_kernel {
+    ($neon:ident, $u:ty, $dup:ident, $load:ident, $xor:ident, $lt:ident, $and:ident, $sum:ident) => {
+        #[cfg(target_arch = "aarch64")]
+        unsafe fn $neon(keys: &[$u], key: $u, signed: bool) -> usize {
+            use std::arch::aarch64::*;
+            const LANES: usize = 16 / std::mem::size_of::<$u>();
+            let bias: $u = if signed { 1 << (<$u>::BITS - 1) } else { 0 };
+            let flip = $dup(bias);
+            let needle = $dup(key ^ bias);
+            let one = $dup(1);
+            let chunks = keys.chunks_exact(LANES);
+            let rest = chunks.remainder();
+            let mut less = 0;
+            for chunk in chunks {
+                let lt = $lt($xor($load(chunk.as_ptr()), flip), needle);
+                let lanes = $sum($and(lt, one)) as usize;
+                less += lanes;
+                if lanes != LANES {
+                    return less;
+                }
+            }
+            less + rest.iter().take_while(|k| (**k ^ bias) < (key ^ bias)).count()
+        }
+    };
+}
+
+neon_kernel!(lower_bound_neon_8, u8, vdupq_n_u8, vld1q_u8, veorq_u8, vcltq_u8, vandq_u8, vaddvq_u8);
+neon_kernel!(lower_bound_neon_16, u16, vdupq_n_u16, vld1q_u16, veorq_u16, vcltq_u16, vandq_u16, vaddvq_u16);
+neon_kernel!(lower_bound_neon_32, u32, vdupq_n_u32, vld1q_u32, veorq_u32, vcltq_u32, vandq_u32, vaddvq_u32);
+neon_kernel!(lower_bound_neon_64, u64, vdupq_n_u64, vld1q_u64, veorq_u64, vcltq_u64, vandq_u64, vaddvq_u64);
+
+macro_rules! lane {
+    ($t:ty, $u:ty, $signed:expr, $avx2:ident, $sse:ident, $sse_feature:tt, $neon:ident) => {
+        impl Lane for $t {
+            fn lower_bound(keys: &[Self], key: Self) -> usize {
+                // same width, so the keys can be read as their bits in place
+                let bits = unsafe { slice::from_raw_parts(keys.as_ptr() as *const $u, keys.len()) };
+                #[cfg(target_arch = "x86_64")]
+                if keys.len() >= MIN_VECTOR_LEN {
+                    if is_x86_feature_detected!("avx2") {
+                        return unsafe { $avx2(bits, key as $u, $signed) };
+                    }
+                    if is_x86_feature_detected!($sse_feature) {
+                        return unsafe { $sse(bits, key as $u, $signed) };
+                    }
+                }
+                #[cfg(target_arch = "aarch64")]
+                if keys.len() >= MIN_VECTOR_LEN {
+                    return unsafe { $neon(bits, key as $u, $signed) };
+                }
+                let _ = bits;
+                keys.partition_point(|k| *k < key)
+            }
+
+            #[cfg(test)]
+            fn all_paths(keys: &[Self], key: Self) -> Vec<usize> {
+                let bits = unsafe { slice::from_raw_parts(keys.as_ptr() as *const $u, keys.len()) };
+                let mut found = vec![keys.partition_point(|k| *k < key)];
+                #[cfg(target_arch = "x86_64")]
+                {
+                    if is_x86_feature_detected!("avx2") {
+                        found.push(unsafe { $avx2(bits, key as $u, $signed) });
+                    }
+                    if is_x86_feature_detected!($sse_feature) {
+                        found.push(unsafe { $sse(bits, key as $u, $signed) });
+                    }
+                }
+                #[cfg(target_arch = "aarch64")]
+                found.push(unsafe { $neon(bits, key as $u, $signed) });
+                let _ = bits;
+                found
+            }
+        }
+    };
+}
+
+lane!(u8, u8, false, lower_bound_avx2_8, lower_bound_sse_8, "sse2", lower_bound_neon_8);
+lane!(i8, u8, true, lower_bound_avx2_8, lower_bound_sse_8, "sse2", lower_bound_neon_8);
+lane!(u16, u16, false, lower_bound_avx2_16, lower_bound_sse_16, "sse2", lower_bound_neon_16);
+lane!(i16, u16, true, lower_bound_avx2_16, lower_bound_sse_16, "sse2", lower_bound_neon_16);
+lane!(u32, u32, false, lower_bound_avx2_32, lower_bound_sse_32, "sse2", lower_bound_neon_32);
+lane!(i32, u32, true, lower_bound_avx2_32, lower_bound_sse_32, "sse2", lower_bound_neon_32);
+lane!(u64, u64, false, lower_bound_avx2_64, lower_bound_sse_64, "sse4.2", lower_bound_neon_64);
+lane!(i64, u64, true, lower_bound_avx2_64, lower_bound_sse_64, "sse4.2", lower_bound_neon_64);
@@ -0,0 +1,92 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::iter;
+use std::ops::RangeBounds;
+use super::bptree::Bptree;
+use super::kvtype::KVType;
+
+/// An ordered set: a tree whose values are all `()`, so no value data is
+/// stored. Next to the key in its leaf, each key carries only the sequence
+/// number of its last write and whether it is removed, 16 bytes on 64-bit
+/// targets. The history and time-to-live deadline an entry of a map may
+/// have are kept apart, and a set's keys never have them. Every operation
+/// is the tree's own, with the same latching.
+#[derive(Debug)]
+pub struct BptreeSet<K> {
+    tree: Bptree<K, ()>,
+}
+
+impl<K> BptreeSet<K>
+    where K : Debug + Clone + Ord + KVType,
+{
+    pub fn new(m: usize) -> Self {
+        Self{
+            tree: Bptree::new(m),
+        }
+    }
+
+    /// Adds `key`, returning whether it was not in the set yet.
+    pub fn insert(&self, key: K) -> bool {
+        self.tree.try_insert(key, ()).is_ok()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.tree.get(key).is_some()
+    }
+
+    /// Takes `key` out, returning whether it was in the set.
+    pub fn remove(&self, key: &K) -> bool {
+        self.tree.remove(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.count_range(..)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = K> + '_ {
+        self.tree.range(range).map(|(key, _)| key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = K> + '_ {
+        self.range(..)
+    }
+
+    /// Keys in either set, in order, each once.
+    pub fn union<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = K> + 'a {
+        let (mut ours, mut theirs) = (self.iter().peekable(), other.iter().peekable());
+        iter::from_fn(move || {
+            let order = match (ours.peek(), theirs.peek()) {
+                (Some(a), Some(b)) => a.cmp(b),
+                (Some(_), None) => Ordering::Less,
+                (None, _) => Ordering::Greater,
+            };
+            match order {
+                Ordering::Less => ours.next(),
+                Ordering::Greater => theirs.next(),
+                Ordering::Equal => {
+                    theirs.next();
+                    ours.next()
+                },
+            }
+        })
+    }
+
+    /// Keys in both sets, in order.
+    pub fn intersection<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = K> + 'a {
+        let (mut ours, mut theirs) = (self.iter().peekable(), other.iter().peekable());
+        iter::from_fn(move || loop {
+            match ours.peek()?.cmp(theirs.peek()?) {
+                Ordering::Less => {ours.next();},
+                Ordering::Greater => {theirs.next();},
+                Ordering::Equal => {
+                    theirs.next();
+                    return ours.next();
+                },
+            }
+        })
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use super::bptree::Bptree;
+use super::kvtype::KVType;
+
+/// A set of independent trees, each owning a contiguous slice of the key
+/// space. Writers to different shards never touch the same root latch, so
+/// write-heavy workloads spread across shards scale with the thread count.
+/// Shards are ordered by key, so iterating them one after another yields a
+/// single ordered sequence.
+#[derive(Debug)]
+pub struct ShardedBptree<K, V> {
+    // shard `i` holds the keys in `bounds[i-1]..bounds[i]`
+    bounds: Vec<K>,
+    shards: Vec<Bptree<K, V>>,
+}
+
+impl<K, V> ShardedBptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// Creates one shard per gap between `bounds`, which are sorted and
+    /// deduplicated first: `n` bounds give `n + 1` shards of order `m`.
+    pub fn new(m: usize, mut bounds: Vec<K>) -> Self {
+        bounds.sort();
+        bounds.dedup();
+        let shards = (0..=bounds.len()).map(|_| Bptree::new(m)).collect();
+        Self{
+            bounds,
+            shards,
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_index(&self, key: &K) -> usize {
+        match self.bounds.binary_search(key) {
+            Ok(i) => i+1,
+            Err(i) => i,
+        }
+    }
+
+    fn shard_for(&self, key: &K) -> &Bptree<K, V> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard_for(key).get(key)
+    }
+
+    pub fn set(&self, key: K, val: V) {
+        self.shard_for(&key).set(key, val)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard_for(key).remove(key)
+    }
+
+    /// Iterates over the entries whose keys fall in `range`, in key order,
+    /// visiting only the shards that overlap it.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = (K, V)> + '_ {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let first = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => self.shard_index(key),
+            Bound::Unbounded => 0,
+        };
+        let last = match &end {
+            Bound::Included(key) | Bound::Excluded(key) => self.shard_index(key),
+            Bound::Unbounded => self.shards.len() - 1,
+        };
+        let shards = if first <= last { &self.shards[first..=last] } else { &self.shards[..0] };
+        shards.iter().flat_map(move |shard| shard.range((start.clone(), end.clone())))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.range(..)
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt::Debug;
+use std::ops::RangeBounds;
+use super::bptree::Bptree;
+use super::diff::Diff;
+use super::iter::{Range, ResumeToken, ScanProgress};
+use super::kvtype::KVType;
+
+/// A frozen view of a tree as of the moment it was taken. While it is open
+/// writers keep the versions it can see, as under a version horizon, so
+/// reads through it stay repeatable however the tree changes meanwhile.
+/// No latch is held between reads. Writes already in flight when the
+/// snapshot is taken may or may not show up in it. A range read through it
+/// judges expiry as of a single moment, so it knows its length exactly.
+pub struct Snapshot<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    tree: &'a Bptree<K, V>,
+    seq: u64,
+}
+
+impl<K, V> Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// Opens a snapshot of the tree as it is now. Writes keep old versions
+    /// around until it is dropped, so drop it once done reading.
+    pub fn snapshot(&self) -> Snapshot<'_, K, V> {
+        Snapshot{
+            tree: self,
+            seq: self.pin(),
+        }
+    }
+
+    /// Iterates over the tree as it is now, unaffected by writes made
+    /// while the iteration runs. Owns its snapshot, see `snapshot`.
+    pub fn iter_snapshot(&self) -> SnapshotIter<'_, K, V> {
+        let snapshot = self.snapshot();
+        SnapshotIter{
+            range: SnapshotRange(self.iter().at(snapshot.seq)),
+            _snapshot: snapshot,
+        }
+    }
+}
+
+impl<'a, K, V> Snapshot<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// Sequence number the snapshot reads at.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.tree.get_as_of(key, self.seq)
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> SnapshotRange<'_, K, V> {
+        SnapshotRange(self.tree.range(range).at(self.seq))
+    }
+
+    pub fn iter(&self) -> SnapshotRange<'_, K, V> {
+        self.range(..)
+    }
+
+    /// Changes that turn the snapshot into the tree as it is now: the delta
+    /// to ship to a replica holding the snapshot's contents. Only entries
+    /// written since the snapshot was taken are read, and since it keeps
+    /// the versions it sees, their old values are always known.
+    pub fn diff(&self) -> Diff<'_, K, V> {
+        self.tree.diff_at(self.seq)
+    }
+}
+
+impl<'a, K, V> Drop for Snapshot<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    fn drop(&mut self) {
+        self.tree.unpin(self.seq);
+    }
+}
+
+/// A `Range` read through a snapshot. Nothing it reads can change, so it
+/// knows how many entries are left: the first call to `len` or `size_hint`
+/// counts them in one pass over the leaves, without copying any. Expiry is
+/// the exception: purging expired entries or calling `expire_range` while
+/// it runs can still take entries away and leave it short of its count.
+pub struct SnapshotRange<'a, K, V>(Range<'a, K, V>)
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType;
+
+impl<'a, K, V> SnapshotRange<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// See `Range::on_progress`.
+    pub fn on_progress<F: FnMut(&ScanProgress) + 'a>(self, f: F) -> Self {
+        Self(self.0.on_progress(f))
+    }
+
+    /// See `Range::token`.
+    pub fn token(&self) -> ResumeToken<K> {
+        self.0.token()
+    }
+}
+
+impl<'a, K, V> Iterator for SnapshotRange<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+
+    fn last(self) -> Option<Self::Item> {
+        self.0.last()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for SnapshotRange<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back()
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for SnapshotRange<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{}
+
+/// Iterator over a snapshot it owns, returned by `Bptree::iter_snapshot`.
+pub struct SnapshotIter<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    range: SnapshotRange<'a, K, V>,
+    _snapshot: Snapshot<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for SnapshotIter<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.range.size_hint()
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for SnapshotIter<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{}
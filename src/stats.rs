@@ -0,0 +1,16 @@
+/// Shape of a tree as measured by `Bptree::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeStats {
+    /// Levels from the root down to the leaves, 0 for an empty tree.
+    pub height: usize,
+    pub inner_nodes: usize,
+    pub leaf_nodes: usize,
+    /// Live entries; tombstones are not counted.
+    pub entries: usize,
+    /// Share of leaf capacity in use, averaged over the leaves. Tombstones
+    /// still take up a slot.
+    pub avg_fill: f64,
+    /// Fill of the emptiest and of the fullest leaf.
+    pub min_fill: f64,
+    pub max_fill: f64,
+}
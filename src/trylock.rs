@@ -0,0 +1,72 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, RawRwLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Why a `try_` or `_timeout` operation gave up on a latch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockError {
+    /// A latch was held and the operation was not allowed to wait.
+    WouldBlock,
+    /// A latch was still held when the deadline passed.
+    Timeout,
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::WouldBlock => write!(f, "a latch on the tree is held"),
+            LockError::Timeout => write!(f, "timed out waiting for a latch on the tree"),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+// How long one operation may wait, all latches together.
+pub(crate) enum Wait {
+    Forever,
+    Never,
+    Until(Instant),
+}
+
+impl Wait {
+    pub(crate) fn fail(&self) -> LockError {
+        match self {
+            Wait::Forever | Wait::Never => LockError::WouldBlock,
+            Wait::Until(_) => LockError::Timeout,
+        }
+    }
+
+    pub(crate) fn read<T>(&self, lock: &Arc<RwLock<T>>) -> Result<ArcRwLockReadGuard<RawRwLock, T>, LockError> {
+        match self {
+            Wait::Forever => return Ok(lock.read_arc()),
+            Wait::Never => lock.try_read_arc(),
+            Wait::Until(deadline) => lock.try_read_arc_until(*deadline),
+        }.ok_or_else(|| self.fail())
+    }
+
+    pub(crate) fn write<T>(&self, lock: &Arc<RwLock<T>>) -> Result<ArcRwLockWriteGuard<RawRwLock, T>, LockError> {
+        match self {
+            Wait::Forever => return Ok(lock.write_arc()),
+            Wait::Never => lock.try_write_arc(),
+            Wait::Until(deadline) => lock.try_write_arc_until(*deadline),
+        }.ok_or_else(|| self.fail())
+    }
+
+    pub(crate) fn read_root<'a, T>(&self, lock: &'a RwLock<T>) -> Result<RwLockReadGuard<'a, T>, LockError> {
+        match self {
+            Wait::Forever => return Ok(lock.read()),
+            Wait::Never => lock.try_read(),
+            Wait::Until(deadline) => lock.try_read_until(*deadline),
+        }.ok_or_else(|| self.fail())
+    }
+
+    pub(crate) fn write_root<'a, T>(&self, lock: &'a RwLock<T>) -> Result<RwLockWriteGuard<'a, T>, LockError> {
+        match self {
+            Wait::Forever => return Ok(lock.write()),
+            Wait::Never => lock.try_write(),
+            Wait::Until(deadline) => lock.try_write_until(*deadline),
+        }.ok_or_else(|| self.fail())
+    }
+}
@@ -0,0 +1,56 @@
+use std::cell::OnceCell;
+use std::ops::{Bound, RangeBounds};
+use std::time::Instant;
+
+/// The current time, read from the system clock the first time it is asked
+/// for. Lookups only ask once some range expiry is registered, so a tree
+/// that never uses expiry runs on targets without a clock, such as
+/// `wasm32-unknown-unknown`, where `Instant::now` panics.
+#[derive(Debug, Default)]
+pub struct Now(OnceCell<Instant>);
+
+impl Now {
+    pub fn get(&self) -> Instant {
+        *self.0.get_or_init(Instant::now)
+    }
+}
+
+/// An expiry attached to a whole key range. Entries inside the range are
+/// hidden once `deadline` passes and physically removed on the next purge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeTtl<K> {
+    start: Bound<K>,
+    end: Bound<K>,
+    deadline: Instant,
+}
+
+impl<K: Ord + Clone> RangeTtl<K> {
+    pub fn new<R: RangeBounds<K>>(range: R, deadline: Instant) -> Self {
+        Self{
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            deadline,
+        }
+    }
+
+    pub fn start(&self) -> Bound<&K> {
+        self.start.as_ref()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        (self.start.as_ref(), self.end.as_ref()).contains(key)
+    }
+
+    /// True if `key` lies beyond the end of the range.
+    pub fn is_past(&self, key: &K) -> bool {
+        match &self.end {
+            Bound::Included(end) => key > end,
+            Bound::Excluded(end) => key >= end,
+            Bound::Unbounded => false,
+        }
+    }
+
+    pub fn expired(&self, now: &Now) -> bool {
+        now.get() >= self.deadline
+    }
+}
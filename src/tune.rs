@@ -0,0 +1,93 @@
+use std::fmt::Debug;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use super::bptree::Bptree;
+use super::builder::MIN_ORDER;
+use super::kvtype::KVType;
+
+/// Orders tried by `Bptree::auto_tune`.
+pub const CANDIDATE_ORDERS: [usize; 6] = [4, 8, 16, 32, 64, 128];
+
+/// One operation of a sample workload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op<K, V> {
+    Get(K),
+    Set(K, V),
+    Remove(K),
+}
+
+/// What replaying the sample against one order measured.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trial {
+    pub m: usize,
+    pub elapsed: Duration,
+    pub leaves: usize,
+    /// Average share of leaf capacity in use once the sample finished.
+    pub fill: f64,
+}
+
+/// Outcome of `Bptree::auto_tune`: every trial, fastest first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuneReport {
+    pub trials: Vec<Trial>,
+}
+
+impl TuneReport {
+    /// The order that replayed the sample fastest.
+    pub fn best(&self) -> &Trial {
+        &self.trials[0]
+    }
+}
+
+impl<K, V> Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// Replays `sample` against a fresh in-memory tree for each of
+    /// `CANDIDATE_ORDERS` and reports how each one did, so `m` can be picked
+    /// from measurements on the real workload.
+    pub fn auto_tune(sample: &[Op<K, V>]) -> TuneReport {
+        let mut trials: Vec<Trial> = CANDIDATE_ORDERS.iter().map(|&m| {
+            let tree = Bptree::new(m);
+            let start = Instant::now();
+            for op in sample {
+                match op {
+                    Op::Get(key) => {tree.get(key);},
+                    Op::Set(key, val) => tree.set(key.clone(), val.clone()),
+                    Op::Remove(key) => {tree.remove(key);},
+                }
+            }
+            let elapsed = start.elapsed();
+            let stats = tree.stats();
+            Trial{ m, elapsed, leaves: stats.leaf_nodes, fill: stats.avg_fill }
+        }).collect();
+        trials.sort_by_key(|trial| trial.elapsed);
+        TuneReport{ trials }
+    }
+
+    /// An empty tree whose order is picked so a full leaf of entries like
+    /// those in `sample` takes about `node_bytes` once serialized, e.g. 4096
+    /// for a page. Sizes are averaged over the sample; an empty sample falls
+    /// back to the in-memory size of an entry.
+    pub fn with_node_bytes(node_bytes: usize, sample: &[(K, V)]) -> Self
+        where K: Serialize, V: Serialize
+    {
+        Bptree::new(order_for_node_bytes(node_bytes, sample))
+    }
+}
+
+/// The order at which a full leaf of entries shaped like `sample` comes to
+/// about `node_bytes` serialized, but never below `MIN_ORDER`.
+pub fn order_for_node_bytes<K: Serialize, V: Serialize>(node_bytes: usize, sample: &[(K, V)]) -> usize {
+    let entry_bytes = match sample.len() {
+        0 => size_of::<(K, V)>(),
+        len => {
+            let total: u64 = sample.iter()
+                .map(|(key, val)| bincode::serialized_size(key).unwrap() + bincode::serialized_size(val).unwrap())
+                .sum();
+            (total as usize).div_ceil(len)
+        },
+    };
+    (node_bytes / entry_bytes.max(1) + 1).max(MIN_ORDER)
+}
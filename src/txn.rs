@@ -0,0 +1,65 @@
+use std::fmt::Debug;
+use super::batch::WriteBatch;
+use super::bptree::Bptree;
+use super::error::BptreeError;
+use super::kvtype::KVType;
+
+/// A batch of writes against a `Bptree` that becomes visible all at once on
+/// `commit`. Dropping the transaction without committing discards it.
+///
+/// The tree stays shared while a transaction is open, so other threads keep
+/// reading and writing it. Reads through the transaction are not isolated
+/// from them: a key the transaction has not written reads whatever the tree
+/// holds at that moment. Only the commit is atomic; it goes through
+/// `Bptree::apply`.
+pub struct Txn<'a, K, V> {
+    tree: &'a Bptree<K, V>,
+    // pending writes sorted by key, `None` is a pending remove
+    writes: Vec<(K, Option<V>)>,
+}
+
+impl<'a, K, V> Txn<'a, K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    pub fn new(tree: &'a Bptree<K, V>) -> Self {
+        Self{
+            tree,
+            writes: vec![],
+        }
+    }
+
+    /// Reads through the transaction, seeing its own uncommitted writes.
+    pub fn get(&self, key: &K) -> Option<V> {
+        match self.writes.binary_search_by(|(k, _)| k.cmp(key)) {
+            Ok(i) => self.writes[i].1.clone(),
+            Err(_) => self.tree.get(key),
+        }
+    }
+
+    pub fn set(&mut self, key: K, val: V) {
+        self.buffer(key, Some(val));
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let old_val = self.get(key);
+        self.buffer(key.clone(), None);
+        old_val
+    }
+
+    /// Writes the transaction through `Bptree::apply`, so the tree's
+    /// duplicate policy and size limits hold for it as for any batch, and a
+    /// refused commit writes nothing.
+    pub fn commit(self) -> Result<(), BptreeError<K, V>> {
+        self.tree.apply(WriteBatch{ ops: self.writes })
+    }
+
+    pub fn rollback(self) {}
+
+    fn buffer(&mut self, key: K, val: Option<V>) {
+        match self.writes.binary_search_by(|(k, _)| k.cmp(&key)) {
+            Ok(i) => {self.writes[i].1 = val;}
+            Err(i) => {self.writes.insert(i, (key, val));}
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::Instant;
+use super::ttl::Now;
+
+/// All versions of one entry. A `None` value marks the entry as removed as
+/// of that sequence number. An entry with neither history nor a time to
+/// live, which is every entry of a tree without a version horizon that is
+/// not written with a TTL, holds just its latest version and sequence
+/// number inline; the history and deadline only take up room, boxed, once
+/// an entry has them.
+#[derive(Debug, Clone)]
+pub struct Versions<V>(Repr<V>);
+
+#[derive(Debug, Clone)]
+enum Repr<V> {
+    Latest((u64, Option<V>)),
+    Full(Box<Full<V>>),
+}
+
+#[derive(Debug, Clone)]
+struct Full<V> {
+    latest: (u64, Option<V>),
+    // versions before the latest, oldest first
+    older: Vec<(u64, Option<V>)>,
+    // when the latest version stops being visible, if it was written with
+    // a time to live
+    deadline: Option<Instant>,
+}
+
+impl<V> Versions<V> {
+    pub fn new(seq: u64, val: V) -> Self {
+        Self(Repr::Latest((seq, Some(val))))
+    }
+
+    // Bytes allocated beside the entry for its history and deadline.
+    #[cfg(test)]
+    pub(crate) fn history_bytes(&self) -> usize {
+        match &self.0 {
+            Repr::Latest(..) => 0,
+            Repr::Full(full) => std::mem::size_of::<Full<V>>() + full.older.capacity() * std::mem::size_of::<(u64, Option<V>)>(),
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        match &self.0 {
+            Repr::Latest(..) => None,
+            Repr::Full(full) => full.deadline,
+        }
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        if deadline.is_some() || matches!(self.0, Repr::Full(_)) {
+            self.full().deadline = deadline;
+            self.settle();
+        }
+    }
+
+    /// True once the latest version has outlived its deadline. Only entries
+    /// with a deadline read the clock.
+    pub fn expired(&self, now: &Now) -> bool {
+        self.deadline().is_some_and(|deadline| now.get() >= deadline)
+    }
+
+    pub fn latest(&self) -> Option<&V> {
+        self.latest_version().1.as_ref()
+    }
+
+    pub fn latest_mut(&mut self) -> Option<&mut V> {
+        match &mut self.0 {
+            Repr::Latest(latest) => latest.1.as_mut(),
+            Repr::Full(full) => full.latest.1.as_mut(),
+        }
+    }
+
+    pub fn into_latest(self) -> Option<V> {
+        match self.0 {
+            Repr::Latest(latest) => latest.1,
+            Repr::Full(full) => full.latest.1,
+        }
+    }
+
+    pub fn latest_seq(&self) -> u64 {
+        self.latest_version().0
+    }
+
+    pub fn at(&self, seq: u64) -> Option<&V> {
+        let (latest_seq, latest) = self.latest_version();
+        if *latest_seq <= seq {
+            return latest.as_ref();
+        }
+        let older = match &self.0 {
+            Repr::Latest(..) => return None,
+            Repr::Full(full) => &full.older,
+        };
+        match older.iter().rposition(|(s, _)| *s <= seq) {
+            Some(i) => older[i].1.as_ref(),
+            None => None,
+        }
+    }
+
+    /// Records a new version. Without a horizon no history is kept and the
+    /// chain collapses to the new version. Any deadline belonged to the
+    /// version being replaced and is dropped.
+    pub fn push(&mut self, seq: u64, val: Option<V>, horizon: Option<u64>) {
+        let horizon = match horizon {
+            None => {
+                self.0 = Repr::Latest((seq, val));
+                return;
+            },
+            Some(horizon) => horizon,
+        };
+        let full = self.full();
+        full.deadline = None;
+        let replaced = std::mem::replace(&mut full.latest, (seq, val));
+        full.older.push(replaced);
+        self.prune(horizon);
+    }
+
+    /// Drops versions that no read at `horizon` or later can observe,
+    /// freeing the history once only the latest is left.
+    pub fn prune(&mut self, horizon: u64) {
+        let full = match &mut self.0 {
+            Repr::Latest(..) => return,
+            Repr::Full(full) => full,
+        };
+        if full.latest.0 <= horizon {
+            full.older = Vec::new();
+        }
+        else if let Some(i) = full.older.iter().rposition(|(s, _)| *s <= horizon) {
+            full.older.drain(..i);
+        }
+        self.settle();
+    }
+
+    /// True once the entry is deleted for every read at `horizon` or later.
+    pub fn is_dead(&self, horizon: Option<u64>) -> bool {
+        match self.latest_version() {
+            (seq, None) => horizon.is_none_or(|horizon| *seq <= horizon),
+            _ => false,
+        }
+    }
+
+    fn latest_version(&self) -> &(u64, Option<V>) {
+        match &self.0 {
+            Repr::Latest(latest) => latest,
+            Repr::Full(full) => &full.latest,
+        }
+    }
+
+    // The boxed form, moving the entry into it if it is not there yet.
+    fn full(&mut self) -> &mut Full<V> {
+        if let Repr::Latest(latest) = &mut self.0 {
+            let latest = (latest.0, latest.1.take());
+            self.0 = Repr::Full(Box::new(Full{ latest, older: Vec::new(), deadline: None }));
+        }
+        match &mut self.0 {
+            Repr::Full(full) => full,
+            Repr::Latest(..) => unreachable!(),
+        }
+    }
+
+    // Back to the inline form once neither history nor deadline is left.
+    fn settle(&mut self) {
+        if let Repr::Full(full) = &mut self.0 {
+            if full.older.is_empty() && full.deadline.is_none() {
+                let latest = (full.latest.0, full.latest.1.take());
+                self.0 = Repr::Latest(latest);
+            }
+        }
+    }
+}
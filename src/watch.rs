@@ -0,0 +1,81 @@
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use super::bptree::Bptree;
+use super::hook::IndexHook;
+use super::kvtype::KVType;
+
+/// One change to an entry, as delivered to a receiver from `Bptree::watch`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeEvent<K, V> {
+    Insert{ key: K, val: V },
+    Update{ key: K, old: V, new: V },
+    Remove{ key: K, old: V },
+}
+
+// Forwards the changes to keys in its range to a channel, retiring itself
+// once the receiving end is gone.
+struct Watcher<K, V> {
+    start: Bound<K>,
+    end: Bound<K>,
+    sender: Sender<ChangeEvent<K, V>>,
+    closed: AtomicBool,
+}
+
+impl<K: Ord, V> Watcher<K, V> {
+    fn send(&self, key: &K, event: impl FnOnce() -> ChangeEvent<K, V>) {
+        if self.closed.load(Ordering::SeqCst) || !(self.start.as_ref(), self.end.as_ref()).contains(key) {
+            return;
+        }
+        if self.sender.send(event()).is_err() {
+            self.closed.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<K, V> IndexHook<K, V> for Watcher<K, V>
+    where K: Ord + Clone + Send + Sync,
+          V: Clone + Send,
+{
+    fn on_insert(&self, key: &K, val: &V) {
+        self.send(key, || ChangeEvent::Insert{ key: key.clone(), val: val.clone() });
+    }
+
+    fn on_update(&self, key: &K, old: &V, new: &V) {
+        self.send(key, || ChangeEvent::Update{ key: key.clone(), old: old.clone(), new: new.clone() });
+    }
+
+    fn on_remove(&self, key: &K, old: &V) {
+        self.send(key, || ChangeEvent::Remove{ key: key.clone(), old: old.clone() });
+    }
+
+    fn is_retired(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+impl<K, V> Bptree<K, V>
+    where K : Debug + Clone + Ord + KVType,
+          V : Debug + Clone + Ord + KVType,
+{
+    /// Subscribes to the changes of keys in `range` from now on. Events are
+    /// sent from the write path as hooks are, see `IndexHook` for which
+    /// writes report, so each key's events arrive in the order the writes
+    /// were made; events of different keys may interleave either way. The
+    /// channel is unbounded and never holds up a writer. Once the receiver
+    /// is dropped the watch stops, and is unregistered with the next hook.
+    pub fn watch<R: RangeBounds<K>>(&self, range: R) -> Receiver<ChangeEvent<K, V>>
+        where K: Send + Sync + 'static, V: Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.add_hook(Arc::new(Watcher{
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            sender,
+            closed: AtomicBool::new(false),
+        }));
+        receiver
+    }
+}